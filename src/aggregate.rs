@@ -0,0 +1,324 @@
+use std::{cmp::Ordering, collections::{BTreeMap, BTreeSet}, fmt, io::Error};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{alba_types::AlbaTypes, gerr, projection::{Projection, ProjectionItem}};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum AggregateFunction{
+    Count,
+    CountDistinct,
+    Sum,
+    Avg,
+    Min,
+    Max,
+    ApproxCountDistinct,
+}
+
+/// An aggregate of the column list of SEARCH, `column` is `None` for `COUNT(*)`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Aggregate{
+    pub function : AggregateFunction,
+    pub column : Option<String>,
+}
+
+impl fmt::Display for Aggregate{
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result{
+        let column = self.column.as_deref().unwrap_or("*");
+        match self.function{
+            AggregateFunction::Count => write!(f, "COUNT({})", column),
+            AggregateFunction::CountDistinct => write!(f, "COUNT(DISTINCT {})", column),
+            AggregateFunction::Sum => write!(f, "SUM({})", column),
+            AggregateFunction::Avg => write!(f, "AVG({})", column),
+            AggregateFunction::Min => write!(f, "MIN({})", column),
+            AggregateFunction::Max => write!(f, "MAX({})", column),
+            AggregateFunction::ApproxCountDistinct => write!(f, "APPROX_COUNT_DISTINCT({})", column),
+        }
+    }
+}
+
+impl Aggregate{
+    fn result_type(&self, column_type : Option<&AlbaTypes>) -> Result<AlbaTypes, Error>{
+        match (self.function, column_type){
+            (AggregateFunction::Count | AggregateFunction::CountDistinct | AggregateFunction::ApproxCountDistinct, _) => Ok(AlbaTypes::Bigint(0)),
+            (AggregateFunction::Sum, Some(AlbaTypes::Int(_) | AlbaTypes::Bigint(_))) => Ok(AlbaTypes::Bigint(0)),
+            (AggregateFunction::Sum, Some(AlbaTypes::Float(_))) => Ok(AlbaTypes::Float(0.0)),
+            (AggregateFunction::Avg, Some(AlbaTypes::Int(_) | AlbaTypes::Bigint(_) | AlbaTypes::Float(_))) => Ok(AlbaTypes::Float(0.0)),
+            (AggregateFunction::Min | AggregateFunction::Max, Some(column_type)) => Ok(column_type.clone()),
+            _ => Err(gerr(&format!("{} needs a numeric column", self)))
+        }
+    }
+}
+
+/// A value ordered by `AlbaTypes::total_cmp`, so it can key groups and distinct sets.
+#[derive(Debug, Clone)]
+pub struct OrderedValue(pub AlbaTypes);
+
+impl PartialEq for OrderedValue{
+    fn eq(&self, other : &Self) -> bool{
+        self.0.total_cmp(&other.0) == Ordering::Equal
+    }
+}
+impl Eq for OrderedValue{}
+impl PartialOrd for OrderedValue{
+    fn partial_cmp(&self, other : &Self) -> Option<Ordering>{
+        Some(self.cmp(other))
+    }
+}
+impl Ord for OrderedValue{
+    fn cmp(&self, other : &Self) -> Ordering{
+        self.0.total_cmp(&other.0)
+    }
+}
+
+enum Output{
+    Group(usize),
+    Aggregate(usize),
+}
+
+/// The aggregates and groups of a search, checked against its column list but not yet bound
+/// to the headers of a container.
+pub struct AggregateQuery{
+    group_by : Vec<String>,
+    aggregates : Vec<Aggregate>,
+    outputs : Vec<Output>,
+    names : Vec<String>,
+}
+
+/// Where the columns an aggregate query reads sit in the rows of one container.
+pub struct AggregateBinding{
+    group_positions : Vec<usize>,
+    aggregate_positions : Vec<Option<usize>>,
+    /// Columns the aggregation reads, to deserialize nothing else.
+    pub columns : Vec<bool>,
+    types : Vec<AlbaTypes>,
+}
+
+impl AggregateQuery{
+    /// Every plain column of the list has to be one of the `group_by` columns.
+    pub fn new(projection : &Projection, group_by : &[String]) -> Result<Self, Error>{
+        let mut query = AggregateQuery { group_by: group_by.to_vec(), aggregates: Vec::new(), outputs: Vec::new(), names: Vec::new() };
+        for item in projection.items.iter(){
+            match item{
+                ProjectionItem::All => return Err(gerr("A SEARCH with aggregates or GROUP BY can not use *, list the GROUP BY columns instead")),
+                ProjectionItem::Column { name, alias } => {
+                    let group = match group_by.iter().position(|g| g == name){
+                        Some(group) => group,
+                        None => return Err(gerr(&format!("The column {} must appear in GROUP BY or be used in an aggregate", name)))
+                    };
+                    query.outputs.push(Output::Group(group));
+                    query.names.push(alias.clone().unwrap_or_else(|| name.clone()));
+                },
+                ProjectionItem::Aggregate { aggregate, alias } => {
+                    query.outputs.push(Output::Aggregate(query.aggregates.len()));
+                    query.names.push(alias.clone().unwrap_or_else(|| aggregate.to_string()));
+                    query.aggregates.push(aggregate.clone());
+                },
+                ProjectionItem::Expression { .. } => return Err(gerr("A SEARCH with aggregates or GROUP BY can not compute columns, only aggregate them"))
+            }
+        }
+        Ok(query)
+    }
+    pub fn names(&self) -> &[String]{
+        &self.names
+    }
+    /// Every name HAVING and ORDER BY can use for an output column, with its position: the output
+    /// name and, for an aliased aggregate, the aggregate as written.
+    pub fn referable_names(&self) -> Vec<(String, usize)>{
+        let mut names : Vec<(String, usize)> = self.names.iter().cloned().zip(0..).collect();
+        for (position, output) in self.outputs.iter().enumerate(){
+            if let Output::Aggregate(index) = output{
+                let written = self.aggregates[*index].to_string();
+                if written != self.names[position]{
+                    names.push((written, position));
+                }
+            }
+        }
+        names
+    }
+    pub fn bind(&self, headers : &[(String,AlbaTypes)], container_name : &str) -> Result<AggregateBinding, Error>{
+        let position = |column : &String| match headers.iter().position(|h| h.0 == *column){
+            Some(position) => Ok(position),
+            None => Err(gerr(&format!("There is no column {} in the container {}", column, container_name)))
+        };
+        let group_positions = self.group_by.iter().map(position).collect::<Result<Vec<usize>, Error>>()?;
+        let aggregate_positions = self.aggregates.iter().map(|a| a.column.as_ref().map(position).transpose()).collect::<Result<Vec<Option<usize>>, Error>>()?;
+        let mut columns = vec![false; headers.len()];
+        for p in group_positions.iter().chain(aggregate_positions.iter().flatten()){
+            columns[*p] = true;
+        }
+        let mut types = Vec::with_capacity(self.outputs.len());
+        for output in self.outputs.iter(){
+            types.push(match output{
+                Output::Group(group) => headers[group_positions[*group]].1.clone(),
+                Output::Aggregate(index) => self.aggregates[*index].result_type(aggregate_positions[*index].map(|p| &headers[p].1))?
+            });
+        }
+        Ok(AggregateBinding { group_positions, aggregate_positions, columns, types })
+    }
+}
+
+/// Bits of the fingerprint choosing the register of a `HyperLogLog`. 2^12 registers of a byte
+/// each give a standard error of about 1.6%.
+const HYPERLOGLOG_PRECISION : u32 = 12;
+
+/// Estimates the number of distinct values in a fixed 4 KiB whatever their count. Each value's
+/// fingerprint picks a register by its top bits, and the register keeps the longest run of
+/// leading zeros seen in the remaining bits.
+struct HyperLogLog{
+    registers : Vec<u8>,
+}
+
+impl HyperLogLog{
+    fn new() -> Self{
+        HyperLogLog { registers: vec![0; 1 << HYPERLOGLOG_PRECISION] }
+    }
+    fn add(&mut self, value : &AlbaTypes){
+        let hash = value.fingerprint();
+        let register = (hash >> (64 - HYPERLOGLOG_PRECISION)) as usize;
+        let rank = ((hash << HYPERLOGLOG_PRECISION).leading_zeros() + 1).min(64 - HYPERLOGLOG_PRECISION + 1) as u8;
+        if rank > self.registers[register]{
+            self.registers[register] = rank;
+        }
+    }
+    fn estimate(&self) -> u64{
+        let m = self.registers.len() as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum : f64 = self.registers.iter().map(|r| 2f64.powi(-(*r as i32))).sum();
+        let raw = alpha * m * m / sum;
+        let empty = self.registers.iter().filter(|r| **r == 0).count();
+        // few values leave registers empty, and counting those is more accurate than the raw estimate
+        if raw <= 2.5 * m && empty > 0{
+            return (m * (m / empty as f64).ln()).round() as u64
+        }
+        raw.round() as u64
+    }
+}
+
+enum State{
+    Count(u64),
+    Distinct(BTreeSet<OrderedValue>),
+    Approximate(HyperLogLog),
+    Sum{ integer : i128, float : f64, rows : u64 },
+    Avg{ total : f64, rows : u64 },
+    Min(Option<AlbaTypes>),
+    Max(Option<AlbaTypes>),
+}
+
+impl State{
+    fn new(function : AggregateFunction) -> Self{
+        match function{
+            AggregateFunction::Count => State::Count(0),
+            AggregateFunction::CountDistinct => State::Distinct(BTreeSet::new()),
+            AggregateFunction::Sum => State::Sum { integer: 0, float: 0.0, rows: 0 },
+            AggregateFunction::Avg => State::Avg { total: 0.0, rows: 0 },
+            AggregateFunction::Min => State::Min(None),
+            AggregateFunction::Max => State::Max(None),
+            AggregateFunction::ApproxCountDistinct => State::Approximate(HyperLogLog::new()),
+        }
+    }
+    // `value` is `None` for COUNT(*), NULL values are left out of every other aggregate.
+    fn add(&mut self, value : Option<&AlbaTypes>){
+        let value = match value{
+            Some(AlbaTypes::NONE) => return,
+            Some(value) => value,
+            None => {
+                if let State::Count(count) = self{
+                    *count += 1;
+                }
+                return
+            }
+        };
+        match self{
+            State::Count(count) => *count += 1,
+            State::Distinct(seen) => { seen.insert(OrderedValue(value.clone())); },
+            State::Approximate(sketch) => sketch.add(value),
+            State::Sum { integer, float, rows } => {
+                match value{
+                    AlbaTypes::Int(n) => *integer += *n as i128,
+                    AlbaTypes::Bigint(n) => *integer += *n as i128,
+                    AlbaTypes::Float(n) => *float += n,
+                    _ => return
+                }
+                *rows += 1;
+            },
+            State::Avg { total, rows } => {
+                *total += match value{
+                    AlbaTypes::Int(n) => *n as f64,
+                    AlbaTypes::Bigint(n) => *n as f64,
+                    AlbaTypes::Float(n) => *n,
+                    _ => return
+                };
+                *rows += 1;
+            },
+            State::Min(current) => if current.as_ref().is_none_or(|c| value.total_cmp(c) == Ordering::Less){
+                *current = Some(value.clone());
+            },
+            State::Max(current) => if current.as_ref().is_none_or(|c| value.total_cmp(c) == Ordering::Greater){
+                *current = Some(value.clone());
+            },
+        }
+    }
+    fn finish(self, result_type : &AlbaTypes) -> Result<AlbaTypes, Error>{
+        Ok(match self{
+            State::Count(count) => AlbaTypes::Bigint(count as i64),
+            State::Distinct(seen) => AlbaTypes::Bigint(seen.len() as i64),
+            State::Approximate(sketch) => AlbaTypes::Bigint(sketch.estimate() as i64),
+            State::Sum { rows: 0, .. } | State::Avg { rows: 0, .. } => AlbaTypes::NONE,
+            State::Sum { float, .. } if matches!(result_type, AlbaTypes::Float(_)) => AlbaTypes::Float(float),
+            State::Sum { integer, .. } => match i64::try_from(integer){
+                Ok(sum) => AlbaTypes::Bigint(sum),
+                Err(_) => return Err(gerr("SUM overflowed the range of a BIGINT"))
+            },
+            State::Avg { total, rows } => AlbaTypes::Float(total / rows as f64),
+            State::Min(value) | State::Max(value) => value.unwrap_or(AlbaTypes::NONE),
+        })
+    }
+}
+
+/// Folds rows into one set of aggregate states per group, groups come out in key order.
+/// Without GROUP BY there is a single group, present even when no row matched.
+pub struct Aggregation<'a>{
+    query : &'a AggregateQuery,
+    groups : BTreeMap<Vec<OrderedValue>, Vec<State>>,
+    types : Option<Vec<AlbaTypes>>,
+}
+
+impl<'a> Aggregation<'a>{
+    pub fn new(query : &'a AggregateQuery) -> Self{
+        Aggregation { query, groups: BTreeMap::new(), types: None }
+    }
+    /// The first source bound decides the types of the output columns.
+    pub fn bind(&mut self, binding : &AggregateBinding){
+        self.types.get_or_insert_with(|| binding.types.clone());
+    }
+    pub fn push(&mut self, binding : &AggregateBinding, row : &[AlbaTypes]){
+        let key : Vec<OrderedValue> = binding.group_positions.iter().map(|p| OrderedValue(row.get(*p).cloned().unwrap_or(AlbaTypes::NONE))).collect();
+        let query = self.query;
+        let states = self.groups.entry(key).or_insert_with(|| query.aggregates.iter().map(|a| State::new(a.function)).collect());
+        for (state, position) in states.iter_mut().zip(binding.aggregate_positions.iter()){
+            state.add(position.map(|p| row.get(p).unwrap_or(&AlbaTypes::NONE)));
+        }
+    }
+    /// The output rows and their column types.
+    pub fn finish(mut self) -> Result<(Vec<Vec<AlbaTypes>>, Vec<AlbaTypes>), Error>{
+        if self.groups.is_empty() && self.query.group_by.is_empty(){
+            self.groups.insert(Vec::new(), self.query.aggregates.iter().map(|a| State::new(a.function)).collect());
+        }
+        let types = self.types.unwrap_or_else(|| vec![AlbaTypes::NONE; self.query.outputs.len()]);
+        let mut rows = Vec::with_capacity(self.groups.len());
+        for (key, states) in self.groups{
+            let mut values : Vec<AlbaTypes> = Vec::with_capacity(states.len());
+            for (index, state) in states.into_iter().enumerate(){
+                // every aggregate is listed once, its output column holds its type
+                let output = self.query.outputs.iter().position(|o| matches!(o, Output::Aggregate(a) if *a == index));
+                values.push(state.finish(output.map_or(&AlbaTypes::NONE, |p| &types[p]))?);
+            }
+            rows.push(self.query.outputs.iter().map(|output| match output{
+                Output::Group(group) => key[*group].0.clone(),
+                Output::Aggregate(index) => values[*index].clone()
+            }).collect());
+        }
+        Ok((rows, types))
+    }
+}
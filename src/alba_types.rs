@@ -1,7 +1,8 @@
-use std::io::{Error, ErrorKind};
+use std::{cmp::Ordering, io::{Error, ErrorKind}};
 
 use base64::{engine::general_purpose, Engine};
 use serde::{Deserialize, Serialize};
+use xxhash_rust::const_xxh3::xxh3_64;
 
 use crate::{database::MAX_STR_LEN, lexer_functions::Token};
 
@@ -87,6 +88,70 @@ impl AlbaTypes {
             AlbaTypes::LargeBytes(_)   => 16,
         }
     }
+    /// Stable 64 bit hash of the value, used wherever values have to be compared by identity (distinct counts, deduplication).
+    pub fn fingerprint(&self) -> u64 {
+        let mut bytes : Vec<u8> = vec![self.get_id()];
+        match self {
+            AlbaTypes::Text(s) | AlbaTypes::NanoString(s) | AlbaTypes::SmallString(s) |
+            AlbaTypes::MediumString(s) | AlbaTypes::BigString(s) | AlbaTypes::LargeString(s) => bytes.extend_from_slice(s.as_bytes()),
+            AlbaTypes::NanoBytes(b) | AlbaTypes::SmallBytes(b) | AlbaTypes::MediumBytes(b) |
+            AlbaTypes::BigSBytes(b) | AlbaTypes::LargeBytes(b) => bytes.extend_from_slice(b),
+            AlbaTypes::Int(n) => bytes.extend_from_slice(&n.to_be_bytes()),
+            AlbaTypes::Bigint(n) => bytes.extend_from_slice(&n.to_be_bytes()),
+            AlbaTypes::Float(f) => bytes.extend_from_slice(&f.to_bits().to_be_bytes()),
+            AlbaTypes::Bool(b) => bytes.push(*b as u8),
+            AlbaTypes::Char(c) => bytes.extend_from_slice(&(*c as u32).to_be_bytes()),
+            AlbaTypes::NONE => {}
+        }
+        xxh3_64(&bytes)
+    }
+    /// Total order over every pair of values, used by conditions and sorting.
+    /// Values of different kinds order as NONE < Bool < numbers < text < bytes. Numbers are
+    /// compared by value without casting (an INT equals the BIGINT or FLOAT holding the same
+    /// number) and NaN sorts above every other number. Chars order with the strings, strings
+    /// and bytes compare lexicographically.
+    pub fn total_cmp(&self, other: &AlbaTypes) -> Ordering {
+        match (self.sort_class(), other.sort_class()) {
+            (a, b) if a != b => return a.cmp(&b),
+            _ => {}
+        }
+        match (self, other) {
+            (AlbaTypes::Bool(a), AlbaTypes::Bool(b)) => a.cmp(b),
+            (AlbaTypes::Float(a), AlbaTypes::Float(b)) => compare_floats(*a, *b),
+            (AlbaTypes::Float(a), b) => compare_integer_float(integer_of(b), *a).reverse(),
+            (a, AlbaTypes::Float(b)) => compare_integer_float(integer_of(a), *b),
+            (a, b) if a.sort_class() == 2 => integer_of(a).cmp(&integer_of(b)),
+            (AlbaTypes::Char(a), AlbaTypes::Char(b)) => a.cmp(b),
+            (AlbaTypes::Char(a), b) => a.encode_utf8(&mut [0u8; 4]).as_bytes().cmp(text_bytes(b)),
+            (a, AlbaTypes::Char(b)) => text_bytes(a).cmp(b.encode_utf8(&mut [0u8; 4]).as_bytes()),
+            (a, b) => text_bytes(a).cmp(text_bytes(b)),
+        }
+    }
+    /// Bytes the value takes in memory, heap allocation included.
+    pub fn memory_size(&self) -> usize {
+        size_of::<AlbaTypes>() + match self {
+            AlbaTypes::Text(s) | AlbaTypes::NanoString(s) | AlbaTypes::SmallString(s) |
+            AlbaTypes::MediumString(s) | AlbaTypes::BigString(s) | AlbaTypes::LargeString(s) => s.capacity(),
+            AlbaTypes::NanoBytes(b) | AlbaTypes::SmallBytes(b) | AlbaTypes::MediumBytes(b) |
+            AlbaTypes::BigSBytes(b) | AlbaTypes::LargeBytes(b) => b.capacity(),
+            _ => 0,
+        }
+    }
+    /// Whether `total_cmp` compares the two values by content: numbers with numbers, text with text...
+    pub fn is_comparable_with(&self, other: &AlbaTypes) -> bool {
+        self.sort_class() == other.sort_class()
+    }
+    fn sort_class(&self) -> u8 {
+        match self {
+            AlbaTypes::NONE => 0,
+            AlbaTypes::Bool(_) => 1,
+            AlbaTypes::Int(_) | AlbaTypes::Bigint(_) | AlbaTypes::Float(_) => 2,
+            AlbaTypes::Char(_) | AlbaTypes::Text(_) | AlbaTypes::NanoString(_) | AlbaTypes::SmallString(_) |
+            AlbaTypes::MediumString(_) | AlbaTypes::BigString(_) | AlbaTypes::LargeString(_) => 3,
+            AlbaTypes::NanoBytes(_) | AlbaTypes::SmallBytes(_) | AlbaTypes::MediumBytes(_) |
+            AlbaTypes::BigSBytes(_) | AlbaTypes::LargeBytes(_) => 4,
+        }
+    }
     // pub fn get_id_from_text(keyword: &str) -> Result<u8, Error> {
     //     match keyword.to_uppercase().as_str() {
     //         "INT"             => Ok(2),
@@ -232,23 +297,23 @@ impl AlbaTypes {
             }
             AlbaTypes::NanoString(_) => {
                 let s = get_string_from_alba_type(i)?;
-                Ok(AlbaTypes::NanoString(truncate_or_pad_string(s, 10)))
+                Ok(AlbaTypes::NanoString(truncate_string(s, 10)))
             }
             AlbaTypes::SmallString(_) => {
                 let s = get_string_from_alba_type(i)?;
-                Ok(AlbaTypes::SmallString(truncate_or_pad_string(s, 100)))
+                Ok(AlbaTypes::SmallString(truncate_string(s, 100)))
             }
             AlbaTypes::MediumString(_) => {
                 let s = get_string_from_alba_type(i)?;
-                Ok(AlbaTypes::MediumString(truncate_or_pad_string(s, 500)))
+                Ok(AlbaTypes::MediumString(truncate_string(s, 500)))
             }
             AlbaTypes::BigString(_) => {
                 let s = get_string_from_alba_type(i)?;
-                Ok(AlbaTypes::BigString(truncate_or_pad_string(s, 2000)))
+                Ok(AlbaTypes::BigString(truncate_string(s, 2000)))
             }
             AlbaTypes::LargeString(_) => {
                 let s = get_string_from_alba_type(i)?;
-                Ok(AlbaTypes::LargeString(truncate_or_pad_string(s, 3000)))
+                Ok(AlbaTypes::LargeString(truncate_string(s, 3000)))
             }
             AlbaTypes::NanoBytes(_) => {
                 let bytes = get_bytes_from_alba_type(i)?;
@@ -297,7 +362,51 @@ impl AlbaTypes {
 
 }
 
-fn get_string_from_alba_type(i: AlbaTypes) -> Result<String, Error> {
+fn integer_of(value: &AlbaTypes) -> i64 {
+    match value {
+        AlbaTypes::Int(n) => *n as i64,
+        AlbaTypes::Bigint(n) => *n,
+        _ => 0,
+    }
+}
+
+// Strings and bytes share the byte-wise order; only called on values of the same sort class.
+fn text_bytes(value: &AlbaTypes) -> &[u8] {
+    match value {
+        AlbaTypes::Text(s) | AlbaTypes::NanoString(s) | AlbaTypes::SmallString(s) |
+        AlbaTypes::MediumString(s) | AlbaTypes::BigString(s) | AlbaTypes::LargeString(s) => s.as_bytes(),
+        AlbaTypes::NanoBytes(b) | AlbaTypes::SmallBytes(b) | AlbaTypes::MediumBytes(b) |
+        AlbaTypes::BigSBytes(b) | AlbaTypes::LargeBytes(b) => b,
+        _ => &[],
+    }
+}
+
+fn compare_floats(a: f64, b: f64) -> Ordering {
+    match (a.is_nan(), b.is_nan()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Greater,
+        (false, true) => Ordering::Less,
+        // -0.0 and 0.0 compare equal here, like they do with ==
+        (false, false) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+    }
+}
+
+// Exact comparison of an integer with a float, without rounding either side.
+fn compare_integer_float(i: i64, f: f64) -> Ordering {
+    if f.is_nan() || f >= 9_223_372_036_854_775_808.0 {
+        return Ordering::Less;
+    }
+    if f < -9_223_372_036_854_775_808.0 {
+        return Ordering::Greater;
+    }
+    let whole = f.trunc();
+    match i.cmp(&(whole as i64)) {
+        Ordering::Equal => whole.partial_cmp(&f).unwrap_or(Ordering::Equal),
+        ordering => ordering,
+    }
+}
+
+pub fn get_string_from_alba_type(i: AlbaTypes) -> Result<String, Error> {
     match i {
         AlbaTypes::Text(s) | AlbaTypes::NanoString(s) | AlbaTypes::SmallString(s) |
         AlbaTypes::MediumString(s) | AlbaTypes::BigString(s) | AlbaTypes::LargeString(s) => Ok(s),
@@ -314,12 +423,15 @@ fn get_string_from_alba_type(i: AlbaTypes) -> Result<String, Error> {
     }
 }
 
-fn truncate_or_pad_string(s: String, max_len: usize) -> String {
-    if s.len() > max_len {
-        s[..max_len].to_string()
-    } else {
-        format!("{: <width$}", s, width = max_len)
+// The stored length prefix keeps the width fixed, so strings are only cut, never padded:
+// padding would leak into comparisons, LIKE and ENDS WITH.
+fn truncate_string(mut s: String, max_len: usize) -> String {
+    let mut end = max_len.min(s.len());
+    while !s.is_char_boundary(end) {
+        end -= 1;
     }
+    s.truncate(end);
+    s
 }
 
 fn get_bytes_from_alba_type(i: AlbaTypes) -> Result<Vec<u8>, Error> {
@@ -374,7 +486,8 @@ impl TryFrom<Token> for AlbaTypes {
                     AlbaTypes::LargeBytes(b)
                 })
             },
-            Token::String(s) =>
+            // a bare word among the values of CREATE ROW is text
+            Token::String(s) | Token::Identifier(s) =>
                 Ok(AlbaTypes::Text(s)), // moved, no clone
 
             Token::Int(i) if (i32::MIN as i64) <= i && i <= (i32::MAX as i64) =>
@@ -415,3 +528,55 @@ impl TryFrom<Token> for AlbaTypes {
 }
 
 
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+
+    use super::AlbaTypes;
+
+    #[test]
+    fn integers_and_floats_compare_by_value() {
+        assert_eq!(AlbaTypes::Int(3).total_cmp(&AlbaTypes::Float(3.0)), Ordering::Equal);
+        assert_eq!(AlbaTypes::Bigint(3).total_cmp(&AlbaTypes::Float(3.5)), Ordering::Less);
+        assert_eq!(AlbaTypes::Float(-0.5).total_cmp(&AlbaTypes::Int(0)), Ordering::Less);
+        assert_eq!(AlbaTypes::Int(7).total_cmp(&AlbaTypes::Bigint(7)), Ordering::Equal);
+        // 2^53 + 1 has no f64, a cast to float would call it equal to 2^53
+        assert_eq!(AlbaTypes::Bigint((1 << 53) + 1).total_cmp(&AlbaTypes::Float(9_007_199_254_740_992.0)), Ordering::Greater);
+        assert_eq!(AlbaTypes::Bigint(i64::MAX).total_cmp(&AlbaTypes::Float(9_223_372_036_854_775_808.0)), Ordering::Less);
+        assert_eq!(AlbaTypes::Float(f64::NEG_INFINITY).total_cmp(&AlbaTypes::Bigint(i64::MIN)), Ordering::Less);
+    }
+
+    #[test]
+    fn nan_sorts_above_every_number() {
+        let nan = AlbaTypes::Float(f64::NAN);
+        assert_eq!(nan.total_cmp(&AlbaTypes::Float(f64::NAN)), Ordering::Equal);
+        assert_eq!(nan.total_cmp(&AlbaTypes::Float(f64::INFINITY)), Ordering::Greater);
+        assert_eq!(nan.total_cmp(&AlbaTypes::Bigint(i64::MAX)), Ordering::Greater);
+        assert_eq!(AlbaTypes::Int(i32::MAX).total_cmp(&nan), Ordering::Less);
+        // but below the kinds that sort after numbers
+        assert_eq!(nan.total_cmp(&AlbaTypes::Text(String::new())), Ordering::Less);
+    }
+
+    #[test]
+    fn kinds_order_none_bool_numbers_text_bytes() {
+        let ordered = [
+            AlbaTypes::NONE,
+            AlbaTypes::Bool(false),
+            AlbaTypes::Bool(true),
+            AlbaTypes::Int(i32::MIN),
+            AlbaTypes::Float(f64::NAN),
+            AlbaTypes::Char('a'),
+            AlbaTypes::Text("ab".to_string()),
+            AlbaTypes::SmallString("b".to_string()),
+            AlbaTypes::NanoBytes(vec![0]),
+            AlbaTypes::LargeBytes(vec![0, 1]),
+        ];
+        for (i, a) in ordered.iter().enumerate() {
+            for (j, b) in ordered.iter().enumerate() {
+                assert_eq!(a.total_cmp(b), i.cmp(&j), "{:?} against {:?}", a, b);
+            }
+        }
+        assert_eq!(AlbaTypes::NONE.total_cmp(&AlbaTypes::NONE), Ordering::Equal);
+        assert_eq!(AlbaTypes::Char('b').total_cmp(&AlbaTypes::Text("b".to_string())), Ordering::Equal);
+    }
+}
@@ -1,13 +1,55 @@
 
-use std::{collections::{BTreeSet, HashMap}, ffi::CString, io::{self, Error, ErrorKind, Write}, os::unix::fs::FileExt, sync::Arc};
+use std::{cmp::Ordering, collections::{BTreeSet, HashMap}, ffi::CString, io::{self, Error, ErrorKind, Write}, os::unix::fs::FileExt, sync::{Arc, LazyLock}};
 use ahash::AHashMap;
 use tokio::{io::AsyncReadExt, sync::RwLock};
 use tokio::fs::{File,self};
 use xxhash_rust::const_xxh3;
-use crate::{alba_types::AlbaTypes, database::{write_data, STRIX}, gerr, indexing::{Add, GetIndex, Indexing, Remove}, logerr, loginfo, strix::DataReference};
+use crate::{alba_types::AlbaTypes, database::{write_data, STRIX}, explain::ExecutionCounters, gerr, indexing::{Add, GetIndex, Indexing, Remove, Search}, locking::Session, logerr, planner::{AccessPath, IndexLookup, PlannerContext}, query::{is_tombstone, scan_matches, SearchArguments}, query_conditions::{QueryConditions, QueryIndexType}, savepoint::{PendingRow, Savepoint}, statistics::ContainerStatistics, strix::DataReference};
 
 
-type MvccType = Arc<RwLock<(AHashMap<u64,(bool,Vec<AlbaTypes>)>,HashMap<String,(bool,String)>)>>;
+/// The column every container gets last, holding the version of the row: the count of the
+/// commits that wrote it. It is stored at the end of every row but not in the header of the
+/// container, so it is no column of its schema.
+pub const VERSION_COLUMN : &str = "_version";
+
+/// Format of the rows of a container, written next to it. The containers without it are of
+/// format 1, whose rows end before the version.
+const ROW_FORMAT_VERSION : u32 = 2;
+/// Rows copied at once while a container of an older format is rewritten.
+const UPGRADE_CHUNK_ROWS : u64 = 4096;
+
+/// The version held by a value of the `_version` column.
+pub fn version_of(value : Option<&AlbaTypes>) -> i64{
+    match value{
+        Some(AlbaTypes::Bigint(version)) => *version,
+        _ => 0
+    }
+}
+
+/// The values of a pending row as the commands of the transaction read them, with the codes of
+/// its uncommitted Text values replaced by their contents.
+pub fn pending_values(texts : &HashMap<String,(bool,String)>, row : &[AlbaTypes]) -> Vec<AlbaTypes>{
+    row.iter().map(|value| match value{
+        AlbaTypes::Text(code) => match texts.get(code){
+            Some((false, text)) => AlbaTypes::Text(text.clone()),
+            _ => value.clone()
+        },
+        _ => value.clone()
+    }).collect()
+}
+
+/// Pending changes of a session: rows by address, Text contents by code, and the savepoints set on them.
+pub type Pending = (AHashMap<u64,PendingRow>,HashMap<String,(bool,String)>,Vec<Savepoint>);
+/// The pending changes of every session that has some. A session reads, commits and rolls back
+/// its own only.
+type MvccType = Arc<RwLock<AHashMap<Session,Pending>>>;
+
+static NO_CHANGES : LazyLock<Pending> = LazyLock::new(Pending::default);
+
+/// The pending changes of `session`.
+pub fn changes_of<'a>(mvcc : &'a AHashMap<Session,Pending>, session : &Session) -> &'a Pending{
+    mvcc.get(session).unwrap_or(&NO_CHANGES)
+}
 #[derive(Debug)]
 pub struct Container{
     pub file : Arc<RwLock<std::fs::File>>,
@@ -19,6 +61,9 @@ pub struct Container{
     pub location : String,
     pub graveyard : Arc<RwLock<BTreeSet<u64>>>,
     pub indexing : Arc<Indexing>,
+    pub secondary_indexing : AHashMap<String,Arc<Indexing>>,
+    pub statistics : Option<ContainerStatistics>,
+    pub name : String,
     file_path : String
 
 }
@@ -67,31 +112,337 @@ impl Container {
             }
             headers.push((name.to_owned(), value.to_owned()));
         }
+        let version = (VERSION_COLUMN.to_string(), AlbaTypes::Bigint(0));
+        let mut element_size = element_size;
+        if row_format(path)? < ROW_FORMAT_VERSION{
+            if headers.last().is_some_and(|h| h.0 == VERSION_COLUMN){
+                // earlier builds stored the column in the header, the rows already hold it
+                remove_header_column(path, headers.len() - 1, str_size, headers_offset)?;
+                headers.pop();
+                element_size -= version.1.size();
+            }else{
+                widen_rows(path, headers_offset, element_size, version.1.size())?;
+            }
+            std::fs::write(row_format_path(path), ROW_FORMAT_VERSION.to_string())?;
+        }
+        element_size += version.1.size();
+        headers.push(version);
         let file = Arc::new(RwLock::new(std::fs::OpenOptions::new().read(true).write(true).open(&path)?));
         let mut hash_header = HashMap::new();
         for i in headers.iter(){
             hash_header.insert(i.0.clone(),i.1.clone());
         }
+        let mut secondary_indexing = AHashMap::new();
+        for column in load_secondary_index_list(&secondary_index_list_path(path))?{
+            let index = Indexing::load_index(&secondary_index_name(&container_name, &column)).await?;
+            secondary_indexing.insert(column, index);
+        }
         let container = Arc::new(RwLock::new(Container{
             file:file.clone(),
-            element_size: element_size.clone(),
+            element_size,
             str_size,
-            mvcc: Arc::new(RwLock::new((AHashMap::new(),HashMap::new()))),
+            mvcc: Arc::new(RwLock::new(AHashMap::new())),
             headers_offset: headers_offset.clone() ,
             headers,
             location,
             graveyard: Arc::new(RwLock::new(BTreeSet::new())),
             indexing:Indexing::load_index(&container_name).await?,
+            secondary_indexing,
+            statistics: ContainerStatistics::load(&statistics_path(path))?,
+            name: container_name,
             file_path: path.to_string()
         }));
+        Container::rebuild_stale_indexes(&container).await?;
         Ok(container)
     }
+    /// Fills again, from the stored rows, the indexes whose files were of an older format.
+    async fn rebuild_stale_indexes(container : &Arc<RwLock<Container>>) -> Result<(),Error>{
+        let (arguments, stale) = {
+            let container = container.read().await;
+            let mut stale : Vec<(usize, Arc<Indexing>)> = Vec::new();
+            if container.indexing.stale{
+                stale.push((0, container.indexing.clone()));
+            }
+            for (column, index) in container.secondary_indexing.iter(){
+                if let Some(position) = container.headers.iter().position(|h| h.0 == *column) && index.stale{
+                    stale.push((position, index.clone()));
+                }
+            }
+            (container.search_arguments(QueryConditions::default(), None), stale)
+        };
+        if stale.is_empty(){
+            return Ok(())
+        }
+        let mut entries : Vec<Vec<(u64, u64)>> = vec![Vec::new(); stale.len()];
+        scan_matches(container, &arguments, |row, address| {
+            for ((position, _), entries) in stale.iter().zip(entries.iter_mut()){
+                entries.push((row[*position].get_index(), address));
+            }
+            Ok(true)
+        }).await?;
+        for ((_, index), mut entries) in stale.into_iter().zip(entries){
+            entries.sort_unstable();
+            for (key, address) in entries{
+                index.add(key, address).await?;
+            }
+        }
+        Ok(())
+    }
     
 }
 impl Container{
     pub fn column_names(&self) -> Vec<String>{
         self.headers.iter().map(|v|v.0.to_string()).collect()
     }
+    /// Position of the `_version` column, the last one.
+    pub fn version_position(&self) -> usize{
+        self.headers.len() - 1
+    }
+}
+
+/// Index entries grouped by the index they go to.
+type IndexEntries = Vec<(Arc<Indexing>,Vec<(u64,u64)>)>;
+
+fn push_entry(entries : &mut IndexEntries, index : Arc<Indexing>, entry : (u64,u64)){
+    match entries.iter_mut().find(|(i, _)| Arc::ptr_eq(i, &index)){
+        Some((_, group)) => group.push(entry),
+        None => entries.push((index, vec![entry])),
+    }
+}
+
+fn statistics_path(container_path : &str) -> String{
+    format!("{}.cstats",container_path)
+}
+fn secondary_index_list_path(container_path : &str) -> String{
+    format!("{}.cindexes",container_path)
+}
+fn row_format_path(container_path : &str) -> String{
+    format!("{}.cformat",container_path)
+}
+fn row_format(container_path : &str) -> Result<u32,Error>{
+    let path = row_format_path(container_path);
+    if !std::fs::exists(&path)?{
+        return Ok(1)
+    }
+    std::fs::read_to_string(&path)?.trim().parse().map_err(|e| gerr(&format!("Failed to read the row format of {}: {}", container_path, e)))
+}
+/// Rewrites the rows of the container with `extra` zeroed bytes at their end, version 0 for
+/// the rows of format 1. Tombstones stay all zeros.
+fn widen_rows(container_path : &str, headers_offset : u64, element_size : usize, extra : usize) -> Result<(),Error>{
+    let source = std::fs::File::open(container_path)?;
+    let rows = source.metadata()?.len().saturating_sub(headers_offset) / element_size.max(1) as u64;
+    let upgraded_path = format!("{}.upgrade",container_path);
+    let mut upgraded = io::BufWriter::new(std::fs::File::create(&upgraded_path)?);
+    let mut header = vec![0u8; headers_offset as usize];
+    source.read_exact_at(&mut header, 0)?;
+    upgraded.write_all(&header)?;
+    let mut read = 0;
+    while read < rows{
+        let count = UPGRADE_CHUNK_ROWS.min(rows - read);
+        let mut buffer = vec![0u8; count as usize * element_size];
+        source.read_exact_at(&mut buffer, headers_offset + read * element_size as u64)?;
+        for row in buffer.chunks(element_size.max(1)){
+            upgraded.write_all(row)?;
+            upgraded.write_all(&vec![0u8; extra])?;
+        }
+        read += count;
+    }
+    upgraded.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    std::fs::rename(&upgraded_path, container_path)
+}
+/// Clears the name and type of the column at `position` from the header of the container.
+fn remove_header_column(container_path : &str, position : usize, str_size : usize, headers_offset : u64) -> Result<(),Error>{
+    let max_columns = headers_offset as usize / (str_size + 1);
+    let file = std::fs::OpenOptions::new().write(true).open(container_path)?;
+    file.write_all_at(&vec![0u8; str_size], (position * str_size) as u64)?;
+    file.write_all_at(&[AlbaTypes::NONE.get_id()], (max_columns * str_size + position) as u64)?;
+    file.sync_all()
+}
+fn secondary_index_name(container_name : &str,column : &str) -> String{
+    format!("{}.{}",container_name,column)
+}
+fn load_secondary_index_list(path : &str) -> Result<Vec<String>,Error>{
+    if !std::fs::exists(path)?{
+        return Ok(Vec::new())
+    }
+    let raw = std::fs::read_to_string(path)?;
+    serde_yaml::from_str(&raw).map_err(|e| gerr(&format!("Failed to load the secondary indexes at {}: {}",path,e)))
+}
+
+impl Container{
+    pub fn statistics_path(&self) -> String{
+        statistics_path(&self.file_path)
+    }
+    pub fn secondary_indexes(&self) -> Vec<String>{
+        let mut columns : Vec<String> = self.secondary_indexing.keys().cloned().collect();
+        columns.sort();
+        columns
+    }
+    /// Builds an index over `column` from the stored rows and registers it next to the container.
+    /// The rows are read straight from the file, so the caller may hold the write lock of the
+    /// container while the index is built; the pending rows join it on commit.
+    pub async fn create_secondary_index(&mut self,column : &str) -> Result<(),Error>{
+        let position = match self.headers.iter().position(|h| h.0 == column){
+            Some(p) => p,
+            None => return Err(gerr(&format!("There is no column {} in the container {}",column,self.name)))
+        };
+        let index = Indexing::load_index(&secondary_index_name(&self.name, column)).await?;
+        let file_rows = self.file_rows().await?;
+        let mut wanted = vec![false; self.headers.len()];
+        wanted[position] = true;
+        let file = self.file.read().await;
+        let mut buffer = vec![0u8; self.element_size];
+        for address in 0..file_rows{
+            file.read_exact_at(&mut buffer, (address * self.element_size as u64) + self.headers_offset)?;
+            if is_tombstone(&buffer){
+                continue;
+            }
+            let row = self.deserialize_columns(&buffer, &wanted).await?;
+            if let Some(value) = row.get(position){
+                index.add(value.get_index(), address).await?;
+            }
+        }
+        drop(file);
+        self.secondary_indexing.insert(column.to_string(), index);
+        let yaml = serde_yaml::to_string(&self.secondary_indexes()).map_err(|e| gerr(&e.to_string()))?;
+        std::fs::write(secondary_index_list_path(&self.file_path), yaml)
+    }
+    /// Removes every index file, statistic and secondary index list that belongs to the container.
+    pub async fn destroy_auxiliary_files(&self) -> Result<(),Error>{
+        self.indexing.destroy(&self.name).await?;
+        for (column,index) in self.secondary_indexing.iter(){
+            index.destroy(&secondary_index_name(&self.name, column)).await?;
+        }
+        for path in [statistics_path(&self.file_path),secondary_index_list_path(&self.file_path),row_format_path(&self.file_path)]{
+            if std::fs::exists(&path)?{
+                std::fs::remove_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+    fn lookup_index(&self,lookup : &IndexLookup) -> Result<(&Arc<Indexing>,Vec<(u64,u64)>),Error>{
+        let index = if lookup.primary{
+            &self.indexing
+        }else{
+            match self.secondary_indexing.get(&lookup.column){
+                Some(index) => index,
+                None => return Err(gerr(&format!("There is no index on the column {}",lookup.column)))
+            }
+        };
+        let ranges = match &lookup.lookup{
+            QueryIndexType::Strict(t) => vec![(*t,*t)],
+            QueryIndexType::InclusiveRange(t) => vec![(*t.start(),*t.end())],
+            QueryIndexType::Set(keys) => keys.iter().map(|k| (*k,*k)).collect(),
+        };
+        Ok((index,ranges))
+    }
+    async fn lookup_addresses(&self,lookup : &IndexLookup,counters : &ExecutionCounters) -> Result<BTreeSet<u64>,Error>{
+        let (index,ranges) = self.lookup_index(lookup)?;
+        let mut addresses = BTreeSet::new();
+        for (start,end) in ranges{
+            let (chunks,bytes) = index.coverage(start, end).await;
+            counters.index_read(chunks, bytes);
+            addresses.extend(index.search(start..=end).await?);
+        }
+        Ok(addresses)
+    }
+    /// The `(index key, address)` entries of a lookup in key order, for reading rows in index order.
+    pub async fn ordered_entries(&self,lookup : &IndexLookup,counters : &ExecutionCounters) -> Result<Vec<(u64,u64)>,Error>{
+        let (index,ranges) = self.lookup_index(lookup)?;
+        let mut entries = Vec::new();
+        for (start,end) in ranges{
+            let (chunks,bytes) = index.coverage(start, end).await;
+            counters.index_read(chunks, bytes);
+            entries.extend(index.entries(start, end).await?);
+        }
+        entries.sort_unstable();
+        entries.dedup();
+        Ok(entries)
+    }
+    /// Arguments of a search over the stored rows and the pending changes of `session`, or over
+    /// the stored rows only without one.
+    pub fn search_arguments(&self,conditions : QueryConditions,session : Option<Session>) -> SearchArguments{
+        SearchArguments{
+            element_size: self.element_size,
+            header_offset: self.headers_offset as usize,
+            file: self.file.clone(),
+            container_values: self.headers.clone(),
+            container_name: self.name.clone(),
+            conditions,
+            counters: Arc::new(ExecutionCounters::default()),
+            columns: vec![true; self.headers.len()],
+            pending: session,
+            after: None,
+        }
+    }
+    /// What the planner knows about the container, the first column being the primary key.
+    pub async fn planner_context(&self) -> Result<PlannerContext<'_>,Error>{
+        let primary_key = match self.headers.first(){
+            Some(a) => a.0.as_str(),
+            None => return Err(gerr("Error, no primary key found"))
+        };
+        Ok(PlannerContext{
+            row_count: self.arrlen().await?,
+            primary_key,
+            secondary_indexes: self.secondary_indexes(),
+            statistics: self.statistics.as_ref(),
+        })
+    }
+    /// Row addresses an index access path points to, `None` when the path is a full scan.
+    /// The pending rows are not indexed before the commit, so every live one is added to the
+    /// addresses of an index path and left to the conditions.
+    pub async fn index_addresses(&self,access : &AccessPath,counters : &ExecutionCounters,session : &Session) -> Result<Option<BTreeSet<u64>>,Error>{
+        let mut addresses = self.indexed_addresses(access, counters).await?;
+        if let Some(addresses) = addresses.as_mut(){
+            addresses.extend(changes_of(&*self.mvcc.read().await, session).0.iter().filter(|(_,(deleted,_))| !deleted).map(|(address,_)| *address));
+        }
+        Ok(addresses)
+    }
+    /// Whether `session` has changes to the container waiting for a commit.
+    pub async fn has_pending(&self,session : &Session) -> bool{
+        !changes_of(&*self.mvcc.read().await, session).0.is_empty()
+    }
+    async fn indexed_addresses(&self,access : &AccessPath,counters : &ExecutionCounters) -> Result<Option<BTreeSet<u64>>,Error>{
+        match access{
+            AccessPath::Scan => Ok(None),
+            AccessPath::Index(lookup) => Ok(Some(self.lookup_addresses(lookup,counters).await?)),
+            AccessPath::Intersection(lookups) => {
+                let mut addresses : Option<BTreeSet<u64>> = None;
+                for lookup in lookups{
+                    let found = self.lookup_addresses(lookup,counters).await?;
+                    addresses = Some(match addresses{
+                        Some(current) => current.intersection(&found).copied().collect(),
+                        None => found
+                    });
+                }
+                Ok(addresses)
+            }
+        }
+    }
+    /// The live rows whose primary key equals `key`, with their addresses. The index is looked up
+    /// for the stored rows and the pending changes are searched for the rest, a pending version
+    /// of a row winning over the stored one, so a row created or deleted since the last commit
+    /// counts as well.
+    pub async fn primary_rows(&self,key : &AlbaTypes,session : &Session) -> Result<Vec<(u64,Vec<AlbaTypes>)>,Error>{
+        let primary_key = match self.headers.first(){
+            Some(a) => a.0.clone(),
+            None => return Err(gerr("Error, no primary key found"))
+        };
+        let is_key = |row : &[AlbaTypes]| row.first().is_some_and(|value| value.total_cmp(key) == Ordering::Equal);
+        let mut addresses : BTreeSet<u64> = {
+            let mvcc = self.mvcc.read().await;
+            let changes = changes_of(&mvcc, session);
+            // the Text values of pending rows are codes of their contents
+            changes.0.iter().filter(|(_,(deleted,row))| !deleted && is_key(&pending_values(&changes.1, row))).map(|(address,_)| *address).collect()
+        };
+        let lookup = IndexLookup{ column: primary_key, primary: true, lookup: QueryIndexType::Strict(key.get_index()), estimated_rows: 0.0 };
+        addresses.extend(self.lookup_addresses(&lookup, &ExecutionCounters::default()).await?);
+        let addresses : Vec<u64> = addresses.into_iter().collect();
+        let positions : Vec<usize> = (0..self.headers.len()).collect();
+        let rows = self.get_rows(&addresses, &positions, session).await?;
+        // an index key covers a range of values and a pending version can change the key
+        Ok(addresses.into_iter().zip(rows).filter_map(|(address,row)| row.filter(|row| is_key(row)).map(|row| (address,row))).collect())
+    }
 }
 
 async fn try_open_file(path: &str) -> io::Result<Option<File>> {
@@ -102,34 +453,13 @@ async fn try_open_file(path: &str) -> io::Result<Option<File>> {
     }
 }
 fn handle_fixed_string(buf: &[u8],index: &mut usize,instance_size: usize,values: &mut Vec<AlbaTypes>) -> Result<(), Error> {
-    let bytes = &buf[*index..*index+instance_size];
+    // the stored width is the length prefix plus the payload, see serialize_closed_string
+    let bytes = &buf[*index..*index+instance_size+8];
+    *index += instance_size+8;
     let mut size : [u8;8] = [0u8;8];
     size.clone_from_slice(&bytes[..8]); 
-    let string_length = usize::from_le_bytes(size);
-    let string_bytes = if string_length > 0 {
-        let end = 8 + string_length;
-        let l = bytes.len()-8;
-        if end >= l {
-            &bytes[8..(l-8)]
-        }else{
-           &bytes[8..(8+string_length)] 
-        }
-        
-    }else{
-        
-        let s = String::new();
-        match instance_size {
-            10 => values.push(AlbaTypes::NanoString(s)),
-            100 => values.push(AlbaTypes::SmallString(s)),
-            500 => values.push(AlbaTypes::MediumString(s)),
-            2_000 => values.push(AlbaTypes::BigString(s)),
-            3_000 => values.push(AlbaTypes::LargeString(s)),
-            _ => unreachable!(),
-        }
-        return Ok(())
-    };
-    
-    *index += instance_size;
+    let string_length = usize::from_le_bytes(size).min(instance_size);
+    let string_bytes = &bytes[8..(8+string_length)];
     let trimmed: Vec<u8> = string_bytes.iter()
         .take_while(|&&b| b != 0)
         .cloned()
@@ -147,36 +477,13 @@ fn handle_fixed_string(buf: &[u8],index: &mut usize,instance_size: usize,values:
     }
     Ok(())
 }
-
 fn handle_bytes(buf: &[u8],index: &mut usize,size: usize,values: &mut Vec<AlbaTypes>) -> Result<(), Error> {
-    let bytes = buf[*index..*index+size].to_vec();
+    let bytes = &buf[*index..*index+size+8];
+    *index += size+8;
     let mut blob_size : [u8;8] = [0u8;8];
     blob_size.clone_from_slice(&bytes[..8]); 
-    let blob_length = usize::from_le_bytes(blob_size);
-    let blob : Vec<u8> = if blob_length > 0 {
-        let end = 8 + blob_length;
-        let l = bytes.len();
-        if end > l {
-            bytes[8..(l-8)].to_vec()
-        }else{
-           bytes[8..(8+blob_length)].to_vec() 
-        }
-        
-    }else{
-        
-        let blob = Vec::new();
-        match size {
-            10 => values.push(AlbaTypes::NanoBytes(blob)),
-            1000 => values.push(AlbaTypes::SmallBytes(blob)),
-            10_000 => values.push(AlbaTypes::MediumBytes(blob)),
-            100_000 => values.push(AlbaTypes::BigSBytes(blob)),
-            1_000_000 => values.push(AlbaTypes::LargeBytes(blob)),
-            _ => unreachable!(),
-        }
-        return Ok(())
-    };
-
-    *index += size;
+    let blob_length = usize::from_le_bytes(blob_size).min(size);
+    let blob : Vec<u8> = bytes[8..(8+blob_length)].to_vec();
     
     match size {
         10 => values.push(AlbaTypes::NanoBytes(blob)),
@@ -193,16 +500,21 @@ impl Container{
     pub async fn len(&self) -> Result<u64,Error>{
         Ok(self.file.read().await.metadata()?.len())
     }
-    pub async fn arrlen(&self) -> Result<u64, Error> {
+    /// Count of the row addresses the file holds, committed rows and zeroed ones.
+    async fn file_rows(&self) -> Result<u64, Error> {
         let file_len = self.len().await?;
-        let file_rows = if file_len > self.headers_offset {
+        Ok(if file_len > self.headers_offset {
             (file_len - self.headers_offset) / self.element_size as u64
         } else {
             0
-        };
+        })
+    }
+    pub async fn arrlen(&self) -> Result<u64, Error> {
+        let file_rows = self.file_rows().await?;
+        // the addresses of the rows a session created are taken for every other session too
         let mvcc_max = {
             let mvcc = self.mvcc.read().await;
-            mvcc.0.keys().copied().max().map_or(0, |max_index| max_index + 1)
+            mvcc.values().flat_map(|pending| pending.0.keys()).copied().max().map_or(0, |max_index| max_index + 1)
         };
         Ok(file_rows.max(mvcc_max))
     }
@@ -216,43 +528,216 @@ impl Container{
     //     None
     // }
 
-    pub async fn get_next_addr(&self) -> Result<u64, Error> {
+    /// Addresses for `count` new rows: the free ones of the graveyard first, then the ones after
+    /// the last row, so the rest of a large batch lands on sequential addresses.
+    pub async fn next_addresses(&self, count : usize) -> Result<Vec<u64>, Error> {
+        let mut addresses = Vec::with_capacity(count);
         let mut graveyard = self.graveyard.write().await;
-        if graveyard.len() > 0{
-            if let Some(id) = graveyard.pop_first(){
-                return Ok(id)
+        while addresses.len() < count && let Some(id) = graveyard.pop_first(){
+            addresses.push(id);
+        }
+        drop(graveyard);
+        let end = self.arrlen().await?;
+        addresses.extend(end..end + (count - addresses.len()) as u64);
+        Ok(addresses)
+    }
+    /// Adds new rows to the pending changes and returns the addresses they were given.
+    pub async fn push_rows(&mut self, rows : Vec<Vec<AlbaTypes>>, session : &Session) -> Result<Vec<u64>,Error>{
+        let addresses = self.next_addresses(rows.len()).await?;
+        self.stage_rows(addresses.iter().copied().zip(rows.into_iter().map(|row| (false,row))), session).await;
+        Ok(addresses)
+    }
+    /// Sets pending rows of `session`, its newest savepoint remembering what each one replaces.
+    pub async fn stage_rows(&self, rows : impl IntoIterator<Item = (u64,PendingRow)>, session : &Session){
+        let mut guard = self.mvcc.write().await;
+        let mvcc = guard.entry(*session).or_default();
+        for (address, row) in rows{
+            if let Some(savepoint) = mvcc.2.last_mut(){
+                savepoint.record_row(address, mvcc.0.get(&address));
+            }
+            mvcc.0.insert(address, row);
+        }
+    }
+    /// Adds pending Text contents of `session` under their codes.
+    pub async fn stage_texts(&self, texts : Vec<(String,String)>, session : &Session){
+        let mut guard = self.mvcc.write().await;
+        let mvcc = guard.entry(*session).or_default();
+        for (code, text) in texts{
+            if let Some(savepoint) = mvcc.2.last_mut(){
+                savepoint.record_text(&code);
             }
+            mvcc.1.insert(code, (false, text));
         }
-        let current_rows = self.arrlen().await?;
+    }
+    pub async fn savepoint(&self, name : &str, session : &Session){
+        self.mvcc.write().await.entry(*session).or_default().2.push(Savepoint::new(name));
+    }
+    /// Undoes the pending changes made since the savepoint `name`, which stays set while the
+    /// later ones are dropped. Nothing changes when the container has no such savepoint, as
+    /// after it was committed on its own.
+    pub async fn rollback_to(&self, name : &str, session : &Session){
+        let mut guard = self.mvcc.write().await;
+        let mvcc = match guard.get_mut(session){
+            Some(a) => a,
+            None => return
+        };
+        let position = match mvcc.2.iter().rposition(|s| s.name == name){
+            Some(p) => p,
+            None => return
+        };
+        while mvcc.2.len() > position{
+            if let Some(mut savepoint) = mvcc.2.pop(){
+                savepoint.undo(&mut mvcc.0, &mut mvcc.1);
+            }
+        }
+        mvcc.2.push(Savepoint::new(name));
+    }
+    /// Drops the savepoint `name` and the later ones, keeping their changes.
+    pub async fn release(&self, name : &str, session : &Session){
+        let mut guard = self.mvcc.write().await;
+        let mvcc = match guard.get_mut(session){
+            Some(a) => a,
+            None => return
+        };
+        let position = match mvcc.2.iter().rposition(|s| s.name == name){
+            Some(p) => p,
+            None => return
+        };
+        for savepoint in mvcc.2.split_off(position){
+            if let Some(older) = mvcc.2.last_mut(){
+                savepoint.merge_into(older);
+            }
+        }
+    }
+    /// Pairs every index of the container with the value of the row it indexes, primary first.
+    fn row_indexes<'a>(&self, row : &'a [AlbaTypes]) -> Vec<(Arc<Indexing>,&'a AlbaTypes)>{
+        let mut indexes = Vec::with_capacity(1 + self.secondary_indexing.len());
+        if let Some(arg) = row.first(){
+            indexes.push((self.indexing.clone(),arg));
+        }
+        for (column,index) in self.secondary_indexing.iter(){
+            if let Some(arg) = self.headers.iter().position(|h| h.0 == *column).and_then(|p| row.get(p)){
+                indexes.push((index.clone(),arg));
+            }
+        }
+        indexes
+    }
+    /// The index entries of the rows stored at `addresses`, read from the file with only the
+    /// indexed columns deserialized. Free and zeroed addresses have none.
+    async fn stored_entries(&self, addresses : &[u64]) -> Result<IndexEntries,Error>{
+        let file_rows = self.file_rows().await?;
+        let mut wanted = vec![false; self.headers.len()];
+        for (position, header) in self.headers.iter().enumerate(){
+            wanted[position] = position == 0 || self.secondary_indexing.contains_key(&header.0);
+        }
+        let file = self.file.read().await;
+        let mut buffer = vec![0u8; self.element_size];
+        let mut entries : IndexEntries = Vec::new();
+        for address in addresses.iter().filter(|address| **address < file_rows){
+            file.read_exact_at(&mut buffer, (address * self.element_size as u64) + self.headers_offset)?;
+            if is_tombstone(&buffer){
+                continue;
+            }
+            let row = self.deserialize_columns(&buffer, &wanted).await?;
+            for (index, value) in self.row_indexes(&row){
+                push_entry(&mut entries, index, (value.get_index(), *address));
+            }
+        }
+        Ok(entries)
+    }
+    /// Drops the pending changes of `session`.
+    pub async fn rollback(&mut self, session : &Session) -> Result<(),Error> {
+        self.mvcc.write().await.remove(session);
+        Ok(())
+    }
+    /// Fails with a version conflict when a row was committed again after the version a pending
+    /// change to it is based on: the version it was read at, or the one `_version = ?` of the
+    /// EDIT or DELETE expected. A new row is based on version 0, like a free address.
+    pub async fn check_versions(&self, session : &Session) -> Result<(), Error> {
+        let position = self.version_position();
+        let file_rows = self.file_rows().await?;
+        let mut wanted = vec![false; self.headers.len()];
+        wanted[position] = true;
+        let file = self.file.read().await;
         let mvcc = self.mvcc.read().await;
-        for (&key, (deleted, _)) in mvcc.0.iter() {
-            if *deleted {
-                return Ok(key);
+        let mut buffer = vec![0u8; self.element_size];
+        for (address, (_, row)) in changes_of(&mvcc, session).0.iter(){
+            let base = version_of(row.get(position));
+            let current = if *address < file_rows{
+                file.read_exact_at(&mut buffer, (address * self.element_size as u64) + self.headers_offset)?;
+                if is_tombstone(&buffer){
+                    0
+                }else{
+                    version_of(self.deserialize_columns(&buffer, &wanted).await?.get(position))
+                }
+            }else{
+                0
+            };
+            if current != base{
+                return Err(gerr(&format!("Version conflict on the row at address {} of {}: it is at version {}, but the pending change to it expects version {}. Nothing was committed", address, self.name, current, base)))
             }
         }
-        Ok(current_rows)
-    } 
-    pub async fn push_row(&mut self, data : &Vec<AlbaTypes>) -> Result<(),Error>{
-        let ind = self.get_next_addr().await?;
-        let mut mvcc_guard = self.mvcc.write().await;
-        mvcc_guard.0.insert(ind, (false,data.clone()));
         Ok(())
     }
-    pub async fn rollback(&mut self) -> Result<(),Error> {
-        let mut mvcc_guard = self.mvcc.write().await;
-        mvcc_guard.0.clear();
-        mvcc_guard.1.clear();
-        drop(mvcc_guard);
+    /// Fails when two live rows would hold the same primary key after the commit: two pending
+    /// rows, or a pending row and a stored one the transaction leaves in place.
+    async fn check_primary_keys(&self, session : &Session) -> Result<(), Error> {
+        let primary_key = match self.headers.first(){
+            Some(a) => a.0.clone(),
+            None => return Ok(())
+        };
+        let (pending, mut keys) = {
+            let mvcc = self.mvcc.read().await;
+            let mvcc = changes_of(&mvcc, session);
+            let keys : Vec<AlbaTypes> = mvcc.0.values()
+                .filter(|(deleted, _)| !deleted)
+                .filter_map(|(_, row)| pending_values(&mvcc.1, &row[..1]).pop())
+                .filter(|key| !matches!(key, AlbaTypes::NONE))
+                .collect();
+            (mvcc.0.keys().copied().collect::<BTreeSet<u64>>(), keys)
+        };
+        keys.sort_by(|a, b| a.total_cmp(b));
+        if let Some(pair) = keys.windows(2).find(|pair| pair[0].total_cmp(&pair[1]) == Ordering::Equal){
+            return Err(gerr(&format!("Duplicate primary key {:?} in {}: more than one pending row holds it. Nothing was committed", pair[0], self.name)))
+        }
+        for key in keys.iter(){
+            let lookup = IndexLookup{ column: primary_key.clone(), primary: true, lookup: QueryIndexType::Strict(key.get_index()), estimated_rows: 0.0 };
+            // the pending rows were compared with each other above
+            let addresses : Vec<u64> = self.lookup_addresses(&lookup, &ExecutionCounters::default()).await?.into_iter().filter(|address| !pending.contains(address)).collect();
+            for row in self.get_rows(&addresses, &[0], session).await?.into_iter().flatten(){
+                if row.first().is_some_and(|value| value.total_cmp(key) == Ordering::Equal){
+                    return Err(gerr(&format!("Duplicate primary key {:?} in {}: a stored row already holds it. Nothing was committed", key, self.name)))
+                }
+            }
+        }
         Ok(())
     }
-    pub async fn commit(&mut self) -> Result<(), Error> {
-        let mut total = self.arrlen().await?;
+    /// Whether the pending changes can be committed, see `check_versions` and `check_primary_keys`.
+    pub async fn check_commit(&self, session : &Session) -> Result<(), Error> {
+        self.check_versions(session).await?;
+        self.check_primary_keys(session).await
+    }
+    /// Writes the pending changes of `session`, every row written taking the next version. When a
+    /// row changed since the version a change to it is based on, or when it would duplicate a
+    /// primary key, nothing is written and the pending changes of the session are dropped.
+    pub async fn commit(&mut self, session : &Session) -> Result<(), Error> {
+        if let Err(e) = self.check_commit(session).await{
+            self.rollback(session).await?;
+            return Err(e)
+        }
+        let version_position = self.version_position();
+        // the entries of the rows as they are stored, removed before the new ones are added
+        let addresses : Vec<u64> = changes_of(&*self.mvcc.read().await, session).0.keys().copied().collect();
+        let removals = self.stored_entries(&addresses).await?;
+        // deleted rows are zeroed in place and their addresses reused, so the file never shrinks;
+        // it grows to the rows of the session, the ones other sessions created are theirs to write
+        let total = self.file_rows().await?.max(addresses.iter().max().map_or(0, |max| max + 1));
         let mut virtual_ward : AHashMap<usize, DataReference> = AHashMap::new();
-        let mut mvcc = self.mvcc.write().await;
+        let mut guard = self.mvcc.write().await;
+        let mvcc = guard.remove(session).unwrap_or_default();
         let mut insertions: Vec<(u64, Vec<AlbaTypes>)> = Vec::new();
         let mut deletes: Vec<(u64, Vec<AlbaTypes>)> = Vec::new();
         for (index, value) in mvcc.0.iter() {
-            loginfo!("{}",index);
             let v = (*index, value.1.clone());
             if value.0 {
                 deletes.push(v);
@@ -260,47 +745,73 @@ impl Container{
                 insertions.push(v);
             }
         }
-        mvcc.0.clear();
+        for (_, row) in insertions.iter_mut(){
+            row[version_position] = AlbaTypes::Bigint(version_of(row.get(version_position)) + 1);
+        }
         insertions.sort_by_key(|(index, _)| *index);
         deletes.sort_by_key(|(index, _)| *index);
         let hdr_off = self.headers_offset;
         let row_sz = self.element_size as u64;
         let buf = vec![0u8; self.element_size];
         let fi = self.file.write().await;
-        for (row_index, row_data) in insertions {
-            let serialized = self.serialize_row(&row_data)?;
-            let offset = hdr_off + row_index * row_sz;
-            if let Some(arg) = row_data.first(){
-                let i = self.indexing.clone();
-                let j = offset.clone();
-                let idx = arg.get_index();
-                tokio::spawn(async move{
-                    if let Err(e) = i.add(idx, j).await{
-                        logerr!("ERROR: {}",e);
-                    };
-                });
+        // rows at consecutive addresses are written with one call, and every index gets its
+        // entries at once instead of one row at a time
+        let mut additions : IndexEntries = Vec::new();
+        let mut run : Vec<u8> = Vec::new();
+        let mut run_start = 0;
+        for (n, (row_index, row_data)) in insertions.iter().enumerate() {
+            if n > 0 && *row_index != insertions[n - 1].0 + 1 {
+                let offset = hdr_off + run_start * row_sz;
+                fi.write_all_at(&run, offset)?;
+                virtual_ward.insert(offset as usize, (const_xxh3::xxh3_64(&run), std::mem::take(&mut run)));
+            }
+            if run.is_empty() {
+                run_start = *row_index;
+            }
+            run.extend_from_slice(&self.serialize_row(row_data)?);
+            // Text is indexed by its content, the way it is read back, not by its code
+            for (i,arg) in self.row_indexes(&pending_values(&mvcc.1, row_data)){
+                push_entry(&mut additions, i, (arg.get_index(), *row_index));
+            }
+        }
+        if !run.is_empty() {
+            let offset = hdr_off + run_start * row_sz;
+            fi.write_all_at(&run, offset)?;
+            virtual_ward.insert(offset as usize, (const_xxh3::xxh3_64(&run), run));
+        }
+        // an entry a row keeps through the change is neither removed nor added again
+        let mut changes : Vec<(Arc<Indexing>, BTreeSet<(u64, u64)>, BTreeSet<(u64, u64)>)> = Vec::new();
+        for (i, entries) in removals {
+            changes.push((i, entries.into_iter().collect(), BTreeSet::new()));
+        }
+        for (i, entries) in additions {
+            match changes.iter_mut().find(|(index, _, _)| Arc::ptr_eq(index, &i)){
+                Some((_, removed, added)) => {
+                    for entry in entries{
+                        if !removed.remove(&entry){
+                            added.insert(entry);
+                        }
+                    }
+                },
+                None => changes.push((i, BTreeSet::new(), entries.into_iter().collect())),
+            }
+        }
+        // applied before the commit returns, under the write lock of the container, since the
+        // primary key checks and the planner of the next command read the indexes
+        for (i, removed, added) in changes {
+            for (idx, row_index) in removed{
+                i.remove(idx, row_index).await?;
+            }
+            for (idx, row_index) in added{
+                i.add(idx, row_index).await?;
             }
-            fi.write_all_at(serialized.as_slice(), offset)?;
-            virtual_ward.insert(offset as usize, (const_xxh3::xxh3_64(serialized.as_slice()),serialized));
         }
         
         let mut graveyard = self.graveyard.write().await;
         for del in &deletes  {
             let from = hdr_off + del.0 * row_sz;
-            if let Some(arg) = del.1.first(){
-                let i = self.indexing.clone();
-                let j = from.clone();
-                let idx = arg.get_index();
-                tokio::spawn(async move{
-                    if let Err(e) = i.remove(idx, j).await{
-                        logerr!("ERROR: {}",e);
-                    };
-                });
-            }
-            
             fi.write_all_at(&buf, from)?;
             virtual_ward.insert(from as usize, (const_xxh3::xxh3_64(&buf),buf.clone()));
-            total -= 1;
             graveyard.insert(del.0);
         }
         drop(graveyard);
@@ -331,43 +842,42 @@ impl Container{
         
         }
 
-        mvcc.1.clear();
-        mvcc.1.shrink_to_fit();
         if let Some(s) = STRIX.get(){
             let mut l = s.write().await;
             l.wards.push(RwLock::new((std::fs::OpenOptions::new().read(true).write(true).open(&self.file_path)?,virtual_ward)));
         }
-        drop(mvcc);
+        drop(guard);
         Ok(())
     }
-    pub async fn get_rows(&self, index: (u64, u64)) -> Result<Vec<Vec<AlbaTypes>>, Error> {
-        // INDEXES WILL BE TREATED AS RELATIVE, EACH BEING A REFERENCE TO (X*ELEMENT_SIZE) + header_size
-        let arrlen = self.arrlen().await?;
-        let max = index.1.min(arrlen);
-        if index.0 > max{
-            return Err(gerr(&format!("Failed to get rows, the first index should be lower than the second. Review the arguments: (index0:{},index1:{})",index.0,index.1)))
+    /// Reads the rows at `addresses` and returns the columns at `positions` of each, in that order.
+    /// The pending changes of `session` win over the file, rows deleted in between come back as
+    /// `None`, and only the requested columns are deserialized.
+    pub async fn get_rows(&self, addresses: &[u64], positions: &[usize], session: &Session) -> Result<Vec<Option<Vec<AlbaTypes>>>, Error> {
+        let file_rows = self.file_rows().await?;
+        let mut wanted = vec![false; self.headers.len()];
+        for position in positions{
+            wanted[*position] = true;
         }
-        let mut buffer = vec![0u8;(max-index.0).max(1) as usize * self.element_size];
-        self.file.read().await.read_exact_at(&mut buffer, (index.0 * self.element_size as u64)+self.headers_offset)?;
-        println!("{}//{}",buffer.len(),self.len().await?);
-        
+        let file = self.file.read().await;
         let mvcc = self.mvcc.read().await;
-        let mut result : Vec<Vec<AlbaTypes>> = Vec::with_capacity((max-index.0) as usize);
-        for i in index.0..=max{
-            let index = i as usize;
-            if let Some(val) = mvcc.0.get(&i){
-                if !val.0{
-                    result.push(val.1.clone());
-                    continue;
-                }
-            }
-            if buffer.len() > self.element_size*index{
-                result.push(
-                    self.deserialize_row(
-                        &buffer[index*self.element_size .. (index+1)*self.element_size] // row-bytes
-                    ).await? // row
-                );
-            }
+        let mvcc = changes_of(&mvcc, session);
+        let mut buffer = vec![0u8; self.element_size];
+        let mut result : Vec<Option<Vec<AlbaTypes>>> = Vec::with_capacity(addresses.len());
+        for address in addresses{
+            let row = match mvcc.0.get(address){
+                Some((true, _)) => { result.push(None); continue },
+                Some((false, row)) => pending_values(&mvcc.1, row),
+                None if *address < file_rows => {
+                    file.read_exact_at(&mut buffer, (address * self.element_size as u64) + self.headers_offset)?;
+                    if is_tombstone(&buffer){
+                        result.push(None);
+                        continue;
+                    }
+                    self.deserialize_columns(&buffer, &wanted).await?
+                },
+                None => { result.push(None); continue }
+            };
+            result.push(Some(positions.iter().map(|p| row.get(*p).cloned().unwrap_or(AlbaTypes::NONE)).collect()));
         }
         Ok(result)
     }
@@ -518,11 +1028,21 @@ impl Container{
     
         Ok(buffer)
     }
-    pub async fn deserialize_row(&self, buf: &[u8]) -> Result<Vec<AlbaTypes>, Error> {
+    /// Deserializes only the columns flagged in `wanted`, the others come back as `NONE` without
+    /// being decoded, so an unwanted Text column never opens its `rf/` file.
+    pub async fn deserialize_columns(&self, buf: &[u8], wanted: &[bool]) -> Result<Vec<AlbaTypes>, Error> {
         let mut index = 0;
         let mut values = Vec::new();
     
-        for column_type in &self.columns() {
+        for (position, column_type) in self.columns().iter().enumerate() {
+            if !wanted.get(position).copied().unwrap_or(false) {
+                index += match column_type {
+                    AlbaTypes::Text(_) => self.str_size,
+                    _ => column_type.size()
+                };
+                values.push(AlbaTypes::NONE);
+                continue;
+            }
             match column_type {
                 // Primitive types
                 AlbaTypes::Bigint(_) => {
@@ -580,7 +1100,12 @@ impl Container{
                         .collect();
                     let str_id = String::from_utf8(trimmed)
                         .map_err(|e| gerr(&format!("Text decoding failed: {}", e)))?;
-    
+                    // a Text value left out of its row has no code, and `rf/` itself is no text
+                    if str_id.is_empty() {
+                        values.push(AlbaTypes::Text(str_id));
+                        continue;
+                    }
+
                     // Check external text storage
                     let mut file = match try_open_file(&format!("{}/rf/{}", self.location, str_id)).await? {
                         Some(f) => f,
@@ -0,0 +1,117 @@
+use std::{fs, io::{Error, Read, Write}, os::unix::fs::OpenOptionsExt, path::Path};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::{gerr, lexer_functions::Token, query::Keyset};
+
+/// File under the database location holding the key continuation tokens are signed with. Server
+/// processes sharing it accept each other's tokens.
+const CONTINUATION_KEY_FILE : &str = ".tytodb-continuation-key";
+
+/// What a continuation token carries: the `SEARCH ... STATELESS` it continues, with its arguments
+/// in place, the row the next page starts after and what is left of its `LIMIT`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Continuation{
+    pub search : Vec<Token>,
+    pub after : Keyset,
+    pub limit : Option<u64>,
+}
+
+impl Continuation{
+    /// The token of the continuation, its JSON and the keyed BLAKE3 hash of it, both in base64.
+    pub fn sign(&self, key : &[u8; 32]) -> Result<String, Error>{
+        let payload = serde_json::to_vec(self).map_err(|e| gerr(&e.to_string()))?;
+        let mac = blake3::keyed_hash(key, &payload);
+        Ok(format!("{}.{}", URL_SAFE_NO_PAD.encode(&payload), URL_SAFE_NO_PAD.encode(mac.as_bytes())))
+    }
+    /// Reads a token, which must have been signed with `key`.
+    pub fn verify(key : &[u8; 32], token : &str) -> Result<Continuation, Error>{
+        let invalid = || gerr("The continuation token is invalid or was not signed by this database");
+        let (payload, mac) = token.split_once('.').ok_or_else(invalid)?;
+        let payload = URL_SAFE_NO_PAD.decode(payload).map_err(|_| invalid())?;
+        let mac : [u8; 32] = URL_SAFE_NO_PAD.decode(mac).ok().and_then(|mac| mac.try_into().ok()).ok_or_else(invalid)?;
+        // the comparison of two hashes takes the same time wherever they differ
+        if blake3::keyed_hash(key, &payload) != blake3::Hash::from(mac){
+            return Err(invalid())
+        }
+        serde_json::from_slice(&payload).map_err(|_| invalid())
+    }
+}
+
+/// The signing key of the database at `location`, created on its first start.
+pub fn load_continuation_key(location : &str) -> Result<[u8; 32], Error>{
+    let path = Path::new(location).join(CONTINUATION_KEY_FILE);
+    let mut key = [0u8; 32];
+    match fs::File::open(&path){
+        Ok(mut file) => file.read_exact(&mut key)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            rand::rngs::OsRng.fill_bytes(&mut key);
+            // readable by the owner of the database only, anyone holding it can forge tokens
+            fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path)?.write_all(&key)?;
+        },
+        Err(e) => return Err(e)
+    }
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests{
+    use crate::alba_types::AlbaTypes;
+
+    use super::*;
+
+    fn continuation() -> Continuation{
+        Continuation {
+            search: vec![Token::Keyword("SEARCH".to_string()), Token::Int(3)],
+            after: Keyset { keys: vec![AlbaTypes::Text("b".to_string()), AlbaTypes::Float(0.5)], address: 42 },
+            limit: Some(7),
+        }
+    }
+
+    #[test]
+    fn round_trip(){
+        let key = [7u8; 32];
+        let read = Continuation::verify(&key, &continuation().sign(&key).unwrap()).unwrap();
+        assert_eq!(read.search, continuation().search);
+        assert_eq!(read.after, continuation().after);
+        assert_eq!(read.limit, Some(7));
+    }
+
+    #[test]
+    fn rejects_a_tampered_token(){
+        let key = [7u8; 32];
+        let token = continuation().sign(&key).unwrap();
+        let (payload, mac) = token.split_once('.').unwrap();
+        // the same search resumed further on, still carrying the original signature
+        let mut forged = continuation();
+        forged.after.address = 1000;
+        let forged_payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&forged).unwrap());
+        let mut flipped = URL_SAFE_NO_PAD.decode(mac).unwrap();
+        flipped[0] ^= 1;
+        for token in [
+            format!("{}.{}", forged_payload, mac),
+            format!("{}.{}", payload, URL_SAFE_NO_PAD.encode(&flipped)),
+            format!("{}.{}", payload, &mac[1..]),
+            payload.to_string(),
+            String::new(),
+        ]{
+            assert!(Continuation::verify(&key, &token).is_err(), "accepted {}", token);
+        }
+        assert!(Continuation::verify(&[8u8; 32], &token).is_err());
+    }
+
+    #[test]
+    fn key_file_is_private_and_kept(){
+        use std::os::unix::fs::PermissionsExt;
+        let location = std::env::temp_dir().join(format!("tyto-key-{}", crate::database::generate_secure_code(16)));
+        fs::create_dir_all(&location).unwrap();
+        let location = location.to_string_lossy().to_string();
+        let key = load_continuation_key(&location).unwrap();
+        let mode = fs::metadata(Path::new(&location).join(CONTINUATION_KEY_FILE)).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(load_continuation_key(&location).unwrap(), key);
+        let _ = fs::remove_dir_all(&location);
+    }
+}
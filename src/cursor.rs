@@ -0,0 +1,65 @@
+use std::{collections::HashMap, io::Error, time::{Duration, Instant}};
+
+use crate::{gerr, locking::Session, query::Query};
+
+/// How the cursors of the sessions are kept: how long one may sit unused and how many a
+/// session may hold.
+#[derive(Debug, Clone, Copy)]
+pub struct CursorLimits{
+    pub ttl : Duration,
+    pub per_session : usize,
+}
+
+/// Where a query control moves a cursor.
+#[derive(Debug, Clone, Copy)]
+pub enum CursorMove{
+    Next,
+    Previous,
+    Page(usize),
+}
+
+#[derive(Debug)]
+struct Cursor{
+    query : Query,
+    session : Session,
+    used : Instant,
+}
+
+/// The searches the sessions page through, by query id. A cursor unused for longer than the
+/// ttl is dropped, and a session opening more than its share drops its least recently used one.
+#[derive(Debug, Default)]
+pub struct Cursors{
+    open : HashMap<String, Cursor>,
+}
+
+impl Cursors{
+    /// Keeps `query` for `session` to page through.
+    pub fn open(&mut self, session : Session, query : Query, limits : CursorLimits){
+        self.expire(limits.ttl);
+        if !self.open.contains_key(&query.id){
+            let mut held : Vec<(Instant, String)> = self.open.iter().filter(|(_, c)| c.session == session).map(|(id, c)| (c.used, id.clone())).collect();
+            held.sort_unstable();
+            let excess = (held.len() + 1).saturating_sub(limits.per_session.max(1));
+            for (_, id) in held.into_iter().take(excess){
+                self.open.remove(&id);
+            }
+        }
+        self.open.insert(query.id.clone(), Cursor { query, session, used: Instant::now() });
+    }
+    /// Takes the cursor `id` of `session` out, for a query control to move it before it opens it
+    /// again.
+    pub fn take(&mut self, session : &Session, id : &str, limits : CursorLimits) -> Result<Query, Error>{
+        self.expire(limits.ttl);
+        if self.open.get(id).is_some_and(|cursor| cursor.session == *session) && let Some(cursor) = self.open.remove(id){
+            return Ok(cursor.query)
+        }
+        Err(gerr(&format!("There is no query with the id {}, it was closed or it expired", id)))
+    }
+    /// Closes the cursor `id` of `session`.
+    pub fn close(&mut self, session : &Session, id : &str, limits : CursorLimits) -> Result<(), Error>{
+        self.take(session, id, limits).map(|_| ())
+    }
+    fn expire(&mut self, ttl : Duration){
+        self.open.retain(|_, cursor| cursor.used.elapsed() < ttl);
+    }
+}
@@ -1,12 +1,12 @@
-use std::{collections::HashMap, fs, io::{Error, ErrorKind, Read, Write}, os::unix::fs::FileExt, path::PathBuf, str::FromStr, sync::Arc};
+use std::{collections::{BTreeSet, HashMap}, fs, io::{Error, ErrorKind, Read, Write}, os::unix::fs::FileExt, path::PathBuf, str::FromStr, sync::Arc, time::{Duration, Instant}};
 use ahash::AHashMap;
 use base64::{alphabet, engine::{self, GeneralPurpose}, Engine};
 use lazy_static::lazy_static;
 use serde::{Serialize,Deserialize};
 use serde_yaml;
-use crate::{alba_types::AlbaTypes, container::Container, gerr, indexing::Search, logerr, parser::{debug_tokens, parse}, query::{indexed_search, indexed_search_direct, search, search_direct, Query, SearchArguments}, query_conditions::{QueryConditions, QueryType}, strix::{start_strix, Strix}, AlbaContainer, AST};
+use crate::{aggregate::{AggregateQuery, Aggregation, OrderedValue}, alba_types::AlbaTypes, container::{Container, VERSION_COLUMN}, continuation::{load_continuation_key, Continuation}, cursor::{CursorLimits, CursorMove, Cursors}, explain::{ExecutionProfile, Explanation}, expression::BoundExpression, gerr, ingest::decode_rows, indexing::GetIndex, locking::{LockConflict, RowLocks, Session, LOCAL_SESSION}, join::{JoinSide, JoinSources, JoinTable}, lexer_functions::Token, logerr, memory::{row_size, MemoryAccountant}, parser::{debug_tokens, parse}, projection::Projection, planner::{plan, plan_join, plan_sort, AccessPath, IndexLookup, JoinPlan, JoinStrategy, QueryPlan, SortRequest, SortStrategy}, query::{fetch_matches, index_ordered_search, indexed_search_direct, scan_matches, search_direct, Keyset, PageBuilder, PrimitivePredicate, PrimitiveQueryConditions, Query, QueryPage, QueryWindow, Rows, SearchArguments}, query_conditions::{is_range_indexable, QueryConditions, QueryIndexType}, row::Row, set_operation::{combine, distinct, SetOperand}, subquery::{parse_exists, parse_in, subquery_predicates, ExistsSubquery}, sorting::{compare_keys, extract_keys, key_positions, ExternalSorter, SortEntry, SortKey}, spill::SpillQueue, statistics::analyze, strix::{start_strix, Strix}, AlbaContainer, AstCreateRow, AstDeleteRow, AstJoin, AstEditRow, AstSearch, AstSetOperation, AstUpsertRow, OnConflict, AST};
 use rand::{Rng, distributions::Alphanumeric};
-use tokio::{net::TcpListener, sync::{OnceCell,RwLock}};
+use tokio::{net::TcpListener, sync::{mpsc, OnceCell,RwLock}};
 /////////////////////////////////////////////////
 /////////     DEFAULT_SETTINGS    ///////////////
 /////////////////////////////////////////////////
@@ -16,7 +16,7 @@ const DEFAULT_SETTINGS : &str = r#"
 max_columns: 50
 min_columns: 1
 auto_commit: false            
-memory_limit: 1048576000
+memory_limit: 1048576000 # bytes the sorts, joins and DISTINCT of the running commands may hold, beyond it they write to tmp/
 ip: 127.0.0.1
 connections_port: 1515
 data_port: 8989
@@ -27,6 +27,10 @@ on_insecure_rejection_delay_ms: 100
 safety_level: strict # strict | permissive
 request_handling: sync # sync | asynchronous
 secret_key_count: 10
+lock_timeout_ms: 5000 # how long a command waits for the rows another session locked
+cursor_ttl_secs: 600 # how long the pages of a search are kept once they are no longer read
+max_cursors_per_session: 64 # searches a session may page through at once, the oldest one is closed beyond
+idle_transaction_timeout_secs: 300 # how long a session may leave its changes uncommitted, they are rolled back and its locks released beyond
 "#;
 #[derive(Serialize, Deserialize, Debug, Default)]
 enum SafetyLevel {
@@ -60,7 +64,31 @@ struct Settings{
     on_insecure_rejection_delay_ms: u64,
     safety_level: SafetyLevel,
     request_handling: RequestHandling,
-    secret_key_count: u64
+    secret_key_count: u64,
+    #[serde(default = "default_lock_timeout_ms")]
+    lock_timeout_ms: u64,
+    #[serde(default = "default_cursor_ttl_secs")]
+    cursor_ttl_secs: u64,
+    #[serde(default = "default_max_cursors_per_session")]
+    max_cursors_per_session: usize,
+    #[serde(default = "default_idle_transaction_timeout_secs")]
+    idle_transaction_timeout_secs: u64,
+}
+
+fn default_lock_timeout_ms() -> u64{
+    5000
+}
+
+fn default_cursor_ttl_secs() -> u64{
+    600
+}
+
+fn default_max_cursors_per_session() -> usize{
+    64
+}
+
+fn default_idle_transaction_timeout_secs() -> u64{
+    300
 }
 
 const SECRET_KEY_PATH : &str = "TytoDB/.tytodb-keys";
@@ -103,8 +131,19 @@ pub struct Database{
     containers : Vec<String>,
     headers : Vec<(Vec<String>,Vec<AlbaTypes>)>,
     pub container : HashMap<String,Arc<RwLock<Container>>>,
-    queries : Arc<RwLock<HashMap<String,Query>>>,
+    cursors : Arc<RwLock<Cursors>>,
     secret_keys : Arc<RwLock<HashMap<[u8;32],Vec<u8>>>>,
+    /// Names of the savepoints every session set since its last COMMIT or ROLLBACK, the oldest first.
+    savepoints : AHashMap<Session, Vec<String>>,
+    locks : Arc<RowLocks>,
+    /// The session the running command belongs to.
+    session : Session,
+    /// When every session last ran a command.
+    activity : AHashMap<Session, Instant>,
+    /// Signs the continuation tokens of the `STATELESS` searches.
+    continuation_key : [u8; 32],
+    /// Keeps the intermediate results of every command within `memory_limit`.
+    memory : Arc<MemoryAccountant>,
 }
 
 fn check_for_reference_folder(location : &String) -> Result<(), Error>{
@@ -121,6 +160,8 @@ fn check_for_reference_folder(location : &String) -> Result<(), Error>{
 
 
 const SETTINGS_FILE : &str = "settings.yaml";
+/// Frames of `POST /stream` read ahead of the client, the scan waits once they are all unread.
+const STREAM_BUFFERED_FRAMES : usize = 4;
 fn calculate_header_size(max_columns: usize) -> usize {
     let column_names_size = MAX_STR_LEN * max_columns;
     let column_types_size = max_columns;
@@ -249,28 +290,48 @@ impl Database{
         Ok(())
     }
     
+    /// Commits the changes of the session to every container, or to none of them when a row of
+    /// any has a version conflict.
     pub async fn commit(&mut self) -> Result<(), Error> {
-        
+        let mut conflict = None;
+        for c in self.container.values() {
+            if let Err(e) = c.read().await.check_commit(&self.session).await {
+                conflict = Some(e);
+                break;
+            }
+        }
+        if let Some(e) = conflict {
+            self.rollback().await?;
+            return Err(e);
+        }
         for (_, c) in self.container.iter_mut() {
             
-            c.write().await.commit().await?;
+            c.write().await.commit(&self.session).await?;
             
         }
-        
+        self.savepoints.remove(&self.session);
         Ok(())
     }
     
+    /// Drops the changes of the session to every container.
     pub async fn rollback(&mut self) -> Result<(), Error> {
         
         for (_, c) in self.container.iter_mut() {
             
-            c.write().await.rollback().await?;
+            c.write().await.rollback(&self.session).await?;
             
         }
-        
+        self.savepoints.remove(&self.session);
         Ok(())
     }
     
+    /// Position of the newest savepoint of the session named `name`.
+    fn savepoint_position(&self, name : &str) -> Result<usize, Error> {
+        match self.savepoints.get(&self.session).and_then(|names| names.iter().rposition(|s| s == name)){
+            Some(a) => Ok(a),
+            None => Err(gerr(&format!("There is no savepoint named {}", name)))
+        }
+    }
     pub async fn setup(&self) -> Result<(), Error> {
         let db_path = database_path();
         
@@ -375,7 +436,7 @@ impl Database{
                     actual_length += 1;
                 }
                 if actual_length > 0 {
-                    String::from_utf8_lossy(&name_headers_bytes[start_pos..start_pos + actual_length]).as_bytes().to_vec();
+                    column_names.push(String::from_utf8_lossy(&name_headers_bytes[start_pos..start_pos + actual_length]).to_string());
                 } else {
                     column_names.push(String::new());
                     
@@ -424,6 +485,10 @@ impl Database{
             AST::CreateContainer(structure) => {
                 
                 let path = format!("{}/{}", self.location, structure.name);
+                // the version of the rows is added by the container, past the columns of its header
+                if structure.col_nam.iter().any(|c| c == VERSION_COLUMN) {
+                    return Err(gerr(&format!("The column name {} is reserved for the version of the rows", VERSION_COLUMN)));
+                }
                 
                 if !match fs::exists(path.clone()) {
                     Ok(a) => a,
@@ -504,6 +569,8 @@ impl Database{
                             return Err(e);
                         }
                     };
+                    // the container reads its header back while it is opened
+                    file.flush().await?;
                     self.containers.push(structure.name.clone());
                     
                     let mut element_size: usize = 0;
@@ -525,6 +592,15 @@ impl Database{
                             column_name_headers.clone(),
                         ).await?,
                     );
+                    // the changes of a container created after a savepoint are all undone by rolling back to it
+                    if let Some(container) = self.container.get(&structure.name){
+                        let container = container.read().await;
+                        for (session, names) in self.savepoints.iter(){
+                            for name in names{
+                                container.savepoint(name, session).await;
+                            }
+                        }
+                    }
                     if let Err(e) = self.save_containers() {
                         
                         return Err(gerr(&e.to_string()));
@@ -542,274 +618,95 @@ impl Database{
                 }
             },
             AST::CreateRow(structure) => {
-                
-                let mut container = match self.container.get_mut(&structure.container) {
+                let container = match self.container.get(&structure.container) {
                     None => {
                         
                         return Err(gerr(&format!("Container '{}' does not exist.", structure.container)));
                     },
-                    Some(a) => a.write().await,
+                    Some(a) => a.clone(),
                 };
-                
-                if structure.col_nam.len() != structure.col_val.len() {
-                    
-                    return Err(gerr(&format!(
-                        "In CREATE ROW, expected {} values for the specified columns, but got {}",
-                        structure.col_nam.len(),
-                        structure.col_val.len()
-                    )));
-                }
-                for i in &structure.col_nam {
-                    if !container.column_names().contains(&i) {
-                        
-                        return Err(gerr(&format!("There is no column {} in the container {}", i, structure.container)));
-                    }
-                }
-                let mut val: Vec<AlbaTypes> = container.columns();
-                
-                for (index, col_name) in structure.col_nam.iter().enumerate() {
-                    match container.column_names().iter().position(|c| c == col_name) {
-                        Some(ri) => {
-                            let input_val = structure.col_val.get(index).cloned().unwrap();
-                            let expected_val = &container.columns()[ri];
-                            
-                            if let AlbaTypes::NONE = &input_val {
-                                val[ri] = AlbaTypes::NONE;
-                                
-                            } else if let AlbaTypes::Text(_) = expected_val {
-                                if let AlbaTypes::Text(s) = &input_val {
-                                    let mut code = generate_secure_code(MAX_STR_LEN);
-                                    let txt_path = format!("{}/rf/{}", self.location, code);
-                                    
-                                    while fs::exists(&txt_path)? {
-                                        let code_full = generate_secure_code(MAX_STR_LEN);
-                                        code = code_full.chars().take(MAX_STR_LEN).collect::<String>();
-                                        
-                                    }
-                                    val[ri] = AlbaTypes::Text(code.clone());
-                                    let mut mvcc = container.mvcc.write().await;
-                                    
-                                    mvcc.1.insert(code, (false, s.to_string()));
-                                } else {
-                                    
-                                    return Err(gerr(&format!(
-                                        "For column '{}', expected Text, but got {:?}.",
-                                        col_name, input_val
-                                    )));
-                                }
-                            } else if std::mem::discriminant(&input_val) == std::mem::discriminant(expected_val) {
-                                val[ri] = input_val;
-                                
-                            } else {
-                                match expected_val.try_from_existing(input_val.clone()) {
-                                    Ok(converted_val) => {
-                                        val[ri] = converted_val;
-                                        
-                                    },
-                                    Err(e) => {
-                                        
-                                        return Err(gerr(&format!(
-                                            "Type conversion error for column '{}': expected {:?}, got {:?}. Error: {}",
-                                            col_name, expected_val, input_val, e
-                                        )));
-                                    }
-                                }
-                            }
-                        },
-                        None => {
-                            
-                            return Err(gerr(&format!(
-                                "Column '{}' not found in container '{}'.",
-                                col_name, structure.container
-                            )));
+                let mut container = container.write().await;
+                check_returning(&container, structure.returning.as_ref())?;
+                let rows = self.row_values(&container, &structure).await?;
+                let addresses = container.push_rows(rows, &self.session).await?;
+                // read back before the commit, while the contents of Text values are at hand
+                let mut rows = Vec::new();
+                if structure.returning.is_some() {
+                    let positions: Vec<usize> = (0..container.headers.len()).collect();
+                    for (row, address) in container.get_rows(&addresses, &positions, &self.session).await?.into_iter().zip(addresses.iter()) {
+                        if let Some(row) = row {
+                            rows.push((row, *address));
                         }
                     }
                 }
-                
-                container.push_row(&val).await?;
                 if self.settings.auto_commit {
                     
-                    container.commit().await?;
+                    container.commit(&self.session).await?;
                 }
-                
+                return write_result(&container, addresses.len(), rows, structure.returning.as_ref())
+            },
+            AST::Search(structure) if structure.stateless.is_some() => {
+                return Err(gerr("STATELESS can only end a SEARCH sent on its own, not a subquery, an operand or an EXPLAIN"))
             },
             AST::Search(structure) => {
-                let mut query : Option<Query> = None;
-                for i in structure.container{
-                    if let AlbaContainer::Virtual(virt) = i{
-                        let ast = debug_tokens(&virt)?;
-                        let result_query = Box::pin(self.run(ast)).await?;
-                        if let Some(ref mut q) = query{
-                            q.join(result_query);
-                        }else{
-                            query = Some(result_query)
-                        }
-                        continue;
-                    }
-                    if let AlbaContainer::Real(container_name) = i{
-                        let container = match self.container.get(&container_name){
-                            Some(a) => a,
-                            None => {return Err(gerr(&format!("Failed to perform the query, there is no container named {}",container_name)))}
-                        };
-                        let header_types = container.read().await.headers.clone();
-
-                        let mut headers_hash_map = HashMap::new();
-                        for i in header_types.iter().cloned(){
-                            headers_hash_map.insert(i.0,i.1);
-                        }
-                        let qc = QueryConditions::from_primitive_conditions( structure.conditions.clone(), &headers_hash_map,if let Some(a) = header_types.first(){a.0.clone()}else{return Err(gerr("Error, no primary key found"))})?;
-                        let container_book = container.read().await;
-                        let qt = qc.query_type()?;
-                        let result = match qt{
-                            QueryType::Scan => { search(container.clone(), SearchArguments{
-                                element_size: container_book.element_size.clone(),
-                                header_offset: container_book.headers_offset.clone() as usize,
-                                file: container_book.file.clone(),
-                                container_values: header_types,
-                                container_name,
-                                conditions: qc,
-                            }).await?}
-                            QueryType::Indexed(query_index_type) => {
-                                let values = match query_index_type{
-                                    crate::query_conditions::QueryIndexType::Strict(t) => container_book.indexing.search(t).await,
-                                    crate::query_conditions::QueryIndexType::Range(t) => container_book.indexing.search(t).await,
-                                    crate::query_conditions::QueryIndexType::InclusiveRange(t) => container_book.indexing.search(t).await,
-                                }?;
-                                indexed_search(container.clone(), SearchArguments{
-                                    element_size: container_book.element_size.clone(),
-                                    header_offset: container_book.headers_offset.clone() as usize,
-                                    file: container_book.file.clone(),
-                                    container_values: header_types,
-                                    container_name,
-                                    conditions: qc,
-                                },&values).await?
-                            }
-                        };
-                        match query{
-                            Some(ref mut b) => b.join(result),
-                            None => {query = Some(result)}
-                        }
-                    };
-                }
-                if let Some(q) = query{
-                    return Ok(q)
-                }else{
-                    return Err(gerr("Error, no query result found"))
-                }
+                return self.search(*structure, &mut ExecutionProfile::default()).await
             },
-            AST::EditRow(structure) => {
+            AST::Continue(_) => {
+                return Err(gerr("CONTINUE can only be sent on its own"))
+            },
+            AST::SetOperation(structure) => {
+                return self.set_operation(structure).await
+            },
+            AST::CreateIndex(structure) => {
                 let container = match self.container.get(&structure.container){
-                    Some(a) => a,
-                    None => {return Err(gerr(&format!("Failed to perform the query, there is no container named {}",structure.container)))}
+                    Some(a) => a.clone(),
+                    None => {return Err(gerr(&format!("Container '{}' does not exist.", structure.container)))}
                 };
-                let header_types = container.read().await.headers.clone();
-
-                let mut headers_hash_map = HashMap::new();
-                for i in header_types.iter().cloned(){
-                    headers_hash_map.insert(i.0,i.1);
-                }
-                let qc = QueryConditions::from_primitive_conditions( structure.conditions.clone(), &headers_hash_map,if let Some(a) = header_types.first(){a.0.clone()}else{return Err(gerr("Error, no primary key found"))})?;
-                let container_book = container.read().await;
-                let qt = qc.query_type()?;
-
-                let mut column_name_idx : AHashMap<String,usize> = AHashMap::new();
-                let mut changes : AHashMap<usize,AlbaTypes> = AHashMap::new();
-                for i in container.read().await.headers.iter().enumerate(){
-                    column_name_idx.insert(i.1.0.clone(), i.0);
-                }
-                for i in structure.col_nam.iter().enumerate(){
-                    let val = if let Some(v) = structure.col_val.get(i.0){
-                        v
-                    }else{
-                        return Err(gerr("Failed to execute edit because there is a value missing for one of the columns entered"))
-                    };
-                    let id = column_name_idx.get(i.1).unwrap();
-                    changes.insert(*id, val.to_owned());
-                }
-
-                let result : Vec<(Vec<AlbaTypes>,u64)> = match qt{
-                    QueryType::Scan => { search_direct(container.clone(), SearchArguments{
-                        element_size: container_book.element_size.clone(),
-                        header_offset: container_book.headers_offset.clone() as usize,
-                        file: container_book.file.clone(),
-                        container_values: header_types,
-                        container_name: structure.container,
-                        conditions: qc,
-                    }).await?}
-                    QueryType::Indexed(query_index_type) => {
-                        let values = match query_index_type{
-                            crate::query_conditions::QueryIndexType::Strict(t) => container_book.indexing.search(t).await,
-                            crate::query_conditions::QueryIndexType::Range(t) => container_book.indexing.search(t).await,
-                            crate::query_conditions::QueryIndexType::InclusiveRange(t) => container_book.indexing.search(t).await,
-                        }?;
-                        indexed_search_direct(container.clone(), SearchArguments{
-                            element_size: container_book.element_size.clone(),
-                            header_offset: container_book.headers_offset.clone() as usize,
-                            file: container_book.file.clone(),
-                            container_values: header_types,
-                            container_name: structure.container,
-                            conditions: qc,
-                        },&values).await?
+                // checked and built under the write lock, so a concurrent CREATE INDEX on the same
+                // column or a commit can not slip in between; the pending rows join it on commit
+                let mut container = container.write().await;
+                let primary_key = container.headers.first().map(|h| h.0.clone()).unwrap_or_default();
+                let indexed = container.secondary_indexes();
+                for (position, column) in structure.col_nam.iter().enumerate(){
+                    if column == VERSION_COLUMN{
+                        return Err(gerr(&format!("The column {} is set by COMMIT and can not be indexed", VERSION_COLUMN)));
                     }
-                }.iter_mut().map(|f|{
-                    for i in &changes{
-                        f.0.insert(i.0.clone(), i.1.clone());
+                    if !container.headers.iter().any(|h| h.0 == *column){
+                        return Err(gerr(&format!("There is no column {} in the container {}", column, structure.container)));
                     }
-                    f.to_owned()
-                }).collect();
-                
-                let container = container.write().await;
-                let mut mvcc = container.mvcc.write().await;
-                for i in result{
-                    mvcc.0.insert(i.1, (false,i.0));
+                    if *column == primary_key || indexed.contains(column) || structure.col_nam[..position].contains(column){
+                        return Err(gerr(&format!("The column {} is already indexed", column)));
+                    }
+                }
+                for column in structure.col_nam.iter(){
+                    container.create_secondary_index(column).await?;
                 }
             },
-            AST::DeleteRow(structure) => {
+            AST::Analyze(structure) => {
                 let container = match self.container.get(&structure.container){
-                    Some(a) => a,
-                    None => {return Err(gerr(&format!("Failed to perform the query, there is no container named {}",structure.container)))}
+                    Some(a) => a.clone(),
+                    None => {return Err(gerr(&format!("Container '{}' does not exist.", structure.container)))}
                 };
-                let header_types = container.read().await.headers.clone();
-
-                let mut headers_hash_map = HashMap::new();
-                for i in header_types.iter().cloned(){
-                    headers_hash_map.insert(i.0,i.1);
-                }
-                let qc = QueryConditions::from_primitive_conditions( if let Some(c) = structure.conditions{c}else{(Vec::new(),Vec::new())}, &headers_hash_map,if let Some(a) = header_types.first(){a.0.clone()}else{return Err(gerr("Error, no primary key found"))})?;
-                let container_book = container.read().await;
-                let qt = qc.query_type()?;
-
-                let result : Vec<(Vec<AlbaTypes>,u64)> = match qt{
-                    QueryType::Scan => { search_direct(container.clone(), SearchArguments{
-                        element_size: container_book.element_size.clone(),
-                        header_offset: container_book.headers_offset.clone() as usize,
-                        file: container_book.file.clone(),
-                        container_values: header_types,
-                        container_name: structure.container,
-                        conditions: qc,
-                    }).await?}
-                    QueryType::Indexed(query_index_type) => {
-                        let values = match query_index_type{
-                            crate::query_conditions::QueryIndexType::Strict(t) => container_book.indexing.search(t).await,
-                            crate::query_conditions::QueryIndexType::Range(t) => container_book.indexing.search(t).await,
-                            crate::query_conditions::QueryIndexType::InclusiveRange(t) => container_book.indexing.search(t).await,
-                        }?;
-                        indexed_search_direct(container.clone(), SearchArguments{
-                            element_size: container_book.element_size.clone(),
-                            header_offset: container_book.headers_offset.clone() as usize,
-                            file: container_book.file.clone(),
-                            container_values: header_types,
-                            container_name: structure.container,
-                            conditions: qc,
-                        },&values).await?
-                    }
-                };
-                
-                let container = container.write().await;
-                let mut mvcc = container.mvcc.write().await;
-                for i in result{
-                    mvcc.0.insert(i.1, (true,i.0));
-                }
+                let statistics = analyze(container.clone()).await?;
+                let mut container = container.write().await;
+                statistics.save(&container.statistics_path())?;
+                let result = statistics.to_query();
+                container.statistics = Some(statistics);
+                return Ok(result)
+            },
+            AST::UpsertRow(structure) => {
+                let mut result = Query::new_none(Vec::new());
+                result.affected = self.upsert_row(structure).await?;
+                return Ok(result)
+            },
+            AST::EditRow(structure) => {
+                return self.edit_rows(structure, &mut ExecutionProfile::default()).await
+            },
+            AST::DeleteRow(structure) => {
+                return self.delete_rows(structure, &mut ExecutionProfile::default()).await
+            },
+            AST::Explain(structure) => {
+                return self.explain(*structure.command, structure.analyze).await
             },
             AST::DeleteContainer(structure) => {
                 
@@ -825,7 +722,10 @@ impl Database{
                         self.containers.remove(i);
                         
                     }
-                    self.container.remove(&structure.container);
+                    if let Some(container) = self.container.remove(&structure.container){
+                        container.read().await.destroy_auxiliary_files().await?;
+                    }
+                    self.locks.forget(&structure.container);
                     
                     let path = format!("{}/{}", self.location, structure.container);
                     tokio::fs::remove_file(path.clone()).await?;
@@ -844,7 +744,10 @@ impl Database{
                         match self.container.get_mut(&container) {
                             Some(a) => {
                                 
-                                a.write().await.commit().await?;
+                                // a commit failing on a version conflict drops the pending changes too
+                                let committed = a.write().await.commit(&self.session).await;
+                                self.locks.release(&self.session, Some(&container));
+                                committed?;
                                 
                                 return Ok(Query::new(Vec::new()));
                             },
@@ -856,8 +759,9 @@ impl Database{
                     },
                     None => {
                         
-                        self.commit().await?;
-                        
+                        let committed = self.commit().await;
+                        self.locks.release(&self.session, None);
+                        committed?;
                     }
                 }
             },
@@ -868,7 +772,8 @@ impl Database{
                         match self.container.get_mut(&container) {
                             Some(a) => {
                                 
-                                a.write().await.rollback().await?;
+                                a.write().await.rollback(&self.session).await?;
+                                self.locks.release(&self.session, Some(&container));
                                 
                                 return Ok(Query::new(Vec::new()));
                             },
@@ -881,54 +786,1195 @@ impl Database{
                     None => {
                         
                         self.rollback().await?;
-                        
+                        self.locks.release(&self.session, None);
                     }
                 }
             },
-            AST::QueryControlNext(cmd) => {
-                
-                let mut q = {
-                    let mut guard = self.queries.write().await;
-                    
-                    guard.remove(&cmd.id).expect("query must exist")
-                };
-                
-                q.next(self).await?;
-                let q1 = q.duplicate();
-                
-                self.queries.write().await.insert(cmd.id, q);
-                return Ok(q1);
+            AST::Savepoint(structure) => {
+                for container in self.container.values(){
+                    container.read().await.savepoint(&structure.name, &self.session).await;
+                }
+                self.savepoints.entry(self.session).or_default().push(structure.name);
+                return Ok(Query::new_none(Vec::new()));
             },
-            AST::QueryControlPrevious(cmd) => {
-                
-                let mut q = {
-                    let mut guard = self.queries.write().await;
-                    
-                    guard.remove(&cmd.id).expect("query must exist")
-                };
-                
-                q.previous(self).await?;
-                let q2 = q.duplicate();
-                
-                self.queries.write().await.insert(cmd.id, q);
-                return Ok(q2);
+            AST::RollbackToSavepoint(structure) => {
+                let position = self.savepoint_position(&structure.name)?;
+                for container in self.container.values(){
+                    container.read().await.rollback_to(&structure.name, &self.session).await;
+                }
+                self.savepoints.entry(self.session).or_default().truncate(position + 1);
+                return Ok(Query::new_none(Vec::new()));
+            },
+            AST::ReleaseSavepoint(structure) => {
+                let position = self.savepoint_position(&structure.name)?;
+                for container in self.container.values(){
+                    container.read().await.release(&structure.name, &self.session).await;
+                }
+                self.savepoints.entry(self.session).or_default().truncate(position);
+                return Ok(Query::new_none(Vec::new()));
             },
+            AST::QueryControlNext(cmd) => return self.move_cursor(&cmd.id, CursorMove::Next).await,
+            AST::QueryControlPrevious(cmd) => return self.move_cursor(&cmd.id, CursorMove::Previous).await,
+            AST::QueryControlGoto(cmd) => return self.move_cursor(&cmd.id, CursorMove::Page(cmd.page)).await,
             AST::QueryControlExit(cmd) => {
-                
-                let mut guard = self.queries.write().await;
-                guard.remove(&cmd.id).expect("query must exist");
-                
+                let limits = self.cursor_limits();
+                self.cursors.write().await.close(&self.session, &cmd.id, limits)?;
             }
         }
         
         Ok(Query::new_none(Vec::new()))
     }
     
-    pub async fn execute(&mut self, input: &str, arguments: Vec<String>) -> Result<Query, Error> {
-        let ast = parse(input.to_owned(), arguments)?;
-        let result = self.run(ast).await?;
+    /// Builds the typed conditions for a container and lets the planner choose how its rows are reached
+    /// and, for an `ORDER BY`, how they get sorted.
+    async fn plan_conditions(&self, container_name: &String, conditions: PrimitiveQueryConditions, sort: &SortRequest<'_>) -> Result<(Arc<RwLock<Container>>, QueryConditions, QueryPlan), Error> {
+        let container = match self.container.get(container_name){
+            Some(a) => a.clone(),
+            None => {return Err(gerr(&format!("Failed to perform the query, there is no container named {}",container_name)))}
+        };
+        let container_book = container.read().await;
+        let mut headers_hash_map = HashMap::new();
+        for i in container_book.headers.iter().cloned(){
+            headers_hash_map.insert(i.0,i.1);
+        }
+        let context = container_book.planner_context().await?;
+        let qc = QueryConditions::from_primitive_conditions(conditions, &headers_hash_map, context.primary_key.to_string())?;
+        let mut plan = plan(&qc, &context)?;
+        if let Some(first) = key_positions(&container_book.headers, sort.order_by)?.first(){
+            // the index holds no pending row, so its order leaves them out
+            let ordered = sort.single_source && is_range_indexable(&container_book.headers[*first].1) && !container_book.has_pending(&self.session).await;
+            plan_sort(&mut plan, &context, sort, ordered);
+        }
+        drop(container_book);
+        Ok((container, qc, plan))
+    }
+    /// Plans the conditions and resolves the index addresses, recording both in the profile.
+    /// No addresses are resolved when the rows will be read in index order instead.
+    async fn prepare_search(&self, container_name: &String, conditions: PrimitiveQueryConditions, sort: &SortRequest<'_>, profile: &mut ExecutionProfile) -> Result<(Arc<RwLock<Container>>, SearchArguments, Option<BTreeSet<u64>>, QueryPlan), Error> {
+        let started = Instant::now();
+        let (container, qc, plan) = self.plan_conditions(container_name, conditions, sort).await?;
+        profile.stage("plan", started);
+        profile.plans.push((container_name.clone(), qc.clone(), plan.clone()));
+
+        let access = match plan.sort{
+            Some(SortStrategy::Index(_)) => &AccessPath::Scan,
+            _ => &plan.access
+        };
+        let (arguments, addresses) = access_rows(&container, qc, access, &self.session, profile).await?;
+        Ok((container, arguments, addresses, plan))
+    }
+    async fn matching_rows(&self, container_name: &String, conditions: PrimitiveQueryConditions, profile: &mut ExecutionProfile) -> Result<(Arc<RwLock<Container>>, Vec<(Vec<AlbaTypes>,u64)>), Error> {
+        let (container, arguments, addresses, _) = self.prepare_search(container_name, conditions, &SortRequest::UNORDERED, profile).await?;
+        let started = Instant::now();
+        let result = match addresses{
+            None => search_direct(container.clone(), arguments).await?,
+            Some(values) => indexed_search_direct(container.clone(), arguments, &values).await?
+        };
+        profile.stage("read", started);
+        Ok((container, result))
+    }
+    async fn source_headers(&self, container_name: &str) -> Result<Vec<(String, AlbaTypes)>, Error> {
+        match self.container.get(container_name){
+            Some(a) => Ok(a.read().await.headers.clone()),
+            None => Err(gerr(&format!("Failed to perform the query, there is no container named {}", container_name)))
+        }
+    }
+    /// Runs the SEARCH of a subquery source. The pages of a JOIN hold pairs of addresses, which
+    /// the other searches can not read.
+    async fn subquery(&mut self, tokens: &Vec<Token>) -> Result<Query, Error> {
+        let subquery = Box::pin(self.run(debug_tokens(tokens)?)).await?;
+        if subquery.joined.is_some(){
+            return Err(gerr("A SEARCH with JOIN can not be the source of another SEARCH"))
+        }
+        Ok(subquery)
+    }
+    /// Runs the IN and EXISTS subqueries of a WHERE and puts their results in their place, so the
+    /// planner sees plain IN lists, able to use the index of their column, and constants. `outer` are
+    /// the containers the WHERE reads, which a correlated EXISTS can refer to; `qualified` is set when
+    /// the WHERE names their columns `container.column`, like the one of a JOIN.
+    async fn resolve_subqueries(&mut self, mut conditions: PrimitiveQueryConditions, outer: &[String], qualified: bool) -> Result<PrimitiveQueryConditions, Error> {
+        let mut predicates = Vec::new();
+        if let Some(predicate) = conditions.as_mut(){
+            subquery_predicates(predicate, &mut predicates);
+        }
+        if predicates.is_empty(){
+            return Ok(conditions)
+        }
+        let mut sources = Vec::with_capacity(outer.len());
+        for name in outer{
+            sources.push((name.clone(), self.source_headers(name).await?.into_iter().map(|h| h.0).collect::<Vec<String>>()));
+        }
+        for predicate in predicates{
+            *predicate = match std::mem::replace(predicate, PrimitivePredicate::Constant(false)){
+                PrimitivePredicate::InSearch(column, command) => {
+                    let search = parse_in(&command, &sources)?;
+                    PrimitivePredicate::InValues(column, self.subquery_column(search).await?)
+                },
+                PrimitivePredicate::Exists(command) => match parse_exists(&command, &sources)?{
+                    ExistsSubquery::Uncorrelated(mut search) => {
+                        // one row is enough to know
+                        if let AST::Search(structure) = &mut search && !is_aggregate(structure){
+                            structure.window.limit = Some(structure.window.limit.map_or(1, |limit| limit.min(1)));
+                        }
+                        PrimitivePredicate::Constant(!self.subquery_rows(search).await?.is_empty())
+                    },
+                    ExistsSubquery::Correlated { search, outer: (container, column) } => {
+                        let column = if qualified{
+                            format!("{}.{}", container, column)
+                        }else if outer.len() == 1{
+                            column
+                        }else{
+                            return Err(gerr("A correlated EXISTS needs a single container in the outer command"))
+                        };
+                        PrimitivePredicate::InValues(Token::String(column), self.subquery_column(search).await?)
+                    }
+                },
+                other => other
+            };
+        }
+        Ok(conditions)
+    }
+    async fn resolve_search(&mut self, structure: &mut AstSearch) -> Result<(), Error> {
+        let mut outer : Vec<String> = structure.container.iter().filter_map(|c| match c{
+            AlbaContainer::Real(name) => Some(name.clone()),
+            AlbaContainer::Virtual(_) => None
+        }).collect();
+        outer.extend(structure.join.as_ref().map(|join| join.container.clone()));
+        structure.conditions = self.resolve_subqueries(structure.conditions.take(), &outer, structure.join.is_some()).await?;
+        structure.having = self.resolve_subqueries(structure.having.take(), &[], false).await?;
+        Ok(())
+    }
+    /// Runs the SEARCH of an IN or EXISTS and reads the values of its rows. Rows deleted since the
+    /// search are left out.
+    async fn subquery_rows(&mut self, search: AST) -> Result<Vec<Vec<AlbaTypes>>, Error> {
+        let aggregate = match &search{
+            AST::Search(structure) if structure.join.is_some() => return Err(gerr("A SEARCH with JOIN can not be the subquery of IN or EXISTS yet")),
+            AST::Search(structure) => is_aggregate(structure),
+            _ => false
+        };
+        let query = Box::pin(self.run(search)).await?;
+        if aggregate{
+            return Ok(query.rows.1)
+        }
+        Ok(SetOperand::read(query, self).await?.into_values())
+    }
+    /// The values of the single column a subquery returns, NULL left out as it equals nothing.
+    async fn subquery_column(&mut self, search: AST) -> Result<Vec<AlbaTypes>, Error> {
+        let mut values = Vec::new();
+        for row in self.subquery_rows(search).await?{
+            let [value] = <[AlbaTypes; 1]>::try_from(row).map_err(|row| gerr(&format!("The SEARCH of IN must return a single column, but it returns {}", row.len())))?;
+            if !matches!(value, AlbaTypes::NONE){
+                values.push(value);
+            }
+        }
+        Ok(values)
+    }
+    async fn search(&mut self, mut structure: AstSearch, profile: &mut ExecutionProfile) -> Result<Query, Error> {
+        if structure.for_update{
+            return self.search_for_update(structure, profile).await
+        }
+        self.resolve_search(&mut structure).await?;
+        if let Some(join) = structure.join.clone(){
+            return self.join_search(structure, join, profile).await
+        }
+        if is_aggregate(&structure){
+            return self.aggregate_search(structure, profile).await
+        }
+        // the window applies to the distinct rows, so every row is searched first
+        let (window, distinct_rows) = (structure.window, structure.distinct);
+        if distinct_rows{
+            structure.window = QueryWindow::default();
+        }
+        let pages = PageBuilder::new(structure.window, structure.page_size, structure.projection.clone());
+        let query = if structure.order_by.is_empty(){
+            self.unordered_search(structure, pages, profile).await?
+        }else{
+            self.ordered_search(structure, pages, profile).await?
+        };
+        if distinct_rows{
+            let started = Instant::now();
+            let query = distinct(query, window, self, &profile.counters).await?;
+            profile.stage("distinct", started);
+            return Ok(query)
+        }
+        Ok(query)
+    }
+    /// `SEARCH ... STATELESS`: a single page, with a signed continuation token for the next one
+    /// instead of a cursor kept by the server.
+    async fn stateless_search(&mut self, mut structure: AstSearch) -> Result<Query, Error> {
+        let container_name = match structure.container.as_slice(){
+            [AlbaContainer::Real(name)] => name.clone(),
+            _ => return Err(gerr("STATELESS needs a single container, not a subquery"))
+        };
+        if is_aggregate(&structure) || structure.join.is_some() || structure.distinct || structure.for_update{
+            return Err(gerr("STATELESS can not be used with aggregates, JOIN, DISTINCT or FOR UPDATE"))
+        }
+        let search = structure.stateless.take().unwrap_or_default();
+        let limit = structure.window.limit;
+        let wanted = limit.map_or(structure.page_size as u64, |limit| limit.min(structure.page_size as u64));
+        // the row after the page tells whether there is a next one
+        structure.window.limit = Some(wanted + 1);
+        let order_by = structure.order_by.clone();
+        let mut result = self.search(structure, &mut ExecutionProfile::default()).await?;
+        let mut addresses : Vec<u64> = std::mem::take(&mut result.pages).into_iter().flat_map(|page| page.addresses).collect();
+        let more = addresses.len() as u64 > wanted;
+        addresses.truncate(wanted as usize);
+        // no cursor is kept for it
+        result.id = String::new();
+        if let Some(&address) = addresses.last() && more && limit.is_none_or(|limit| limit > wanted){
+            let keys = {
+                let container = match self.container.get(&container_name){
+                    Some(a) => a.read().await,
+                    None => return Err(gerr(&format!("Failed to perform the query, there is no container named {}", container_name)))
+                };
+                let positions = key_positions(&container.headers, &order_by)?;
+                container.get_rows(&[address], &positions, &self.session).await?.pop().flatten().unwrap_or_default()
+            };
+            let continuation = Continuation { search, after: Keyset { keys, address }, limit: limit.map(|limit| limit - wanted) };
+            result.continuation = Some(continuation.sign(&self.continuation_key)?);
+        }
+        if !addresses.is_empty(){
+            result.pages.push(QueryPage { addresses, container: container_name, projection: 0 });
+        }
+        result.load_rows(self, &self.session).await?;
         Ok(result)
     }
+    /// `CONTINUE`: the next page of the `STATELESS` search a continuation token was given by.
+    async fn continue_search(&mut self, token: &str) -> Result<Query, Error> {
+        let continuation = Continuation::verify(&self.continuation_key, token)?;
+        let mut structure = match debug_tokens(&continuation.search)?{
+            AST::Search(structure) if structure.stateless.is_some() => structure,
+            _ => return Err(gerr("The continuation token does not continue a STATELESS search"))
+        };
+        structure.resume = Some(continuation.after);
+        structure.window = QueryWindow { limit: continuation.limit, offset: 0 };
+        self.stateless_search(*structure).await
+    }
+    /// `SEARCH ... FOR UPDATE`, which locks the rows of every page of the result.
+    async fn search_for_update(&mut self, mut structure: AstSearch, profile: &mut ExecutionProfile) -> Result<Query, Error> {
+        if is_aggregate(&structure) || structure.join.is_some() || structure.distinct{
+            return Err(gerr("FOR UPDATE can not be used with aggregates, JOIN or DISTINCT"))
+        }
+        structure.for_update = false;
+        let query = Box::pin(self.search(structure, profile)).await?;
+        let rows = query.pages.iter().flat_map(|page| page.addresses.iter().map(|address| (page.container.as_str(), *address)));
+        self.locks.acquire_rows(&self.session, rows)?;
+        Ok(query)
+    }
+    /// `SEARCH` with aggregates or `GROUP BY`. The matching rows of every source are folded into their
+    /// groups as they are read, then HAVING, ORDER BY and the window apply to the groups, which come
+    /// back as the rows of a single page.
+    async fn aggregate_search(&mut self, structure: AstSearch, profile: &mut ExecutionProfile) -> Result<Query, Error> {
+        let query = AggregateQuery::new(&structure.projection, &structure.group_by)?;
+        let mut aggregation = Aggregation::new(&query);
+        for i in structure.container{
+            match i{
+                AlbaContainer::Virtual(virt) => {
+                    let subquery = self.subquery(&virt).await?;
+                    for QueryPage { addresses, container: container_name, .. } in subquery.pages{
+                        let container = match self.container.get(&container_name){
+                            Some(a) => a.clone(),
+                            None => return Err(gerr(&format!("Failed to perform the query, there is no container named {}", container_name)))
+                        };
+                        let mut arguments = container.read().await.search_arguments(QueryConditions::default(), Some(self.session));
+                        arguments.counters = profile.counters.clone();
+                        let binding = query.bind(&arguments.container_values, &container_name)?;
+                        aggregation.bind(&binding);
+                        arguments.columns = binding.columns.clone();
+                        let started = Instant::now();
+                        fetch_matches(&container, &arguments, addresses, |row, _| {
+                            aggregation.push(&binding, &row);
+                            Ok(true)
+                        }).await?;
+                        profile.stage("read", started);
+                    }
+                },
+                AlbaContainer::Real(container_name) => {
+                    let (container, mut arguments, addresses, _) = self.prepare_search(&container_name, structure.conditions.clone(), &SortRequest::UNORDERED, profile).await?;
+                    let binding = query.bind(&arguments.container_values, &container_name)?;
+                    aggregation.bind(&binding);
+                    arguments.load_only_needed(&[]);
+                    for (loaded, read) in arguments.columns.iter_mut().zip(binding.columns.iter()){
+                        *loaded |= *read;
+                    }
+                    let started = Instant::now();
+                    let push = |row: Vec<AlbaTypes>, _: u64| {
+                        aggregation.push(&binding, &row);
+                        Ok(true)
+                    };
+                    match addresses{
+                        None => scan_matches(&container, &arguments, push).await?,
+                        Some(values) => fetch_matches(&container, &arguments, values, push).await?
+                    }
+                    profile.stage("read", started);
+                }
+            }
+        }
+        let started = Instant::now();
+        let (mut rows, types) = aggregation.finish()?;
+        let names = query.referable_names();
+        let headers : Vec<(String, AlbaTypes)> = names.iter().map(|(name, p)| (name.clone(), types.get(*p).cloned().unwrap_or(AlbaTypes::NONE))).collect();
+        if let Some(having) = structure.having{
+            let properties : HashMap<String, AlbaTypes> = headers.iter().cloned().collect();
+            let conditions = QueryConditions::from_primitive_conditions(Some(having), &properties, headers[0].0.clone())?;
+            let mut kept = Vec::with_capacity(rows.len());
+            for row in rows{
+                let data = names.iter().map(|(name, p)| (name.clone(), row[*p].clone())).collect();
+                if conditions.row_match(&Row { data })?{
+                    kept.push(row);
+                }
+            }
+            rows = kept;
+        }
+        if !structure.order_by.is_empty(){
+            let positions : Vec<usize> = key_positions(&headers, &structure.order_by)?.into_iter().map(|p| names[p].1).collect();
+            let mut keyed : Vec<(Vec<AlbaTypes>, Vec<AlbaTypes>)> = rows.into_iter().map(|row| (extract_keys(&row, &positions), row)).collect();
+            keyed.sort_by(|a, b| compare_keys(&a.0, &b.0, &structure.order_by));
+            rows = keyed.into_iter().map(|(_, row)| row).collect();
+        }
+        if structure.distinct{
+            let mut seen = BTreeSet::new();
+            rows.retain(|row| seen.insert(row.iter().cloned().map(OrderedValue).collect::<Vec<_>>()));
+        }
+        let limit = structure.window.limit.map_or(usize::MAX, |l| usize::try_from(l).unwrap_or(usize::MAX));
+        let rows : Vec<Vec<AlbaTypes>> = rows.into_iter().skip(usize::try_from(structure.window.offset).unwrap_or(usize::MAX)).take(limit).collect();
+        profile.stage("aggregate", started);
+        let mut result = Query::new_none(types);
+        result.column_names = query.names().to_vec();
+        result.rows = (result.column_names.clone(), rows);
+        Ok(result)
+    }
+    /// `SEARCH` without `ORDER BY`. The sources are read one after the other and the search stops
+    /// as soon as the `LIMIT` is reached, leaving the rest of the rows and containers unread.
+    async fn unordered_search(&mut self, structure: AstSearch, mut pages: PageBuilder, profile: &mut ExecutionProfile) -> Result<Query, Error> {
+        for i in structure.container{
+            if pages.is_complete(){
+                break;
+            }
+            match i{
+                AlbaContainer::Virtual(virt) => {
+                    let subquery = self.subquery(&virt).await?;
+                    'pages: for QueryPage { addresses, container: container_name, .. } in subquery.pages{
+                        pages.columns(&self.source_headers(&container_name).await?, &container_name)?;
+                        for address in addresses{
+                            if !pages.push(address, &container_name){
+                                break 'pages;
+                            }
+                        }
+                    }
+                },
+                AlbaContainer::Real(container_name) => {
+                    let (container, mut arguments, addresses, _) = self.prepare_search(&container_name, structure.conditions.clone(), &SortRequest::UNORDERED, profile).await?;
+                    pages.columns(&arguments.container_values, &container_name)?;
+                    arguments.load_only_needed(&[]);
+                    if let Some(keyset) = &structure.resume{
+                        arguments.resume(keyset, &[])?;
+                    }
+                    let started = Instant::now();
+                    let push = |_: Vec<AlbaTypes>, address: u64| Ok(pages.push(address, &container_name));
+                    match addresses{
+                        None => scan_matches(&container, &arguments, push).await?,
+                        Some(values) => fetch_matches(&container, &arguments, values, push).await?
+                    }
+                    profile.stage("read", started);
+                }
+            }
+        }
+        Ok(pages.finish())
+    }
+    /// Reads up to `page_size` matching rows of the container at `source` in the list of a streamed
+    /// SEARCH, as `session` sees them, after the row at `after`. Returns the column names and the
+    /// rows, and the address of the last row read.
+    pub async fn stream_page(&self, session: &Session, structure: &AstSearch, source: usize, after: Option<u64>, page_size: usize) -> Result<(Rows, Option<u64>), Error> {
+        let container_name = match structure.container.get(source){
+            Some(AlbaContainer::Real(name)) => name,
+            _ => return Err(gerr("Only the stored containers of a SEARCH can be streamed"))
+        };
+        let (container, qc, plan) = self.plan_conditions(container_name, structure.conditions.clone(), &SortRequest::UNORDERED).await?;
+        let (mut arguments, addresses) = access_rows(&container, qc, &plan.access, session, &mut ExecutionProfile::default()).await?;
+        let projection = structure.projection.resolve(&arguments.container_values, container_name)?;
+        arguments.load_only_needed(&[]);
+        for position in projection.positions.iter(){
+            arguments.columns[*position] = true;
+        }
+        if let Some(address) = after{
+            arguments.resume(&Keyset { keys: Vec::new(), address }, &[])?;
+        }
+        let mut rows = Vec::new();
+        let mut last = None;
+        let push = |row: Vec<AlbaTypes>, address: u64| {
+            rows.push(projection.project(projection.positions.iter().map(|p| row[*p].clone()).collect())?);
+            last = Some(address);
+            Ok(rows.len() < page_size)
+        };
+        // the index path seeks past the rows of the earlier pages, the same way the scan starts after them
+        let start = after.map_or(0, |address| address.saturating_add(1));
+        match addresses{
+            None => scan_matches(&container, &arguments, push).await?,
+            Some(values) => fetch_matches(&container, &arguments, values.range(start..).copied(), push).await?
+        }
+        Ok(((projection.names, rows), last))
+    }
+    /// `SEARCH ... ORDER BY`. A single container whose plan reads it in index order is paged straight
+    /// from the index; everything else goes through the external merge sort, which spills sorted runs
+    /// to `tmp/` once the memory of the database runs out. Either way no more than `LIMIT` plus `OFFSET`
+    /// rows come out of the sort.
+    async fn ordered_search(&mut self, structure: AstSearch, mut pages: PageBuilder, profile: &mut ExecutionProfile) -> Result<Query, Error> {
+        let order_by = &structure.order_by;
+        let request = SortRequest { order_by, single_source: structure.container.len() == 1, wanted_rows: structure.window.wanted_rows() };
+        let mut sorter = self.sorter(order_by.clone()).with_limit(request.wanted_rows);
+        let mut sources : Vec<String> = Vec::new();
+        for i in structure.container{
+            match i{
+                AlbaContainer::Virtual(virt) => {
+                    let subquery = self.subquery(&virt).await?;
+                    for QueryPage { addresses, container: container_name, .. } in subquery.pages{
+                        let container = match self.container.get(&container_name){
+                            Some(a) => a.clone(),
+                            None => return Err(gerr(&format!("Failed to perform the query, there is no container named {}", container_name)))
+                        };
+                        let mut arguments = container.read().await.search_arguments(QueryConditions::default(), Some(self.session));
+                        arguments.counters = profile.counters.clone();
+                        pages.columns(&arguments.container_values, &container_name)?;
+                        arguments.load_only_needed(order_by);
+                        let positions = key_positions(&arguments.container_values, order_by)?;
+                        let source = match sources.iter().position(|s| *s == container_name){
+                            Some(source) => source,
+                            None => { sources.push(container_name); sources.len() - 1 }
+                        };
+                        let started = Instant::now();
+                        fetch_matches(&container, &arguments, addresses, |row, address| {
+                            sorter.push(SortEntry { keys: extract_keys(&row, &positions), source, address })?;
+                            Ok(true)
+                        }).await?;
+                        profile.stage("read", started);
+                    }
+                },
+                AlbaContainer::Real(container_name) => {
+                    let (container, mut arguments, addresses, plan) = self.prepare_search(&container_name, structure.conditions.clone(), &request, profile).await?;
+                    pages.columns(&arguments.container_values, &container_name)?;
+                    arguments.load_only_needed(order_by);
+                    if let Some(keyset) = &structure.resume{
+                        arguments.resume(keyset, order_by)?;
+                    }
+                    if let Some(SortStrategy::Index(lookup)) = &plan.sort{
+                        let started = Instant::now();
+                        let entries = container.read().await.ordered_entries(lookup, &profile.counters).await?;
+                        profile.stage("index lookup", started);
+                        let started = Instant::now();
+                        index_ordered_search(container, arguments, entries, order_by, &mut pages).await?;
+                        profile.stage("read", started);
+                        return Ok(pages.finish())
+                    }
+                    let positions = key_positions(&arguments.container_values, order_by)?;
+                    let source = match sources.iter().position(|s| *s == container_name){
+                        Some(source) => source,
+                        None => { sources.push(container_name); sources.len() - 1 }
+                    };
+                    let started = Instant::now();
+                    let push = |row: Vec<AlbaTypes>, address: u64| {
+                        sorter.push(SortEntry { keys: extract_keys(&row, &positions), source, address })?;
+                        Ok(true)
+                    };
+                    match addresses{
+                        None => scan_matches(&container, &arguments, push).await?,
+                        Some(values) => fetch_matches(&container, &arguments, values, push).await?
+                    }
+                    profile.stage("read", started);
+                }
+            }
+        }
+        let started = Instant::now();
+        profile.counters.spilled(sorter.spilled_runs());
+        profile.counters.spilled_bytes(sorter.spilled_bytes());
+        sorter.finish(|entry| Ok(pages.push(entry.address, &sources[entry.source])))?;
+        profile.stage("sort", started);
+        Ok(pages.finish())
+    }
+    /// `UNION [ALL]`, `INTERSECT` and `EXCEPT`. Both operands are searched, then their rows are
+    /// read once to be compared by value.
+    async fn set_operation(&mut self, structure: AstSetOperation) -> Result<Query, Error> {
+        let left = self.set_operand(*structure.left).await?;
+        let right = self.set_operand(*structure.right).await?;
+        combine(structure.operator, left, right)
+    }
+    async fn set_operand(&mut self, ast: AST) -> Result<SetOperand, Error> {
+        match &ast{
+            AST::Search(structure) if is_aggregate(structure) || structure.join.is_some() => {
+                return Err(gerr("Aggregate searches and JOIN can not be operands of UNION, INTERSECT and EXCEPT yet"))
+            },
+            AST::Search(_) | AST::SetOperation(_) => {},
+            _ => return Err(gerr("The operands of UNION, INTERSECT and EXCEPT must be SEARCH commands"))
+        }
+        let query = Box::pin(self.run(ast)).await?;
+        SetOperand::read(query, self).await
+    }
+    /// Plans both containers of a JOIN with the terms of the WHERE that only name their own
+    /// columns, then chooses how the right rows are found for each left row.
+    async fn plan_join_search(&self, structure: &AstSearch, join: &AstJoin) -> Result<JoinSetup, Error> {
+        let left_name = match structure.container.as_slice(){
+            [AlbaContainer::Real(name)] => name.clone(),
+            _ => return Err(gerr("JOIN needs a single container, not a subquery, before it"))
+        };
+        let sources = JoinSources::new(left_name.clone(), self.source_headers(&left_name).await?, join.container.clone(), self.source_headers(&join.container).await?)?;
+        let columns = sources.on(&join.on.0, &join.on.1)?;
+        let conditions = sources.split_conditions(structure.conditions.clone())?;
+        let left = self.plan_conditions(&sources.left, conditions.left, &SortRequest::UNORDERED).await?;
+        let right = self.plan_conditions(&sources.right, conditions.right, &SortRequest::UNORDERED).await?;
+        let headers = sources.joined_headers();
+        let properties : HashMap<String, AlbaTypes> = headers.iter().cloned().collect();
+        let joined = QueryConditions::from_primitive_conditions(conditions.joined, &properties, headers[0].0.clone())?;
+        let positions = (column_position(&sources.left_headers, &columns.0), column_position(&sources.right_headers, &columns.1));
+        // index keys of different types do not line up, a BIGINT key is not the key of the same INT
+        let indexable = std::mem::discriminant(&sources.left_headers[positions.0].1) == std::mem::discriminant(&sources.right_headers[positions.1].1);
+        let right_book = right.0.read().await;
+        let plan = plan_join(&left.2, &right.2, &right_book.planner_context().await?, &columns.1, indexable);
+        drop(right_book);
+        Ok(JoinSetup { sources, columns, positions, left, right, joined, plan })
+    }
+    /// `SEARCH ... JOIN`. The hash join reads the right container once into a table keyed by its join
+    /// column and probes it while scanning the left one, so it stops as soon as the `LIMIT` is
+    /// reached. A table outgrowing the memory of the database is probed one part at a time, the
+    /// left container being scanned once per part. The index nested loop collects the matching
+    /// left rows first, on disk past the memory of the database, then looks each of them up in the
+    /// index of the right join column. NULL join values never match.
+    async fn join_search(&mut self, structure: AstSearch, join: AstJoin, profile: &mut ExecutionProfile) -> Result<Query, Error> {
+        if is_aggregate(&structure) || !structure.order_by.is_empty() || structure.distinct{
+            return Err(gerr("DISTINCT, aggregates, GROUP BY, HAVING and ORDER BY can not be combined with JOIN yet"))
+        }
+        let started = Instant::now();
+        let setup = self.plan_join_search(&structure, &join).await?;
+        profile.stage("plan", started);
+        setup.record(profile);
+        let JoinSetup { sources, positions: (left_position, right_position), left, right, joined, plan, .. } = setup;
+        let mut pages = PageBuilder::new(structure.window, structure.page_size, sources.projection(&structure.projection)?).joined(&sources.right);
+        pages.columns(&sources.joined_headers(), &format!("{} JOIN {}", sources.left, sources.right))?;
+
+        let (left_container, left_conditions, left_plan) = left;
+        let (right_container, right_conditions, right_plan) = right;
+        let right_access = match plan.strategy{
+            JoinStrategy::Hash => &right_plan.access,
+            JoinStrategy::IndexNestedLoop { .. } => &AccessPath::Scan
+        };
+        let (mut left_arguments, left_addresses) = access_rows(&left_container, left_conditions, &left_plan.access, &self.session, profile).await?;
+        let (mut right_arguments, right_addresses) = access_rows(&right_container, right_conditions, right_access, &self.session, profile).await?;
+        left_arguments.load_only_needed(&[]);
+        right_arguments.load_only_needed(&[]);
+        left_arguments.columns[left_position] = true;
+        right_arguments.columns[right_position] = true;
+        // the conditions on the joined rows read their columns from either row
+        let mut joined_columns : Vec<(String, JoinSide, usize)> = Vec::new();
+        for column in joined.columns(){
+            let (side, bare) = sources.resolve(column)?;
+            let position = column_position(sources.headers(side), &bare);
+            match side{
+                JoinSide::Left => left_arguments.columns[position] = true,
+                JoinSide::Right => right_arguments.columns[position] = true,
+            }
+            joined_columns.push((column.to_string(), side, position));
+        }
+        let joined_match = |left_row: &[AlbaTypes], right_row: &[AlbaTypes]| -> Result<bool, Error> {
+            if joined_columns.is_empty(){
+                return Ok(true)
+            }
+            let data = joined_columns.iter().map(|(name, side, position)| (name.clone(), match side{
+                JoinSide::Left => left_row[*position].clone(),
+                JoinSide::Right => right_row[*position].clone(),
+            })).collect();
+            joined.row_match(&Row { data })
+        };
+
+        let started = Instant::now();
+        match plan.strategy{
+            JoinStrategy::Hash => {
+                let mut table = JoinTable::new(right_position, self.memory.grant(), self.spill_directory());
+                let build = |row: Vec<AlbaTypes>, address: u64| {
+                    table.insert(address, row)?;
+                    Ok(true)
+                };
+                match right_addresses{
+                    None => scan_matches(&right_container, &right_arguments, build).await?,
+                    Some(values) => fetch_matches(&right_container, &right_arguments, values, build).await?
+                }
+                // the left rows are read again for every part of the right rows that spilled
+                loop{
+                    let probe = |row: Vec<AlbaTypes>, address: u64| {
+                        let Some(matches) = table.get(&row[left_position]) else { return Ok(true) };
+                        for (right_address, right_row) in matches{
+                            if joined_match(&row, right_row)? && !pages.push_pair(address, *right_address, &sources.left){
+                                return Ok(false)
+                            }
+                        }
+                        Ok(true)
+                    };
+                    match &left_addresses{
+                        None => scan_matches(&left_container, &left_arguments, probe).await?,
+                        Some(values) => fetch_matches(&left_container, &left_arguments, values.iter().copied(), probe).await?
+                    }
+                    if pages.is_complete() || !table.refill()?{
+                        break;
+                    }
+                }
+                profile.counters.spilled_bytes(table.spilled_bytes());
+            },
+            JoinStrategy::IndexNestedLoop { column, primary } => {
+                let mut left_rows : SpillQueue<(Vec<AlbaTypes>, u64)> = SpillQueue::new(self.memory.grant(), self.spill_directory());
+                let collect = |row: Vec<AlbaTypes>, address: u64| {
+                    let size = row_size(&row);
+                    left_rows.push((row, address), size)?;
+                    Ok(true)
+                };
+                match left_addresses{
+                    None => scan_matches(&left_container, &left_arguments, collect).await?,
+                    Some(values) => fetch_matches(&left_container, &left_arguments, values, collect).await?
+                }
+                profile.counters.spilled_bytes(left_rows.spilled_bytes());
+                let mut left_rows = left_rows.drain()?;
+                while let Some((row, address)) = left_rows.next()?{
+                    let key = &row[left_position];
+                    if matches!(key, AlbaTypes::NONE){
+                        continue;
+                    }
+                    let lookup = IndexLookup { column: column.clone(), primary, lookup: QueryIndexType::Strict(key.get_index()), estimated_rows: 0.0 };
+                    let candidates = right_container.read().await.index_addresses(&AccessPath::Index(lookup), &profile.counters, &self.session).await?.unwrap_or_default();
+                    let mut complete = false;
+                    fetch_matches(&right_container, &right_arguments, candidates, |right_row, right_address| {
+                        // an index key covers a range of values, only the equal ones join
+                        if right_row[right_position].total_cmp(key) != std::cmp::Ordering::Equal || !joined_match(&row, &right_row)?{
+                            return Ok(true)
+                        }
+                        complete = !pages.push_pair(address, right_address, &sources.left);
+                        Ok(!complete)
+                    }).await?;
+                    if complete{
+                        break;
+                    }
+                }
+            }
+        }
+        profile.stage("join", started);
+        Ok(pages.finish())
+    }
+    /// The values of the new rows of `container`, the columns left out being empty. The columns
+    /// are checked once for the whole batch, and the contents of Text values go to the pending
+    /// text map under fresh codes, which the rows hold.
+    async fn row_values(&self, container: &Container, structure: &AstCreateRow) -> Result<Vec<Vec<AlbaTypes>>, Error> {
+        let columns = container.columns();
+        let names = container.column_names();
+        let mut positions = Vec::with_capacity(structure.col_nam.len());
+        for col_name in &structure.col_nam {
+            if col_name == VERSION_COLUMN {
+                return Err(gerr(&format!("The column {} is set by COMMIT and can not be written", VERSION_COLUMN)));
+            }
+            match names.iter().position(|c| c == col_name) {
+                Some(ri) => positions.push(ri),
+                None => return Err(gerr(&format!("There is no column {} in the container {}", col_name, structure.container)))
+            }
+        }
+        let mut rows = Vec::with_capacity(structure.rows.len());
+        let mut texts = Vec::new();
+        for values in structure.rows.iter() {
+            if values.len() != positions.len() {
+                return Err(gerr(&format!(
+                    "In CREATE ROW, expected {} values for the specified columns, but got {}",
+                    positions.len(),
+                    values.len()
+                )));
+            }
+            let mut val: Vec<AlbaTypes> = columns.clone();
+            for ((input_val, ri), col_name) in values.iter().zip(positions.iter()).zip(structure.col_nam.iter()) {
+                let expected_val = &columns[*ri];
+                if let AlbaTypes::NONE = input_val {
+                    val[*ri] = AlbaTypes::NONE;
+                } else if let AlbaTypes::Text(_) = expected_val {
+                    if let AlbaTypes::Text(s) = input_val {
+                        let mut code = generate_secure_code(MAX_STR_LEN);
+                        while fs::exists(format!("{}/rf/{}", self.location, code))? {
+                            code = generate_secure_code(MAX_STR_LEN);
+                        }
+                        val[*ri] = AlbaTypes::Text(code.clone());
+                        texts.push((code, s.to_string()));
+                    } else {
+                        return Err(gerr(&format!(
+                            "For column '{}', expected Text, but got {:?}.",
+                            col_name, input_val
+                        )));
+                    }
+                } else if std::mem::discriminant(input_val) == std::mem::discriminant(expected_val) {
+                    val[*ri] = input_val.clone();
+                } else {
+                    match expected_val.try_from_existing(input_val.clone()) {
+                        Ok(converted_val) => {
+                            val[*ri] = converted_val;
+                        },
+                        Err(e) => {
+                            return Err(gerr(&format!(
+                                "Type conversion error for column '{}': expected {:?}, got {:?}. Error: {}",
+                                col_name, expected_val, input_val, e
+                            )));
+                        }
+                    }
+                }
+            }
+            rows.push(val);
+        }
+        // the texts are only kept once the whole batch is valid
+        container.stage_texts(texts, &self.session).await;
+        Ok(rows)
+    }
+    /// `UPSERT ROW`. The rows holding the primary key are looked up and changed while the container
+    /// stays locked, so no other command can create the key in between. The groups of values are
+    /// applied in order, so a later group sees the rows an earlier one created.
+    async fn upsert_row(&mut self, structure: AstUpsertRow) -> Result<u64, Error> {
+        let AstUpsertRow { row, on_conflict } = structure;
+        let container = match self.container.get(&row.container){
+            Some(a) => a.clone(),
+            None => return Err(gerr(&format!("Container '{}' does not exist.", row.container)))
+        };
+        let mut container = container.write().await;
+        let primary = match container.headers.first(){
+            Some(a) => a.clone(),
+            None => return Err(gerr("Error, no primary key found"))
+        };
+        let key_position = match row.col_nam.iter().position(|c| *c == primary.0){
+            Some(p) => p,
+            None => return Err(gerr(&format!("UPSERT ROW needs a value for the primary column {}", primary.0)))
+        };
+        let updated = match &on_conflict{
+            OnConflict::Nothing => Vec::new(),
+            OnConflict::Replace => row.col_nam.clone(),
+            OnConflict::Update(columns) => columns.clone(),
+        };
+        let mut updated_positions = Vec::with_capacity(updated.len());
+        for column in updated.iter(){
+            match row.col_nam.iter().position(|c| c == column){
+                Some(p) => updated_positions.push(p),
+                None => return Err(gerr(&format!("The column {} of ON CONFLICT DO UPDATE has no value in UPSERT ROW", column)))
+            }
+        }
+        let positions : Vec<usize> = updated.iter().map(|c| column_position(&container.headers, c)).collect();
+        if on_conflict != OnConflict::Nothing{
+            // every row the upsert may change is locked before any is written
+            let mut addresses = Vec::new();
+            for values in row.rows.iter(){
+                if let Some(value) = values.get(key_position) && let Ok(key) = column_value(&primary, value.clone()){
+                    addresses.extend(container.primary_rows(&key, &self.session).await?.into_iter().map(|(address, _)| address));
+                }
+            }
+            self.locks.acquire(&self.session, &row.container, &addresses)?;
+        }
+        let mut affected = 0;
+        for values in row.rows{
+            let key = match values.get(key_position){
+                Some(value) => column_value(&primary, value.clone())?,
+                None => return Err(gerr(&format!("UPSERT ROW needs a value for the primary column {}", primary.0)))
+            };
+            if matches!(key, AlbaTypes::NONE){
+                return Err(gerr(&format!("The primary column {} of UPSERT ROW can not be NULL", primary.0)))
+            }
+            let existing = container.primary_rows(&key, &self.session).await?;
+            if existing.is_empty(){
+                let created = AstCreateRow { col_nam: row.col_nam.clone(), rows: vec![values], container: row.container.clone(), returning: None };
+                let rows = self.row_values(&container, &created).await?;
+                container.push_rows(rows, &self.session).await?;
+                affected += 1;
+                continue;
+            }
+            if on_conflict == OnConflict::Nothing{
+                continue;
+            }
+            let mut changes = Vec::with_capacity(updated_positions.len());
+            for p in updated_positions.iter(){
+                match values.get(*p){
+                    Some(value) => changes.push(value.clone()),
+                    None => return Err(gerr(&format!("The column {} of ON CONFLICT DO UPDATE has no value in UPSERT ROW", row.col_nam[*p])))
+                }
+            }
+            let changes = AstCreateRow { col_nam: updated.clone(), rows: vec![changes], container: row.container.clone(), returning: None };
+            let values = match self.row_values(&container, &changes).await?.pop(){
+                Some(a) => a,
+                None => return Err(gerr("UPSERT ROW produced no values"))
+            };
+            affected += existing.len() as u64;
+            container.stage_rows(existing.into_iter().map(|(address, mut current)| {
+                for position in positions.iter(){
+                    current[*position] = values[*position].clone();
+                }
+                (address, (false, current))
+            }), &self.session).await;
+        }
+        if self.settings.auto_commit{
+            container.commit(&self.session).await?;
+            self.locks.release(&self.session, Some(&row.container));
+        }
+        Ok(affected)
+    }
+    /// Where the commands write what does not fit in their share of `memory_limit`.
+    fn spill_directory(&self) -> String {
+        format!("{}/tmp", self.location)
+    }
+    /// The session the running command belongs to.
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+    /// An external sort drawing on the memory of the database.
+    pub fn sorter(&self, order: Vec<SortKey>) -> ExternalSorter {
+        ExternalSorter::new(order, self.memory.grant(), self.spill_directory())
+    }
+    fn cursor_limits(&self) -> CursorLimits {
+        CursorLimits { ttl: Duration::from_secs(self.settings.cursor_ttl_secs), per_session: self.settings.max_cursors_per_session }
+    }
+    /// Keeps the pages of `query` for `session` to page through.
+    pub async fn open_cursor(&self, session: Session, query: Query) {
+        if !query.id.is_empty(){
+            self.cursors.write().await.open(session, query, self.cursor_limits());
+        }
+    }
+    /// Moves a cursor of the session and returns it with the rows of its new page. The cursor is
+    /// kept even when the page can not be read.
+    async fn move_cursor(&mut self, id: &str, movement: CursorMove) -> Result<Query, Error> {
+        let limits = self.cursor_limits();
+        let mut query = self.cursors.write().await.take(&self.session, id, limits)?;
+        let moved = match movement{
+            CursorMove::Next => query.next(self).await,
+            CursorMove::Previous => query.previous(self).await,
+            CursorMove::Page(page) => query.goto(page, self).await,
+        };
+        let result = query.duplicate();
+        self.cursors.write().await.open(self.session, query, limits);
+        moved.map(|_| result)
+    }
+    /// Locks the matched rows of `container` for the session of the command.
+    fn lock_rows(&self, container: &str, rows: &[(Vec<AlbaTypes>, u64)]) -> Result<(), Error> {
+        let addresses : Vec<u64> = rows.iter().map(|row| row.1).collect();
+        self.locks.acquire(&self.session, container, &addresses)
+    }
+    async fn edit_rows(&mut self, mut structure: AstEditRow, profile: &mut ExecutionProfile) -> Result<Query, Error> {
+        structure.conditions = self.resolve_subqueries(structure.conditions.take(), &[structure.container.clone()], false).await?;
+        let container = match self.container.get(&structure.container){
+            Some(a) => a.clone(),
+            None => {return Err(gerr(&format!("Container '{}' does not exist.", structure.container)))}
+        };
+        let headers = {
+            let container = container.read().await;
+            check_returning(&container, structure.returning.as_ref())?;
+            container.headers.clone()
+        };
+        let column_properties : HashMap<String,AlbaTypes> = headers.iter().cloned().collect();
+        let mut changes : Vec<(usize,BoundExpression)> = Vec::with_capacity(structure.col_nam.len());
+        for i in structure.col_nam.iter().enumerate(){
+            let val = if let Some(v) = structure.col_val.get(i.0){
+                v
+            }else{
+                return Err(gerr("Failed to execute edit because there is a value missing for one of the columns entered"))
+            };
+            if i.1 == VERSION_COLUMN{
+                return Err(gerr(&format!("The column {} is set by COMMIT and can not be written", VERSION_COLUMN)))
+            }
+            let id = match headers.iter().position(|h| h.0 == *i.1){
+                Some(id) => id,
+                None => return Err(gerr(&format!("There is no column {} in the container {}", i.1, structure.container)))
+            };
+            changes.push((id, val.bind(&column_properties)?));
+        }
+
+        let expected = take_version_condition(&mut structure.conditions)?;
+        let (container, mut result) = self.matching_rows(&structure.container, structure.conditions, profile).await?;
+        let started = Instant::now();
+        expect_version(&headers, &mut result, expected);
+        for f in result.iter_mut(){
+            // every new value is computed from the row as it was before the edit
+            let row = Row { data: headers.iter().map(|h| h.0.clone()).zip(f.0.iter().cloned()).collect() };
+            for (id, expression) in &changes{
+                f.0[*id] = column_value(&headers[*id], expression.value(&row)?)?;
+            }
+        }
+        self.lock_rows(&structure.container, &result)?;
+        let container = container.write().await;
+        container.stage_rows(result.iter().map(|i| (i.1, (false,i.0.clone()))), &self.session).await;
+        profile.stage("apply", started);
+        write_result(&container, result.len(), result, structure.returning.as_ref())
+    }
+    async fn delete_rows(&mut self, mut structure: AstDeleteRow, profile: &mut ExecutionProfile) -> Result<Query, Error> {
+        structure.conditions = self.resolve_subqueries(structure.conditions.take(), &[structure.container.clone()], false).await?;
+        let expected = take_version_condition(&mut structure.conditions)?;
+        let (container, mut result) = self.matching_rows(&structure.container, structure.conditions, profile).await?;
+        let started = Instant::now();
+        let container = container.write().await;
+        check_returning(&container, structure.returning.as_ref())?;
+        expect_version(&container.headers, &mut result, expected);
+        self.lock_rows(&structure.container, &result)?;
+        container.stage_rows(result.iter().map(|i| (i.1, (true,i.0.clone()))), &self.session).await;
+        profile.stage("apply", started);
+        write_result(&container, result.len(), result, structure.returning.as_ref())
+    }
+    /// Builds the result of `EXPLAIN`, running the command first when `analyze` is set.
+    async fn explain(&mut self, mut command: AST, analyze: bool) -> Result<Query, Error> {
+        let mut explanation = Explanation::default();
+        let mut profile = ExecutionProfile::default();
+        let started = Instant::now();
+        // the plans depend on what the subqueries return, so they run even without ANALYZE
+        match &mut command{
+            AST::Search(structure) => self.resolve_search(structure).await?,
+            AST::EditRow(AstEditRow { container, conditions, .. }) | AST::DeleteRow(AstDeleteRow { container, conditions, .. }) => {
+                *conditions = self.resolve_subqueries(conditions.take(), std::slice::from_ref(container), false).await?;
+            },
+            _ => {}
+        }
+        match command{
+            AST::Search(structure) if analyze => {
+                for i in structure.container.iter(){
+                    if let AlbaContainer::Virtual(virt) = i{
+                        let subquery = Box::pin(self.explain(debug_tokens(virt)?, false)).await?;
+                        explanation.subquery(subquery);
+                    }
+                }
+                self.search(*structure, &mut profile).await?;
+            },
+            AST::Search(structure) if structure.join.is_some() => {
+                if let Some(join) = &structure.join{
+                    self.plan_join_search(&structure, join).await?.record(&mut profile);
+                }
+            },
+            AST::Search(structure) => {
+                // the ORDER BY of an aggregate search sorts the groups, not the rows of the container
+                let order_by = if is_aggregate(&structure) { &[] } else { structure.order_by.as_slice() };
+                let request = SortRequest { order_by, single_source: structure.container.len() == 1, wanted_rows: structure.window.wanted_rows() };
+                for i in structure.container.iter(){
+                    match i{
+                        AlbaContainer::Virtual(virt) => {
+                            let subquery = Box::pin(self.explain(debug_tokens(virt)?, false)).await?;
+                            explanation.subquery(subquery);
+                        },
+                        AlbaContainer::Real(container_name) => {
+                            let (_, qc, plan) = self.plan_conditions(container_name, structure.conditions.clone(), &request).await?;
+                            profile.plans.push((container_name.clone(), qc, plan));
+                        }
+                    }
+                }
+            },
+            AST::EditRow(structure) if analyze => { self.edit_rows(structure, &mut profile).await?; },
+            AST::EditRow(structure) => {
+                let (_, qc, plan) = self.plan_conditions(&structure.container, structure.conditions, &SortRequest::UNORDERED).await?;
+                profile.plans.push((structure.container, qc, plan));
+            },
+            AST::DeleteRow(structure) if analyze => { self.delete_rows(structure, &mut profile).await?; },
+            AST::DeleteRow(structure) => {
+                let (_, qc, plan) = self.plan_conditions(&structure.container, structure.conditions, &SortRequest::UNORDERED).await?;
+                profile.plans.push((structure.container, qc, plan));
+            },
+            _ => return Err(gerr("EXPLAIN only supports SEARCH, EDIT and DELETE commands"))
+        }
+        profile.stage("total", started);
+        for (container_name, qc, plan) in profile.plans.iter(){
+            explanation.plan(container_name, qc, plan);
+        }
+        if let Some((on, qc, plan)) = &profile.join{
+            explanation.join(on, qc, plan);
+        }
+        if analyze{
+            explanation.profile(&profile);
+        }
+        Ok(explanation.into_query())
+    }
+    
+    pub async fn execute_ast(&mut self, ast: AST) -> Result<Query, Error> {
+        let mut result = match ast{
+            AST::Search(structure) if structure.stateless.is_some() => self.stateless_search(*structure).await?,
+            AST::Continue(structure) => self.continue_search(&structure.token).await?,
+            ast => self.run(ast).await?
+        };
+        result.count();
+        Ok(result)
+    }
+    /// Rolls back the changes of the sessions that ran no command for
+    /// `idle_transaction_timeout_secs` and releases their locks.
+    async fn expire_idle_sessions(&mut self) -> Result<(), Error> {
+        let timeout = Duration::from_secs(self.settings.idle_transaction_timeout_secs);
+        let idle: Vec<Session> = self.activity.iter().filter(|(_, last)| last.elapsed() >= timeout).map(|(session, _)| *session).collect();
+        for session in idle {
+            for container in self.container.values() {
+                container.write().await.rollback(&session).await?;
+            }
+            self.locks.release(&session, None);
+            self.savepoints.remove(&session);
+            self.activity.remove(&session);
+        }
+        Ok(())
+    }
+    /// Runs `ast` for `session`, which takes the row locks it needs and releases them on its
+    /// COMMIT or ROLLBACK.
+    pub async fn execute_as(&mut self, session: Session, ast: AST) -> Result<Query, Error> {
+        self.enter(session).await?;
+        let result = self.execute_ast(ast).await;
+        self.session = LOCAL_SESSION;
+        result
+    }
+    /// Makes `session` the one running the next command, once the idle sessions are expired.
+    async fn enter(&mut self, session: Session) -> Result<(), Error> {
+        self.expire_idle_sessions().await?;
+        self.activity.insert(session, Instant::now());
+        self.session = session;
+        Ok(())
+    }
+    /// Starts a SEARCH streamed to `session` by `stream_page`, running its subqueries.
+    pub async fn begin_stream(&mut self, session: Session, mut structure: AstSearch) -> Result<AstSearch, Error> {
+        self.enter(session).await?;
+        let resolved = self.resolve_search(&mut structure).await;
+        self.session = LOCAL_SESSION;
+        resolved.map(|_| structure)
+    }
+}
+
+/// Runs `input` for `session`. A command needing rows locked by another session is run again
+/// once that session releases locks, with the database unlocked in between, until it runs,
+/// its wait would be a deadlock or `lock_timeout_ms` passes.
+pub async fn execute_waiting(dbref: &Arc<RwLock<Database>>, session: Session, input: &str, arguments: Vec<String>) -> Result<Query, Error> {
+    run_waiting(dbref, session, parse(input.to_owned(), arguments)?).await
+}
+
+/// `execute_waiting` for a parsed command.
+pub async fn run_waiting(dbref: &Arc<RwLock<Database>>, session: Session, ast: AST) -> Result<Query, Error> {
+    let mut deadline: Option<Instant> = None;
+    loop {
+        let mut db = dbref.write().await;
+        let error = match db.execute_as(session, ast.clone()).await {
+            Ok(query) => return Ok(query),
+            Err(e) => e
+        };
+        let holder = match LockConflict::of(&error) {
+            Some(conflict) => conflict.holder,
+            None => return Err(error)
+        };
+        let deadline = *deadline.get_or_insert_with(|| Instant::now() + Duration::from_millis(db.settings.lock_timeout_ms));
+        let locks = db.locks.clone();
+        drop(db);
+        locks.wait(&session, &holder, deadline.saturating_duration_since(Instant::now())).await?;
+    }
+}
+
+/// Search arguments for planned conditions and the row addresses `access` points to,
+/// `None` when the container is scanned.
+async fn access_rows(container: &Arc<RwLock<Container>>, conditions: QueryConditions, access: &AccessPath, session: &Session, profile: &mut ExecutionProfile) -> Result<(SearchArguments, Option<BTreeSet<u64>>), Error> {
+    let started = Instant::now();
+    let container_book = container.read().await;
+    let mut arguments = container_book.search_arguments(conditions, Some(*session));
+    arguments.counters = profile.counters.clone();
+    let addresses = container_book.index_addresses(access, &profile.counters, session).await?;
+    drop(container_book);
+    if addresses.is_some(){
+        profile.stage("index lookup", started);
+    }
+    Ok((arguments, addresses))
+}
+
+fn column_position(headers: &[(String, AlbaTypes)], column: &str) -> usize{
+    headers.iter().position(|h| h.0 == column).unwrap_or_default()
+}
+
+/// A JOIN planned but not run yet.
+struct JoinSetup{
+    sources: JoinSources,
+    /// The join columns of the left and the right container.
+    columns: (String, String),
+    positions: (usize, usize),
+    left: (Arc<RwLock<Container>>, QueryConditions, QueryPlan),
+    right: (Arc<RwLock<Container>>, QueryConditions, QueryPlan),
+    /// The terms of the WHERE naming both containers.
+    joined: QueryConditions,
+    plan: JoinPlan,
+}
+
+impl JoinSetup{
+    fn record(&self, profile: &mut ExecutionProfile){
+        profile.plans.push((self.sources.left.clone(), self.left.1.clone(), self.left.2.clone()));
+        profile.plans.push((self.sources.right.clone(), self.right.1.clone(), self.right.2.clone()));
+        let on = format!("{}.{} = {}.{}", self.sources.left, self.columns.0, self.sources.right, self.columns.1);
+        profile.join = Some((on, self.joined.clone(), self.plan.clone()));
+    }
+}
+
+/// Whether a SEARCH can be streamed from its scan, page by page: an unordered one reading stored
+/// containers, whose rows need no other step before they are returned.
+fn is_streamable(structure: &AstSearch) -> bool{
+    structure.order_by.is_empty() && structure.join.is_none() && !structure.distinct && !structure.for_update
+        && structure.stateless.is_none() && structure.resume.is_none() && !is_aggregate(structure)
+        && structure.container.iter().all(|c| matches!(c, AlbaContainer::Real(_)))
+}
+
+/// Aggregates, GROUP BY or HAVING turn a search into an aggregate search.
+pub fn is_aggregate(structure: &AstSearch) -> bool{
+    structure.projection.has_aggregates() || !structure.group_by.is_empty() || structure.having.is_some()
+}
+
+/// Takes `_version = <value>` out of the WHERE of an EDIT or DELETE, when it is the whole WHERE
+/// or one of its terms joined by AND. The rows are matched without it, and the version becomes
+/// the one their changes are based on, so COMMIT fails if a row is at another version by then.
+fn take_version_condition(conditions: &mut PrimitiveQueryConditions) -> Result<Option<i64>, Error>{
+    let is_version = |predicate: &PrimitivePredicate| matches!(predicate, PrimitivePredicate::Comparison(Token::String(column) | Token::Identifier(column), Token::Operator(operator), _) if column == VERSION_COLUMN && operator == "=");
+    let predicate = match conditions.take(){
+        Some(predicate) if is_version(&predicate) => predicate,
+        Some(PrimitivePredicate::And(mut terms)) => match terms.iter().position(is_version){
+            Some(position) => {
+                let predicate = terms.remove(position);
+                *conditions = match terms.len(){
+                    0 => None,
+                    1 => terms.pop(),
+                    _ => Some(PrimitivePredicate::And(terms)),
+                };
+                predicate
+            },
+            None => {
+                *conditions = Some(PrimitivePredicate::And(terms));
+                return Ok(None)
+            }
+        },
+        other => {
+            *conditions = other;
+            return Ok(None)
+        }
+    };
+    match predicate{
+        PrimitivePredicate::Comparison(_, _, Token::Int(version)) => Ok(Some(version)),
+        PrimitivePredicate::Comparison(_, _, value) => Err(gerr(&format!("{} must be compared to an integer, not {:?}", VERSION_COLUMN, value))),
+        _ => Ok(None)
+    }
+}
+
+/// Bases the changes to `rows` on the version `expected` by `_version = ?`.
+fn expect_version(headers: &[(String, AlbaTypes)], rows: &mut [(Vec<AlbaTypes>, u64)], expected: Option<i64>){
+    if let Some(version) = expected && let Some(position) = headers.iter().position(|h| h.0 == VERSION_COLUMN){
+        for row in rows.iter_mut(){
+            row.0[position] = AlbaTypes::Bigint(version);
+        }
+    }
+}
+
+/// Resolves the columns of `RETURNING` before a write changes anything.
+fn check_returning(container: &Container, returning: Option<&Projection>) -> Result<(), Error>{
+    if let Some(projection) = returning{
+        projection.resolve(&container.headers, &container.name)?;
+    }
+    Ok(())
+}
+
+/// The answer of a write: the count of the rows it affected and, with `RETURNING`, those rows,
+/// given with every column of `container` and their address.
+fn write_result(container: &Container, affected: usize, rows: Vec<(Vec<AlbaTypes>, u64)>, returning: Option<&Projection>) -> Result<Query, Error>{
+    let mut query = match returning{
+        Some(projection) => Query::returning(projection, &container.headers, &container.name, rows)?,
+        None => Query::new_none(Vec::new()),
+    };
+    query.affected = affected as u64;
+    Ok(query)
+}
+
+/// A value computed by EDIT ROW, converted to the type of the column it goes to.
+fn column_value(column: &(String, AlbaTypes), value: AlbaTypes) -> Result<AlbaTypes, Error>{
+    if matches!(value, AlbaTypes::NONE) || std::mem::discriminant(&value) == std::mem::discriminant(&column.1){
+        return Ok(value)
+    }
+    column.1.try_from_existing(value.clone()).map_err(|e| gerr(&format!("Type conversion error for column '{}': expected {:?}, got {:?}. Error: {}", column.0, column.1, value, e)))
 }
 
 pub async fn connect() -> Result<Database, Error>{
@@ -955,12 +2001,19 @@ pub async fn connect() -> Result<Database, Error>{
         start_strix(strix.clone()).await;
     }
 
-    let mut db = Database{location:path.to_string(),settings:Default::default(),containers:Vec::new(),headers:Vec::new(),container:HashMap::new(),queries:Arc::new(RwLock::new(HashMap::new())),secret_keys:Arc::new(RwLock::new(HashMap::new()))};
+    let mut db = Database{location:path.to_string(),settings:Default::default(),containers:Vec::new(),headers:Vec::new(),container:HashMap::new(),cursors:Arc::new(RwLock::new(Cursors::default())),secret_keys:Arc::new(RwLock::new(HashMap::new())),savepoints:AHashMap::new(),locks:Arc::new(RowLocks::default()),session:LOCAL_SESSION,activity:AHashMap::new(),continuation_key:[0u8; 32],memory:Arc::new(MemoryAccountant::default())};
     db.setup().await?;
+    db.continuation_key = load_continuation_key(path)?;
     if let Err(e) = db.load_settings(){
         logerr!("err: load_settings");
         return Err(e)
-    };if let Err(e) = db.load_containers().await{
+    };
+    db.memory = Arc::new(MemoryAccountant::new(db.settings.memory_limit));
+    // the temporary files of the commands running when the last process stopped
+    if fs::exists(db.spill_directory())?{
+        fs::remove_dir_all(db.spill_directory())?;
+    }
+    if let Err(e) = db.load_containers().await{
         logerr!("err: load_containers");
         return Err(e)
     };
@@ -1120,7 +2173,6 @@ async fn handle_data_tcp_inner(dbref: Arc<RwLock<Database>>,rc_payload:Vec<u8>)
 
 
     let ssr = session_secret_rel.read().await;
-    let mut db = dbref.write().await;
     let mut payload: Vec<u8> = Vec::with_capacity(512);
     payload.extend_from_slice(&rc_payload[32..]);
     if let Some(_) = ssr.get(&session_id) {
@@ -1146,10 +2198,10 @@ async fn handle_data_tcp_inner(dbref: Arc<RwLock<Database>>,rc_payload:Vec<u8>)
     match serde_json::from_slice::<DataConnection>(&payload) {
         Ok(v) => {
             //
-            match db.execute(&v.command,v.arguments).await {
+            match execute_waiting(&dbref, session_id, &v.command, v.arguments).await {
                 Ok(query_result) => {
                     //
-                    db.queries.write().await.insert(query_result.id.clone(), query_result.clone());
+                    dbref.read().await.open_cursor(session_id, query_result.clone()).await;
                     //
                     match serde_json::to_string(&query_result) {
                         
@@ -1236,30 +2288,227 @@ async fn handle_data_tcp_inner(dbref: Arc<RwLock<Database>>,rc_payload:Vec<u8>)
 }
 
 
+/// `POST /ingest`: the session id followed by an encrypted binary columnar batch of rows, see
+/// ingest.rs. The rows are created for the session like those of a `CREATE ROW` with one group
+/// of values per row, and the payload is decrypted and decoded before the database is locked.
+async fn handle_ingest_tcp_inner(dbref: Arc<RwLock<Database>>,rc_payload:Vec<u8>) -> Vec<u8>{
+    if rc_payload.len() <= 32{
+        logerr!("the payload is too short | size :{}",rc_payload.len());
+        return 0u64.to_be_bytes().to_vec()
+    }
+    let mut session_id : [u8;32] = [0u8;32];
+    session_id.clone_from_slice(&rc_payload[..32]);
+    if session_secret_rel.read().await.get(&session_id).is_none(){
+        logerr!("No session secret found for session_id");
+        return 0u64.to_be_bytes().to_vec();
+    }
+    let payload = match decrypt(&rc_payload[32..], &session_id).await{
+        Ok(a) => a,
+        Err(_) => return 0u64.to_be_bytes().to_vec()
+    };
+    let result = match decode_rows(&payload){
+        Ok(rows) => run_waiting(&dbref, session_id, AST::CreateRow(rows)).await,
+        Err(e) => Err(e)
+    };
+    let answer = match result.map(|query| serde_json::to_string(&query)){
+        Ok(Ok(q)) => TytoDBResponse{ content: q, success: 1 },
+        Ok(Err(e)) => TytoDBResponse{ content: format!("Failed to serialize query result: {}", e), success: 0 },
+        Err(e) => {
+            logerr!("Failed to ingest rows: {}", e);
+            TytoDBResponse{ content: format!("Failed to ingest rows: {}", e), success: 0 }
+        }
+    };
+    let mut response: Vec<u8> = Vec::new();
+    if let Ok(b) = answer.to_bytes(&session_id).await{
+        response.extend_from_slice(&(b.len() as u64).to_be_bytes());
+        response.extend_from_slice(&b);
+    }else{
+        response.extend_from_slice(&0u64.to_be_bytes());
+    }
+    response
+}
+
+/// A response frame of `POST /stream`: its length in 8 bytes, then the encrypted response.
+async fn stream_frame(answer: TytoDBResponse, session_id: &[u8;32]) -> Bytes{
+    let mut frame: Vec<u8> = Vec::new();
+    match answer.to_bytes(session_id).await{
+        Ok(b) => {
+            frame.extend_from_slice(&(b.len() as u64).to_be_bytes());
+            frame.extend_from_slice(&b);
+        },
+        Err(_) => frame.extend_from_slice(&0u64.to_be_bytes())
+    }
+    Bytes::from(frame)
+}
+
+/// `POST /stream`: a command sent like to `POST /`, answered with a chunked body of frames, one
+/// per page of its result, each holding the column names and rows of the page. The frames go
+/// through a channel of `STREAM_BUFFERED_FRAMES`, so the pages are read as the client takes them
+/// and a slow reader holds up no other session: the database is only read-locked while a page is
+/// read. An unordered SEARCH of stored containers is streamed from its scan, which stops once a
+/// page is full and resumes after its last row, so its memory stays within a page; any other
+/// command runs in full first, and the rows deleted since are left out of its pages. A frame with
+/// success 0 ends the stream when the command or a page fails.
+async fn handle_stream_tcp_inner(dbref: Arc<RwLock<Database>>,rc_payload:Vec<u8>) -> UnsyncBoxBody<Bytes, Infallible>{
+    let single = |frame: Bytes| Full::new(frame).boxed_unsync();
+    if rc_payload.len() <= 32{
+        logerr!("the payload is too short | size :{}",rc_payload.len());
+        return single(Bytes::from(0u64.to_be_bytes().to_vec()))
+    }
+    let mut session_id : [u8;32] = [0u8;32];
+    session_id.clone_from_slice(&rc_payload[..32]);
+    if session_secret_rel.read().await.get(&session_id).is_none(){
+        logerr!("No session secret found for session_id");
+        return single(Bytes::from(0u64.to_be_bytes().to_vec()))
+    }
+    let request = match decrypt(&rc_payload[32..], &session_id).await.ok().and_then(|payload| serde_json::from_slice::<DataConnection>(&payload).ok()){
+        Some(a) => a,
+        None => return single(Bytes::from(0u64.to_be_bytes().to_vec()))
+    };
+    let failure = |e: Error| TytoDBResponse{ content: e.to_string(), success: 0 };
+    let ast = match parse(request.command, request.arguments){
+        Ok(a) => a,
+        Err(e) => return single(stream_frame(failure(e), &session_id).await)
+    };
+    let (sender, receiver) = mpsc::channel::<Bytes>(STREAM_BUFFERED_FRAMES);
+    match ast{
+        AST::Search(structure) if is_streamable(&structure) => {
+            let structure = match dbref.write().await.begin_stream(session_id, *structure).await{
+                Ok(a) => a,
+                Err(e) => return single(stream_frame(failure(e), &session_id).await)
+            };
+            tokio::spawn(stream_search(dbref, session_id, structure, sender));
+        },
+        ast => {
+            let query = match run_waiting(&dbref, session_id, ast).await{
+                Ok(a) => a,
+                Err(e) => return single(stream_frame(failure(e), &session_id).await)
+            };
+            // aggregates and writes come back with their rows already read
+            if query.pages.is_empty(){
+                return single(stream_frame(rows_answer(&query.rows), &session_id).await)
+            }
+            tokio::spawn(stream_pages(dbref, session_id, query, sender));
+        }
+    }
+    let frames = futures::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|frame| (Ok::<_, Infallible>(Frame::data(frame)), receiver))
+    });
+    StreamBody::new(frames).boxed_unsync()
+}
+
+/// The frame answering with `rows`.
+fn rows_answer(rows: &Rows) -> TytoDBResponse{
+    match serde_json::to_string(rows){
+        Ok(rows) => TytoDBResponse{ content: rows, success: 1 },
+        Err(e) => TytoDBResponse{ content: format!("Failed to serialize query result: {}", e), success: 0 }
+    }
+}
+
+/// Waits for room in the channel of a stream and sends `answer`. Returns whether the stream goes
+/// on: the client still reads it and `answer` is no failure.
+async fn send_frame(sender: &mpsc::Sender<Bytes>, answer: TytoDBResponse, session_id: &Session) -> bool{
+    let failed = answer.success == 0;
+    sender.send(stream_frame(answer, session_id).await).await.is_ok() && !failed
+}
+
+/// Sends the pages of a streamed SEARCH as its scan reads them. Every page is read with the
+/// database read-locked and the scan is resumed after its last row for the next one.
+async fn stream_search(dbref: Arc<RwLock<Database>>, session_id: Session, structure: AstSearch, sender: mpsc::Sender<Bytes>){
+    let (window, page_size) = (structure.window, structure.page_size);
+    let (mut seen, mut names, mut sent) = (0u64, Vec::new(), false);
+    let mut page: Vec<Vec<AlbaTypes>> = Vec::new();
+    'sources: for source in 0..structure.container.len(){
+        let mut after = None;
+        loop{
+            let read = dbref.read().await.stream_page(&session_id, &structure, source, after, page_size).await;
+            let ((columns, mut rows), last) = match read{
+                Ok(a) => a,
+                Err(e) => {
+                    send_frame(&sender, TytoDBResponse{ content: format!("Failed to read the rows: {}", e), success: 0 }, &session_id).await;
+                    return
+                }
+            };
+            let exhausted = rows.len() < page_size;
+            after = last;
+            // the first source decides the column names
+            if source == 0{
+                names = columns;
+            }
+            let skipped = window.offset.saturating_sub(seen).min(rows.len() as u64);
+            seen += rows.len() as u64;
+            rows.drain(..skipped as usize);
+            if let Some(wanted) = window.wanted_rows(){
+                rows.truncate(rows.len().saturating_sub(seen.saturating_sub(wanted) as usize));
+            }
+            page.extend(rows);
+            while page.len() >= page_size{
+                let rest = page.split_off(page_size);
+                sent = true;
+                if !send_frame(&sender, rows_answer(&(names.clone(), std::mem::replace(&mut page, rest))), &session_id).await{
+                    return
+                }
+            }
+            if window.wanted_rows().is_some_and(|wanted| seen >= wanted){
+                break 'sources
+            }
+            if exhausted{
+                break
+            }
+        }
+    }
+    if !page.is_empty() || !sent{
+        send_frame(&sender, rows_answer(&(names, page)), &session_id).await;
+    }
+}
+
+/// Sends the pages of a command run in full, each read with the database read-locked.
+async fn stream_pages(dbref: Arc<RwLock<Database>>, session_id: Session, mut query: Query, sender: mpsc::Sender<Bytes>){
+    for page in 0..query.pages.len(){
+        query.current_page = page;
+        let loaded = query.load_rows(&*dbref.read().await, &session_id).await;
+        let answer = match loaded{
+            Ok(_) => rows_answer(&query.rows),
+            Err(e) => TytoDBResponse{ content: format!("Failed to read the page {}: {}", page, e), success: 0 }
+        };
+        if !send_frame(&sender, answer, &session_id).await{
+            return
+        }
+    }
+}
+
+
 use std::convert::Infallible;
 use std::net::SocketAddr;
 
-use http_body_util::{BodyExt, Full};
-use hyper::{body::Bytes, Method, StatusCode};
+use http_body_util::{combinators::UnsyncBoxBody, BodyExt, Full, StreamBody};
+use hyper::{body::{Bytes, Frame}, Method, StatusCode};
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::{Request, Response};
 use hyper_util::rt::TokioIo;
 
-async fn handle_data(req: Request<hyper::body::Incoming>,dbref: Arc<RwLock<Database>>) -> Result<Response<Full<Bytes>>, Infallible> {
+async fn handle_data(req: Request<hyper::body::Incoming>,dbref: Arc<RwLock<Database>>) -> Result<Response<UnsyncBoxBody<Bytes, Infallible>>, Infallible> {
     let method = req.method().to_owned();
+    let path = req.uri().path().to_owned();
     let frame_stream = match req.collect().await{
         Ok(v)=> {v.to_bytes().to_vec()},
         Err(e) => {
             logerr!("{}",e);
-            let r = Response::builder().status(StatusCode::BAD_REQUEST).body(Full::from(Bytes::from("Invalid input"))).unwrap();
+            let r = Response::builder().status(StatusCode::BAD_REQUEST).body(Full::from(Bytes::from("Invalid input")).boxed_unsync()).unwrap();
             return Ok(r)
         }
     };
+    if method == Method::POST && path == "/ingest"{
+        return Ok(Response::new(Full::new(Bytes::from(handle_ingest_tcp_inner(dbref, frame_stream).await)).boxed_unsync()))
+    }
+    if method == Method::POST && path == "/stream"{
+        return Ok(Response::new(handle_stream_tcp_inner(dbref, frame_stream).await))
+    }
     if method == Method::POST{
-        return Ok(Response::new(Full::new(Bytes::from(handle_data_tcp_inner(dbref, frame_stream).await))))
+        return Ok(Response::new(Full::new(Bytes::from(handle_data_tcp_inner(dbref, frame_stream).await)).boxed_unsync()))
     }else{
-        Ok(Response::new(Full::new(Bytes::from(handle_connections_tcp_inner(frame_stream, dbref).await))))
+        Ok(Response::new(Full::new(Bytes::from(handle_connections_tcp_inner(frame_stream, dbref).await)).boxed_unsync()))
     }
 
 }
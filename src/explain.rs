@@ -0,0 +1,143 @@
+use std::{sync::{atomic::{AtomicU64, Ordering}, Arc}, time::{Duration, Instant}};
+
+use crate::{alba_types::AlbaTypes, planner::{AccessPath, IndexLookup, JoinPlan, JoinStrategy, QueryPlan, SortStrategy}, query::Query, query_conditions::{QueryConditions, QueryIndexType}};
+
+/// Work done by the readers of a single command, shared through `SearchArguments`.
+#[derive(Debug, Default)]
+pub struct ExecutionCounters{
+    rows_examined : AtomicU64,
+    rows_matched : AtomicU64,
+    index_chunks_read : AtomicU64,
+    bytes_read : AtomicU64,
+    sort_runs_spilled : AtomicU64,
+    bytes_spilled : AtomicU64,
+}
+
+impl ExecutionCounters{
+    pub fn examined(&self, rows : u64){
+        self.rows_examined.fetch_add(rows, Ordering::Relaxed);
+    }
+    pub fn matched(&self, rows : u64){
+        self.rows_matched.fetch_add(rows, Ordering::Relaxed);
+    }
+    pub fn read(&self, bytes : u64){
+        self.bytes_read.fetch_add(bytes, Ordering::Relaxed);
+    }
+    pub fn spilled(&self, runs : u64){
+        self.sort_runs_spilled.fetch_add(runs, Ordering::Relaxed);
+    }
+    pub fn spilled_bytes(&self, bytes : u64){
+        self.bytes_spilled.fetch_add(bytes, Ordering::Relaxed);
+    }
+    pub fn index_read(&self, chunks : u64, bytes : u64){
+        self.index_chunks_read.fetch_add(chunks, Ordering::Relaxed);
+        self.bytes_read.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+/// Everything `EXPLAIN ANALYZE` reports about a command: the plans used, the counters and
+/// the time spent on each stage. Stages with the same name are summed.
+#[derive(Default)]
+pub struct ExecutionProfile{
+    pub counters : Arc<ExecutionCounters>,
+    pub plans : Vec<(String, QueryConditions, QueryPlan)>,
+    /// The `ON` of a JOIN as written, the conditions checked on the joined rows and the join plan.
+    pub join : Option<(String, QueryConditions, JoinPlan)>,
+    stages : Vec<(&'static str, Duration)>,
+}
+
+impl ExecutionProfile{
+    pub fn stage(&mut self, name : &'static str, started : Instant){
+        let elapsed = started.elapsed();
+        match self.stages.iter_mut().find(|s| s.0 == name){
+            Some(stage) => stage.1 += elapsed,
+            None => self.stages.push((name, elapsed))
+        }
+    }
+}
+
+fn describe_lookup(lookup : &IndexLookup) -> String{
+    let keys = match &lookup.lookup{
+        QueryIndexType::Strict(key) => format!("key {}", key),
+        QueryIndexType::InclusiveRange(range) => format!("keys {}..={}", range.start(), range.end()),
+        QueryIndexType::Set(keys) => format!("keys {{{}}}", keys.iter().map(|k| k.to_string()).collect::<Vec<String>>().join(", ")),
+    };
+    let index = if lookup.primary { "primary" } else { "secondary" };
+    format!("{} ({} index), {}, ~{:.0} rows", lookup.column, index, keys, lookup.estimated_rows)
+}
+
+/// The rows of an `EXPLAIN` result, one property per row.
+#[derive(Default)]
+pub struct Explanation{
+    rows : Vec<Vec<AlbaTypes>>,
+}
+
+impl Explanation{
+    fn push(&mut self, property : &str, value : String){
+        self.rows.push(vec![AlbaTypes::Text(property.to_string()), AlbaTypes::Text(value)]);
+    }
+    pub fn plan(&mut self, container : &str, conditions : &QueryConditions, plan : &QueryPlan){
+        self.push("container", container.to_string());
+        match &plan.access{
+            AccessPath::Scan => self.push("access", "scan".to_string()),
+            AccessPath::Index(lookup) => {
+                self.push("access", "index".to_string());
+                self.push("index", describe_lookup(lookup));
+            },
+            AccessPath::Intersection(lookups) => {
+                self.push("access", "index intersection".to_string());
+                for lookup in lookups{
+                    self.push("index", describe_lookup(lookup));
+                }
+            }
+        }
+        match &plan.sort{
+            Some(SortStrategy::Index(lookup)) => self.push("order", format!("index order on {}", lookup.column)),
+            Some(SortStrategy::External) => self.push("order", "external merge sort".to_string()),
+            None => {}
+        }
+        self.push("planner", if plan.cost_based { "cost based".to_string() } else { "rule based".to_string() });
+        self.push("estimated reads", format!("{:.0}", plan.estimated_reads));
+        self.push("estimated rows", format!("{:.0}", plan.estimated_rows));
+        self.push("estimated cost", format!("{:.1}", plan.estimated_cost));
+        for condition in conditions.describe(){
+            self.push("condition", condition);
+        }
+    }
+    pub fn join(&mut self, on : &str, conditions : &QueryConditions, plan : &JoinPlan){
+        match &plan.strategy{
+            JoinStrategy::Hash => self.push("join", format!("hash join on {}", on)),
+            JoinStrategy::IndexNestedLoop { column, primary } => {
+                let index = if *primary { "primary" } else { "secondary" };
+                self.push("join", format!("index nested loop on {}, {} ({} index)", on, column, index));
+            }
+        }
+        self.push("estimated join rows", format!("{:.0}", plan.estimated_rows));
+        self.push("estimated join cost", format!("{:.1}", plan.estimated_cost));
+        for condition in conditions.describe(){
+            self.push("join condition", condition);
+        }
+    }
+    pub fn subquery(&mut self, subquery : Query){
+        self.push("subquery", String::new());
+        self.rows.extend(subquery.rows.1);
+    }
+    pub fn profile(&mut self, profile : &ExecutionProfile){
+        let counters = &profile.counters;
+        self.push("rows examined", counters.rows_examined.load(Ordering::Relaxed).to_string());
+        self.push("rows matched", counters.rows_matched.load(Ordering::Relaxed).to_string());
+        self.push("index chunks read", counters.index_chunks_read.load(Ordering::Relaxed).to_string());
+        self.push("bytes read", counters.bytes_read.load(Ordering::Relaxed).to_string());
+        self.push("sort runs spilled", counters.sort_runs_spilled.load(Ordering::Relaxed).to_string());
+        self.push("bytes spilled", counters.bytes_spilled.load(Ordering::Relaxed).to_string());
+        for (stage, elapsed) in profile.stages.iter(){
+            self.push(&format!("time {}", stage), format!("{:.3} ms", elapsed.as_secs_f64() * 1000.0));
+        }
+    }
+    pub fn into_query(self) -> Query{
+        let mut query = Query::new_none(vec![AlbaTypes::Text(String::new()), AlbaTypes::Text(String::new())]);
+        query.column_names = vec!["property".to_string(), "value".to_string()];
+        query.rows = (query.column_names.clone(), self.rows);
+        query
+    }
+}
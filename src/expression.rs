@@ -0,0 +1,458 @@
+use std::{collections::HashMap, io::Error};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{alba_types::{get_string_from_alba_type, AlbaTypes}, gerr, query::PrimitivePredicate, query_conditions::Predicate, row::Row};
+
+/// `+ - * / %` between numbers and `||` between texts.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum BinaryOperator{
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+    Concat,
+}
+
+impl BinaryOperator{
+    pub fn from_symbol(symbol : &str) -> Option<Self>{
+        Some(match symbol{
+            "+" => BinaryOperator::Add,
+            "-" => BinaryOperator::Subtract,
+            "*" => BinaryOperator::Multiply,
+            "/" => BinaryOperator::Divide,
+            "%" => BinaryOperator::Remainder,
+            "||" => BinaryOperator::Concat,
+            _ => return None
+        })
+    }
+    pub fn symbol(&self) -> &'static str{
+        match self{
+            BinaryOperator::Add => "+",
+            BinaryOperator::Subtract => "-",
+            BinaryOperator::Multiply => "*",
+            BinaryOperator::Divide => "/",
+            BinaryOperator::Remainder => "%",
+            BinaryOperator::Concat => "||",
+        }
+    }
+    /// `* / %` bind tighter than `+ - ||`.
+    pub fn precedence(&self) -> u8{
+        match self{
+            BinaryOperator::Multiply | BinaryOperator::Divide | BinaryOperator::Remainder => 2,
+            _ => 1
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ScalarFunction{
+    Upper,
+    Lower,
+    Length,
+    /// `SUBSTR(text, start[, length])`, counting characters from 1.
+    Substr,
+    /// The first of its arguments that is not NULL.
+    Coalesce,
+}
+
+impl ScalarFunction{
+    pub fn from_name(name : &str) -> Option<Self>{
+        Some(match name.to_uppercase().as_str(){
+            "UPPER" => ScalarFunction::Upper,
+            "LOWER" => ScalarFunction::Lower,
+            "LENGTH" => ScalarFunction::Length,
+            "SUBSTR" => ScalarFunction::Substr,
+            "COALESCE" => ScalarFunction::Coalesce,
+            _ => return None
+        })
+    }
+    pub fn name(&self) -> &'static str{
+        match self{
+            ScalarFunction::Upper => "UPPER",
+            ScalarFunction::Lower => "LOWER",
+            ScalarFunction::Length => "LENGTH",
+            ScalarFunction::Substr => "SUBSTR",
+            ScalarFunction::Coalesce => "COALESCE",
+        }
+    }
+    fn arguments(&self) -> (usize, usize){
+        match self{
+            ScalarFunction::Substr => (2, 3),
+            ScalarFunction::Coalesce => (1, usize::MAX),
+            _ => (1, 1)
+        }
+    }
+}
+
+/// A value computed from the columns of a row, as the parser reads it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Expression{
+    Column(String),
+    Literal(AlbaTypes),
+    Negate(Box<Expression>),
+    Binary(Box<Expression>, BinaryOperator, Box<Expression>),
+    Function(ScalarFunction, Vec<Expression>),
+    /// `CASE WHEN condition THEN value ... [ELSE value] END`, NULL when no condition holds and there is no ELSE.
+    Case{ branches : Vec<(PrimitivePredicate, Expression)>, otherwise : Option<Box<Expression>> },
+}
+
+/// An expression checked against the column types of a container, ready to be evaluated.
+#[derive(Clone)]
+pub struct BoundExpression{
+    node : Node,
+    /// Type of the values, NONE when the expression is always NULL.
+    pub result_type : AlbaTypes,
+}
+
+#[derive(Clone)]
+enum Node{
+    Column(String),
+    Literal(AlbaTypes),
+    Negate(Box<BoundExpression>),
+    Binary(Box<BoundExpression>, BinaryOperator, Box<BoundExpression>),
+    Function(ScalarFunction, Vec<BoundExpression>),
+    Case{ branches : Vec<(Predicate, BoundExpression)>, otherwise : Option<Box<BoundExpression>> },
+}
+
+fn is_numeric(value : &AlbaTypes) -> bool{
+    matches!(value, AlbaTypes::Int(_) | AlbaTypes::Bigint(_) | AlbaTypes::Float(_) | AlbaTypes::NONE)
+}
+
+fn is_text(value : &AlbaTypes) -> bool{
+    matches!(value, AlbaTypes::Text(_) | AlbaTypes::Char(_) | AlbaTypes::NanoString(_) | AlbaTypes::SmallString(_) |
+        AlbaTypes::MediumString(_) | AlbaTypes::BigString(_) | AlbaTypes::LargeString(_) | AlbaTypes::NONE)
+}
+
+/// The type of an arithmetic result: FLOAT if either side is one, then BIGINT, then INT.
+fn widest(left : &AlbaTypes, right : &AlbaTypes) -> AlbaTypes{
+    match (left, right){
+        (AlbaTypes::Float(_), _) | (_, AlbaTypes::Float(_)) => AlbaTypes::Float(0.0),
+        (AlbaTypes::Bigint(_), _) | (_, AlbaTypes::Bigint(_)) => AlbaTypes::Bigint(0),
+        (AlbaTypes::Int(_), _) | (_, AlbaTypes::Int(_)) => AlbaTypes::Int(0),
+        _ => AlbaTypes::NONE
+    }
+}
+
+/// The type shared by values that can each be the result, like the branches of CASE.
+fn common_type(types : &[&AlbaTypes], what : &str) -> Result<AlbaTypes, Error>{
+    let mut result = AlbaTypes::NONE;
+    for value_type in types{
+        result = match (&result, value_type){
+            (_, AlbaTypes::NONE) => continue,
+            (AlbaTypes::NONE, value_type) => (*value_type).clone(),
+            (current, value_type) if is_numeric(current) && is_numeric(value_type) => widest(current, value_type),
+            (current, value_type) if current.is_comparable_with(value_type) => continue,
+            (current, value_type) => return Err(gerr(&format!("The values of {} must have the same kind of type, found {} and {}", what, type_name(current), type_name(value_type)))),
+        };
+    }
+    Ok(result)
+}
+
+fn type_name(value : &AlbaTypes) -> &'static str{
+    match value{
+        AlbaTypes::Int(_) => "INT",
+        AlbaTypes::Bigint(_) => "BIGINT",
+        AlbaTypes::Float(_) => "FLOAT",
+        AlbaTypes::Bool(_) => "BOOL",
+        AlbaTypes::NONE => "NULL",
+        value if is_text(value) => "text",
+        _ => "bytes"
+    }
+}
+
+/// Converts a computed value to the type of its expression, so every value of a column has the same one.
+fn cast(value : AlbaTypes, result_type : &AlbaTypes) -> Result<AlbaTypes, Error>{
+    if matches!(value, AlbaTypes::NONE) || matches!(result_type, AlbaTypes::NONE) || std::mem::discriminant(&value) == std::mem::discriminant(result_type){
+        return Ok(value)
+    }
+    result_type.try_from_existing(value)
+}
+
+impl Expression{
+    /// Checks the columns and the types of the operands against the columns of a container.
+    pub fn bind(&self, column_properties : &HashMap<String, AlbaTypes>) -> Result<BoundExpression, Error>{
+        let bind_all = |list : &[Expression]| list.iter().map(|e| e.bind(column_properties)).collect::<Result<Vec<BoundExpression>, Error>>();
+        let (node, result_type) = match self{
+            Expression::Column(name) => match column_properties.get(name){
+                Some(column_type) => (Node::Column(name.clone()), column_type.clone()),
+                None => return Err(gerr(&format!("There is no column {}", name)))
+            },
+            Expression::Literal(value) => (Node::Literal(value.clone()), value.clone()),
+            Expression::Negate(inner) => {
+                let inner = inner.bind(column_properties)?;
+                if !is_numeric(&inner.result_type){
+                    return Err(gerr(&format!("- needs a number, but {} is {}", inner.describe(), type_name(&inner.result_type))))
+                }
+                let result_type = inner.result_type.clone();
+                (Node::Negate(Box::new(inner)), result_type)
+            },
+            Expression::Binary(left, operator, right) => {
+                let (left, right) = (left.bind(column_properties)?, right.bind(column_properties)?);
+                let result_type = match operator{
+                    BinaryOperator::Concat if is_text(&left.result_type) || is_text(&right.result_type) => AlbaTypes::Text(String::new()),
+                    BinaryOperator::Concat => return Err(gerr(&format!("|| needs text on one of its sides, but found {} and {}", type_name(&left.result_type), type_name(&right.result_type)))),
+                    _ if is_numeric(&left.result_type) && is_numeric(&right.result_type) => widest(&left.result_type, &right.result_type),
+                    _ => return Err(gerr(&format!("{} needs numbers, but found {} and {}", operator.symbol(), type_name(&left.result_type), type_name(&right.result_type)))),
+                };
+                (Node::Binary(Box::new(left), *operator, Box::new(right)), result_type)
+            },
+            Expression::Function(function, arguments) => {
+                let (least, most) = function.arguments();
+                if arguments.len() < least || arguments.len() > most{
+                    return Err(gerr(&format!("{} takes {} arguments, but found {}", function.name(), if least == most { least.to_string() } else if most == usize::MAX { format!("at least {}", least) } else { format!("{} to {}", least, most) }, arguments.len())))
+                }
+                let arguments = bind_all(arguments)?;
+                let text_argument = || if is_text(&arguments[0].result_type) { Ok(()) } else { Err(gerr(&format!("{} needs text, but {} is {}", function.name(), arguments[0].describe(), type_name(&arguments[0].result_type)))) };
+                let result_type = match function{
+                    ScalarFunction::Upper | ScalarFunction::Lower => { text_argument()?; AlbaTypes::Text(String::new()) },
+                    ScalarFunction::Length => { text_argument()?; AlbaTypes::Bigint(0) },
+                    ScalarFunction::Substr => {
+                        text_argument()?;
+                        if arguments[1..].iter().any(|a| !matches!(a.result_type, AlbaTypes::Int(_) | AlbaTypes::Bigint(_) | AlbaTypes::NONE)){
+                            return Err(gerr("The start and length of SUBSTR must be integers"))
+                        }
+                        AlbaTypes::Text(String::new())
+                    },
+                    ScalarFunction::Coalesce => common_type(&arguments.iter().map(|a| &a.result_type).collect::<Vec<&AlbaTypes>>(), "COALESCE")?,
+                };
+                (Node::Function(*function, arguments), result_type)
+            },
+            Expression::Case { branches, otherwise } => {
+                let mut bound = Vec::with_capacity(branches.len());
+                for (condition, value) in branches{
+                    bound.push((Predicate::from_primitive(condition.clone(), column_properties)?, value.bind(column_properties)?));
+                }
+                let otherwise = match otherwise{
+                    Some(value) => Some(Box::new(value.bind(column_properties)?)),
+                    None => None
+                };
+                let mut types : Vec<&AlbaTypes> = bound.iter().map(|b| &b.1.result_type).collect();
+                if let Some(value) = &otherwise{
+                    types.push(&value.result_type);
+                }
+                let result_type = common_type(&types, "CASE")?;
+                (Node::Case { branches: bound, otherwise }, result_type)
+            },
+        };
+        Ok(BoundExpression { node, result_type })
+    }
+    /// Every column the expression reads, CASE conditions included.
+    pub fn columns(&self) -> Vec<&str>{
+        match self{
+            Expression::Column(name) => vec![name.as_str()],
+            Expression::Literal(_) => Vec::new(),
+            Expression::Negate(inner) => inner.columns(),
+            Expression::Binary(left, _, right) => left.columns().into_iter().chain(right.columns()).collect(),
+            Expression::Function(_, arguments) => arguments.iter().flat_map(|a| a.columns()).collect(),
+            Expression::Case { branches, otherwise } => {
+                let mut columns : Vec<&str> = branches.iter().flat_map(|(condition, value)| crate::join::columns(condition).into_iter().chain(value.columns())).collect();
+                if let Some(value) = otherwise{
+                    columns.extend(value.columns());
+                }
+                columns
+            },
+        }
+    }
+    /// The expression with every column renamed by `name`.
+    pub fn rename(self, name : &dyn Fn(&str) -> Result<String, Error>) -> Result<Self, Error>{
+        Ok(match self{
+            Expression::Column(column) => Expression::Column(name(&column)?),
+            Expression::Literal(value) => Expression::Literal(value),
+            Expression::Negate(inner) => Expression::Negate(Box::new(inner.rename(name)?)),
+            Expression::Binary(left, operator, right) => Expression::Binary(Box::new(left.rename(name)?), operator, Box::new(right.rename(name)?)),
+            Expression::Function(function, arguments) => Expression::Function(function, arguments.into_iter().map(|a| a.rename(name)).collect::<Result<_, Error>>()?),
+            Expression::Case { branches, otherwise } => Expression::Case {
+                branches: branches.into_iter().map(|(condition, value)| Ok((crate::join::rename(condition, name)?, value.rename(name)?))).collect::<Result<_, Error>>()?,
+                otherwise: match otherwise{
+                    Some(value) => Some(Box::new(value.rename(name)?)),
+                    None => None
+                },
+            },
+        })
+    }
+}
+
+impl BoundExpression{
+    /// The value of the expression for a row holding every column it reads.
+    pub fn value(&self, row : &Row) -> Result<AlbaTypes, Error>{
+        self.evaluate(row, &mut HashMap::new())
+    }
+    pub fn evaluate(&self, row : &Row, regex_cache : &mut HashMap<String, Regex>) -> Result<AlbaTypes, Error>{
+        match &self.node{
+            Node::Column(name) => match row.data.get(name){
+                Some(value) => Ok(value.clone()),
+                None => Err(gerr(&format!("No value found for the column {}", name)))
+            },
+            Node::Literal(value) => Ok(value.clone()),
+            Node::Negate(inner) => Ok(match inner.evaluate(row, regex_cache)?{
+                AlbaTypes::Int(n) => AlbaTypes::Int(n.checked_neg().ok_or_else(|| gerr(&format!("-{} is out of range for INT", n)))?),
+                AlbaTypes::Bigint(n) => AlbaTypes::Bigint(n.checked_neg().ok_or_else(|| gerr(&format!("-{} is out of range for BIGINT", n)))?),
+                AlbaTypes::Float(f) => AlbaTypes::Float(-f),
+                other => other
+            }),
+            Node::Binary(left, operator, right) => {
+                let (left, right) = (left.evaluate(row, regex_cache)?, right.evaluate(row, regex_cache)?);
+                if matches!(left, AlbaTypes::NONE) || matches!(right, AlbaTypes::NONE){
+                    return Ok(AlbaTypes::NONE)
+                }
+                match operator{
+                    BinaryOperator::Concat => Ok(AlbaTypes::Text(get_string_from_alba_type(left)? + &get_string_from_alba_type(right)?)),
+                    _ => arithmetic(*operator, &left, &right, &self.result_type),
+                }
+            },
+            Node::Function(function, arguments) => {
+                if *function == ScalarFunction::Coalesce{
+                    for argument in arguments{
+                        let value = argument.evaluate(row, regex_cache)?;
+                        if !matches!(value, AlbaTypes::NONE){
+                            return cast(value, &self.result_type)
+                        }
+                    }
+                    return Ok(AlbaTypes::NONE)
+                }
+                let mut values = Vec::with_capacity(arguments.len());
+                for argument in arguments{
+                    match argument.evaluate(row, regex_cache)?{
+                        AlbaTypes::NONE => return Ok(AlbaTypes::NONE),
+                        value => values.push(value)
+                    }
+                }
+                let text = get_string_from_alba_type(values.remove(0))?;
+                Ok(match function{
+                    ScalarFunction::Upper => AlbaTypes::Text(text.to_uppercase()),
+                    ScalarFunction::Lower => AlbaTypes::Text(text.to_lowercase()),
+                    ScalarFunction::Length => AlbaTypes::Bigint(text.chars().count() as i64),
+                    ScalarFunction::Substr => {
+                        let start = integer(&values[0])?;
+                        let length = match values.get(1){
+                            Some(length) => Some(integer(length)?),
+                            None => None
+                        };
+                        AlbaTypes::Text(substring(&text, start, length)?)
+                    },
+                    ScalarFunction::Coalesce => AlbaTypes::NONE,
+                })
+            },
+            Node::Case { branches, otherwise } => {
+                for (condition, value) in branches{
+                    if condition.matches(row, regex_cache)?{
+                        return cast(value.evaluate(row, regex_cache)?, &self.result_type)
+                    }
+                }
+                match otherwise{
+                    Some(value) => cast(value.evaluate(row, regex_cache)?, &self.result_type),
+                    None => Ok(AlbaTypes::NONE)
+                }
+            },
+        }
+    }
+    /// Every column the expression reads.
+    pub fn columns<'a>(&'a self, output : &mut Vec<&'a str>){
+        match &self.node{
+            Node::Column(name) => output.push(name),
+            Node::Literal(_) => {},
+            Node::Negate(inner) => inner.columns(output),
+            Node::Binary(left, _, right) => { left.columns(output); right.columns(output); },
+            Node::Function(_, arguments) => arguments.iter().for_each(|a| a.columns(output)),
+            Node::Case { branches, otherwise } => {
+                for (condition, value) in branches{
+                    condition.columns(output);
+                    value.columns(output);
+                }
+                if let Some(value) = otherwise{
+                    value.columns(output);
+                }
+            },
+        }
+    }
+    /// The expression as it would be written, with parentheses only where precedence needs them.
+    pub fn describe(&self) -> String{
+        match &self.node{
+            Node::Column(name) => name.clone(),
+            Node::Literal(AlbaTypes::NONE) => "NULL".to_string(),
+            Node::Literal(value) if is_text(value) => format!("'{}'", get_string_from_alba_type(value.clone()).unwrap_or_default()),
+            Node::Literal(value) => get_string_from_alba_type(value.clone()).unwrap_or_default(),
+            Node::Negate(inner) => match inner.node{
+                Node::Binary(..) => format!("-({})", inner.describe()),
+                _ => format!("-{}", inner.describe())
+            },
+            Node::Binary(left, operator, right) => {
+                let operand = |side : &BoundExpression, tighter : bool| match &side.node{
+                    Node::Binary(_, inner, _) if inner.precedence() < operator.precedence() || (tighter && inner.precedence() == operator.precedence()) => format!("({})", side.describe()),
+                    _ => side.describe()
+                };
+                // the right side of `a - (b - c)` needs its parentheses, the left of `(a - b) - c` does not
+                format!("{} {} {}", operand(left, false), operator.symbol(), operand(right, true))
+            },
+            Node::Function(function, arguments) => format!("{}({})", function.name(), arguments.iter().map(|a| a.describe()).collect::<Vec<String>>().join(", ")),
+            Node::Case { branches, otherwise } => {
+                let mut text = String::from("CASE");
+                for (condition, value) in branches{
+                    text.push_str(&format!(" WHEN {} THEN {}", condition.describe(), value.describe()));
+                }
+                if let Some(value) = otherwise{
+                    text.push_str(&format!(" ELSE {}", value.describe()));
+                }
+                text + " END"
+            },
+        }
+    }
+}
+
+fn integer(value : &AlbaTypes) -> Result<i64, Error>{
+    match value{
+        AlbaTypes::Int(n) => Ok(*n as i64),
+        AlbaTypes::Bigint(n) => Ok(*n),
+        _ => Err(gerr("Expected an integer"))
+    }
+}
+
+/// `length` characters of `text` from the 1-based `start`. A start before the first character
+/// shortens the result, like it does in SQL.
+fn substring(text : &str, start : i64, length : Option<i64>) -> Result<String, Error>{
+    let end = match length{
+        Some(length) if length < 0 => return Err(gerr("The length of SUBSTR can not be negative")),
+        Some(length) => start.saturating_add(length),
+        None => i64::MAX
+    };
+    let (first, end) = (start.max(1), end.max(1));
+    Ok(text.chars().skip((first - 1) as usize).take(end.saturating_sub(first) as usize).collect())
+}
+
+fn arithmetic(operator : BinaryOperator, left : &AlbaTypes, right : &AlbaTypes, result_type : &AlbaTypes) -> Result<AlbaTypes, Error>{
+    if let AlbaTypes::Float(_) = result_type{
+        let float = |value : &AlbaTypes| match value{
+            AlbaTypes::Float(f) => *f,
+            other => integer(other).unwrap_or_default() as f64
+        };
+        let (a, b) = (float(left), float(right));
+        if b == 0.0 && matches!(operator, BinaryOperator::Divide | BinaryOperator::Remainder){
+            return Err(gerr("Division by zero"))
+        }
+        return Ok(AlbaTypes::Float(match operator{
+            BinaryOperator::Add => a + b,
+            BinaryOperator::Subtract => a - b,
+            BinaryOperator::Multiply => a * b,
+            BinaryOperator::Divide => a / b,
+            _ => a % b,
+        }))
+    }
+    let (a, b) = (integer(left)?, integer(right)?);
+    let result = match operator{
+        BinaryOperator::Add => a.checked_add(b),
+        BinaryOperator::Subtract => a.checked_sub(b),
+        BinaryOperator::Multiply => a.checked_mul(b),
+        BinaryOperator::Divide | BinaryOperator::Remainder if b == 0 => return Err(gerr("Division by zero")),
+        BinaryOperator::Divide => a.checked_div(b),
+        _ => a.checked_rem(b),
+    };
+    let overflow = || gerr(&format!("{} {} {} is out of range for {}", a, operator.symbol(), b, type_name(result_type)));
+    let result = result.ok_or_else(overflow)?;
+    match result_type{
+        AlbaTypes::Int(_) => Ok(AlbaTypes::Int(i32::try_from(result).map_err(|_| overflow())?)),
+        _ => Ok(AlbaTypes::Bigint(result)),
+    }
+}
@@ -1,10 +1,17 @@
 use tokio::sync::RwLock;
 
 use crate::{alba_types::AlbaTypes, gerr};
-use std::{collections::BTreeSet, fs::{self, File}, hash::{DefaultHasher, Hash, Hasher}, io::{Error, Read}, ops::{RangeInclusive,Range}, os::unix::fs::{FileExt, MetadataExt}, sync::Arc, time::Duration};
+use std::{collections::BTreeSet, fs::{self, File, OpenOptions}, hash::{DefaultHasher, Hash, Hasher}, io::{Error, Read}, ops::{RangeInclusive,Range}, os::unix::fs::FileExt, sync::Arc, time::Duration};
 
-const INDEX_CHUNK_SIZE : u64 = GERAL_DISK_CHUNK as u64;
+pub const INDEX_CHUNK_SIZE : u64 = GERAL_DISK_CHUNK as u64;
 const GERAL_DISK_CHUNK : usize = 4096;
+const INDEX_ELEMENT_SIZE : u64 = 16;
+const METADATA_ELEMENT_SIZE : u64 = 18;
+/// Version of the key encoding, kept in the first slot of the metadata file before the chunks.
+/// Index files of another version, or written before there was one, are emptied on load for the
+/// container to fill them again.
+const INDEX_FORMAT_VERSION : u64 = 2;
+const INDEX_FORMAT_MAGIC : u64 = u64::from_be_bytes(*b"TYTOIDX\0");
 
 
 //type IndexElement = (u64,u64); // index value , offset value
@@ -36,7 +43,9 @@ pub struct Indexing{
     indexes_metadata_file : Arc<RwLock<File>>,
     metadata : Arc<RwLock<Vec<(u64,u64,u16)>>>,
     changes : Arc<RwLock<bool>>,
-    destroyed : Arc<RwLock<bool>>
+    destroyed : Arc<RwLock<bool>>,
+    /// The files were of another format and were emptied, the index has to be filled again.
+    pub stale : bool,
 }
 impl Indexing{
     pub async fn create_index(container_name : String) -> Result<(),Error>{
@@ -61,23 +70,34 @@ impl Indexing{
             return Err(gerr("One of the indexing files are missing"))
         }
 
-        let indexes_file = File::open(&ifp)?;
-        let mut metadata_file = File::open(&mtp)?;
+        let indexes_file = OpenOptions::new().read(true).write(true).open(&ifp)?;
+        let mut metadata_file = OpenOptions::new().read(true).write(true).open(&mtp)?;
 
-        let index_metadata  = {
-            let size = metadata_file.metadata()?.size() as usize;
-            let mut buffer = vec![0u8;size];
-            metadata_file.read_to_end(&mut buffer)?;
-            let mut elements : Vec<MetadataElement> = Vec::with_capacity(size/18);
-            for i in buffer.chunks_exact(18){
+        let mut buffer = Vec::new();
+        metadata_file.read_to_end(&mut buffer)?;
+        let current = buffer.get(..METADATA_ELEMENT_SIZE as usize).is_some_and(|header| {
+            u64::from_be_bytes(header[0..8].try_into().unwrap()) == INDEX_FORMAT_MAGIC && u64::from_be_bytes(header[8..16].try_into().unwrap()) == INDEX_FORMAT_VERSION
+        });
+        let stale = !current && !buffer.is_empty();
+        let index_metadata = if current{
+            let mut elements : Vec<MetadataElement> = Vec::with_capacity(buffer.len()/18);
+            for i in buffer[METADATA_ELEMENT_SIZE as usize..].chunks_exact(18){
                 let minimum_index_value = u64::from_be_bytes(i[0..8].try_into().unwrap());
                 let maximum_index_value = u64::from_be_bytes(i[8..16].try_into().unwrap());
                 let length_of_chunk     = u16::from_be_bytes(i[16..18].try_into().unwrap());
                 elements.push((minimum_index_value,maximum_index_value,length_of_chunk));
             }
             elements
+        }else{
+            indexes_file.set_len(0)?;
+            metadata_file.set_len(0)?;
+            let mut header = [0u8; METADATA_ELEMENT_SIZE as usize];
+            header[0..8].copy_from_slice(&INDEX_FORMAT_MAGIC.to_be_bytes());
+            header[8..16].copy_from_slice(&INDEX_FORMAT_VERSION.to_be_bytes());
+            metadata_file.write_all_at(&header, 0)?;
+            Vec::new()
         };
-        let me = Arc::new(Indexing { indexes_file: Arc::new(RwLock::new(indexes_file)), indexes_metadata_file: Arc::new(RwLock::new(metadata_file)), metadata: Arc::new(RwLock::new(index_metadata)), changes: Arc::new(RwLock::new(false)), destroyed:Arc::new(RwLock::new(false)) });
+        let me = Arc::new(Indexing { indexes_file: Arc::new(RwLock::new(indexes_file)), indexes_metadata_file: Arc::new(RwLock::new(metadata_file)), metadata: Arc::new(RwLock::new(index_metadata)), changes: Arc::new(RwLock::new(false)), destroyed:Arc::new(RwLock::new(false)), stale });
         let virt_me = me.clone();
         tokio::spawn(async move{
             let me = virt_me;
@@ -100,111 +120,141 @@ impl Indexing{
         });
         Ok(me)
     }
-    pub async fn create_index_chunk(&self,arg : u64,arg_offset : u64) -> Result<(),Error>{
-        let mut metadata = self.metadata.write().await;
+    /// Stops the background sync task and deletes the index files of `container_name`.
+    pub async fn destroy(&self,container_name : &String) -> Result<(),Error>{
+        *self.destroyed.write().await = true;
+        for path in [format!("./{}.cindex",container_name),format!("./{}.cimeta",container_name)]{
+            if fs::exists(&path)?{
+                fs::remove_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+    fn write_metadata_element(&self,file : &File,position : usize,element : &MetadataElement) -> Result<(),Error>{
+        let mut buffer = [0u8; 18];
+        buffer[0..8].copy_from_slice(&element.0.to_be_bytes());
+        buffer[8..16].copy_from_slice(&element.1.to_be_bytes());
+        buffer[16..18].copy_from_slice(&element.2.to_be_bytes());
+        // the first slot holds the format version
+        file.write_all_at(&buffer, (position as u64 + 1) * METADATA_ELEMENT_SIZE)
+    }
+    fn read_chunk(&self,file : &File,position : usize,items : u16) -> Result<Vec<(u64,u64)>,Error>{
+        let mut buffer = vec![0u8;items as usize * INDEX_ELEMENT_SIZE as usize];
+        file.read_exact_at(&mut buffer, position as u64 * INDEX_CHUNK_SIZE * INDEX_ELEMENT_SIZE)?;
+        let mut elements = Vec::with_capacity(items as usize);
+        for chunk in buffer.chunks_exact(INDEX_ELEMENT_SIZE as usize){
+            let index_value = u64::from_be_bytes(chunk[..8].try_into().unwrap());
+            let index_offset = u64::from_be_bytes(chunk[8..].try_into().unwrap());
+            elements.push((index_value,index_offset));
+        }
+        Ok(elements)
+    }
+    pub async fn create_index_chunk(&self,metadata : &mut Vec<MetadataElement>,arg : u64,arg_offset : u64) -> Result<(),Error>{
         let index_file = self.indexes_file.write().await;
-        metadata.push((arg.clone(),arg+INDEX_CHUNK_SIZE,1));
-        
         let metadata_file = self.indexes_metadata_file.write().await;
-        let metadata_size = metadata_file.metadata()?.size();
-        metadata_file.set_len(metadata_size + 18)?;
-        let value = (arg.clone().to_be_bytes(),(arg+INDEX_CHUNK_SIZE as u64).to_be_bytes(),(1 as u16).to_be_bytes());
-        let mut buffer = [0u8; 18];
-        buffer[0..8].copy_from_slice(&value.0);
-        buffer[8..16].copy_from_slice(&value.1);
-        buffer[16..18].copy_from_slice(&value.2);
-        metadata_file.write_all_at(&mut buffer, metadata_size)?;
-        let _ = buffer;
-        let _ = metadata_size;
-        
-        let index_file_size = index_file.metadata()?.size();
-        index_file.set_len(index_file_size+INDEX_CHUNK_SIZE * 16)?;
-        let mut buffer = [0u8;(INDEX_CHUNK_SIZE*16) as usize];
-        let mut ib = [0u8;16];
-        ib[..8].copy_from_slice(&arg.to_be_bytes());
-        ib[8..].copy_from_slice(&arg_offset.to_be_bytes());
-        buffer[..16].copy_from_slice(&ib);
-        index_file.write_all_at(&mut buffer,index_file_size)?;
+        let element = (arg,arg.saturating_add(INDEX_CHUNK_SIZE),1);
+        self.write_metadata_element(&metadata_file, metadata.len(), &element)?;
+
+        let mut buffer = vec![0u8;(INDEX_CHUNK_SIZE*INDEX_ELEMENT_SIZE) as usize];
+        buffer[..8].copy_from_slice(&arg.to_be_bytes());
+        buffer[8..16].copy_from_slice(&arg_offset.to_be_bytes());
+        index_file.write_all_at(&buffer,metadata.len() as u64 * INDEX_CHUNK_SIZE * INDEX_ELEMENT_SIZE)?;
+        metadata.push(element);
         *self.changes.write().await = true;
         Ok(())
     }
-    pub async fn insert_index(&self,arg : u64, arg_offset : u64,meta : (usize, &(u64, u64, u16))) -> Result<(),Error>{
+    pub async fn insert_index(&self,metadata : &mut [MetadataElement],arg : u64, arg_offset : u64,position : usize) -> Result<(),Error>{
         let index_file = self.indexes_file.write().await;
-        let meta_file = self.indexes_file.write().await;
-        
+        let meta_file = self.indexes_metadata_file.write().await;
+        let element = &mut metadata[position];
+
         let mut index_buff = [0u8;16];
-        let mut meta_buff = [0u8;18];
         index_buff[..8].copy_from_slice(&arg.to_be_bytes());
         index_buff[8..].copy_from_slice(&arg_offset.to_be_bytes());
-        meta_buff[..8].copy_from_slice(&meta.1.0.to_be_bytes());
-        meta_buff[8..16].copy_from_slice(&meta.1.1.to_be_bytes());
-        meta_buff[16..].copy_from_slice(&meta.1.2.to_be_bytes());
-        index_file.write_all_at(&index_buff, (((meta.0*INDEX_CHUNK_SIZE as usize) as usize + (meta.1.2) as usize) as usize).try_into().unwrap())?;
-        meta_file.write_all_at(&meta_buff, meta.0 as u64*18)?;
+        index_file.write_all_at(&index_buff, (position as u64 * INDEX_CHUNK_SIZE + element.2 as u64) * INDEX_ELEMENT_SIZE)?;
+        element.2 += 1;
+        self.write_metadata_element(&meta_file, position, element)?;
         *self.changes.write().await = true;
         Ok(())
     }
     pub async  fn remove_index(&self,arg : u64,arg_offset : u64) -> Result<(),Error>{
         let index_file = self.indexes_file.write().await;
-        let meta_file = self.indexes_file.write().await;
+        let meta_file = self.indexes_metadata_file.write().await;
         let mut metadata = self.metadata.write().await;
-        let mut slots = Vec::with_capacity(5);
 
-        for (index,value) in metadata.iter_mut().enumerate(){
-            if arg >= value.0 && arg <= value.1{
-                slots.push((index,value));
+        for (idx,v) in metadata.iter_mut().enumerate(){
+            if arg < v.0 || arg > v.1 || v.2 == 0{
+                continue;
             }
-        }
-
-        for (idx,v) in slots{
-            let mut buffer = [0u8;INDEX_CHUNK_SIZE as usize * 16];
-            let offset = idx * INDEX_CHUNK_SIZE as usize;
-            index_file.read_exact_at(&mut buffer, offset as u64)?;
-            let mut index_value_vector = Vec::with_capacity(INDEX_CHUNK_SIZE as usize);
-            for i in buffer.chunks_exact(16){
-                let index_value = u64::from_be_bytes(i[..8].try_into().unwrap());
-                let offset_value = u64::from_be_bytes(i[8..].try_into().unwrap());
-                if index_value == arg && arg_offset == offset_value{
-                    continue;
-                }else{
-                    index_value_vector.push((index_value,offset_value))
-                }
+            let elements = self.read_chunk(&index_file, idx, v.2)?;
+            let kept : Vec<(u64,u64)> = elements.iter().copied().filter(|(index_value,offset_value)| !(*index_value == arg && *offset_value == arg_offset)).collect();
+            if kept.len() == elements.len(){
+                continue;
             }
-            buffer = [0u8;INDEX_CHUNK_SIZE as usize * 16];
-            for i in index_value_vector.iter().enumerate(){
-                let index = i.0 * 16;
-                buffer[index..index+8].copy_from_slice(&i.1.0.to_be_bytes());
-                buffer[index+8..index+16].copy_from_slice(&i.1.1.to_be_bytes());
+            let mut buffer = vec![0u8;elements.len() * INDEX_ELEMENT_SIZE as usize];
+            for (i,(index_value,offset_value)) in kept.iter().enumerate(){
+                let index = i * INDEX_ELEMENT_SIZE as usize;
+                buffer[index..index+8].copy_from_slice(&index_value.to_be_bytes());
+                buffer[index+8..index+16].copy_from_slice(&offset_value.to_be_bytes());
             }
-            index_file.write_all_at(&mut buffer, INDEX_CHUNK_SIZE *(idx as u64 *16))?;
-            v.2 -= 1;
-            let mut metadata_buffer = [0u8;18];
-            metadata_buffer[..8].copy_from_slice(&v.0.to_be_bytes());
-            metadata_buffer[8..16].copy_from_slice(&v.1.to_be_bytes());
-            metadata_buffer[16..].copy_from_slice(&v.2.to_be_bytes());
-            meta_file.write_all_at(&mut metadata_buffer, idx as u64*18)?;
+            index_file.write_all_at(&buffer, idx as u64 * INDEX_CHUNK_SIZE * INDEX_ELEMENT_SIZE)?;
+            v.2 = kept.len() as u16;
+            self.write_metadata_element(&meta_file, idx, v)?;
             *self.changes.write().await = true;
         }
         Ok(())
     }
+    /// Chunks and bytes a search over `start..=end` reads, without reading them.
+    pub async fn coverage(&self,start : u64,end : u64) -> (u64,u64){
+        let metadata = self.metadata.read().await;
+        let mut chunks = 0;
+        let mut bytes = 0;
+        for i in metadata.iter(){
+            if i.2 == 0 || start > i.1 || end < i.0 {
+                continue;
+            }
+            chunks += 1;
+            bytes += i.2 as u64 * INDEX_ELEMENT_SIZE;
+        }
+        (chunks,bytes)
+    }
+    /// Collects the offsets of every entry whose index value is inside `start..=end`.
+    async fn search_inclusive(&self,start : u64,end : u64) -> Result<BTreeSet<u64>,Error>{
+        Ok(self.entries(start, end).await?.into_iter().map(|(_,offset)| offset).collect())
+    }
+    /// Every `(index value, offset)` entry inside `start..=end`, ordered by index value and then offset.
+    pub async fn entries(&self,start : u64,end : u64) -> Result<Vec<(u64,u64)>,Error>{
+        let metadata = self.metadata.read().await;
+        let indexes_file = self.indexes_file.read().await;
+        let mut entries : Vec<(u64,u64)> = Vec::new();
+        if start > end{
+            return Ok(entries)
+        }
+
+        for (idx, i) in metadata.iter().enumerate() {
+            if i.2 == 0 || start > i.1 || end < i.0 {
+                continue;
+            }
+            for (index_value,index_offset) in self.read_chunk(&indexes_file, idx, i.2)?{
+                if index_value >= start && index_value <= end {
+                    entries.push((index_value,index_offset));
+                }
+            }
+        }
+        entries.sort_unstable();
+        entries.dedup();
+        Ok(entries)
+    }
 }
 
 impl Add for Indexing {
     async fn add(&self, arg: u64,arg_offset : u64) -> Result<(),Error> {
-        let metadata = self.metadata.read().await;
-        let meta: (usize, &(u64, u64, u16)) = {
-            let mut index : (usize,&(u64,u64,u16)) = (0,&(0,0,0));
-            let mut alloc = false;
-            for (i,v) in metadata.iter().enumerate(){
-                if v.2 < u16::MAX && arg <= v.1 && arg >= v.0{
-                    index = (i,v);
-                    alloc = true;
-                    break;
-                }
-            }
-            if alloc{index}else{return self.create_index_chunk(arg, arg_offset).await}
-        };
-        self.insert_index(arg, arg_offset,meta).await
+        let mut metadata = self.metadata.write().await;
+        let position = metadata.iter().position(|v| (v.2 as u64) < INDEX_CHUNK_SIZE && arg <= v.1 && arg >= v.0);
+        match position{
+            Some(position) => self.insert_index(&mut metadata, arg, arg_offset, position).await,
+            None => self.create_index_chunk(&mut metadata, arg, arg_offset).await
+        }
     }
 }
 impl Remove for Indexing{
@@ -214,113 +264,42 @@ impl Remove for Indexing{
 }
 impl Search<Range<u64>> for Indexing {
     async fn search(&self, arg: Range<u64>) -> Result<BTreeSet<u64>, Error> {
-        let metadata = self.metadata.read().await;
-        let mut groups = Vec::new();
-        for (idx,i) in metadata.iter().enumerate(){
-            if arg.contains(&i.0) && arg.contains(&i.1){
-                groups.push(idx);
-            }
-        }
-        groups.sort();
-
-        let indexes_file = self.indexes_file.read().await;
-        let mut offsets : BTreeSet<u64> = BTreeSet::new();
-        
-        for i in groups{
-            let mut buffer = [0u8;INDEX_CHUNK_SIZE as usize * 16];
-            indexes_file.read_exact_at(&mut buffer, i as u64*INDEX_CHUNK_SIZE*16)?;
-            for chunk in buffer.chunks_exact(16){
-                let index_value = u64::from_be_bytes(chunk[..8].try_into().unwrap());
-                let index_offset = u64::from_be_bytes(chunk[8..].try_into().unwrap());
-                if arg.contains(&index_value) {
-                    offsets.insert(index_offset);
-                }
-
-            }
+        if arg.is_empty(){
+            return Ok(BTreeSet::new())
         }
-        
-        Ok(offsets)
+        self.search_inclusive(arg.start, arg.end - 1).await
     }
 }
 
 impl Search<RangeInclusive<u64>> for Indexing {
     async fn search(&self, arg: RangeInclusive<u64>) -> Result<BTreeSet<u64>, Error> {
-        let metadata = self.metadata.read().await;
-        let mut groups = Vec::new();
-        let (start, end) = (*arg.start(), *arg.end());
-
-        for (idx, i) in metadata.iter().enumerate() {
-            if start <= i.1 && end >= i.0 {
-                groups.push(idx);
-            }
-        }
-        groups.sort();
-
-        let indexes_file = self.indexes_file.read().await;
-        let mut offsets : BTreeSet<u64> = BTreeSet::new();
-        
-        for i in groups{
-            let mut buffer = [0u8;INDEX_CHUNK_SIZE as usize * 16];
-            indexes_file.read_exact_at(&mut buffer, i as u64*INDEX_CHUNK_SIZE*16)?;
-            for chunk in buffer.chunks_exact(16){
-                let index_value = u64::from_be_bytes(chunk[..8].try_into().unwrap());
-                let index_offset = u64::from_be_bytes(chunk[8..].try_into().unwrap());
-                if arg.contains(&index_value) {
-                    offsets.insert(index_offset);
-                }
-
-            }
-        }
-        
-        Ok(offsets)
+        self.search_inclusive(*arg.start(), *arg.end()).await
     }
 }
 
 impl Search<u64> for Indexing {
     async fn search(&self, arg: u64) -> Result<BTreeSet<u64>, Error> {
-        let metadata = self.metadata.read().await;
-        let mut groups = Vec::new();
-
-        for (idx, i) in metadata.iter().enumerate() {
-            if i.1 >= arg && i.0 <= arg {
-                groups.push(idx);
-            }
-        }
-        groups.sort();
-
-        let indexes_file = self.indexes_file.read().await;
-        let mut offsets : BTreeSet<u64> = BTreeSet::new();
-        
-        for i in groups{
-            let mut buffer = [0u8;INDEX_CHUNK_SIZE as usize * 16];
-            indexes_file.read_exact_at(&mut buffer, i as u64*INDEX_CHUNK_SIZE*16)?;
-            for chunk in buffer.chunks_exact(16){
-                let index_value = u64::from_be_bytes(chunk[..8].try_into().unwrap());
-                let index_offset = u64::from_be_bytes(chunk[8..].try_into().unwrap());
-                if arg == index_value {
-                    offsets.insert(index_offset);
-                }
-
-            }
-        }
-        
-        Ok(offsets)
+        self.search_inclusive(arg, arg).await
     }
 }
 
 
+/// Index key of a value. Within one column type the keys of Int, Bigint, Float, Char and Bool
+/// never contradict `AlbaTypes::total_cmp`, so key ranges can answer range conditions.
+/// Strings and bytes are hashed and only support equality lookups.
 pub trait GetIndex{
     fn get_index(&self) -> u64;
 }
 
 impl GetIndex for i32{
     fn get_index(&self) -> u64{
-        *self as u64/INDEX_CHUNK_SIZE
+        (*self as i64).get_index()
     }
 }
 impl GetIndex for i64{
     fn get_index(&self) -> u64{
-        *self as u64/INDEX_CHUNK_SIZE
+        // flipping the sign bit keeps negative values ordered below the positive ones
+        ((*self as u64) ^ (1 << 63))/INDEX_CHUNK_SIZE
     }
 }
 impl GetIndex for i16{
@@ -363,7 +342,9 @@ impl GetIndex for f64{
         if self.is_nan(){
             return 0
         }
-        (self.abs() as u64) / INDEX_CHUNK_SIZE
+        let bits = (if *self == 0.0 { 0.0 } else { *self }).to_bits();
+        let ordered = if bits >> 63 == 1 { !bits } else { bits | (1 << 63) };
+        ordered / INDEX_CHUNK_SIZE
     }
 }
 impl GetIndex for bool{
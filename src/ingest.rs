@@ -0,0 +1,190 @@
+use std::io::Error;
+
+use crate::{alba_types::AlbaTypes, gerr, AstCreateRow};
+
+/*
+Binary columnar payload of POST /ingest, once decrypted. Every number is big endian.
+
+u16  length of the container name, then its UTF-8 bytes
+u16  number of columns
+u32  number of rows
+for every column:
+  u16  length of the column name, then its UTF-8 bytes
+  u8   type id of the values, as AlbaTypes::get_id (1 to 16)
+  the value of every row, one after another:
+    Char u32 code point | Int i32 | Bigint i64 | Bool u8 | Float f64
+    Text and strings    u32 length, then the UTF-8 bytes
+    bytes               u32 length, then the bytes
+
+The values are converted to the types of the columns like the values of CREATE ROW, and the
+columns left out take their empty value.
+*/
+
+struct Cursor<'a>{
+    bytes : &'a [u8],
+    position : usize,
+}
+
+impl<'a> Cursor<'a>{
+    fn take(&mut self, length : usize) -> Result<&'a [u8], Error>{
+        match self.bytes.get(self.position..self.position.saturating_add(length)){
+            Some(a) => {
+                self.position += length;
+                Ok(a)
+            },
+            None => Err(gerr(&format!("The ingest payload ends at byte {}, but a value goes on to byte {}", self.bytes.len(), self.position.saturating_add(length))))
+        }
+    }
+    fn array<const N : usize>(&mut self) -> Result<[u8; N], Error>{
+        let mut array = [0u8; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+    fn text(&mut self, length : usize) -> Result<String, Error>{
+        match String::from_utf8(self.take(length)?.to_vec()){
+            Ok(a) => Ok(a),
+            Err(e) => Err(gerr(&format!("Invalid UTF-8 in the ingest payload: {}", e)))
+        }
+    }
+    fn name(&mut self) -> Result<String, Error>{
+        let length = u16::from_be_bytes(self.array()?) as usize;
+        self.text(length)
+    }
+    fn value(&mut self, kind : &AlbaTypes) -> Result<AlbaTypes, Error>{
+        Ok(match kind{
+            AlbaTypes::Char(_) => match char::from_u32(u32::from_be_bytes(self.array()?)){
+                Some(c) => AlbaTypes::Char(c),
+                None => return Err(gerr("Invalid Char in the ingest payload"))
+            },
+            AlbaTypes::Int(_) => AlbaTypes::Int(i32::from_be_bytes(self.array()?)),
+            AlbaTypes::Bigint(_) => AlbaTypes::Bigint(i64::from_be_bytes(self.array()?)),
+            AlbaTypes::Bool(_) => AlbaTypes::Bool(self.array::<1>()?[0] != 0),
+            AlbaTypes::Float(_) => AlbaTypes::Float(f64::from_be_bytes(self.array()?)),
+            AlbaTypes::Text(_) | AlbaTypes::NanoString(_) | AlbaTypes::SmallString(_) | AlbaTypes::MediumString(_) |
+            AlbaTypes::BigString(_) | AlbaTypes::LargeString(_) => {
+                let length = u32::from_be_bytes(self.array()?) as usize;
+                let text = self.text(length)?;
+                match kind{
+                    AlbaTypes::Text(_) => AlbaTypes::Text(text),
+                    AlbaTypes::NanoString(_) => AlbaTypes::NanoString(text),
+                    AlbaTypes::SmallString(_) => AlbaTypes::SmallString(text),
+                    AlbaTypes::MediumString(_) => AlbaTypes::MediumString(text),
+                    AlbaTypes::BigString(_) => AlbaTypes::BigString(text),
+                    _ => AlbaTypes::LargeString(text),
+                }
+            },
+            AlbaTypes::NanoBytes(_) | AlbaTypes::SmallBytes(_) | AlbaTypes::MediumBytes(_) |
+            AlbaTypes::BigSBytes(_) | AlbaTypes::LargeBytes(_) => {
+                let length = u32::from_be_bytes(self.array()?) as usize;
+                let bytes = self.take(length)?.to_vec();
+                match kind{
+                    AlbaTypes::NanoBytes(_) => AlbaTypes::NanoBytes(bytes),
+                    AlbaTypes::SmallBytes(_) => AlbaTypes::SmallBytes(bytes),
+                    AlbaTypes::MediumBytes(_) => AlbaTypes::MediumBytes(bytes),
+                    AlbaTypes::BigSBytes(_) => AlbaTypes::BigSBytes(bytes),
+                    _ => AlbaTypes::LargeBytes(bytes),
+                }
+            },
+            AlbaTypes::NONE => return Err(gerr("The type of a column of the ingest payload can not be NONE"))
+        })
+    }
+}
+
+/// Decodes an ingest payload into the rows of a `CREATE ROW`, one group of values per row.
+pub fn decode_rows(payload : &[u8]) -> Result<AstCreateRow, Error>{
+    let mut cursor = Cursor { bytes: payload, position: 0 };
+    let container = cursor.name()?;
+    let column_count = u16::from_be_bytes(cursor.array()?) as usize;
+    let row_count = u32::from_be_bytes(cursor.array()?) as usize;
+    if column_count == 0{
+        return Err(gerr("The ingest payload has no columns"))
+    }
+    // every value takes at least a byte, so a forged count can not reserve more than the payload
+    let mut rows : Vec<Vec<AlbaTypes>> = Vec::with_capacity(row_count.min(payload.len()));
+    let mut col_nam = Vec::with_capacity(column_count);
+    for _ in 0..column_count{
+        let name = cursor.name()?;
+        if col_nam.contains(&name){
+            return Err(gerr(&format!("The column {} is repeated in the ingest payload", name)))
+        }
+        let kind = AlbaTypes::from_id(cursor.array::<1>()?[0])?;
+        for row in 0..row_count{
+            let value = cursor.value(&kind)?;
+            match rows.get_mut(row){
+                Some(values) => values.push(value),
+                None => rows.push(vec![value]),
+            }
+        }
+        col_nam.push(name);
+    }
+    if cursor.position != payload.len(){
+        return Err(gerr(&format!("The ingest payload has {} bytes left after its last column", payload.len() - cursor.position)))
+    }
+    Ok(AstCreateRow { col_nam, rows, container, returning: None })
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn name(payload : &mut Vec<u8>, name : &str){
+        payload.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        payload.extend_from_slice(name.as_bytes());
+    }
+
+    // container t, columns id INT [1, 2] and s TEXT ["a", "bc"]
+    fn payload() -> Vec<u8>{
+        let mut payload = Vec::new();
+        name(&mut payload, "t");
+        payload.extend_from_slice(&2u16.to_be_bytes());
+        payload.extend_from_slice(&2u32.to_be_bytes());
+        name(&mut payload, "id");
+        payload.push(AlbaTypes::Int(0).get_id());
+        payload.extend_from_slice(&1i32.to_be_bytes());
+        payload.extend_from_slice(&2i32.to_be_bytes());
+        name(&mut payload, "s");
+        payload.push(AlbaTypes::Text(String::new()).get_id());
+        for text in ["a", "bc"]{
+            payload.extend_from_slice(&(text.len() as u32).to_be_bytes());
+            payload.extend_from_slice(text.as_bytes());
+        }
+        payload
+    }
+
+    #[test]
+    fn decodes_the_columns_into_rows(){
+        let rows = decode_rows(&payload()).unwrap();
+        assert_eq!(rows.container, "t");
+        assert_eq!(rows.col_nam, vec!["id".to_string(), "s".to_string()]);
+        assert_eq!(rows.rows, vec![
+            vec![AlbaTypes::Int(1), AlbaTypes::Text("a".to_string())],
+            vec![AlbaTypes::Int(2), AlbaTypes::Text("bc".to_string())],
+        ]);
+    }
+
+    #[test]
+    fn rejects_a_truncated_payload(){
+        let payload = payload();
+        for length in 0..payload.len(){
+            assert!(decode_rows(&payload[..length]).is_err(), "decoded the first {} bytes", length);
+        }
+    }
+
+    #[test]
+    fn rejects_a_repeated_column(){
+        let mut payload = payload();
+        // renames the column s to id
+        let position = payload.windows(3).rposition(|w| w == [0, 1, b's']).unwrap();
+        payload.splice(position..position + 3, [0, 2, b'i', b'd']);
+        let error = decode_rows(&payload).unwrap_err();
+        assert!(error.to_string().contains("repeated"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_trailing_bytes(){
+        let mut payload = payload();
+        payload.push(0);
+        let error = decode_rows(&payload).unwrap_err();
+        assert!(error.to_string().contains("1 bytes left"), "{}", error);
+    }
+}
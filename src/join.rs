@@ -0,0 +1,247 @@
+use std::{collections::BTreeMap, io::Error};
+
+use crate::{aggregate::OrderedValue, alba_types::AlbaTypes, gerr, lexer_functions::Token, memory::{row_size, MemoryGrant}, projection::{Projection, ProjectionItem}, query::{PrimitivePredicate, PrimitiveQueryConditions}, spill::{SpillReader, SpillWriter}};
+
+/// Which container of a JOIN a column belongs to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JoinSide{
+    Left,
+    Right,
+}
+
+/// The containers of a JOIN and their headers. Columns are written `container.column`, or
+/// bare when only one of the two containers has a column of that name.
+pub struct JoinSources{
+    pub left : String,
+    pub right : String,
+    pub left_headers : Vec<(String, AlbaTypes)>,
+    pub right_headers : Vec<(String, AlbaTypes)>,
+}
+
+/// The WHERE of a JOIN cut at its top level ANDs. The terms naming the columns of a single
+/// container are checked while that container is read, with the column names left bare; the
+/// terms naming both need the joined row and keep the qualified names.
+pub struct JoinConditions{
+    pub left : PrimitiveQueryConditions,
+    pub right : PrimitiveQueryConditions,
+    pub joined : PrimitiveQueryConditions,
+}
+
+/// Headers of a joined row: the columns of the left container followed by those of the right one,
+/// named `container.column`.
+pub fn joined_headers(left : &str, left_headers : &[(String, AlbaTypes)], right : &str, right_headers : &[(String, AlbaTypes)]) -> Vec<(String, AlbaTypes)>{
+    let qualify = |container : &str, headers : &[(String, AlbaTypes)]| headers.iter().map(|(name, column_type)| (format!("{}.{}", container, name), column_type.clone())).collect::<Vec<_>>();
+    let mut headers = qualify(left, left_headers);
+    headers.extend(qualify(right, right_headers));
+    headers
+}
+
+impl JoinSources{
+    pub fn new(left : String, left_headers : Vec<(String, AlbaTypes)>, right : String, right_headers : Vec<(String, AlbaTypes)>) -> Result<Self, Error>{
+        if left == right{
+            return Err(gerr(&format!("A JOIN of the container {} with itself is not supported", left)))
+        }
+        Ok(JoinSources { left, right, left_headers, right_headers })
+    }
+    pub fn name(&self, side : JoinSide) -> &str{
+        match side{
+            JoinSide::Left => &self.left,
+            JoinSide::Right => &self.right,
+        }
+    }
+    pub fn headers(&self, side : JoinSide) -> &[(String, AlbaTypes)]{
+        match side{
+            JoinSide::Left => &self.left_headers,
+            JoinSide::Right => &self.right_headers,
+        }
+    }
+    pub fn joined_headers(&self) -> Vec<(String, AlbaTypes)>{
+        joined_headers(&self.left, &self.left_headers, &self.right, &self.right_headers)
+    }
+    /// The container a column belongs to and its name inside that container.
+    pub fn resolve(&self, column : &str) -> Result<(JoinSide, String), Error>{
+        for side in [JoinSide::Left, JoinSide::Right]{
+            if let Some(bare) = column.strip_prefix(self.name(side)).and_then(|rest| rest.strip_prefix('.'))
+                && self.headers(side).iter().any(|h| h.0 == bare){
+                return Ok((side, bare.to_string()))
+            }
+        }
+        let in_left = self.left_headers.iter().any(|h| h.0 == column);
+        let in_right = self.right_headers.iter().any(|h| h.0 == column);
+        match (in_left, in_right){
+            (true, false) => Ok((JoinSide::Left, column.to_string())),
+            (false, true) => Ok((JoinSide::Right, column.to_string())),
+            (true, true) => Err(gerr(&format!("The column {} is in both {} and {}, write it as {}.{} or {}.{}", column, self.left, self.right, self.left, column, self.right, column))),
+            (false, false) => Err(gerr(&format!("There is no column {} in the containers {} and {}", column, self.left, self.right))),
+        }
+    }
+    pub fn qualify(&self, column : &str) -> Result<String, Error>{
+        let (side, bare) = self.resolve(column)?;
+        Ok(format!("{}.{}", self.name(side), bare))
+    }
+    /// The columns compared by `ON`, as (left column, right column), whatever order they were written in.
+    pub fn on(&self, first : &str, second : &str) -> Result<(String, String), Error>{
+        match (self.resolve(first)?, self.resolve(second)?){
+            ((JoinSide::Left, left), (JoinSide::Right, right)) | ((JoinSide::Right, right), (JoinSide::Left, left)) => Ok((left, right)),
+            _ => Err(gerr(&format!("The ON of a JOIN compares a column of {} with a column of {}, but found {} = {}", self.left, self.right, first, second))),
+        }
+    }
+    /// The column list with every column qualified, so it resolves against `joined_headers`.
+    pub fn projection(&self, projection : &Projection) -> Result<Projection, Error>{
+        let mut items = Vec::with_capacity(projection.items.len());
+        for item in projection.items.iter(){
+            items.push(match item{
+                ProjectionItem::Column { name, alias } => ProjectionItem::Column { name: self.qualify(name)?, alias: alias.clone() },
+                ProjectionItem::Expression { expression, alias } => ProjectionItem::Expression { expression: expression.clone().rename(&|column| self.qualify(column))?, alias: alias.clone() },
+                other => other.clone(),
+            });
+        }
+        Ok(Projection { items })
+    }
+    pub fn split_conditions(&self, conditions : PrimitiveQueryConditions) -> Result<JoinConditions, Error>{
+        let terms = match conditions{
+            None => Vec::new(),
+            Some(PrimitivePredicate::And(terms)) => terms,
+            Some(term) => vec![term],
+        };
+        let (mut left, mut right, mut joined) = (Vec::new(), Vec::new(), Vec::new());
+        for term in terms{
+            let mut sides = Vec::new();
+            for column in columns(&term){
+                let side = self.resolve(column)?.0;
+                if !sides.contains(&side){
+                    sides.push(side);
+                }
+            }
+            match sides.as_slice(){
+                [JoinSide::Right] => right.push(rename(term, &|column| Ok(self.resolve(column)?.1))?),
+                [_, _] => joined.push(rename(term, &|column| self.qualify(column))?),
+                _ => left.push(rename(term, &|column| Ok(self.resolve(column)?.1))?),
+            }
+        }
+        Ok(JoinConditions { left: conjunction(left), right: conjunction(right), joined: conjunction(joined) })
+    }
+}
+
+/// A right row of a hash join: its address and its values.
+type BuildRow = (u64, Vec<AlbaTypes>);
+
+/// The table of a hash join, the right rows by the value of their join column. The rows that
+/// do not fit in the memory grant go to a temporary file, and once the left rows were probed
+/// against the table, `refill` loads the next part of them in place of the rows already joined.
+pub struct JoinTable{
+    rows : BTreeMap<OrderedValue, Vec<BuildRow>>,
+    position : usize,
+    grant : MemoryGrant,
+    directory : String,
+    overflow : Option<SpillWriter<BuildRow>>,
+    spilled : Option<SpillReader<BuildRow>>,
+    // the row read last that did not fit in the part before
+    held_back : Option<BuildRow>,
+    spilled_bytes : u64,
+}
+
+impl JoinTable{
+    /// A table keyed by the values at `position` of the right rows.
+    pub fn new(position : usize, grant : MemoryGrant, directory : String) -> Self{
+        JoinTable { rows: BTreeMap::new(), position, grant, directory, overflow: None, spilled: None, held_back: None, spilled_bytes: 0 }
+    }
+    /// Adds a right row. Rows with a NULL join value never match and are left out.
+    pub fn insert(&mut self, address : u64, row : Vec<AlbaTypes>) -> Result<(), Error>{
+        if matches!(row[self.position], AlbaTypes::NONE){
+            return Ok(())
+        }
+        if self.overflow.is_none() && self.grant.grow(row_size(&row)){
+            self.rows.entry(OrderedValue(row[self.position].clone())).or_default().push((address, row));
+            return Ok(())
+        }
+        let overflow = match &mut self.overflow{
+            Some(overflow) => overflow,
+            None => self.overflow.insert(SpillWriter::create(&self.directory, "join")?)
+        };
+        overflow.write(&(address, row))
+    }
+    pub fn get(&self, key : &AlbaTypes) -> Option<&Vec<BuildRow>>{
+        self.rows.get(&OrderedValue(key.clone()))
+    }
+    /// Replaces the rows in memory with the next part of the ones written to disk, returns false
+    /// once every part was loaded.
+    pub fn refill(&mut self) -> Result<bool, Error>{
+        if let Some(overflow) = self.overflow.take(){
+            self.spilled_bytes = overflow.bytes();
+            self.spilled = Some(overflow.finish()?);
+        }
+        let Some(spilled) = &mut self.spilled else { return Ok(false) };
+        self.rows.clear();
+        self.grant.release();
+        loop{
+            let row = match self.held_back.take(){
+                Some(row) => row,
+                None => match spilled.next()?{
+                    Some(row) => row,
+                    None => break
+                }
+            };
+            let size = row_size(&row.1);
+            if !self.grant.grow(size){
+                // a part holds one row at least, however little memory is left
+                if !self.rows.is_empty(){
+                    self.held_back = Some(row);
+                    break;
+                }
+                self.grant.force(size);
+            }
+            self.rows.entry(OrderedValue(row.1[self.position].clone())).or_default().push(row);
+        }
+        Ok(!self.rows.is_empty())
+    }
+    /// Bytes of the right rows written to disk.
+    pub fn spilled_bytes(&self) -> u64{
+        self.overflow.as_ref().map_or(self.spilled_bytes, |overflow| overflow.bytes())
+    }
+}
+
+pub fn conjunction(mut terms : Vec<PrimitivePredicate>) -> PrimitiveQueryConditions{
+    match terms.len(){
+        0 => None,
+        1 => terms.pop(),
+        _ => Some(PrimitivePredicate::And(terms)),
+    }
+}
+
+/// Every column a condition reads.
+pub fn columns(predicate : &PrimitivePredicate) -> Vec<&str>{
+    match predicate{
+        PrimitivePredicate::Comparison(column, _, _) | PrimitivePredicate::In(column, _) |
+        PrimitivePredicate::Between(column, _, _) | PrimitivePredicate::IsNull(column) |
+        PrimitivePredicate::InSearch(column, _) | PrimitivePredicate::InValues(column, _) => match column{
+            Token::String(name) => vec![name.as_str()],
+            _ => Vec::new(),
+        },
+        PrimitivePredicate::Compare(left, _, right) => left.columns().into_iter().chain(right.columns()).collect(),
+        PrimitivePredicate::Exists(_) | PrimitivePredicate::Constant(_) => Vec::new(),
+        PrimitivePredicate::Not(inner) => columns(inner),
+        PrimitivePredicate::And(terms) | PrimitivePredicate::Or(terms) => terms.iter().flat_map(columns).collect(),
+    }
+}
+
+/// The condition with every column renamed by `name`.
+pub fn rename(predicate : PrimitivePredicate, name : &dyn Fn(&str) -> Result<String, Error>) -> Result<PrimitivePredicate, Error>{
+    let column = |token : Token| -> Result<Token, Error> { match token{
+        Token::String(column) => Ok(Token::String(name(&column)?)),
+        other => Ok(other),
+    }};
+    Ok(match predicate{
+        PrimitivePredicate::Comparison(c, operator, value) => PrimitivePredicate::Comparison(column(c)?, operator, value),
+        PrimitivePredicate::Compare(left, operator, right) => PrimitivePredicate::Compare(left.rename(name)?, operator, right.rename(name)?),
+        PrimitivePredicate::In(c, values) => PrimitivePredicate::In(column(c)?, values),
+        PrimitivePredicate::Between(c, low, high) => PrimitivePredicate::Between(column(c)?, low, high),
+        PrimitivePredicate::IsNull(c) => PrimitivePredicate::IsNull(column(c)?),
+        PrimitivePredicate::InSearch(c, command) => PrimitivePredicate::InSearch(column(c)?, command),
+        PrimitivePredicate::InValues(c, values) => PrimitivePredicate::InValues(column(c)?, values),
+        other @ (PrimitivePredicate::Exists(_) | PrimitivePredicate::Constant(_)) => other,
+        PrimitivePredicate::Not(inner) => PrimitivePredicate::Not(Box::new(rename(*inner, name)?)),
+        PrimitivePredicate::And(terms) => PrimitivePredicate::And(terms.into_iter().map(|t| rename(t, name)).collect::<Result<_, Error>>()?),
+        PrimitivePredicate::Or(terms) => PrimitivePredicate::Or(terms.into_iter().map(|t| rename(t, name)).collect::<Result<_, Error>>()?),
+    })
+}
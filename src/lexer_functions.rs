@@ -1,20 +1,26 @@
 use lazy_static::lazy_static;
 use std::io::Error;
 use base64::{alphabet, engine::{self, general_purpose}, Engine as _};
+use serde::{Deserialize, Serialize};
 
 use crate::lexer;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Token{
     Keyword(String),
     String(String),
+    /// A bare word, like `balance` or `users.id`: a column in an expression, a name anywhere else.
+    Identifier(String),
     Bytes(Vec<u8>),
     Int(i64),
     Float(f64),
     Bool(bool),
     Operator(String),
     Group(Vec<Token>),
+    /// An element of a group made of more than one token, like `'name' AS 'n'`.
+    Sequence(Vec<Token>),
     SubCommand(Vec<Token>),
+    Separator,
     Argument,
 }
 
@@ -22,12 +28,18 @@ const KEYWORDS: &[&str] = &[
     "CREATE",
     "COMMIT",
     "ROLLBACK",
+    "SAVEPOINT",
+    "RELEASE",
+    "TO",
     "DELETE",
     "EDIT",
     "SEARCH",
     "WHERE",
     "ROW",
     "CONTAINER",
+    "INDEX",
+    "ANALYZE",
+    "EXPLAIN",
     "ON",
     "USING",
     "INT",
@@ -37,6 +49,53 @@ const KEYWORDS: &[&str] = &[
     "FLOAT",
     "AND",
     "OR",
+    "NOT",
+    "IN",
+    "BETWEEN",
+    "IS",
+    "NULL",
+    "LIKE",
+    "STARTS",
+    "ENDS",
+    "WITH",
+    "ORDER",
+    "BY",
+    "ASC",
+    "DESC",
+    "AS",
+    "GROUP",
+    "HAVING",
+    "COUNT",
+    "SUM",
+    "AVG",
+    "MIN",
+    "MAX",
+    "APPROX_COUNT_DISTINCT",
+    "DISTINCT",
+    "LIMIT",
+    "OFFSET",
+    "PAGE",
+    "SIZE",
+    "JOIN",
+    "UNION",
+    "ALL",
+    "INTERSECT",
+    "EXCEPT",
+    "EXISTS",
+    "UPSERT",
+    "CONFLICT",
+    "DO",
+    "NOTHING",
+    "UPDATE",
+    "FOR",
+    "STATELESS",
+    "CONTINUE",
+    "RETURNING",
+    "CASE",
+    "WHEN",
+    "THEN",
+    "ELSE",
+    "END",
     "NANO-STRING",
     "SMALL-STRING",
     "MEDIUM-STRING",
@@ -51,10 +110,15 @@ const KEYWORDS: &[&str] = &[
     // weird looking because connection handlers that should use this, not users
     "QYCNPVS", // query control previous 
     "QYCNNXT", // query control next
-    "QYCNEXT" // query control exit
+    "QYCNEXT", // query control exit
+    "QYCNGTO" // query control goto
 ];
 
-pub fn lexer_keyword_match(result: &mut Vec<Token>, dough: &mut String) -> bool {
+pub fn lexer_keyword_match(result: &mut Vec<Token>, dough: &mut String, next: Option<&char>) -> bool {
+    // a keyword followed by more of a word is the prefix of something else, "IN" of "INT" or "INDEX"
+    if next.is_some_and(|c| c.is_alphanumeric() || *c == '-' || *c == '_'){
+        return false
+    }
     let keyword = dough.to_uppercase(); 
 
     if KEYWORDS.contains(&keyword.as_str()) {
@@ -65,6 +129,29 @@ pub fn lexer_keyword_match(result: &mut Vec<Token>, dough: &mut String) -> bool
     false
 }
 
+/// Whether `word` can be written bare: a letter or `_` followed by letters, digits, `_` and `.`.
+fn is_identifier(word: &str) -> bool {
+    let mut characters = word.chars();
+    characters.next().is_some_and(|c| c.is_alphabetic() || c == '_') && characters.all(|c| c.is_alphanumeric() || c == '_' || c == '.')
+}
+
+pub fn lexer_identifier_match(result: &mut Vec<Token>, dough: &mut String, next: Option<&char>) -> bool {
+    if next.is_some_and(|c| c.is_alphanumeric() || *c == '_' || *c == '.'){
+        return false
+    }
+    let word = dough.trim_start();
+    if !is_identifier(word){
+        return false
+    }
+    // "SMALL" of "SMALL-STRING" is the start of a keyword, not a word of its own
+    if next == Some(&'-') && KEYWORDS.iter().any(|k| k.starts_with(&format!("{}-", word.to_uppercase()))){
+        return false
+    }
+    result.push(Token::Identifier(word.to_string()));
+    dough.clear();
+    true
+}
+
 pub fn lexer_string_match<T:Iterator<Item = char>>(result : &mut Vec<Token>,dough : &mut String, itr : &mut T) -> bool{
     if dough.starts_with(' '){
         dough.drain(..1);
@@ -170,9 +257,12 @@ pub fn lexer_group_match<T: Iterator<Item = char>>(
                     continue;
                 }
                 match lexer(part.to_string()) {
-                    Ok(mut toks) if !toks.is_empty() => {
+                    Ok(mut toks) if toks.len() == 1 => {
                         abstract_tokens.push(toks.remove(0));
                     }
+                    Ok(toks) if !toks.is_empty() => {
+                        abstract_tokens.push(Token::Sequence(toks));
+                    }
                     _ => {
                         continue;
                     }
@@ -195,6 +285,7 @@ pub fn lexer_subcommand_match<T: Iterator<Item = char>>(
     if dough.starts_with('(') {
         let mut in_string : bool = false;
         let mut string_sort : char = '\\';
+        let mut i = 1;
         while let Some(c) = itr.next() {
             dough.push(c);
             if (c == '\'' || c == '"') && !in_string{
@@ -206,8 +297,14 @@ pub fn lexer_subcommand_match<T: Iterator<Item = char>>(
                 in_string = false;
                 continue;
             }
+            if c == '(' && !in_string{
+                i += 1;
+            }
             if c == ')' && !in_string {
-                break;
+                i -= 1;
+                if i == 0{
+                    break;
+                }
             }
         }
         ////println!("{}",dough);
@@ -227,46 +324,38 @@ const RADIX : u32= 10;
 pub fn lexer_number_match<T:Iterator<Item = char>>(result : &mut Vec<Token>,dough : &mut String, itr : &mut std::iter::Peekable<T>) -> bool{
     if let Some(d) = dough.chars().nth(0){
         let mut had_dot = false;
-        let mut negative = false;
         if d.is_digit(RADIX) || d == '-'{
-            if d == '-'{
-                negative = true;
-            }
             let mut cn : u8 = 0;
-            while let Some(n) = itr.next(){
+            // peek so the character that ends the number (a comma, a parenthesis...) is left for the next token
+            while let Some(&n) = itr.peek(){
                 if n.is_digit(RADIX){
-                    dough.push(n.clone());
+                    dough.push(n);
+                }else if n == '.' && !had_dot{
+                    dough.push(n);
+                    had_dot = true;
+                }else if n == 'e' && cn == 0{
+                    cn = 1;
+                    dough.push(n);
+                }else if (n == '-' || n == '+') && cn == 1{
+                    cn = 2;
+                    dough.push(n);
                 }else{
-                    if n == '.' && !had_dot{
-                        dough.push(n.clone());
-                        had_dot = true;
-                        continue;
-                    }
-                    if n == 'e' && cn == 0{
-                        cn = 1;
-                        dough.push(n.clone());
-                        continue
-                    }
-                    if (n == '-' || n == '+') && cn == 1{
-                        cn = 2;
-                        dough.push(n.clone());
-                        continue;
-                    }
                     break;
                 }
+                itr.next();
             }
             if cn == 2 && (dough.ends_with("e+")||dough.ends_with("e-")){
                 return false
             }
             if had_dot{
                 if let Ok(float) = dough.parse::<f64>(){
-                    result.push(Token::Float(if negative {float*-1.0}else{float}));
+                    result.push(Token::Float(float));
                     dough.clear();
                     return true
                 }
             }else{
                 if let Ok(int) = dough.parse::<i64>(){
-                    result.push(Token::Int(if negative {int*-1}else{int}));
+                    result.push(Token::Int(int));
                     dough.clear();
                     return true
                 }
@@ -276,7 +365,8 @@ pub fn lexer_number_match<T:Iterator<Item = char>>(result : &mut Vec<Token>,doug
     false
 }  
 pub fn lexer_ignore_comments_match<T:Iterator<Item = char>>(dough : &mut String, itr : &mut std::iter::Peekable<T>) -> bool{
-    if dough.starts_with('/'){
+    // a lone / is the division operator
+    if dough.starts_with('/') && itr.peek() == Some(&'*'){
         while let Some(c) = itr.next(){
             dough.push(c);
             let n = dough.len();
@@ -374,6 +464,18 @@ pub fn lexer_operator_match<T: Iterator<Item = char>>(
         '-' => "-",
         '/' => "/",
         '%' => "%",
+        '|' => {
+            if let Some(&next_char) = itr.peek() {
+                if next_char == '|' {
+                    itr.next();
+                    "||"
+                } else {
+                    "|"
+                }
+            } else {
+                "|"
+            }
+        },
         '!' => {
             if let Some(&next_char) = itr.peek() {
                 if next_char == '=' {
@@ -0,0 +1,116 @@
+use std::{fmt, io::{Error, ErrorKind}, sync::{Mutex, MutexGuard}, time::Duration};
+
+use ahash::AHashMap;
+use tokio::sync::Notify;
+
+use crate::gerr;
+
+/// The id of the session a command runs for, the one its connection was given.
+pub type Session = [u8; 32];
+
+/// The session of the commands run directly, outside of a connection.
+pub const LOCAL_SESSION : Session = [0u8; 32];
+
+/// Row locks of the sessions. EDIT, DELETE, the updates of UPSERT and `SEARCH ... FOR UPDATE`
+/// lock the rows they match, and the session keeps them until it commits or rolls back, so
+/// another session writing the same rows waits for it instead of overwriting its changes.
+#[derive(Debug, Default)]
+pub struct RowLocks{
+    table : Mutex<LockTable>,
+    released : Notify,
+}
+
+#[derive(Debug, Default)]
+struct LockTable{
+    held : AHashMap<(String, u64), Session>,
+    /// The session every waiting session waits for.
+    waiting : AHashMap<Session, Session>,
+}
+
+/// A row locked by another session, carried by the error of the command that needed it.
+#[derive(Debug)]
+pub struct LockConflict{
+    pub holder : Session,
+    pub container : String,
+    pub address : u64,
+}
+
+impl fmt::Display for LockConflict{
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result{
+        write!(f, "The row at address {} of {} is locked by another session", self.address, self.container)
+    }
+}
+
+impl std::error::Error for LockConflict{}
+
+impl LockConflict{
+    /// The conflict behind `error`, when it is one.
+    pub fn of(error : &Error) -> Option<&LockConflict>{
+        error.get_ref().and_then(|e| e.downcast_ref::<LockConflict>())
+    }
+}
+
+impl RowLocks{
+    fn table(&self) -> MutexGuard<'_, LockTable>{
+        self.table.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    /// Locks the rows at `addresses` of `container` for `session`. When another session holds one
+    /// of them none is locked, and the error is a `LockConflict`.
+    pub fn acquire(&self, session : &Session, container : &str, addresses : &[u64]) -> Result<(), Error>{
+        self.acquire_rows(session, addresses.iter().map(|address| (container, *address)))
+    }
+    /// Locks rows of any container, all of them or none.
+    pub fn acquire_rows<'a>(&self, session : &Session, rows : impl Iterator<Item = (&'a str, u64)> + Clone) -> Result<(), Error>{
+        let mut table = self.table();
+        for (container, address) in rows.clone(){
+            if let Some(holder) = table.held.get(&(container.to_string(), address)) && holder != session{
+                return Err(Error::new(ErrorKind::WouldBlock, LockConflict { holder: *holder, container: container.to_string(), address }))
+            }
+        }
+        for (container, address) in rows{
+            table.held.insert((container.to_string(), address), *session);
+        }
+        Ok(())
+    }
+    /// Releases the locks of `session` on `container`, or on every container.
+    pub fn release(&self, session : &Session, container : Option<&str>){
+        let mut table = self.table();
+        let before = table.held.len();
+        table.held.retain(|(name, _), holder| holder != session || container.is_some_and(|c| c != name));
+        if table.held.len() != before{
+            self.released.notify_waiters();
+        }
+    }
+    /// Drops every lock on `container`, once it is deleted.
+    pub fn forget(&self, container : &str){
+        self.table().held.retain(|(name, _), _| name != container);
+        self.released.notify_waiters();
+    }
+    /// Waits until `holder` releases some of its locks, for `timeout` at most. Waiting for a session
+    /// that waits, directly or through others, for `session` would never end, so it fails at once.
+    pub async fn wait(&self, session : &Session, holder : &Session, timeout : Duration) -> Result<(), Error>{
+        let released = self.released.notified();
+        tokio::pin!(released);
+        released.as_mut().enable();
+        {
+            let mut table = self.table();
+            if !table.held.values().any(|s| s == holder){
+                return Ok(())
+            }
+            let mut next = holder;
+            while let Some(waited) = table.waiting.get(next){
+                if waited == session{
+                    return Err(gerr("Deadlock detected: the session holding the row waits for a row locked by this session, roll back and try again"))
+                }
+                next = waited;
+            }
+            table.waiting.insert(*session, *holder);
+        }
+        let waited = tokio::time::timeout(timeout, released).await;
+        self.table().waiting.remove(session);
+        match waited{
+            Ok(()) => Ok(()),
+            Err(_) => Err(Error::new(ErrorKind::TimedOut, "Lock wait timeout exceeded, the row is still locked by another session"))
+        }
+    }
+}
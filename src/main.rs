@@ -8,12 +8,33 @@ mod query;
 mod indexing;
 mod alba_types;
 mod query_conditions;
+mod statistics;
+mod planner;
+mod explain;
+mod sorting;
+mod projection;
+mod aggregate;
+mod join;
+mod set_operation;
+mod subquery;
+mod expression;
+mod ingest;
+mod savepoint;
+mod locking;
+mod cursor;
+mod continuation;
+mod memory;
+mod spill;
 use std::io::{Error,ErrorKind};
 use alba_types::AlbaTypes;
 use tokio;
 use database::connect;
+use query::{Keyset, PrimitiveQueryConditions, QueryWindow};
+use sorting::SortKey;
+use projection::Projection;
+use expression::Expression;
 use lexer_functions::{
-    lexer_boolean_match, lexer_bytes_match, lexer_group_match, lexer_ignore_comments_match, lexer_keyword_match, lexer_number_match, lexer_operator_match, lexer_string_match, lexer_subcommand_match, Token
+    lexer_boolean_match, lexer_bytes_match, lexer_group_match, lexer_identifier_match, lexer_ignore_comments_match, lexer_keyword_match, lexer_number_match, lexer_operator_match, lexer_string_match, lexer_subcommand_match, Token
 };
 pub mod better_logs;
 
@@ -31,10 +52,15 @@ fn lexer(input: String) -> Result<Vec<Token>, Error> {
             result.push(Token::Argument);
             continue;
         }
+        if c == ',' && dough.trim().is_empty(){
+            dough.clear();
+            result.push(Token::Separator);
+            continue;
+        }
         dough.push(c);
 
         lexer_ignore_comments_match(&mut dough, &mut characters);
-        lexer_keyword_match(&mut result, &mut dough);
+        lexer_keyword_match(&mut result, &mut dough, characters.peek());
         lexer_subcommand_match(&mut result, &mut dough, &mut characters)?;
         lexer_group_match(&mut result, &mut dough, &mut characters);
         lexer_boolean_match(&mut result, &mut dough, &mut characters);
@@ -42,10 +68,11 @@ fn lexer(input: String) -> Result<Vec<Token>, Error> {
         lexer_operator_match(&mut result, &mut dough, &mut characters);
         lexer_string_match(&mut result, &mut dough, &mut characters);
         lexer_bytes_match(&mut result, &mut dough, &mut characters);
+        lexer_identifier_match(&mut result, &mut dough, characters.peek());
     }
 
     if !dough.trim().is_empty() {
-        lexer_keyword_match(&mut result, &mut dough);
+        lexer_keyword_match(&mut result, &mut dough, None);
         lexer_subcommand_match(&mut result, &mut dough, &mut characters)?;
         lexer_group_match(&mut result, &mut dough, &mut characters);
         lexer_boolean_match(&mut result, &mut dough, &mut characters);
@@ -53,6 +80,7 @@ fn lexer(input: String) -> Result<Vec<Token>, Error> {
         lexer_number_match(&mut result, &mut dough, &mut characters);
         lexer_string_match(&mut result, &mut dough, &mut characters);
         lexer_bytes_match(&mut result, &mut dough, &mut characters);
+        lexer_identifier_match(&mut result, &mut dough, None);
     }
 
     if !dough.trim().is_empty() {
@@ -73,33 +101,181 @@ fn lexer(input: String) -> Result<Vec<Token>, Error> {
 - CREATE <Instance> ...
 | CREATE CONTAINER <name> [col_nam][col_typ] 
 | CREATE ROW [col_nam][col_val] ON <container:name>
+| CREATE ROW [col_nam][col_val], [col_val], ... ON <container:name>
+  (every group of values is a row, created together; the commas are optional. Large batches
+   can also be sent as a binary columnar payload to the /ingest endpoint, see ingest.rs)
+| CREATE ROW ... ON <container:name> RETURNING <columns>
+| CREATE INDEX [col_nam] ON <container:name>
+
+- UPSERT <Instance> ...
+| UPSERT ROW [col_nam][col_val] ON <container:name>
+| UPSERT ROW [col_nam][col_val], [col_val], ... ON <container:name>
+| UPSERT ROW [col_nam][col_val] ON <container:name> ON CONFLICT DO NOTHING
+| UPSERT ROW [col_nam][col_val] ON <container:name> ON CONFLICT DO UPDATE [col_nam, ...]
+  (creates the row unless a row already holds its value of the primary column, the first one,
+   in which case that row takes every given value, keeps its values, or takes only the values
+   of the listed columns)
 
 - EDIT <Instance> ...
 | EDIT ROW [col_name][col_val] ON <container:name> WHERE <conditions>
+  (every <col_val> is an <expression> computed from the row it replaces, like balance - ?)
+| EDIT ROW ... RETURNING <columns>
 
 - DELETE <instance> ...
 | DELETE ROW ON <container> WHERE <conditions>
 | DELETE ROW ON <container>
+| DELETE ROW ... RETURNING <columns>
 | DELETE CONTAINER <container>
 
-- SEARCH <col_nam> ON <container> ... 
-| SEARCH <col_nam> ON <container>
-| SEARCH <col_nam> ON <container> WHERE <conditions>
+- RETURNING <columns> ends a CREATE ROW, EDIT ROW or DELETE ROW. The affected rows come back as
+  the pages of a search, at the addresses they were written at, with the new values of an edit
+  and the last values of a deletion; <columns> are written like those of SEARCH, without
+  aggregates. Every write answers with the count of the rows it created, changed or deleted.
+
+- SEARCH <columns> ON <container> ... 
+| SEARCH <columns> ON <container>
+| SEARCH <columns> ON <container> WHERE <conditions>
+| SEARCH <columns> ON <container> [WHERE <conditions>] ORDER BY <col_nam> [ASC|DESC], ...
+| SEARCH <columns> ON <container> [WHERE <conditions>] [ORDER BY ...] [LIMIT <int>] [OFFSET <int>] [PAGE SIZE <int>]
+| SEARCH <columns> ON <container> [WHERE <conditions>] [GROUP BY <col_nam>, ...] [HAVING <conditions>] [ORDER BY ...] [LIMIT ...] [OFFSET ...]
+| SEARCH <columns> ON [<container>] JOIN <container> ON <container.col_nam> = <container.col_nam> [WHERE <conditions>] [LIMIT ...] [OFFSET ...] [PAGE SIZE ...]
+  (the columns of a JOIN are named <container.col_nam>, a bare <col_nam> works when only one
+   of the containers has it, and the result names every column <container.col_nam>)
+| SEARCH DISTINCT <columns> ON <container> ...
+  (rows with equal values in every column are returned once, before LIMIT and OFFSET apply)
+| SEARCH <columns> ON <container> ... FOR UPDATE
+  (the rows returned are locked for the session until it commits or rolls back, like the rows
+   matched by EDIT, DELETE and the updates of UPSERT. A command needing a row another session
+   locked waits for it, and fails when lock_timeout_ms of the settings pass or when the wait
+   would be a deadlock. A session running no command for idle_transaction_timeout_secs of the
+   settings is rolled back and its locks released. FOR UPDATE can not lock the groups of an
+   aggregate search, a JOIN or DISTINCT)
+| SEARCH <columns> ON <container> ... STATELESS
+| CONTINUE <token>
+  (no cursor is kept for a STATELESS search: it answers with its first page and, while rows
+   follow, a continuation token carrying the search and the sort keys and address of its last
+   row, signed with the key in .tytodb-continuation-key of the database. CONTINUE reads the page
+   after that row, with any server process sharing the key file, and the rows created or
+   deleted meanwhile are seen or left out as they fall before or after it. STATELESS searches
+   a single container, without aggregates, JOIN, DISTINCT or FOR UPDATE, and is sent on its own)
+
+- A command sent to the /stream endpoint instead of the root one is answered with a chunked body
+  holding every page of its result, one encrypted frame of PAGE SIZE rows at a time, instead of a
+  cursor to move through. The pages are read as the client takes the frames, a few ahead of it.
+  An unordered SEARCH without JOIN, DISTINCT, aggregates or subquery sources is read straight from
+  its scan, page by page, so the rows it finds are never all held at once.
+
+- <SEARCH command> UNION [ALL] | INTERSECT | EXCEPT <SEARCH command> ...
+  (INTERSECT binds tighter than UNION and EXCEPT, which apply from left to right; the operands
+   need the same number of columns, the result is named after the first one, and every clause
+   belongs to its own operand. UNION, INTERSECT and EXCEPT drop rows with equal values, UNION ALL
+   keeps them)
+
+- <columns> ...
+| [*]
+| [<col_nam>, <col_nam> AS <alias>, *, ...]
+| [<col_nam>, <aggregate> [AS <alias>], ...]      (every <col_nam> must be in GROUP BY)
+| [<col_nam>, <expression> [AS <alias>], ...]     (not mixed with aggregates, an expression
+                                                   without alias is named as written)
+
+- <expression> ...
+| <col_nam> | <value> | ? | NULL
+| <expression> + | - | * | / | % <expression>      (* / % bind tighter than + -)
+| <expression> || <expression>                    (text concatenation)
+| -<expression> | (<expression>)
+| UPPER(<expression>) | LOWER(<expression>) | LENGTH(<expression>)
+| SUBSTR(<expression>, <start>[, <length>])      (the first character is 1)
+| COALESCE(<expression>, <expression>, ...)
+| CASE WHEN <conditions> THEN <expression> ... [ELSE <expression>] END
+  (a column name can be written bare or quoted, a name that is also a keyword must be quoted;
+   an argument is always a value, never a column)
+
+- <aggregate> ...
+| COUNT(*) | COUNT(<col_nam>) | COUNT(DISTINCT <col_nam>)
+| SUM(<col_nam>) | AVG(<col_nam>) | MIN(<col_nam>) | MAX(<col_nam>)
+| APPROX_COUNT_DISTINCT(<col_nam>)   (HyperLogLog estimate in fixed memory, about 1.6% off)
+  (HAVING and ORDER BY of an aggregate search name the output columns, an aggregate
+   without alias is named as written, like COUNT(*) or SUM(age))
+
+- <conditions> ...
+| <col_nam> <operator> <value>
+| <expression> <operator> <expression>   (like qty * 2 > cost or UPPER(name) = ?)
+| <col_nam> [NOT] IN (<value>, <value>, ...)
+| <col_nam> [NOT] IN (<SEARCH command>)   (the SEARCH returns one column, its NULLs are left out)
+| [NOT] EXISTS (<SEARCH command>)
+  (the subqueries run once, before the command is planned. An EXISTS refers to the outer row with
+   <col_nam> = <container.col_nam> joined by AND to the rest of its WHERE, and becomes an IN over
+   that column, so either form can use the index of the outer column)
+| <col_nam> [NOT] BETWEEN <value> AND <value>
+| <col_nam> IS [NOT] NULL
+| <col_nam> [NOT] LIKE <pattern>          (% any run, _ one character)
+| <col_nam> STARTS WITH <value>
+| <col_nam> ENDS WITH <value>
+| NOT <conditions>
+| (<conditions>)
+| <conditions> AND <conditions>   (AND binds tighter than OR)
+| <conditions> OR <conditions>
+
+- COMMIT [<container>] | ROLLBACK [<container>]
+  (until they are committed, the rows a session created, edited and deleted are seen as it left
+   them by its own SEARCH, EDIT ROW, DELETE ROW and UPSERT ROW, the index and the scan alike,
+   and other sessions see the committed rows. COMMIT and ROLLBACK only write or drop the changes
+   of the session running them; ANALYZE and CREATE INDEX read the committed rows only)
+
+- _version
+  (every container gets a last BIGINT column _version, the count of the commits that wrote the
+   row. It is no column of the schema, left out of [*] and of max_columns, but can be searched
+   and compared like any column, and only COMMIT writes it. The rows of a container from before
+   it are given version 0 when the container is loaded. EDIT ROW ... WHERE ... AND _version = ? and DELETE ROW ... WHERE ... AND _version = ?
+   match their rows without it and expect the rows to still be at that version when they are
+   committed: a COMMIT finding any row it writes at another version than the one its change was
+   based on, the read one by default, fails with a version conflict, commits nothing and drops
+   the pending changes)
+
+- SAVEPOINT <name>
+| ROLLBACK TO [SAVEPOINT] <name>
+| RELEASE [SAVEPOINT] <name>
+  (ROLLBACK TO undoes the pending changes made since the savepoint, which stays set, and the
+   Text contents they added are never written; RELEASE drops the savepoint and the later ones
+   and keeps their changes. COMMIT and ROLLBACK drop every savepoint, and a container committed
+   or rolled back on its own drops the savepoints it had)
+
+- ANALYZE <container>
+
+- EXPLAIN <SEARCH|EDIT|DELETE command>
+| EXPLAIN ANALYZE <SEARCH|EDIT|DELETE command>
+
+- QYCNNXT <query id> | QYCNPVS <query id> | QYCNGTO <query id> <page> | QYCNEXT <query id>
+  (sent by the connection handlers to move through the pages of a search: the next, the
+   previous or the given one, counted from 0, and to close it. Every result carries page_count
+   and row_count. A search stays open for the session that ran it until cursor_ttl_secs of the
+   settings pass without a move, and a session opening more than max_cursors_per_session
+   searches closes its least recently used one)
 
 */
 #[derive(Debug, Clone, PartialEq)]
 enum AST{
     CreateContainer(AstCreateContainer),
     CreateRow(AstCreateRow),
+    UpsertRow(AstUpsertRow),
+    CreateIndex(AstCreateIndex),
     EditRow(AstEditRow),
     DeleteRow(AstDeleteRow),
     DeleteContainer(AstDeleteContainer),
-    Search(AstSearch),
+    Search(Box<AstSearch>),
+    SetOperation(AstSetOperation),
     Commit(AstCommit),
     Rollback(AstRollback),
+    Savepoint(AstSavepoint),
+    RollbackToSavepoint(AstSavepoint),
+    ReleaseSavepoint(AstSavepoint),
+    Analyze(AstAnalyze),
+    Explain(AstExplain),
     QueryControlNext(AstQueryControlNext),
     QueryControlPrevious(AstQueryControlPrevious),
     QueryControlExit(AstQueryControlExit),
+    QueryControlGoto(AstQueryControlGoto),
+    Continue(AstContinue),
 }
 
 
@@ -113,20 +289,48 @@ struct AstCreateContainer{
 #[derive(Debug, Clone, PartialEq)]
 struct AstCreateRow{
     col_nam : Vec<String>,
-    col_val : Vec<AlbaTypes>,
+    /// The values of every row, in the order of `col_nam`.
+    rows : Vec<Vec<AlbaTypes>>,
+    container : String,
+    /// `RETURNING [columns]`, the created rows are returned with these columns.
+    returning : Option<Projection>,
+}
+/// What `UPSERT ROW` does with the rows already holding the primary key it writes.
+#[derive(Debug, Clone, PartialEq)]
+enum OnConflict{
+    /// Every given column takes its new value.
+    Replace,
+    /// The rows are left as they are.
+    Nothing,
+    /// Only these columns take their new value.
+    Update(Vec<String>),
+}
+#[derive(Debug, Clone, PartialEq)]
+struct AstUpsertRow{
+    row : AstCreateRow,
+    on_conflict : OnConflict,
+}
+#[derive(Debug, Clone, PartialEq)]
+struct AstCreateIndex{
+    col_nam : Vec<String>,
     container : String
 }
 #[derive(Debug, Clone, PartialEq)]
 struct AstEditRow{
     col_nam : Vec<String>,
-    col_val : Vec<AlbaTypes>,
+    /// The new values, computed from the row they replace.
+    col_val : Vec<Expression>,
     container : String,
-    conditions : (Vec<(Token,Token,Token)>,Vec<(usize,char)>)
+    conditions : PrimitiveQueryConditions,
+    /// `RETURNING [columns]`, the edited rows are returned with these columns and their new values.
+    returning : Option<Projection>,
 }
 #[derive(Debug, Clone, PartialEq)]
 struct AstDeleteRow{
     container : String,
-    conditions : Option<(Vec<(Token,Token,Token)>,Vec<(usize,char)>)>
+    conditions : PrimitiveQueryConditions,
+    /// `RETURNING [columns]`, the deleted rows are returned with these columns as they were.
+    returning : Option<Projection>,
 }
 #[derive(Debug, Clone, PartialEq)]
 struct AstDeleteContainer{
@@ -142,8 +346,41 @@ enum AlbaContainer {
 #[derive(Debug, Clone, PartialEq)]
 struct AstSearch{
     container : Vec<AlbaContainer>,
-    conditions : (Vec<(Token,Token,Token)>,Vec<(usize,char)>),
-    col_nam : Vec<String>,
+    /// `SEARCH DISTINCT`, rows with equal values are returned once.
+    distinct : bool,
+    join : Option<AstJoin>,
+    conditions : PrimitiveQueryConditions,
+    projection : Projection,
+    group_by : Vec<String>,
+    having : PrimitiveQueryConditions,
+    order_by : Vec<SortKey>,
+    window : QueryWindow,
+    page_size : usize,
+    /// `FOR UPDATE`, the rows found are locked like the rows of an EDIT.
+    for_update : bool,
+    /// The tokens of a `STATELESS` search, carried by its continuation tokens to run it again.
+    stateless : Option<Vec<Token>>,
+    /// Set by `CONTINUE`: the rows up to this one were returned already.
+    resume : Option<Keyset>,
+}
+#[derive(Debug, Clone, PartialEq)]
+struct AstJoin{
+    container : String,
+    /// The two columns compared by `ON`, as written.
+    on : (String, String),
+}
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SetOperator{
+    Union,
+    UnionAll,
+    Intersect,
+    Except,
+}
+#[derive(Debug, Clone, PartialEq)]
+struct AstSetOperation{
+    operator : SetOperator,
+    left : Box<AST>,
+    right : Box<AST>,
 }
 #[derive(Debug, Clone, PartialEq)]
 struct AstCommit{
@@ -155,6 +392,19 @@ struct AstRollback{
     container : Option<String>,
 }
 #[derive(Debug, Clone, PartialEq)]
+struct AstSavepoint{
+    name : String,
+}
+#[derive(Debug, Clone, PartialEq)]
+struct AstAnalyze{
+    container : String,
+}
+#[derive(Debug, Clone, PartialEq)]
+struct AstExplain{
+    analyze : bool,
+    command : Box<AST>,
+}
+#[derive(Debug, Clone, PartialEq)]
 struct AstQueryControlNext{
     id : String,
 }
@@ -166,6 +416,15 @@ struct AstQueryControlPrevious{
 struct AstQueryControlExit{
     id : String,
 }
+#[derive(Debug, Clone, PartialEq)]
+struct AstContinue{
+    token : String,
+}
+#[derive(Debug, Clone, PartialEq)]
+struct AstQueryControlGoto{
+    id : String,
+    page : usize,
+}
 
 fn gerr(msg : &str) -> Error{
     return Error::new(ErrorKind::Other, msg.to_string())
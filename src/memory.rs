@@ -0,0 +1,76 @@
+use std::sync::{atomic::{AtomicU64, Ordering}, Arc};
+
+use crate::alba_types::AlbaTypes;
+
+/// The memory held by the intermediate results of every running command, kept within
+/// `memory_limit`. An operator asks its `MemoryGrant` for room before it keeps more in memory,
+/// and writes to disk instead when the database has none left to give.
+#[derive(Debug, Default)]
+pub struct MemoryAccountant{
+    limit : u64,
+    used : AtomicU64,
+}
+
+impl MemoryAccountant{
+    pub fn new(limit : u64) -> Self{
+        MemoryAccountant { limit, used: AtomicU64::new(0) }
+    }
+    /// A grant holding nothing yet, for one operator to grow.
+    pub fn grant(self : &Arc<Self>) -> MemoryGrant{
+        MemoryGrant { accountant: self.clone(), held: 0 }
+    }
+    fn reserve(&self, bytes : u64) -> bool{
+        self.used.fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| used.checked_add(bytes).filter(|total| *total <= self.limit)).is_ok()
+    }
+    fn release(&self, bytes : u64){
+        self.used.fetch_sub(bytes, Ordering::AcqRel);
+    }
+}
+
+/// The share of the accountant one operator holds, given back when it is dropped.
+#[derive(Debug)]
+pub struct MemoryGrant{
+    accountant : Arc<MemoryAccountant>,
+    held : u64,
+}
+
+impl MemoryGrant{
+    /// Takes `bytes` more, unless that would put the database over its limit.
+    pub fn grow(&mut self, bytes : u64) -> bool{
+        let granted = self.accountant.reserve(bytes);
+        if granted{
+            self.held += bytes;
+        }
+        granted
+    }
+    /// Takes `bytes` more whatever the limit. An operator that wrote everything it held to disk
+    /// still keeps the value at hand, so it always moves on.
+    pub fn force(&mut self, bytes : u64){
+        self.accountant.used.fetch_add(bytes, Ordering::AcqRel);
+        self.held += bytes;
+    }
+    /// Gives back `bytes` of what is held.
+    pub fn shrink(&mut self, bytes : u64){
+        let bytes = bytes.min(self.held);
+        self.accountant.release(bytes);
+        self.held -= bytes;
+    }
+    /// Gives back everything held.
+    pub fn release(&mut self){
+        self.shrink(self.held);
+    }
+    pub fn held(&self) -> u64{
+        self.held
+    }
+}
+
+impl Drop for MemoryGrant{
+    fn drop(&mut self){
+        self.release();
+    }
+}
+
+/// What a row of values takes in memory.
+pub fn row_size(values : &[AlbaTypes]) -> u64{
+    (size_of::<Vec<AlbaTypes>>() + values.iter().map(|v| v.memory_size()).sum::<usize>()) as u64
+}
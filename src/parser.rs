@@ -1,13 +1,13 @@
 use std::io::{Error, ErrorKind};
 
-use crate::{gerr, lexer, alba_types::AlbaTypes,lexer_functions::{lexer_boolean_match, lexer_bytes_match, lexer_number_match, Token}, AlbaContainer, AstCommit, AstCreateContainer, AstCreateRow, AstEditRow, AstQueryControlExit, AstQueryControlNext, AstQueryControlPrevious, AstRollback, AstSearch, AST};
+use crate::{gerr, lexer, alba_types::AlbaTypes, query::{PrimitivePredicate, PrimitiveQueryConditions, QueryWindow, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE},lexer_functions::{lexer_boolean_match, lexer_bytes_match, lexer_number_match, Token}, aggregate::{Aggregate, AggregateFunction}, expression::{BinaryOperator, Expression, ScalarFunction}, projection::{Projection, ProjectionItem}, sorting::SortKey, AlbaContainer, AstAnalyze, AstExplain, AstCommit, AstContinue, AstJoin, AstSetOperation, SetOperator, AstCreateContainer, AstCreateIndex, AstCreateRow, AstEditRow, AstQueryControlExit, AstQueryControlGoto, AstQueryControlNext, AstQueryControlPrevious, AstRollback, AstSavepoint, AstSearch, AstUpsertRow, OnConflict, AST};
 
 
 
 fn parser_debugger_extract_string(output : &mut String,list : &Vec<Token>,index : usize) -> Option<Error>{
     if let Some(cn) = list.get(index){
         match cn{
-            Token::String(s) => {
+            Token::String(s) | Token::Identifier(s) => {
                 output.push_str(s.as_str());
                 return None
             },
@@ -24,7 +24,7 @@ fn parser_debugger_extract_group_elstr(output : &mut Vec<String>,list : &Vec<Tok
             Token::Group(ggggg) => {
                 for i in ggggg{
                     match i{
-                        Token::String(s) => {output.push(s.clone());},
+                        Token::String(s) | Token::Identifier(s) => {output.push(s.clone());},
                         _=>{return Some(gerr("Invalid type, must be a group with only strings inside"));}
                     }
                 }
@@ -53,6 +53,82 @@ fn parser_debugger_extract_group_albatype(output: &mut Vec<AlbaTypes>, list: &[T
     }
 }
 
+/// Parses `[columns][values]... ON container` after `CREATE ROW` and `UPSERT ROW`, where every
+/// group of values is a row and the groups may be separated by commas. Returns the position of
+/// the token after the container name.
+fn debug_row(tokens: &Vec<Token>) -> Result<(AstCreateRow, usize),Error>{
+    let mut col_names : Vec<String> = Vec::with_capacity(5);
+    let mut rows : Vec<Vec<AlbaTypes>> = Vec::with_capacity(1);
+    let mut container = String::new();
+
+    if let Some(cva) = parser_debugger_extract_group_elstr(&mut col_names, tokens, 2){
+        return Err(cva)
+    }
+    let mut position = 3;
+    while let Some(token) = tokens.get(position){
+        match token{
+            Token::Group(_) => {
+                let mut col_values : Vec<AlbaTypes> = Vec::with_capacity(col_names.len());
+                if let Some(bruh) = parser_debugger_extract_group_albatype(&mut col_values, tokens, position){
+                    return Err(bruh)
+                }
+                rows.push(col_values);
+            },
+            Token::Separator if !rows.is_empty() => {},
+            _ => break
+        }
+        position += 1;
+    }
+    if rows.is_empty(){
+        return Err(gerr("Missing the values of the row"))
+    }
+    if !is_keyword(tokens.get(position), "ON"){
+        return Err(gerr(&format!("Expected ON <container> after the values of the rows, but found {:?}", tokens.get(position))))
+    }
+    if let Some(err) = parser_debugger_extract_string(&mut container, tokens, position + 1){
+        return Err(err)
+    }
+    Ok((AstCreateRow { col_nam: col_names, rows, container, returning: None }, position + 2))
+}
+
+/// Splits `RETURNING [columns]` off the end of CREATE ROW, EDIT ROW and DELETE ROW. The columns
+/// are written like those of SEARCH.
+fn debug_returning(tokens: &[Token]) -> Result<(Vec<Token>, Option<Projection>),Error>{
+    let position = match tokens.iter().position(|t| is_keyword(Some(t), "RETURNING")){
+        Some(p) => p,
+        None => return Ok((tokens.to_vec(), None))
+    };
+    match &tokens[position + 1..]{
+        [Token::Group(items)] => Ok((tokens[..position].to_vec(), Some(debug_projection(items)?))),
+        rest => Err(gerr(&format!("Expected RETURNING [columns] at the end of the command, but found {:?}", rest)))
+    }
+}
+
+/// Parses `UPSERT ROW [columns][values]... ON container`, optionally followed by
+/// `ON CONFLICT DO NOTHING` or `ON CONFLICT DO UPDATE [columns]`.
+fn debug_upsert(tokens: &Vec<Token>) -> Result<AST,Error>{
+    if !is_keyword(tokens.get(1), "ROW"){
+        return Err(gerr("Invalid instance type, expected UPSERT ROW"))
+    }
+    let (row, end) = debug_row(tokens)?;
+    let on_conflict = match tokens.get(end..).unwrap_or_default(){
+        [] => OnConflict::Replace,
+        [Token::Keyword(on), Token::Keyword(conflict), Token::Keyword(action), rest @ ..] if on == "ON" && conflict == "CONFLICT" && action == "DO" => match rest{
+            [Token::Keyword(nothing)] if nothing == "NOTHING" => OnConflict::Nothing,
+            [Token::Keyword(update), Token::Group(_)] if update == "UPDATE" => {
+                let mut columns = Vec::new();
+                if let Some(err) = parser_debugger_extract_group_elstr(&mut columns, &rest.to_vec(), 1){
+                    return Err(err)
+                }
+                OnConflict::Update(columns)
+            },
+            _ => return Err(gerr(&format!("Expected NOTHING or UPDATE [columns] after ON CONFLICT DO, but found {:?}", rest)))
+        },
+        rest => return Err(gerr(&format!("In UPSERT ROW command, expected ON CONFLICT DO NOTHING or ON CONFLICT DO UPDATE [columns] after the container, but found {:?}", rest)))
+    };
+    Ok(AST::UpsertRow(AstUpsertRow { row, on_conflict }))
+}
+
 fn debug_create_command(tokens: &Vec<Token>) -> Result<AST,Error>{
     if let Some(instance) = tokens.get(1){
         match instance{
@@ -86,26 +162,30 @@ fn debug_create_command(tokens: &Vec<Token>) -> Result<AST,Error>{
                         return Ok(AST::CreateContainer(AstCreateContainer { name: cname, col_nam: col_name, col_val: col_types }))
                     }
                     "ROW" => {
-                        let mut col_names : Vec<String> = Vec::with_capacity(5);
-                        let mut col_values : Vec<AlbaTypes> = Vec::with_capacity(5);
+                        let (tokens, returning) = debug_returning(tokens)?;
+                        let (row, end) = debug_row(&tokens)?;
+                        if let Some(rest) = tokens.get(end..).filter(|rest| !rest.is_empty()){
+                            return Err(gerr(&format!("Unexpected {:?} after the container of CREATE ROW", rest)))
+                        }
+                        return Ok(AST::CreateRow(AstCreateRow { returning, ..row }))
+                    },
+                    "INDEX" => {
+                        let mut col_names : Vec<String> = Vec::with_capacity(2);
                         let mut container = String::new();
-
                         if let Some(cva) = parser_debugger_extract_group_elstr(&mut col_names, tokens, 2){
                             return Err(cva)
                         }
-                        if let Some(bruh) = parser_debugger_extract_group_albatype(&mut col_values, tokens, 3){
-                            return Err(bruh)
+                        if col_names.is_empty(){
+                            return Err(gerr("CREATE INDEX expects at least one column"))
                         }
-                        if let Some(cn) = tokens.get(5){
-                            match cn{
-                                Token::String(s) => {
-                                    container = s.clone()
-                                },
-                                _ => {return Err(gerr("Invalid type, the container name must be a string"));}
-                            }
+                        match tokens.get(3){
+                            Some(Token::Keyword(kw)) if kw == "ON" => {},
+                            _ => return Err(gerr("Expected keyword 'ON' after the indexed columns"))
                         }
-                        return Ok(AST::CreateRow(AstCreateRow { col_nam: col_names, col_val: col_values, container: container }))
-                        
+                        if let Some(err) = parser_debugger_extract_string(&mut container, tokens, 4){
+                            return Err(err)
+                        }
+                        return Ok(AST::CreateIndex(AstCreateIndex { col_nam: col_names, container }))
                     },
                     _ => {return Err(gerr("Invalid instance type"))}
                 }
@@ -119,22 +199,26 @@ fn debug_create_command(tokens: &Vec<Token>) -> Result<AST,Error>{
     return Err(gerr("Missing the instance to be created"));
 }
 
-fn debug_edit_command(tokens : &Vec<Token>) -> Result<AST,Error> {
+fn debug_edit_command(tokens : &[Token]) -> Result<AST,Error> {
+    let (tokens, returning) = debug_returning(tokens)?;
     if let Some(instance) = tokens.get(1){
         match instance{
             Token::Keyword(st) => {
                 match st.as_str(){
                     "ROW" => {
                         let mut ed_col_name : Vec<String> = Vec::with_capacity(20);
-                        let mut ed_col_type : Vec<AlbaTypes> = Vec::with_capacity(20);
+                        let mut ed_col_values : Vec<Expression> = Vec::with_capacity(20);
                         let mut ed_container : String = String::new();
-                        let mut conditions: (Vec<(Token, Token, Token)>, Vec<(usize, char)>) = (Vec::with_capacity(10), Vec::with_capacity(10));   
+                        let mut conditions: PrimitiveQueryConditions = None;
 
                         if let Some(errrrrr) = parser_debugger_extract_group_elstr(&mut ed_col_name, &tokens, 2){
                             return Err(errrrrr)
                         }
-                        if let Some(errrrrr) = parser_debugger_extract_group_albatype(&mut ed_col_type, &tokens, 3){
-                            return Err(errrrrr)
+                        match tokens.get(3){
+                            Some(Token::Group(values)) => for value in values{
+                                ed_col_values.push(debug_edit_value(value)?);
+                            },
+                            _ => return Err(gerr("Missing the new values of the columns"))
                         }
                         if let Some(t) = tokens.get(4){
                             if let Token::Keyword(kw) = t{
@@ -155,74 +239,15 @@ fn debug_edit_command(tokens : &Vec<Token>) -> Result<AST,Error> {
                                 return Err(gerr(&format!(r#"In EDIT ROW command, expected keyword 'WHERE' at position 6, but found {:?}"#, tok)));
                             }
 
-                            if let Some(iterator_of_tokens) = tokens.get(7..) {
-                                let mut bushes: Vec<Token> = Vec::new();
-
-                                for i in iterator_of_tokens {
-                                    if bushes.len() == 3 {
-                                        conditions.0.push((
-                                            get_from_bushes_with_safety(&bushes, 0)?,
-                                            get_from_bushes_with_safety(&bushes, 1)?,
-                                            get_from_bushes_with_safety(&bushes, 2)?,
-                                        ));
-                                        bushes.clear();
-
-                                        conditions.1.push((
-                                            conditions.0.len() - 1,
-                                            match i {
-                                                Token::Keyword(a) => match a.to_uppercase().as_str() {
-                                                    "OR" => 'o',
-                                                    "AND" => 'a',
-                                                    _ => {
-                                                        return Err(gerr(
-                                                            "Expected logical operator 'AND' or 'OR' after a condition",
-                                                        ))
-                                                    }
-                                                },
-                                                _ => return Err(gerr("Expected logical operator after condition")),
-                                            },
-                                        ));
-                                        continue;
-                                    }
-
-                                    match i {
-                                        Token::String(_) => match bushes.len() {
-                                            0 | 2 => bushes.push(i.clone()),
-                                            _ => return Err(gerr("Unexpected string: operator might be missing")),
-                                        },
-                                        Token::Bool(_) | Token::Int(_) | Token::Float(_) => {
-                                            if bushes.len() == 2 {
-                                                bushes.push(i.clone())
-                                            } else {
-                                                return Err(gerr("Unexpected value: condition must follow 'column OP value' pattern"));
-                                            }
-                                        }
-                                        Token::Operator(_) => {
-                                            if bushes.len() == 1 {
-                                                bushes.push(i.clone());
-                                            } else {
-                                                return Err(gerr("Unexpected operator: check condition structure"));
-                                            }
-                                        }
-                                        _ => return Err(gerr("Unexpected token in WHERE clause")),
-                                    }
-                                }
-
-                                if bushes.len() == 3 {
-                                    conditions.0.push((
-                                        get_from_bushes_with_safety(&bushes, 0)?,
-                                        get_from_bushes_with_safety(&bushes, 1)?,
-                                        get_from_bushes_with_safety(&bushes, 2)?,
-                                    ));
-                                }
-                            }
+                            conditions = debug_conditions(&tokens[7..])?;
                         }
 
                         return Ok(AST::EditRow(AstEditRow{
                             col_nam:ed_col_name,
-                            col_val: ed_col_type,
+                            col_val: ed_col_values,
                             container: ed_container,
-                            conditions
+                            conditions,
+                            returning
                         }))
                     },
                     _ => {
@@ -238,13 +263,376 @@ fn debug_edit_command(tokens : &Vec<Token>) -> Result<AST,Error> {
     return Err(gerr("Missing the instance to be editted"));
 }
 
-fn get_from_bushes_with_safety(bushes : &Vec<Token>,index : usize) -> Result<Token,Error>{
-    match bushes.get(index){ Some(a) => Ok(a.clone()), None => return Err(gerr("Missing"))}
+/// A value of EDIT ROW: a literal like before, or an expression computed from the row being edited.
+fn debug_edit_value(token : &Token) -> Result<Expression,Error>{
+    match token{
+        Token::Sequence(parts) => debug_whole_expression(parts),
+        other => debug_whole_expression(std::slice::from_ref(other)),
+    }
+}
+
+fn is_keyword(token : Option<&Token>,keyword : &str) -> bool{
+    matches!(token, Some(Token::Keyword(k)) if k.to_uppercase() == keyword)
+}
+
+/// Parses everything after WHERE. OR has the lowest precedence, then AND, then NOT;
+/// parentheses arrive from the lexer as a `SubCommand` and are parsed recursively.
+fn debug_conditions(tokens : &[Token]) -> Result<PrimitiveQueryConditions,Error>{
+    if tokens.is_empty(){
+        return Err(gerr("Missing conditions after WHERE"))
+    }
+    let mut position = 0;
+    let predicate = debug_condition_or(tokens, &mut position)?;
+    if let Some(token) = tokens.get(position){
+        return Err(gerr(&format!("Unexpected token in WHERE clause: {:?}",token)))
+    }
+    Ok(Some(predicate))
+}
+
+fn debug_condition_or(tokens : &[Token],position : &mut usize) -> Result<PrimitivePredicate,Error>{
+    let mut terms = vec![debug_condition_and(tokens, position)?];
+    while is_keyword(tokens.get(*position), "OR"){
+        *position += 1;
+        terms.push(debug_condition_and(tokens, position)?);
+    }
+    Ok(if terms.len() == 1 { terms.remove(0) } else { PrimitivePredicate::Or(terms) })
+}
+
+fn debug_condition_and(tokens : &[Token],position : &mut usize) -> Result<PrimitivePredicate,Error>{
+    let mut terms = vec![debug_condition_not(tokens, position)?];
+    while is_keyword(tokens.get(*position), "AND"){
+        *position += 1;
+        terms.push(debug_condition_not(tokens, position)?);
+    }
+    Ok(if terms.len() == 1 { terms.remove(0) } else { PrimitivePredicate::And(terms) })
+}
+
+fn debug_condition_not(tokens : &[Token],position : &mut usize) -> Result<PrimitivePredicate,Error>{
+    match tokens.get(*position){
+        Some(Token::Keyword(k)) if k.to_uppercase() == "NOT" => {
+            *position += 1;
+            Ok(PrimitivePredicate::Not(Box::new(debug_condition_not(tokens, position)?)))
+        },
+        Some(Token::Keyword(k)) if k == "EXISTS" => {
+            let command = debug_subquery(tokens.get(*position + 1), "EXISTS")?;
+            *position += 2;
+            Ok(PrimitivePredicate::Exists(command))
+        },
+        // `(a + b) > c` starts with an expression, not with a group of conditions
+        Some(Token::SubCommand(inner)) if !starts_comparison(tokens.get(*position + 1)) => {
+            *position += 1;
+            match debug_conditions(inner)?{
+                Some(predicate) => Ok(predicate),
+                None => Err(gerr("Empty parentheses in WHERE clause"))
+            }
+        },
+        _ => debug_condition_comparison(tokens, position)
+    }
+}
+
+/// The parenthesized SEARCH after IN or EXISTS, kept as tokens until the outer command runs it.
+fn debug_subquery(token : Option<&Token>,after : &str) -> Result<Vec<Token>,Error>{
+    match token{
+        Some(Token::SubCommand(command)) if is_keyword(command.first(), "SEARCH") => Ok(command.clone()),
+        _ => Err(gerr(&format!("Expected a parenthesized SEARCH after {}",after)))
+    }
+}
+
+fn debug_condition_value(token : Option<&Token>) -> Result<Token,Error>{
+    match token{
+        Some(v @ (Token::String(_) | Token::Bool(_) | Token::Int(_) | Token::Float(_) | Token::Bytes(_))) => Ok(v.clone()),
+        _ => Err(gerr("Unexpected value: condition must follow 'column OP value' pattern")),
+    }
+}
+
+/// Whether the token after an operand continues a comparison, rather than ending a condition.
+fn starts_comparison(token : Option<&Token>) -> bool{
+    match token{
+        Some(Token::Operator(_)) => true,
+        Some(Token::Keyword(k)) => ["IS", "IN", "BETWEEN", "LIKE", "NOT", "STARTS", "ENDS"].contains(&k.as_str()),
+        token => binary_operator(token).is_some(),
+    }
+}
+
+/// The left side of a condition: a lone column, as conditions have always been written, or a computed value.
+enum Subject{
+    Column(String),
+    Computed(Expression),
+}
+
+/// The right side of `column OP ...`.
+enum Operand{
+    /// A single value, which keeps the condition able to use an index.
+    Value(Token),
+    Computed(Expression),
+}
+
+fn debug_condition_operand(tokens : &[Token],position : &mut usize) -> Result<Operand,Error>{
+    match tokens.get(*position){
+        Some(value @ (Token::String(_) | Token::Bool(_) | Token::Int(_) | Token::Float(_) | Token::Bytes(_))) if binary_operator(tokens.get(*position + 1)).is_none() => {
+            *position += 1;
+            Ok(Operand::Value(value.clone()))
+        },
+        _ => Ok(Operand::Computed(debug_expression(tokens, position)?))
+    }
+}
+
+/// `column OP value`, `column [NOT] IN (values)`, `column [NOT] IN (SEARCH ...)`, `column [NOT] BETWEEN low AND high`,
+/// `column [NOT] LIKE pattern`, `column STARTS|ENDS WITH value` and `column IS [NOT] NULL`. Either side can also be
+/// an expression, like `price * quantity > ?` or `UPPER(name) = 'BOB'`.
+fn debug_condition_comparison(tokens : &[Token],position : &mut usize) -> Result<PrimitivePredicate,Error>{
+    let subject = match (tokens.get(*position), tokens.get(*position + 1)){
+        (None, _) => return Err(gerr("Missing condition after logical operator")),
+        (Some(Token::String(s) | Token::Identifier(s)), next) if !matches!(next, Some(Token::SubCommand(_))) && binary_operator(next).is_none() => {
+            *position += 1;
+            Subject::Column(s.clone())
+        },
+        _ => Subject::Computed(debug_expression(tokens, position)?),
+    };
+    let compare = |operator : Token, position : &mut usize| -> Result<PrimitivePredicate,Error>{
+        Ok(match &subject{
+            Subject::Column(column) => match debug_condition_operand(tokens, position)?{
+                Operand::Value(value) => PrimitivePredicate::Comparison(Token::String(column.clone()), operator, value),
+                Operand::Computed(expression) => PrimitivePredicate::Compare(Expression::Column(column.clone()), operator, expression),
+            },
+            Subject::Computed(left) => PrimitivePredicate::Compare(left.clone(), operator, debug_expression(tokens, position)?),
+        })
+    };
+    if let Some(Token::Operator(o)) = tokens.get(*position){
+        let operator = Token::Operator(o.clone());
+        *position += 1;
+        return compare(operator, position)
+    }
+
+    if is_keyword(tokens.get(*position), "IS"){
+        let negated = is_keyword(tokens.get(*position + 1), "NOT");
+        let null_position = *position + 1 + negated as usize;
+        if !is_keyword(tokens.get(null_position), "NULL"){
+            return Err(gerr("Expected NULL after IS"))
+        }
+        *position = null_position + 1;
+        let predicate = match &subject{
+            Subject::Column(column) => PrimitivePredicate::IsNull(Token::String(column.clone())),
+            Subject::Computed(left) => PrimitivePredicate::Compare(left.clone(), Token::Operator("IS NULL".to_string()), Expression::Literal(AlbaTypes::NONE)),
+        };
+        return Ok(if negated { PrimitivePredicate::Not(Box::new(predicate)) } else { predicate })
+    }
+
+    let negated = is_keyword(tokens.get(*position), "NOT");
+    if negated{
+        *position += 1;
+    }
+    let predicate = match tokens.get(*position){
+        Some(Token::Keyword(k)) if k == "IN" && matches!(tokens.get(*position + 1), Some(Token::SubCommand(list)) if is_keyword(list.first(), "SEARCH")) => {
+            let command = debug_subquery(tokens.get(*position + 1), "IN")?;
+            *position += 2;
+            match &subject{
+                Subject::Column(column) => PrimitivePredicate::InSearch(Token::String(column.clone()), command),
+                Subject::Computed(_) => return Err(gerr("IN (SEARCH ...) needs a column on its left")),
+            }
+        },
+        Some(Token::Keyword(k)) if k == "IN" => {
+            let values = match tokens.get(*position + 1){
+                Some(Token::SubCommand(list)) => list,
+                _ => return Err(gerr("Expected a parenthesized list of values after IN"))
+            };
+            *position += 2;
+            let items = values.split(|t| *t == Token::Separator).collect::<Vec<&[Token]>>();
+            if items.iter().any(|item| item.is_empty()){
+                return Err(gerr("IN needs values separated by commas"))
+            }
+            match &subject{
+                Subject::Column(column) => {
+                    let mut list = Vec::with_capacity(items.len());
+                    for item in items{
+                        match item{
+                            [value] => list.push(debug_condition_value(Some(value))?),
+                            _ => return Err(gerr("The values of IN must be single values"))
+                        }
+                    }
+                    PrimitivePredicate::In(Token::String(column.clone()), list)
+                },
+                // a computed value is compared with each of them
+                Subject::Computed(left) => PrimitivePredicate::Or(items.into_iter().map(|item| Ok(PrimitivePredicate::Compare(left.clone(), Token::Operator("=".to_string()), debug_whole_expression(item)?))).collect::<Result<Vec<_>,Error>>()?),
+            }
+        },
+        Some(Token::Keyword(k)) if k == "BETWEEN" => {
+            *position += 1;
+            match &subject{
+                Subject::Column(column) => {
+                    let low = debug_condition_value(tokens.get(*position))?;
+                    if !is_keyword(tokens.get(*position + 1), "AND"){
+                        return Err(gerr("Expected AND between the bounds of BETWEEN"))
+                    }
+                    let high = debug_condition_value(tokens.get(*position + 2))?;
+                    *position += 3;
+                    PrimitivePredicate::Between(Token::String(column.clone()), low, high)
+                },
+                Subject::Computed(left) => {
+                    let low = debug_expression(tokens, position)?;
+                    if !is_keyword(tokens.get(*position), "AND"){
+                        return Err(gerr("Expected AND between the bounds of BETWEEN"))
+                    }
+                    *position += 1;
+                    let high = debug_expression(tokens, position)?;
+                    PrimitivePredicate::And(vec![
+                        PrimitivePredicate::Compare(left.clone(), Token::Operator(">=".to_string()), low),
+                        PrimitivePredicate::Compare(left.clone(), Token::Operator("<=".to_string()), high),
+                    ])
+                },
+            }
+        },
+        Some(Token::Keyword(k)) if k == "LIKE" => {
+            *position += 1;
+            compare(Token::Operator("LIKE".to_string()), position)?
+        },
+        Some(Token::Keyword(k)) if (k == "STARTS" || k == "ENDS") && !negated => {
+            if !is_keyword(tokens.get(*position + 1), "WITH"){
+                return Err(gerr(&format!("Expected WITH after {}",k)))
+            }
+            let operator = Token::Operator(format!("{} WITH",k));
+            *position += 2;
+            compare(operator, position)?
+        },
+        _ => return Err(gerr("Unexpected token: operator might be missing")),
+    };
+    Ok(if negated { PrimitivePredicate::Not(Box::new(predicate)) } else { predicate })
+}
+
+/// The binary operator at a token. A negative number right after an operand, like the `-1` of
+/// `balance -1`, is a subtraction of the positive number, which comes along as the right operand.
+fn binary_operator(token : Option<&Token>) -> Option<(BinaryOperator, Option<Expression>)>{
+    match token{
+        Some(Token::Operator(symbol)) => BinaryOperator::from_symbol(symbol).map(|operator| (operator, None)),
+        Some(Token::Int(number)) if *number < 0 => {
+            let positive = AlbaTypes::try_from(Token::Int(number.checked_neg()?)).ok()?;
+            Some((BinaryOperator::Subtract, Some(Expression::Literal(positive))))
+        },
+        Some(Token::Float(number)) if *number < 0.0 => Some((BinaryOperator::Subtract, Some(Expression::Literal(AlbaTypes::Float(-number))))),
+        _ => None
+    }
+}
+
+/// Parses an expression from `position`: `* / %` bind tighter than `+ - ||`, which apply from left
+/// to right. Bare words are columns and quoted strings are text, so an argument is always a value.
+fn debug_expression(tokens : &[Token],position : &mut usize) -> Result<Expression,Error>{
+    let operand = debug_expression_operand(tokens, position)?;
+    debug_expression_rest(operand, tokens, position, 1)
+}
+
+/// An expression that has to take every token, like a value of EDIT ROW or a column of SEARCH.
+fn debug_whole_expression(tokens : &[Token]) -> Result<Expression,Error>{
+    let mut position = 0;
+    let expression = debug_expression(tokens, &mut position)?;
+    match tokens.get(position){
+        Some(token) => Err(gerr(&format!("Unexpected {:?} in the expression {:?}", token, tokens))),
+        None => Ok(expression)
+    }
+}
+
+fn debug_expression_rest(mut left : Expression,tokens : &[Token],position : &mut usize,least_precedence : u8) -> Result<Expression,Error>{
+    while let Some((operator, operand)) = binary_operator(tokens.get(*position)){
+        if operator.precedence() < least_precedence{
+            break;
+        }
+        *position += 1;
+        let operand = match operand{
+            Some(operand) => operand,
+            None => debug_expression_operand(tokens, position)?
+        };
+        let right = debug_expression_rest(operand, tokens, position, operator.precedence() + 1)?;
+        left = Expression::Binary(Box::new(left), operator, Box::new(right));
+    }
+    Ok(left)
+}
+
+fn debug_expression_operand(tokens : &[Token],position : &mut usize) -> Result<Expression,Error>{
+    let token = match tokens.get(*position){
+        Some(token) => token,
+        None => return Err(gerr("Missing a value at the end of the expression"))
+    };
+    *position += 1;
+    Ok(match token{
+        Token::Operator(o) if o == "-" => Expression::Negate(Box::new(debug_expression_operand(tokens, position)?)),
+        Token::Identifier(name) => match tokens.get(*position){
+            Some(Token::SubCommand(arguments)) => {
+                let function = match ScalarFunction::from_name(name){
+                    Some(function) => function,
+                    None => return Err(gerr(&format!("Unknown function {}, expected UPPER, LOWER, LENGTH, SUBSTR or COALESCE", name)))
+                };
+                *position += 1;
+                let arguments = if arguments.is_empty(){
+                    Vec::new()
+                }else{
+                    arguments.split(|t| *t == Token::Separator).map(debug_whole_expression).collect::<Result<Vec<Expression>,Error>>()?
+                };
+                Expression::Function(function, arguments)
+            },
+            _ => Expression::Column(name.clone()),
+        },
+        Token::Keyword(k) if k == "NULL" => Expression::Literal(AlbaTypes::NONE),
+        Token::Keyword(k) if k == "CASE" => debug_case(tokens, position)?,
+        Token::SubCommand(inner) => debug_whole_expression(inner)?,
+        Token::String(_) | Token::Int(_) | Token::Float(_) | Token::Bool(_) | Token::Bytes(_) => match AlbaTypes::try_from(token.clone()){
+            Ok(value) => Expression::Literal(value),
+            Err(e) => return Err(gerr(e))
+        },
+        other => return Err(gerr(&format!("Unexpected {:?} in an expression", other)))
+    })
+}
+
+/// `CASE WHEN condition THEN value ... [ELSE value] END`, after CASE.
+fn debug_case(tokens : &[Token],position : &mut usize) -> Result<Expression,Error>{
+    let mut branches = Vec::new();
+    while is_keyword(tokens.get(*position), "WHEN"){
+        *position += 1;
+        let condition = debug_condition_or(tokens, position)?;
+        if !is_keyword(tokens.get(*position), "THEN"){
+            return Err(gerr("Expected THEN after the condition of WHEN"))
+        }
+        *position += 1;
+        branches.push((condition, debug_expression(tokens, position)?));
+    }
+    if branches.is_empty(){
+        return Err(gerr("CASE needs at least one WHEN condition THEN value"))
+    }
+    let otherwise = if is_keyword(tokens.get(*position), "ELSE"){
+        *position += 1;
+        Some(Box::new(debug_expression(tokens, position)?))
+    }else{
+        None
+    };
+    if !is_keyword(tokens.get(*position), "END"){
+        return Err(gerr("Expected END at the end of CASE"))
+    }
+    *position += 1;
+    Ok(Expression::Case { branches, otherwise })
+}
+
+fn debug_analyze(tokens : &Vec<Token>) -> Result<AST, Error> {
+    let mut container = String::new();
+    if let Some(err) = parser_debugger_extract_string(&mut container, tokens, 1){
+        return Err(err)
+    }
+    Ok(AST::Analyze(AstAnalyze{container}))
+}
+
+fn debug_explain(tokens : &Vec<Token>) -> Result<AST, Error> {
+    let analyze = matches!(tokens.get(1), Some(Token::Keyword(s)) if s.to_uppercase() == "ANALYZE");
+    let start = if analyze { 2 } else { 1 };
+    let command = match tokens.get(start..){
+        Some(rest) if !rest.is_empty() => debug_tokens(&rest.to_vec())?,
+        _ => return Err(gerr("Missing command to explain"))
+    };
+    match command{
+        AST::Search(_) | AST::EditRow(_) | AST::DeleteRow(_) => Ok(AST::Explain(AstExplain{analyze,command:Box::new(command)})),
+        _ => Err(gerr("EXPLAIN only supports SEARCH, EDIT and DELETE commands"))
+    }
 }
 
 fn debug_qycnpvs(tokens : &Vec<Token>) -> Result<AST, Error> {
     if let Some(ii) = tokens.get(1) {
-        if let Token::String(a) = ii{
+        if let Token::String(a) | Token::Identifier(a) = ii{
             return Ok(AST::QueryControlPrevious(AstQueryControlPrevious{id:a.to_string()}))
         }
     }
@@ -253,7 +641,7 @@ fn debug_qycnpvs(tokens : &Vec<Token>) -> Result<AST, Error> {
 
 fn debug_qycnnxt(tokens : &Vec<Token>) -> Result<AST, Error> {
     if let Some(ii) = tokens.get(1) {
-        if let Token::String(a) = ii{
+        if let Token::String(a) | Token::Identifier(a) = ii{
             return Ok(AST::QueryControlNext(AstQueryControlNext{id:a.to_string()}))
         }
     }
@@ -261,21 +649,42 @@ fn debug_qycnnxt(tokens : &Vec<Token>) -> Result<AST, Error> {
 }
 fn debug_qycnext(tokens : &Vec<Token>) -> Result<AST, Error> {
     if let Some(ii) = tokens.get(1) {
-        if let Token::String(a) = ii{
+        if let Token::String(a) | Token::Identifier(a) = ii{
             return Ok(AST::QueryControlExit(AstQueryControlExit{id:a.to_string()}))
         }
     }
     Err(gerr("Missing container"))
 }
+fn debug_qycngto(tokens : &[Token]) -> Result<AST, Error> {
+    let id = match tokens.get(1) {
+        Some(Token::String(a) | Token::Identifier(a)) => a.to_string(),
+        _ => return Err(gerr("Missing query id"))
+    };
+    match tokens.get(2) {
+        Some(Token::Int(page)) if *page >= 0 => Ok(AST::QueryControlGoto(AstQueryControlGoto{id, page: *page as usize})),
+        _ => Err(gerr("QYCNGTO needs the page to go to, counted from 0"))
+    }
+}
+
+fn debug_continue(tokens : &[Token]) -> Result<AST, Error> {
+    match tokens {
+        [_, Token::String(token)] => Ok(AST::Continue(AstContinue{token: token.clone()})),
+        _ => Err(gerr("CONTINUE needs the continuation token of a STATELESS search"))
+    }
+}
 
 fn debug_search(tokens: &Vec<Token>) -> Result<AST, Error> {
+    let command = tokens;
+    // SEARCH DISTINCT [...] parses like SEARCH [...]
+    let distinct = is_keyword(tokens.get(1), "DISTINCT");
+    let tokens = &if distinct { [&tokens[..1], &tokens[2..]].concat() } else { tokens.clone() };
     let container: Vec<AlbaContainer> = match tokens.get(3) {
         Some(s) => match s {
             Token::Group(a) => {
                 let mut containers : Vec<AlbaContainer> = Vec::new();
                 for i in a{
                     match i{
-                        Token::String(str) => {containers.push(AlbaContainer::Real(str.clone()));},
+                        Token::String(str) | Token::Identifier(str) => {containers.push(AlbaContainer::Real(str.clone()));},
                         Token::SubCommand(a) => {containers.push(AlbaContainer::Virtual(a.clone()))},
                         _ => {
                             return Err(gerr("..."));
@@ -289,21 +698,11 @@ fn debug_search(tokens: &Vec<Token>) -> Result<AST, Error> {
         None => return Err(gerr("Missing container group (expected at position 3)")),
     };
 
-    let mut conditions: (Vec<(Token, Token, Token)>, Vec<(usize, char)>) =
-        (Vec::with_capacity(10), Vec::with_capacity(10));
+    let mut conditions: PrimitiveQueryConditions = None;
 
-    let columns: Vec<String> = match tokens.get(1) {
-        Some(a) => match a {
-            Token::Group(g) => {
-                g.iter()
-                    .map(|a| match a {
-                        Token::String(s) => s.to_string(),
-                        _ => "".to_string(),
-                    })
-                    .collect()
-            }
-            _ => return Err(gerr("Expected a group of column names (strings) at position 1")),
-        },
+    let projection = match tokens.get(1) {
+        Some(Token::Group(g)) => debug_projection(g)?,
+        Some(_) => return Err(gerr("Expected a group of column names (strings) at position 1")),
         None => return Err(gerr("Missing column group (expected at position 1)")),
     };
 
@@ -316,85 +715,248 @@ fn debug_search(tokens: &Vec<Token>) -> Result<AST, Error> {
         }
     }
 
-    if let Some(tok) = tokens.get(4) {
-        if match tok {
-            Token::Keyword(a) if a.to_uppercase() == "WHERE" => false,
-            _ => true,
-        } {
-            return Err(gerr(r#"Expected keyword "WHERE" at position 4"#));
-        }
-
-        if let Some(iterator_of_tokens) = tokens.get(5..) {
-            let mut bushes: Vec<Token> = Vec::new();
-
-            for i in iterator_of_tokens {
-                if bushes.len() == 3 {
-                    conditions.0.push((
-                        get_from_bushes_with_safety(&bushes, 0)?,
-                        get_from_bushes_with_safety(&bushes, 1)?,
-                        get_from_bushes_with_safety(&bushes, 2)?,
-                    ));
-                    bushes.clear();
-
-                    conditions.1.push((
-                        conditions.0.len() - 1,
-                        match i {
-                            Token::Keyword(a) => match a.to_uppercase().as_str() {
-                                "OR" => 'o',
-                                "AND" => 'a',
-                                _ => {
-                                    return Err(gerr(
-                                        "Expected logical operator 'AND' or 'OR' after a condition",
-                                    ))
-                                }
-                            },
-                            _ => return Err(gerr("Expected logical operator after condition")),
-                        },
-                    ));
-                    continue;
+    let mut join: Option<AstJoin> = None;
+    let mut group_by: Vec<String> = Vec::new();
+    let mut having: PrimitiveQueryConditions = None;
+    let mut order_by: Vec<SortKey> = Vec::new();
+    let mut window = QueryWindow::default();
+    let mut page_size = DEFAULT_PAGE_SIZE;
+    let mut for_update = false;
+    let mut stateless = None;
+    for (clause, body) in search_clauses(tokens.get(4..).unwrap_or_default())? {
+        match clause {
+            "JOIN" => join = Some(debug_join(body)?),
+            "WHERE" => conditions = debug_conditions(body)?,
+            "GROUP BY" => group_by = debug_group_by(body)?,
+            "HAVING" => having = debug_having(body)?,
+            "ORDER BY" => order_by = debug_order_by(body)?,
+            "LIMIT" => window.limit = Some(debug_count(clause, body)?),
+            "OFFSET" => window.offset = debug_count(clause, body)?,
+            "PAGE SIZE" => {
+                page_size = debug_count(clause, body)? as usize;
+                if !(1..=MAX_PAGE_SIZE).contains(&page_size) {
+                    return Err(gerr(&format!("PAGE SIZE must be between 1 and {}, but found {}", MAX_PAGE_SIZE, page_size)));
                 }
-
-                match i {
-                    Token::String(_) => match bushes.len() {
-                        0 | 2 => bushes.push(i.clone()),
-                        _ => return Err(gerr("Unexpected string: operator might be missing")),
-                    },
-                    Token::Bool(_) | Token::Int(_) | Token::Float(_) => {
-                        if bushes.len() == 2 {
-                            bushes.push(i.clone())
-                        } else {
-                            return Err(gerr("Unexpected value: condition must follow 'column OP value' pattern"));
-                        }
-                    }
-                    Token::Operator(_) => {
-                        if bushes.len() == 1 {
-                            bushes.push(i.clone());
-                        } else {
-                            return Err(gerr("Unexpected operator: check condition structure"));
-                        }
-                    }
-                    _ => return Err(gerr("Unexpected token in WHERE clause")),
+            },
+            "FOR UPDATE" => {
+                if let Some(token) = body.first() {
+                    return Err(gerr(&format!("Unexpected {:?} after FOR UPDATE", token)));
                 }
+                for_update = true;
+            },
+            "STATELESS" => {
+                if let Some(token) = body.first() {
+                    return Err(gerr(&format!("Unexpected {:?} after STATELESS", token)));
+                }
+                stateless = Some(command.clone());
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    Ok(AST::Search(Box::new(AstSearch {
+        container,
+        distinct,
+        join,
+        conditions,
+        projection,
+        group_by,
+        having,
+        order_by,
+        window,
+        page_size,
+        for_update,
+        stateless,
+        resume: None,
+    })))
+}
+
+/// Parses the column list of SEARCH: `*`, `'column'`, `'column' AS 'alias'`, aggregates and expressions.
+fn debug_projection(items: &[Token]) -> Result<Projection, Error> {
+    if items.is_empty() {
+        return Err(gerr("SEARCH needs at least one column, use [*] for all of them"));
+    }
+    let mut projection = Projection { items: Vec::with_capacity(items.len()) };
+    for item in items {
+        let entry = match item {
+            Token::Operator(o) if o == "*" => ProjectionItem::All,
+            Token::String(name) | Token::Identifier(name) => ProjectionItem::Column { name: name.clone(), alias: None },
+            Token::Sequence(parts) => match parts.as_slice() {
+                [Token::String(name) | Token::Identifier(name), Token::Keyword(k), Token::String(alias) | Token::Identifier(alias)] if k == "AS" => ProjectionItem::Column { name: name.clone(), alias: Some(alias.clone()) },
+                [Token::Keyword(function), Token::SubCommand(arguments)] => ProjectionItem::Aggregate { aggregate: debug_aggregate(function, arguments)?, alias: None },
+                [Token::Keyword(function), Token::SubCommand(arguments), Token::Keyword(k), Token::String(alias) | Token::Identifier(alias)] if k == "AS" => ProjectionItem::Aggregate { aggregate: debug_aggregate(function, arguments)?, alias: Some(alias.clone()) },
+                [expression @ .., Token::Keyword(k), Token::String(alias) | Token::Identifier(alias)] if k == "AS" => ProjectionItem::Expression { expression: debug_whole_expression(expression)?, alias: Some(alias.clone()) },
+                expression => ProjectionItem::Expression { expression: debug_whole_expression(expression)?, alias: None },
+            },
+            other => ProjectionItem::Expression { expression: debug_whole_expression(std::slice::from_ref(other))?, alias: None },
+        };
+        projection.items.push(entry);
+    }
+    Ok(projection)
+}
+
+/// Parses `COUNT(*)`, `COUNT(DISTINCT 'column')` and `FUNCTION('column')`.
+fn debug_aggregate(function: &str, arguments: &[Token]) -> Result<Aggregate, Error> {
+    let (function, column) = match (function, arguments) {
+        ("COUNT", [Token::Operator(o)]) if o == "*" => (AggregateFunction::Count, None),
+        ("COUNT", [Token::Keyword(k), Token::String(column) | Token::Identifier(column)]) if k == "DISTINCT" => (AggregateFunction::CountDistinct, Some(column)),
+        ("COUNT", [Token::String(column) | Token::Identifier(column)]) => (AggregateFunction::Count, Some(column)),
+        ("SUM", [Token::String(column) | Token::Identifier(column)]) => (AggregateFunction::Sum, Some(column)),
+        ("AVG", [Token::String(column) | Token::Identifier(column)]) => (AggregateFunction::Avg, Some(column)),
+        ("MIN", [Token::String(column) | Token::Identifier(column)]) => (AggregateFunction::Min, Some(column)),
+        ("MAX", [Token::String(column) | Token::Identifier(column)]) => (AggregateFunction::Max, Some(column)),
+        ("APPROX_COUNT_DISTINCT", [Token::String(column) | Token::Identifier(column)]) => (AggregateFunction::ApproxCountDistinct, Some(column)),
+        _ => return Err(gerr(&format!("Invalid aggregate {}{:?}, expected COUNT(*), COUNT(DISTINCT 'column') or COUNT, SUM, AVG, MIN, MAX, APPROX_COUNT_DISTINCT of a 'column'", function, arguments))),
+    };
+    Ok(Aggregate { function, column: column.cloned() })
+}
+
+/// HAVING filters the output rows of an aggregate search, so an aggregate written in it
+/// stands for the output column of the same name.
+fn debug_having(tokens: &[Token]) -> Result<PrimitiveQueryConditions, Error> {
+    let mut named = Vec::with_capacity(tokens.len());
+    let mut position = 0;
+    while position < tokens.len() {
+        match (&tokens[position], tokens.get(position + 1)) {
+            (Token::Keyword(function), Some(Token::SubCommand(arguments))) if ["COUNT", "SUM", "AVG", "MIN", "MAX", "APPROX_COUNT_DISTINCT"].contains(&function.as_str()) => {
+                named.push(Token::String(debug_aggregate(function, arguments)?.to_string()));
+                position += 2;
+            },
+            (token, _) => {
+                named.push(token.clone());
+                position += 1;
             }
+        }
+    }
+    debug_conditions(&named)
+}
 
-            if bushes.len() == 3 {
-                conditions.0.push((
-                    get_from_bushes_with_safety(&bushes, 0)?,
-                    get_from_bushes_with_safety(&bushes, 1)?,
-                    get_from_bushes_with_safety(&bushes, 2)?,
-                ));
+/// Parses `'container' ON 'column' = 'column'` after JOIN.
+fn debug_join(tokens: &[Token]) -> Result<AstJoin, Error> {
+    match tokens {
+        [Token::String(container) | Token::Identifier(container), Token::Keyword(on), Token::String(left) | Token::Identifier(left), Token::Operator(operator), Token::String(right) | Token::Identifier(right)] if on == "ON" && (operator == "=" || operator == "==") => {
+            Ok(AstJoin { container: container.clone(), on: (left.clone(), right.clone()) })
+        },
+        _ => Err(gerr(&format!("Invalid JOIN {:?}, expected 'container' ON 'container.column' = 'container.column'", tokens))),
+    }
+}
+
+/// Parses `'column', ...` after GROUP BY.
+fn debug_group_by(tokens: &[Token]) -> Result<Vec<String>, Error> {
+    tokens.split(|t| *t == Token::Separator).map(|part| match part {
+        [Token::String(column) | Token::Identifier(column)] => Ok(column.clone()),
+        _ => Err(gerr(&format!("Invalid GROUP BY term {:?}, expected 'column'", part))),
+    }).collect()
+}
+
+const SET_OPERATORS: &[&str] = &["UNION", "INTERSECT", "EXCEPT"];
+
+/// Parses SEARCH commands chained by UNION [ALL], INTERSECT and EXCEPT. INTERSECT binds tighter
+/// than UNION and EXCEPT, which apply from left to right.
+fn debug_set_operation(tokens: &[Token]) -> Result<AST, Error> {
+    let mut result: Option<AST> = None;
+    let mut pending: Option<SetOperator> = None;
+    let mut operand_start = 0;
+    let mut position = 0;
+    loop {
+        let next = match tokens.get(position) {
+            None => None,
+            Some(token) if is_keyword(Some(token), "UNION") && is_keyword(tokens.get(position + 1), "ALL") => Some((SetOperator::UnionAll, 2)),
+            Some(token) if is_keyword(Some(token), "UNION") => Some((SetOperator::Union, 1)),
+            Some(token) if is_keyword(Some(token), "EXCEPT") => Some((SetOperator::Except, 1)),
+            Some(_) => {
+                position += 1;
+                continue;
             }
+        };
+        let operand = debug_intersection(&tokens[operand_start..position])?;
+        result = Some(match (result, pending) {
+            (Some(left), Some(operator)) => AST::SetOperation(AstSetOperation { operator, left: Box::new(left), right: Box::new(operand) }),
+            _ => operand,
+        });
+        match next {
+            Some((operator, width)) => {
+                pending = Some(operator);
+                position += width;
+                operand_start = position;
+            },
+            None => break,
         }
     }
+    result.ok_or_else(|| gerr("Missing SEARCH command"))
+}
 
-    Ok(AST::Search(AstSearch {
-        container,
-        conditions,
-        col_nam: columns,
-    }))
+fn debug_intersection(tokens: &[Token]) -> Result<AST, Error> {
+    let mut result: Option<AST> = None;
+    for operand in tokens.split(|t| is_keyword(Some(t), "INTERSECT")) {
+        if !is_keyword(operand.first(), "SEARCH") {
+            return Err(gerr(&format!("The operands of UNION, INTERSECT and EXCEPT must be SEARCH commands, but found {:?}", operand.first())));
+        }
+        let operand = debug_search(&operand.to_vec())?;
+        result = Some(match result {
+            Some(left) => AST::SetOperation(AstSetOperation { operator: SetOperator::Intersect, left: Box::new(left), right: Box::new(operand) }),
+            None => operand,
+        });
+    }
+    result.ok_or_else(|| gerr("Missing SEARCH command"))
+}
+
+// Optional clauses of SEARCH, in the order they have to be written.
+const SEARCH_CLAUSES: &[&str] = &["JOIN", "WHERE", "GROUP BY", "HAVING", "ORDER BY", "LIMIT", "OFFSET", "PAGE SIZE", "FOR UPDATE", "STATELESS"];
+
+/// Cuts the tokens after the container group of SEARCH into its clauses.
+/// Every clause starts with its keywords and runs until the next clause keyword.
+fn search_clauses(tokens: &[Token]) -> Result<Vec<(&'static str, &[Token])>, Error> {
+    let clause_at = |position: usize| SEARCH_CLAUSES.iter().position(|clause| {
+        clause.split(' ').enumerate().all(|(offset, keyword)| is_keyword(tokens.get(position + offset), keyword))
+    });
+    let mut starts: Vec<(usize, usize)> = Vec::new();
+    for position in 0..tokens.len() {
+        if let Some(clause) = clause_at(position) {
+            if starts.last().is_some_and(|last| last.0 >= clause) {
+                return Err(gerr(&format!("The {} clause is repeated or out of place, the clauses of SEARCH go in the order {}", SEARCH_CLAUSES[clause], SEARCH_CLAUSES.join(", "))));
+            }
+            starts.push((clause, position));
+        }
+    }
+    match starts.first() {
+        Some((_, 0)) => {},
+        _ if tokens.is_empty() => {},
+        _ => return Err(gerr(&format!("In SEARCH command, expected one of {} after the containers, but found {:?}", SEARCH_CLAUSES.join(", "), tokens[0]))),
+    }
+    let mut clauses = Vec::with_capacity(starts.len());
+    for (i, (clause, position)) in starts.iter().enumerate() {
+        let body_start = position + SEARCH_CLAUSES[*clause].split(' ').count();
+        let body_end = starts.get(i + 1).map_or(tokens.len(), |next| next.1);
+        clauses.push((SEARCH_CLAUSES[*clause], &tokens[body_start..body_end]));
+    }
+    Ok(clauses)
+}
+
+/// Parses `'column' [ASC|DESC], ...` after ORDER BY.
+fn debug_order_by(tokens: &[Token]) -> Result<Vec<SortKey>, Error> {
+    let mut keys = Vec::new();
+    for part in tokens.split(|t| *t == Token::Separator) {
+        let key = match part {
+            [Token::String(column) | Token::Identifier(column)] => SortKey { column: column.clone(), descending: false },
+            [Token::String(column) | Token::Identifier(column), Token::Keyword(direction)] if direction == "ASC" || direction == "DESC" => SortKey { column: column.clone(), descending: direction == "DESC" },
+            _ => return Err(gerr(&format!("Invalid ORDER BY term {:?}, expected 'column' optionally followed by ASC or DESC", part))),
+        };
+        keys.push(key);
+    }
+    Ok(keys)
 }
 
-fn debug_delete(tokens : &Vec<Token>) -> Result<AST,Error>{
+/// Parses the single non-negative integer of LIMIT, OFFSET and PAGE SIZE.
+fn debug_count(clause: &str, tokens: &[Token]) -> Result<u64, Error> {
+    match tokens {
+        [Token::Int(value)] if *value >= 0 => Ok(*value as u64),
+        _ => Err(gerr(&format!("{} expects a single non-negative integer, but found {:?}", clause, tokens))),
+    }
+}
+
+fn debug_delete(tokens : &[Token]) -> Result<AST,Error>{
+    let (tokens, returning) = debug_returning(tokens)?;
     if let Some(t) = tokens.get(0){
         if let Token::Keyword(s) = t{
             if s.to_lowercase() != "delete".to_string(){
@@ -420,7 +982,7 @@ fn debug_delete(tokens : &Vec<Token>) -> Result<AST,Error>{
     }
     if !path{
         if let Some(t) = tokens.get(2){
-            if let Token::String(s) = t{
+            if let Token::String(s) | Token::Identifier(s) = t{
                 return Ok(AST::DeleteContainer(crate::AstDeleteContainer { container: s.to_string() }))
             }else{
                 return Err(gerr("Missing container name"))
@@ -429,7 +991,7 @@ fn debug_delete(tokens : &Vec<Token>) -> Result<AST,Error>{
             return Err(gerr("Missing container name"))
         }
     }else{
-        if let Some(t) = tokens.get(3){
+        if let Some(t) = tokens.get(2){
             if let Token::Keyword(s) = t{
                 if s.to_lowercase() != "on".to_string(){
                     return Err(gerr("Invalid keyword, expected \"ON\"."))
@@ -441,10 +1003,9 @@ fn debug_delete(tokens : &Vec<Token>) -> Result<AST,Error>{
             return Err(gerr("Missing tokens"))
         }
         let mut container : String = String::new();
-        let mut conditions: (Vec<(Token, Token, Token)>, Vec<(usize, char)>) =
-        (Vec::with_capacity(10), Vec::with_capacity(10));
-        if let Some(t) = tokens.get(4){
-            if let Token::String(s) = t{
+        let mut conditions: PrimitiveQueryConditions = None;
+        if let Some(t) = tokens.get(3){
+            if let Token::String(s) | Token::Identifier(s) = t{
                 container = s.to_string();
             }else{
                 drop(container);
@@ -454,94 +1015,43 @@ fn debug_delete(tokens : &Vec<Token>) -> Result<AST,Error>{
             return Err(gerr("Missing container name"))
         }
 
-        if let Some(tok) = tokens.get(5) {
+        if let Some(tok) = tokens.get(4) {
             if match tok {
                 Token::Keyword(a) if a.to_uppercase() == "WHERE" => false,
                 _ => true,
             } {
-                return Err(gerr(r#"Expected keyword "WHERE" at position 5"#));
+                return Err(gerr(r#"Expected keyword "WHERE" at position 4"#));
             }
 
-            if let Some(iterator_of_tokens) = tokens.get(6..) {
-                let mut bushes: Vec<Token> = Vec::new();
-
-                for i in iterator_of_tokens {
-                    if bushes.len() == 3 {
-                        conditions.0.push((
-                            get_from_bushes_with_safety(&bushes, 0)?,
-                            get_from_bushes_with_safety(&bushes, 1)?,
-                            get_from_bushes_with_safety(&bushes, 2)?,
-                        ));
-                        bushes.clear();
-
-                        conditions.1.push((
-                            conditions.0.len() - 1,
-                            match i {
-                                Token::Keyword(a) => match a.to_uppercase().as_str() {
-                                    "OR" => 'o',
-                                    "AND" => 'a',
-                                    _ => {
-                                        return Err(gerr(
-                                            "Expected logical operator 'AND' or 'OR' after a condition",
-                                        ))
-                                    }
-                                },
-                                _ => return Err(gerr("Expected logical operator after condition")),
-                            },
-                        ));
-                        continue;
-                    }
-
-                    match i {
-                        Token::String(_) => match bushes.len() {
-                            0 | 2 => bushes.push(i.clone()),
-                            _ => return Err(gerr("Unexpected string: operator might be missing")),
-                        },
-                        Token::Bool(_) | Token::Int(_) | Token::Float(_) => {
-                            if bushes.len() == 2 {
-                                bushes.push(i.clone())
-                            } else {
-                                return Err(gerr("Unexpected value: condition must follow 'column OP value' pattern"));
-                            }
-                        }
-                        Token::Operator(_) => {
-                            if bushes.len() == 1 {
-                                bushes.push(i.clone());
-                            } else {
-                                return Err(gerr("Unexpected operator: check condition structure"));
-                            }
-                        }
-                        _ => return Err(gerr("Unexpected token in WHERE clause")),
-                    }
-                }
-
-                if bushes.len() == 3 {
-                    conditions.0.push((
-                        get_from_bushes_with_safety(&bushes, 0)?,
-                        get_from_bushes_with_safety(&bushes, 1)?,
-                        get_from_bushes_with_safety(&bushes, 2)?,
-                    ));
-                }
-            }
+            conditions = debug_conditions(&tokens[5..])?;
         }
-
-        return Ok(AST::DeleteRow(crate::AstDeleteRow { container, conditions: Some(conditions) }))
+        return Ok(AST::DeleteRow(crate::AstDeleteRow { container, conditions, returning }))
     }
     
 }
 
 pub fn debug_tokens(tokens: &Vec<Token>) -> Result<AST, Error> {
     let first = tokens.first().ok_or_else(|| gerr("Token list is empty"))?;
+    if is_keyword(Some(first), "SEARCH") && tokens.iter().any(|t| SET_OPERATORS.iter().any(|k| is_keyword(Some(t), k))) {
+        return debug_set_operation(tokens);
+    }
     if let Token::Keyword(command) = first {
         return match command.to_uppercase().as_str() {
             "CREATE" => debug_create_command(tokens),
             "EDIT" => debug_edit_command(tokens),
+            "UPSERT" => debug_upsert(tokens),
             "SEARCH" => debug_search(tokens),
+            "ROLLBACK" if is_keyword(tokens.get(1), "TO") => debug_savepoint(tokens),
             "COMMIT"|"ROLLBACK" => debug_finishers_command(tokens),
+            "SAVEPOINT"|"RELEASE" => debug_savepoint(tokens),
             "DELETE" => debug_delete(tokens),
+            "ANALYZE" => debug_analyze(tokens),
+            "EXPLAIN" => debug_explain(tokens),
             "QYCNPVS" => debug_qycnpvs(tokens),
             "QYCNNXT" => debug_qycnnxt(tokens),
             "QYCNEXT" => debug_qycnext(tokens),
+            "QYCNGTO" => debug_qycngto(tokens),
+            "CONTINUE" => debug_continue(tokens),
             _ => Err(gerr("Invalid command keyword")),
         }
     } else {
@@ -557,7 +1067,7 @@ fn debug_finishers_command(tokens : &Vec<Token>) -> Result<AST,Error> {
                 "COMMIT" => {
                     let con : Option<String> = match tokens.get(1){
                         Some(ttt) => match ttt{
-                            Token::String(a) => Some(a.to_string()),
+                            Token::String(a) | Token::Identifier(a) => Some(a.to_string()),
                             _ => None
                         },
                         None => None
@@ -569,7 +1079,7 @@ fn debug_finishers_command(tokens : &Vec<Token>) -> Result<AST,Error> {
                 "ROLLBACK" => {
                     let con : Option<String> = match tokens.get(1){
                         Some(ttt) => match ttt{
-                            Token::String(a) => Some(a.to_string()),
+                            Token::String(a) | Token::Identifier(a) => Some(a.to_string()),
                             _ => None
                         },
                         None => None
@@ -587,6 +1097,28 @@ fn debug_finishers_command(tokens : &Vec<Token>) -> Result<AST,Error> {
     return Err(gerr("Missing the instance to be editted"));
 }
 
+/// `SAVEPOINT name`, `ROLLBACK TO [SAVEPOINT] name` and `RELEASE [SAVEPOINT] name`.
+fn debug_savepoint(tokens : &[Token]) -> Result<AST,Error> {
+    let (command, mut rest) = match tokens.first(){
+        Some(Token::Keyword(k)) if k == "ROLLBACK" => ("ROLLBACK TO", tokens.get(2..).unwrap_or_default()),
+        Some(Token::Keyword(k)) => (k.as_str(), tokens.get(1..).unwrap_or_default()),
+        _ => return Err(gerr("Failed to process the entered savepoint command"))
+    };
+    if command != "SAVEPOINT" && is_keyword(rest.first(), "SAVEPOINT"){
+        rest = &rest[1..];
+    }
+    let name = match rest{
+        [Token::String(a) | Token::Identifier(a)] => a.to_string(),
+        [] => return Err(gerr(&format!("Missing the name of the savepoint of {}", command))),
+        _ => return Err(gerr(&format!("Expected only the name of the savepoint after {}", command)))
+    };
+    let savepoint = AstSavepoint { name };
+    Ok(match command{
+        "SAVEPOINT" => AST::Savepoint(savepoint),
+        "RELEASE" => AST::ReleaseSavepoint(savepoint),
+        _ => AST::RollbackToSavepoint(savepoint),
+    })
+}
 
 fn replace_arguments(token: Token, arg_iter: &mut impl Iterator<Item = Token>) -> Result<Token, Error> {
     match token {
@@ -605,6 +1137,12 @@ fn replace_arguments(token: Token, arg_iter: &mut impl Iterator<Item = Token>) -
                 .collect::<Result<Vec<_>, _>>()?;
             Ok(Token::SubCommand(new_tokens))
         }
+        Token::Sequence(tokens) => {
+            let new_tokens = tokens.into_iter()
+                .map(|t| replace_arguments(t, arg_iter))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(Token::Sequence(new_tokens))
+        }
         other => Ok(other),
     }
 }
@@ -0,0 +1,254 @@
+use std::io::Error;
+
+use crate::{indexing::{GetIndex, INDEX_CHUNK_SIZE}, query_conditions::{IndexCandidate, Operator, Predicate, QueryConditions, QueryIndexType, QueryType}, sorting::SortKey, statistics::ContainerStatistics};
+
+// Relative costs: reading a row sequentially is the unit, a row fetched through an
+// index address is a random read, and every index chunk touched is a 64KiB read.
+const SEQUENTIAL_ROW_COST : f64 = 1.0;
+const RANDOM_ROW_COST : f64 = 4.0;
+const INDEX_CHUNK_COST : f64 = 16.0;
+// Comparing rows while sorting, charged per row and per merge level (log2 of the rows).
+const SORT_ROW_COST : f64 = 0.25;
+
+// Selectivities assumed for columns that were never analyzed.
+const DEFAULT_EQUALITY_FRACTION : f64 = 0.005;
+const DEFAULT_RANGE_FRACTION : f64 = 0.25;
+const DEFAULT_OTHER_FRACTION : f64 = 0.5;
+
+#[derive(Clone, Debug)]
+pub struct IndexLookup{
+    pub column : String,
+    pub primary : bool,
+    pub lookup : QueryIndexType,
+    pub estimated_rows : f64,
+}
+
+#[derive(Clone, Debug)]
+pub enum AccessPath{
+    Scan,
+    Index(IndexLookup),
+    Intersection(Vec<IndexLookup>),
+}
+
+/// How the rows of an `ORDER BY` come out sorted.
+#[derive(Clone, Debug)]
+pub enum SortStrategy{
+    /// Walk the index of the first sort column in key order and sort only the rows sharing a key.
+    Index(IndexLookup),
+    /// Collect every matching row and sort them with the external merge sort.
+    External,
+}
+
+#[derive(Clone, Debug)]
+pub struct QueryPlan{
+    pub access : AccessPath,
+    /// Rows the access path is expected to read before the conditions are applied.
+    pub estimated_reads : f64,
+    /// Rows expected to match every condition.
+    pub estimated_rows : f64,
+    pub estimated_cost : f64,
+    /// False when the container has no statistics and the rule based choice was used.
+    pub cost_based : bool,
+    /// Set when the command has an `ORDER BY`.
+    pub sort : Option<SortStrategy>,
+}
+
+/// How the rows of the right container of a JOIN are found for a row of the left one.
+#[derive(Clone, Debug)]
+pub enum JoinStrategy{
+    /// Read the matching rows of the right container once along its own plan, keyed by the
+    /// join column, and probe them with every left row.
+    Hash,
+    /// Look the join value of every left row up in the index of the right join column.
+    IndexNestedLoop{ column : String, primary : bool },
+}
+
+#[derive(Clone, Debug)]
+pub struct JoinPlan{
+    pub strategy : JoinStrategy,
+    /// Joined rows expected.
+    pub estimated_rows : f64,
+    /// Cost of both containers and of the join itself.
+    pub estimated_cost : f64,
+}
+
+pub struct PlannerContext<'a>{
+    pub row_count : u64,
+    pub primary_key : &'a str,
+    pub secondary_indexes : Vec<String>,
+    pub statistics : Option<&'a ContainerStatistics>,
+}
+
+impl PlannerContext<'_>{
+    pub fn is_indexed(&self, column : &str) -> bool{
+        column == self.primary_key || self.secondary_indexes.iter().any(|c| c == column)
+    }
+    fn candidate_fraction(&self, candidate : &IndexCandidate) -> f64{
+        let column = self.statistics.and_then(|s| s.column(&candidate.column));
+        match (&candidate.lookup, column){
+            (QueryIndexType::Strict(key), Some(column)) => column.key_equal_fraction(*key),
+            (QueryIndexType::InclusiveRange(range), Some(column)) => column.key_range_fraction(*range.start(), *range.end()),
+            (QueryIndexType::Set(keys), Some(column)) => keys.iter().map(|k| column.key_equal_fraction(*k)).sum::<f64>().min(1.0),
+            (QueryIndexType::Strict(_), None) => DEFAULT_EQUALITY_FRACTION,
+            (QueryIndexType::Set(keys), None) => (keys.len() as f64 * DEFAULT_EQUALITY_FRACTION).min(1.0),
+            (QueryIndexType::InclusiveRange(_), None) => DEFAULT_RANGE_FRACTION,
+        }
+    }
+    fn lookup(&self, candidate : IndexCandidate) -> IndexLookup{
+        let estimated_rows = self.candidate_fraction(&candidate) * self.row_count as f64;
+        IndexLookup { primary: candidate.column == self.primary_key, column: candidate.column, lookup: candidate.lookup, estimated_rows }
+    }
+    /// Share of the rows expected to satisfy the predicate, treating its terms as independent.
+    fn predicate_fraction(&self, predicate : &Predicate) -> f64{
+        match predicate{
+            Predicate::Atom(atom) => {
+                let column = self.statistics.and_then(|s| s.column(&atom.column));
+                match (&atom.operator, column){
+                    (Operator::Equal | Operator::StrictEqual, Some(column)) => column.value_equal_fraction(),
+                    (Operator::Equal | Operator::StrictEqual, None) => DEFAULT_EQUALITY_FRACTION,
+                    (Operator::In, Some(column)) => (atom.values.len() as f64 * column.value_equal_fraction()).min(1.0),
+                    (Operator::In, None) => (atom.values.len() as f64 * DEFAULT_EQUALITY_FRACTION).min(1.0),
+                    (Operator::Between, Some(column)) => column.key_range_fraction(atom.values[0].get_index(), atom.values[1].get_index()),
+                    (Operator::IsNull, _) => DEFAULT_EQUALITY_FRACTION,
+                    (Operator::Greater | Operator::GreaterEquality | Operator::Lower | Operator::LowerEquality | Operator::Between, _) => DEFAULT_RANGE_FRACTION,
+                    _ => DEFAULT_OTHER_FRACTION,
+                }
+            },
+            Predicate::Compare(..) => DEFAULT_OTHER_FRACTION,
+            Predicate::Constant(value) => if *value { 1.0 } else { 0.0 },
+            Predicate::Not(inner) => 1.0 - self.predicate_fraction(inner),
+            Predicate::And(list) => list.iter().map(|p| self.predicate_fraction(p)).product(),
+            Predicate::Or(list) => 1.0 - list.iter().map(|p| 1.0 - self.predicate_fraction(p)).product::<f64>(),
+        }
+    }
+}
+
+fn index_cost(lookups : &[&IndexLookup], fetched_rows : f64) -> f64{
+    let chunks : f64 = lookups.iter().map(|l| (l.estimated_rows / INDEX_CHUNK_SIZE as f64).ceil().max(1.0)).sum();
+    chunks * INDEX_CHUNK_COST + fetched_rows * RANDOM_ROW_COST
+}
+
+fn scan_plan(context : &PlannerContext, estimated_rows : f64, cost_based : bool) -> QueryPlan{
+    QueryPlan { access: AccessPath::Scan, estimated_reads: context.row_count as f64, estimated_rows, estimated_cost: context.row_count as f64 * SEQUENTIAL_ROW_COST, cost_based, sort: None }
+}
+
+fn index_plan(lookup : IndexLookup, estimated_rows : f64, cost_based : bool) -> QueryPlan{
+    let estimated_cost = index_cost(&[&lookup], lookup.estimated_rows);
+    QueryPlan { estimated_reads: lookup.estimated_rows, access: AccessPath::Index(lookup), estimated_rows, estimated_cost, cost_based, sort: None }
+}
+
+/// Picks how the rows of a container are reached for the given conditions.
+/// Without statistics the old rule applies: the primary index when a condition allows it,
+/// otherwise an equality on a secondary index, otherwise a scan.
+/// With statistics every index candidate, every intersection of the most selective candidates
+/// and the full scan are costed and the cheapest one wins.
+pub fn plan(conditions : &QueryConditions, context : &PlannerContext) -> Result<QueryPlan, Error>{
+    let estimated_rows = conditions.predicate().map_or(1.0, |p| context.predicate_fraction(p)) * context.row_count as f64;
+    let candidates : Vec<IndexCandidate> = conditions.index_candidates().into_iter().filter(|c| context.is_indexed(&c.column)).collect();
+
+    if context.statistics.is_none(){
+        if let QueryType::Indexed(lookup) = conditions.query_type()?{
+            let column = context.primary_key.to_string();
+            return Ok(index_plan(context.lookup(IndexCandidate { column, lookup }), estimated_rows, false))
+        }
+        if let Some(candidate) = candidates.into_iter().find(|c| matches!(c.lookup, QueryIndexType::Strict(_))){
+            return Ok(index_plan(context.lookup(candidate), estimated_rows, false))
+        }
+        return Ok(scan_plan(context, estimated_rows, false))
+    }
+
+    let mut best = scan_plan(context, estimated_rows, true);
+    let mut lookups : Vec<IndexLookup> = candidates.into_iter().map(|c| context.lookup(c)).collect();
+    lookups.sort_by(|a, b| a.estimated_rows.total_cmp(&b.estimated_rows));
+
+    for lookup in lookups.iter(){
+        let plan = index_plan(lookup.clone(), estimated_rows, true);
+        if plan.estimated_cost < best.estimated_cost{
+            best = plan;
+        }
+    }
+    for size in 2..=lookups.len(){
+        let members : Vec<&IndexLookup> = lookups.iter().take(size).collect();
+        let fraction : f64 = members.iter().map(|l| if context.row_count == 0 { 0.0 } else { l.estimated_rows / context.row_count as f64 }).product();
+        let fetched_rows = fraction * context.row_count as f64;
+        let estimated_cost = index_cost(&members, fetched_rows);
+        if estimated_cost < best.estimated_cost{
+            best = QueryPlan { access: AccessPath::Intersection(members.into_iter().cloned().collect()), estimated_reads: fetched_rows, estimated_rows, estimated_cost, cost_based: true, sort: None };
+        }
+    }
+    Ok(best)
+}
+
+// Sorting for a LIMIT only keeps the best `kept` rows around, so each row costs log(kept).
+fn sort_cost(rows : f64, kept : f64) -> f64{
+    rows * kept.min(rows).max(2.0).log2() * SORT_ROW_COST
+}
+
+/// What a search asks of the order of its rows.
+pub struct SortRequest<'a>{
+    pub order_by : &'a [SortKey],
+    /// Index order is only possible when the container is the only source of the search.
+    pub single_source : bool,
+    /// `LIMIT` plus `OFFSET`, the rows that have to come out before the search can stop.
+    pub wanted_rows : Option<u64>,
+}
+
+impl SortRequest<'_>{
+    pub const UNORDERED : SortRequest<'static> = SortRequest { order_by: &[], single_source: true, wanted_rows: None };
+}
+
+/// Chooses how an `ORDER BY` is satisfied. Index order needs an index on the first sort column
+/// whose keys keep the order of the values (`ordered`). It is free when the plan already reads
+/// that index; when the plan scans, the random reads of walking the whole index are weighed
+/// against sorting the matching rows. With a `LIMIT` the index walk stops after the wanted rows,
+/// so only the share of the index holding them is paid for.
+pub fn plan_sort(plan : &mut QueryPlan, context : &PlannerContext, request : &SortRequest, ordered : bool){
+    let column = match request.order_by.first(){
+        Some(key) => &key.column,
+        None => return
+    };
+    let available = ordered && context.is_indexed(column);
+    let wanted = request.wanted_rows.map_or(f64::INFINITY, |w| w as f64);
+    let external_cost = plan.estimated_cost + sort_cost(plan.estimated_rows, wanted);
+    let strategy = match &plan.access{
+        AccessPath::Index(lookup) if available && lookup.column == *column => SortStrategy::Index(lookup.clone()),
+        AccessPath::Scan if available && plan.cost_based => {
+            let lookup = IndexLookup { column: column.clone(), primary: column == context.primary_key, lookup: QueryIndexType::InclusiveRange(0..=u64::MAX), estimated_rows: context.row_count as f64 };
+            // the matching rows are spread evenly over the index, so reaching `wanted` of them walks this many
+            let walked = (wanted * context.row_count as f64 / plan.estimated_rows.max(1.0)).min(lookup.estimated_rows);
+            let index_order_cost = index_cost(&[&lookup], walked);
+            if index_order_cost < external_cost{
+                // the rows are then reached through the whole index instead of the scan
+                plan.access = AccessPath::Index(lookup.clone());
+                plan.estimated_cost = index_order_cost;
+                SortStrategy::Index(lookup)
+            }else{
+                SortStrategy::External
+            }
+        },
+        _ => SortStrategy::External
+    };
+    if let SortStrategy::External = strategy{
+        plan.estimated_cost = external_cost;
+    }
+    plan.sort = Some(strategy);
+}
+
+/// Chooses how a JOIN finds the right rows, given the plans of both containers and the context
+/// of the right one. The hash join pays for the right plan once; the index nested loop pays an
+/// index chunk and the random reads of the rows sharing the key for every left row, so it wins
+/// when few left rows meet a large right container. `indexable` is false when the index keys of
+/// the two join columns are not comparable, that is when their types differ.
+pub fn plan_join(left : &QueryPlan, right : &QueryPlan, context : &PlannerContext, column : &str, indexable : bool) -> JoinPlan{
+    let row_count = context.row_count as f64;
+    let per_key = row_count * context.statistics.and_then(|s| s.column(column)).map_or(DEFAULT_EQUALITY_FRACTION, |c| c.value_equal_fraction());
+    let right_fraction = if context.row_count == 0 { 0.0 } else { right.estimated_rows / row_count };
+    let estimated_rows = left.estimated_rows * per_key * right_fraction;
+    let hash_cost = right.estimated_cost;
+    let loop_cost = left.estimated_rows * (INDEX_CHUNK_COST + per_key * RANDOM_ROW_COST);
+    if indexable && context.is_indexed(column) && loop_cost < hash_cost{
+        let strategy = JoinStrategy::IndexNestedLoop { column: column.to_string(), primary: column == context.primary_key };
+        return JoinPlan { strategy, estimated_rows, estimated_cost: left.estimated_cost + loop_cost }
+    }
+    JoinPlan { strategy: JoinStrategy::Hash, estimated_rows, estimated_cost: left.estimated_cost + hash_cost }
+}
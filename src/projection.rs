@@ -0,0 +1,124 @@
+use std::{collections::HashMap, io::Error};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{aggregate::Aggregate, alba_types::AlbaTypes, container::VERSION_COLUMN, expression::{BoundExpression, Expression}, gerr, row::Row};
+
+/// One entry of the column list of SEARCH.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ProjectionItem{
+    /// `*`, every column of the container in its order.
+    All,
+    /// `'column'` or `'column' AS 'alias'`.
+    Column{ name : String, alias : Option<String> },
+    /// `COUNT(*)`, `SUM('column') AS 'alias'`...
+    Aggregate{ aggregate : Aggregate, alias : Option<String> },
+    /// `price * 2`, `UPPER(name) AS 'shout'`... named as written when it has no alias.
+    Expression{ expression : Expression, alias : Option<String> },
+}
+
+/// The column list of SEARCH. It is resolved against the headers of the container every page
+/// comes from, so the rows of a subquery source show the columns of the container they live in.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Projection{
+    pub items : Vec<ProjectionItem>,
+}
+
+/// A projection bound to the headers of one container.
+pub struct ResolvedProjection{
+    /// Position in the row of every column to read: one per plain output column, in order,
+    /// followed by the ones only computed columns use.
+    pub positions : Vec<usize>,
+    pub names : Vec<String>,
+    pub types : Vec<AlbaTypes>,
+    /// Names of the columns read at `positions`, for the computed columns to find their values.
+    read_names : Vec<String>,
+    outputs : Vec<ProjectedColumn>,
+}
+
+enum ProjectedColumn{
+    /// The value read at this index of `positions`.
+    Read(usize),
+    Computed(BoundExpression),
+}
+
+impl Projection{
+    pub fn all() -> Self{
+        Projection { items: vec![ProjectionItem::All] }
+    }
+    pub fn has_aggregates(&self) -> bool{
+        self.items.iter().any(|item| matches!(item, ProjectionItem::Aggregate { .. }))
+    }
+    pub fn resolve(&self, headers : &[(String,AlbaTypes)], container_name : &str) -> Result<ResolvedProjection, Error>{
+        let mut resolved = ResolvedProjection { positions: Vec::new(), names: Vec::new(), types: Vec::new(), read_names: Vec::new(), outputs: Vec::new() };
+        for item in self.items.iter(){
+            match item{
+                ProjectionItem::All => {
+                    // `_version`, `container._version` in a JOIN, is only shown when asked for by name
+                    for (position, (name, column_type)) in headers.iter().enumerate().filter(|(_, h)| h.0.rsplit('.').next() != Some(VERSION_COLUMN)){
+                        resolved.read(position, headers);
+                        resolved.names.push(name.clone());
+                        resolved.types.push(column_type.clone());
+                    }
+                },
+                ProjectionItem::Column { name, alias } => {
+                    let position = match headers.iter().position(|h| h.0 == *name){
+                        Some(position) => position,
+                        None => return Err(gerr(&format!("There is no column {} in the container {}", name, container_name)))
+                    };
+                    resolved.read(position, headers);
+                    resolved.names.push(alias.clone().unwrap_or_else(|| name.clone()));
+                    resolved.types.push(headers[position].1.clone());
+                },
+                ProjectionItem::Expression { expression, alias } => {
+                    let column_properties : HashMap<String, AlbaTypes> = headers.iter().cloned().collect();
+                    let bound = expression.bind(&column_properties)?;
+                    resolved.names.push(alias.clone().unwrap_or_else(|| bound.describe()));
+                    // a column that is always NULL still needs a type for the result to show
+                    resolved.types.push(match bound.result_type{
+                        AlbaTypes::NONE => AlbaTypes::Text(String::new()),
+                        ref result_type => result_type.clone(),
+                    });
+                    resolved.outputs.push(ProjectedColumn::Computed(bound));
+                },
+                ProjectionItem::Aggregate { aggregate, .. } => return Err(gerr(&format!("{} can only be computed by an aggregate search", aggregate)))
+            }
+        }
+        // the columns only computed columns read go after the plain ones, which keep their places
+        let mut columns = Vec::new();
+        for output in resolved.outputs.iter(){
+            if let ProjectedColumn::Computed(bound) = output{
+                bound.columns(&mut columns);
+            }
+        }
+        for column in columns{
+            if resolved.read_names.iter().any(|r| r == column){
+                continue;
+            }
+            if let Some(position) = headers.iter().position(|h| h.0 == column){
+                resolved.positions.push(position);
+                resolved.read_names.push(column.to_string());
+            }
+        }
+        Ok(resolved)
+    }
+}
+
+impl ResolvedProjection{
+    fn read(&mut self, position : usize, headers : &[(String,AlbaTypes)]){
+        self.outputs.push(ProjectedColumn::Read(self.positions.len()));
+        self.positions.push(position);
+        self.read_names.push(headers[position].0.clone());
+    }
+    /// The output columns of a row, from its values read at `positions`.
+    pub fn project(&self, values : Vec<AlbaTypes>) -> Result<Vec<AlbaTypes>, Error>{
+        if self.outputs.iter().all(|o| matches!(o, ProjectedColumn::Read(_))){
+            return Ok(values)
+        }
+        let row = Row { data: self.read_names.iter().cloned().zip(values.iter().cloned()).collect() };
+        self.outputs.iter().map(|output| match output{
+            ProjectedColumn::Read(index) => Ok(values[*index].clone()),
+            ProjectedColumn::Computed(bound) => bound.value(&row),
+        }).collect()
+    }
+}
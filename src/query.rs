@@ -3,15 +3,42 @@ use tokio::sync::RwLock;
 
 use serde::{Deserialize, Serialize};
 
-use crate::{container::Container, database::{generate_secure_code, Database}, gerr, lexer_functions::Token, alba_types::AlbaTypes, query_conditions::QueryConditions, row::Row};
+use crate::{container::{changes_of, pending_values, Container}, locking::Session, database::{generate_secure_code, Database}, explain::ExecutionCounters, expression::Expression, gerr, indexing::GetIndex, join::joined_headers, lexer_functions::Token, alba_types::AlbaTypes, query_conditions::QueryConditions, projection::Projection, row::Row, savepoint::PendingRow, sorting::{compare_keys, extract_keys, key_positions, SortKey}};
 
 
-const PAGE_SIZE: usize = 100;
+pub const DEFAULT_PAGE_SIZE: usize = 100;
+pub const MAX_PAGE_SIZE: usize = 10_000;
 
-type QueryPage = (Vec<u64>, String);
-pub type PrimitiveQueryConditions = (Vec<(Token, Token, Token)>, Vec<(usize, char)>);
+/// Row addresses of one page, all in the same container and shown with the same columns.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct QueryPage {
+    pub addresses: Vec<u64>,
+    pub container: String,
+    /// Index in `Query.projections` of the columns of the rows.
+    pub projection: usize,
+}
+/// Conditions as the parser reads them, before the column types are known.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum PrimitivePredicate {
+    Comparison(Token, Token, Token),
+    /// A comparison with a computed value or another column on either side, like `price * 2 > cost`.
+    Compare(Expression, Token, Expression),
+    In(Token, Vec<Token>),
+    Between(Token, Token, Token),
+    IsNull(Token),
+    /// `column IN (SEARCH ...)`, replaced by `InValues` once the subquery has run.
+    InSearch(Token, Vec<Token>),
+    /// `EXISTS (SEARCH ...)`, replaced by `Constant` or, when correlated, by `InValues`.
+    Exists(Vec<Token>),
+    InValues(Token, Vec<AlbaTypes>),
+    Constant(bool),
+    Not(Box<PrimitivePredicate>),
+    And(Vec<PrimitivePredicate>),
+    Or(Vec<PrimitivePredicate>),
+}
+pub type PrimitiveQueryConditions = Option<PrimitivePredicate>;
 
-type Rows = (Vec<String>, Vec<Vec<AlbaTypes>>);
+pub type Rows = (Vec<String>, Vec<Vec<AlbaTypes>>);
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Query {
@@ -21,6 +48,27 @@ pub struct Query {
     pub column_names: Vec<String>,
     pub column_types: Vec<AlbaTypes>,
     pub id: String,
+    /// Row addresses per page, chosen by the client with `PAGE SIZE`.
+    pub page_size: usize,
+    /// Columns returned by `load_rows`. A set operation keeps the column list of every operand,
+    /// any other search has a single one.
+    pub projections: Vec<Projection>,
+    /// Container of the right rows of a JOIN. Its pages then hold pairs of addresses, the row of
+    /// the page's container followed by the row of this one it joined.
+    pub joined: Option<String>,
+    /// Rows created, changed or deleted by the write this query answers.
+    pub affected: u64,
+    /// Pages of the query, set by `count`.
+    pub page_count: usize,
+    /// Rows the pages point to, set by `count`. Rows deleted since the search are still counted.
+    pub row_count: u64,
+    /// The signed token a `STATELESS` search answers with while rows follow its page, for
+    /// `CONTINUE` to read the next one.
+    pub continuation: Option<String>,
+    /// Rows a write returned with `RETURNING`, in the order of the pages. They are kept with the
+    /// query because a deleted row can not be read again.
+    #[serde(skip)]
+    pub returned: Option<Vec<Vec<AlbaTypes>>>,
 }
 
 impl Query {
@@ -33,6 +81,14 @@ impl Query {
             column_names: self.column_names.clone(),
             column_types: self.column_types.clone(),
             id: self.id.clone(),
+            page_size: self.page_size,
+            projections: self.projections.clone(),
+            joined: self.joined.clone(),
+            affected: self.affected,
+            page_count: self.page_count,
+            row_count: self.row_count,
+            continuation: self.continuation.clone(),
+            returned: self.returned.clone(),
         }
     }
 
@@ -62,6 +118,14 @@ impl Query {
             column_names: Vec::new(), 
             column_types,
             id: generate_secure_code(100),
+            page_size: DEFAULT_PAGE_SIZE,
+            projections: vec![Projection::all()],
+            joined: None,
+            affected: 0,
+            page_count: 0,
+            row_count: 0,
+            continuation: None,
+            returned: None,
         };
         n.trim();
         
@@ -77,88 +141,79 @@ impl Query {
             column_names: Vec::new(), 
             column_types,
             id: "".to_string(),
+            page_size: DEFAULT_PAGE_SIZE,
+            projections: vec![Projection::all()],
+            joined: None,
+            affected: 0,
+            page_count: 0,
+            row_count: 0,
+            continuation: None,
+            returned: None,
         };
         a.trim();
         
         a
     }
 
-    pub fn join(&mut self, foreign: Query) {
-        if foreign.column_types != self.column_types {
-            return;
+    /// The answer of a write with `RETURNING`: the affected rows, given with every column of the
+    /// container and the address they were written at, shown with the columns of `projection`.
+    /// The first page is loaded.
+    pub fn returning(projection: &Projection, headers: &[(String, AlbaTypes)], container_name: &str, rows: Vec<(Vec<AlbaTypes>, u64)>) -> Result<Self, Error> {
+        let resolved = projection.resolve(headers, container_name)?;
+        let mut query = Query::new(resolved.types.clone());
+        query.column_names = resolved.names.clone();
+        query.projections = vec![projection.clone()];
+        let mut returned = Vec::with_capacity(rows.len());
+        for (values, address) in rows {
+            returned.push(resolved.project(resolved.positions.iter().map(|p| values.get(*p).cloned().unwrap_or(AlbaTypes::NONE)).collect())?);
+            query.push_address(address, container_name, 0);
         }
-        
-        for (idx, (foreign_ids, container_name)) in foreign.pages.into_iter().enumerate() {
-            if idx < self.pages.len() {
-                let (self_ids, _) = &mut self.pages[idx];
-                
-                for foreign_id in foreign_ids {
-                    if self_ids.len() < PAGE_SIZE {
-                        self_ids.push(foreign_id);
-                    } else {
-                        break;
-                    }
-                }
-            } else {
-                self.pages.push((foreign_ids, container_name));
-            }
-        }
-        
-        self.trim();
+        query.affected = returned.len() as u64;
+        query.returned = Some(returned);
+        query.load_returned();
+        query.trim();
+        Ok(query)
     }
 
-    pub async fn load_rows(&mut self, database: &mut Database) -> Result<(), Error> {
-        
-        
-        if self.pages.is_empty() {
-            
-            return Ok(());
+    /// Takes the rows of the current page from `returned`, false when the query has none.
+    fn load_returned(&mut self) -> bool {
+        let (returned, page) = match (&self.returned, self.pages.get(self.current_page)) {
+            (Some(returned), Some(page)) => (returned, page),
+            (returned, _) => return returned.is_some(),
+        };
+        let start: usize = self.pages[..self.current_page].iter().map(|p| p.addresses.len()).sum();
+        let rows = returned.get(start..start + page.addresses.len()).unwrap_or_default().to_vec();
+        self.rows = (self.column_names.clone(), rows);
+        true
+    }
+
+    /// Reads the rows of the current page as `session` sees them, keeping only the columns of
+    /// the projection.
+    pub async fn load_rows(&mut self, database: &Database, session: &Session) -> Result<(), Error> {
+        if self.load_returned() {
+            return Ok(())
         }
-        
-        
         let page = match self.pages.get(self.current_page) {
-            Some(a) => {
-                
-                a
-            },
-            None => {
-                
-                return Err(gerr("There is no page"))
-            }
+            Some(a) => a,
+            None if self.pages.is_empty() => return Ok(()),
+            None => return Err(gerr("There is no page"))
         };
-        
-        let container_name = &page.1;
-        
-        let container = match database.container.get(container_name) {
-            Some(a) => {
-                
-                a.read().await
-            },
-            None => {
-                
-                return Err(gerr(&format!("There is no container in the given database named {}", container_name)))
-            }
+        let projection = match self.projections.get(page.projection) {
+            Some(a) => a,
+            None => return Err(gerr("There is no column list for the page"))
         };
-        
-        let mut rows = Vec::new();
-        
-        for i in page.0.iter() {
-            
-            let indexes = (*i, *i + 1);
-            println!("load_rows: {:?}",indexes);
-            match container.get_rows(indexes).await?.get(0) {
-                Some(a) => {
-                    rows.push(a.clone());
-                },
-                None => {
-                    
-                    continue;
-                }
-            }
+        if let Some(joined) = &self.joined {
+            self.rows = load_joined_rows(database, page, joined, projection, session).await?;
+            self.trim();
+            return Ok(())
         }
-        
-        
-        self.rows = (container.column_names(), rows);
+        let container = match database.container.get(&page.container) {
+            Some(a) => a.read().await,
+            None => return Err(gerr(&format!("There is no container in the given database named {}", page.container)))
+        };
+        let projection = projection.resolve(&container.headers, &container.name)?;
+        let rows = container.get_rows(&page.addresses, &projection.positions, session).await?.into_iter().flatten().map(|values| projection.project(values)).collect::<Result<Vec<_>, Error>>()?;
+        self.rows = (projection.names, rows);
         self.trim();
         Ok(())
     }
@@ -181,13 +236,32 @@ impl Query {
         
         
         
-        self.load_rows(database).await?;
+        self.load_rows(database, database.session()).await?;
         self.trim();
         
         
         Ok(())
     }
 
+    /// Moves to `page`, counted from 0 like `current_page`, and reads its rows.
+    pub async fn goto(&mut self, page: usize, database: &mut Database) -> Result<(), Error> {
+        if page >= self.pages.len() {
+            return Err(gerr(&format!("There is no page {}, the query has {} pages", page, self.pages.len())))
+        }
+        self.current_page = page;
+        self.load_rows(database, database.session()).await
+    }
+
+    /// Sets `page_count` and `row_count` from the pages.
+    pub fn count(&mut self) {
+        let addresses: usize = self.pages.iter().map(|p| p.addresses.len()).sum();
+        self.page_count = self.pages.len();
+        self.row_count = match self.joined {
+            Some(_) => addresses / 2,
+            None => addresses,
+        } as u64;
+    }
+
     pub async fn previous(&mut self, database: &mut Database) -> Result<(), Error> {
         
         
@@ -206,278 +280,393 @@ impl Query {
         
         
         
-        self.load_rows(database).await?;
+        self.load_rows(database, database.session()).await?;
         self.trim();
         
         
         Ok(())
     }
 
-    pub fn push(&mut self, subject: (Vec<u64>, String)) {
-        
-        self.pages.push(subject);
-        
+    /// Appends one row address, starting a new page when the last one is full or belongs to another
+    /// container or column list.
+    pub fn push_address(&mut self, address: u64, container_name: &str, projection: usize) {
+        match self.pages.last_mut() {
+            Some(page) if page.container == container_name && page.projection == projection && page.addresses.len() < self.page_size => page.addresses.push(address),
+            _ => self.pages.push(QueryPage { addresses: vec![address], container: container_name.to_string(), projection }),
+        }
+    }
+
+    /// Appends the addresses of a joined pair, a page of a JOIN holds `page_size` pairs.
+    pub fn push_pair(&mut self, left: u64, right: u64, container_name: &str) {
+        match self.pages.last_mut() {
+            Some(page) if page.container == container_name && page.addresses.len() < self.page_size * 2 => page.addresses.extend([left, right]),
+            _ => self.pages.push(QueryPage { addresses: vec![left, right], container: container_name.to_string(), projection: 0 }),
+        }
     }
 }
 
+/// Reads the pairs of a JOIN page. Each container is read on its own so no two container locks
+/// are held at once, and a pair is left out when either of its rows was deleted since the search.
+async fn load_joined_rows(database: &Database, page: &QueryPage, joined: &str, projection: &Projection, session: &Session) -> Result<Rows, Error> {
+    let container = |name: &str| match database.container.get(name) {
+        Some(a) => Ok(a.clone()),
+        None => Err(gerr(&format!("There is no container in the given database named {}", name)))
+    };
+    let (left, right) = (container(&page.container)?, container(joined)?);
+    let left_headers = left.read().await.headers.clone();
+    let right_headers = right.read().await.headers.clone();
+    let headers = joined_headers(&page.container, &left_headers, joined, &right_headers);
+    let resolved = projection.resolve(&headers, &format!("{} JOIN {}", page.container, joined))?;
+    // every column read is taken from one of the two rows, at its place in the columns read from that container
+    let mut left_positions: Vec<usize> = Vec::new();
+    let mut right_positions: Vec<usize> = Vec::new();
+    let sources: Vec<(bool, usize)> = resolved.positions.iter().map(|p| match p.checked_sub(left_headers.len()) {
+        None => { left_positions.push(*p); (true, left_positions.len() - 1) },
+        Some(position) => { right_positions.push(position); (false, right_positions.len() - 1) },
+    }).collect();
+    let left_addresses: Vec<u64> = page.addresses.iter().step_by(2).copied().collect();
+    let right_addresses: Vec<u64> = page.addresses.iter().skip(1).step_by(2).copied().collect();
+    let left_rows = left.read().await.get_rows(&left_addresses, &left_positions, session).await?;
+    let right_rows = right.read().await.get_rows(&right_addresses, &right_positions, session).await?;
+    let mut rows = Vec::with_capacity(left_rows.len());
+    for (left_row, right_row) in left_rows.into_iter().zip(right_rows) {
+        let (Some(left_row), Some(right_row)) = (left_row, right_row) else { continue };
+        rows.push(resolved.project(sources.iter().map(|(left, at)| if *left { left_row[*at].clone() } else { right_row[*at].clone() }).collect())?);
+    }
+    Ok((resolved.names, rows))
+}
+
 pub struct SearchArguments {
     pub element_size : usize,
     pub header_offset : usize,
     pub file : Arc<RwLock<File>>,
     pub container_values : Vec<(String,AlbaTypes)>,
     pub container_name : String,
-    pub conditions : QueryConditions
-
+    pub conditions : QueryConditions,
+    pub counters : Arc<ExecutionCounters>,
+    /// Columns deserialized while matching, the others are handed to the visitors as `NONE`.
+    pub columns : Vec<bool>,
+    /// The session whose pending changes are read over the stored rows, as the commands of its
+    /// transaction see them, None for the stored rows only.
+    pub pending : Option<Session>,
+    /// Only the rows coming after a keyset match, for a search resumed by `CONTINUE`.
+    pub after : Option<After>,
 }
-const CHUNK_MATRIX : usize = 4096 * 10;
 
-pub async fn search(container : Arc<RwLock<Container>>,args : SearchArguments) -> Result<Query,Error>{
-    let element_size = args.element_size;
-    let header_offset = args.header_offset;
+/// Where a stateless search resumes: after the row with these sort keys at this address. The
+/// rows of a search are in the order of its `ORDER BY` and then of their addresses, so a search
+/// without one has no keys.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Keyset {
+    pub keys: Vec<AlbaTypes>,
+    pub address: u64,
+}
 
-    let file = args.file.read().await;
-    let file_size = file.metadata()?.size() as usize;
-    let total_rows = (file_size-header_offset)/element_size;
-    let mut readen_rows = 0;
-    let rows_per_iteration = std::cmp::max(1, CHUNK_MATRIX / element_size).min(total_rows);
+/// A keyset with the sort keys it is compared in.
+#[derive(Debug, Clone)]
+pub struct After {
+    keyset: Keyset,
+    order: Vec<SortKey>,
+    positions: Vec<usize>,
+}
 
-    
-    let container = container.read().await;
-    let mut rows : Vec<(Row,usize)> = Vec::new();
-    while readen_rows < total_rows{
-        let to_read = rows_per_iteration.min(total_rows-readen_rows);
-        let read_size = to_read * element_size;
-        let mut buffer = vec![0u8;read_size];
-        file.read_exact_at(&mut buffer, (header_offset + (readen_rows * element_size)) as u64)?;
-        for i in 0..to_read{
-            let buff = &buffer[(i*element_size)..((i+1)*element_size)];
-            let row = match container.deserialize_row(buff).await{
-                Ok(row_content) => {
-                    let mut data : HashMap<String,AlbaTypes> = HashMap::new();
-                    for (index,value) in container.headers.iter().enumerate(){
-                        let column_value = match row_content.get(index){
-                            Some(a) => {
-                                let cv = a.to_owned();
-                                if std::mem::discriminant(&cv) != std::mem::discriminant(&value.1){
-                                    return Err(gerr("Invalid alba type row order, unmatching stuff"))
-                                }
-                                cv
-                            },
-                            None => {
-                                return Err(gerr("Invalid alba type row order, missing stuff"));
-                            }
-                        };
-                        data.insert(value.0.clone(),column_value);
-                    }
-                    Row{
-                        data
-                    }
-                },
-                Err(e) => {
-                    return Err(e)
-                }
-            };
-            rows.push((row,readen_rows+i));
+impl After {
+    fn follows(&self, row: &[AlbaTypes], address: u64) -> bool {
+        compare_keys(&extract_keys(row, &self.positions), &self.keyset.keys, &self.order).then(address.cmp(&self.keyset.address)).is_gt()
+    }
+    /// The last address left out when the rows come in address order, without sort keys.
+    fn address(&self) -> Option<u64> {
+        self.order.is_empty().then_some(self.keyset.address)
+    }
+}
+const CHUNK_MATRIX : usize = 4096 * 10;
 
+impl SearchArguments {
+    /// Leaves out the rows up to `keyset` in the order of `order_by`, whose columns are loaded.
+    pub fn resume(&mut self, keyset: &Keyset, order_by: &[SortKey]) -> Result<(), Error> {
+        let positions = key_positions(&self.container_values, order_by)?;
+        for position in positions.iter() {
+            self.columns[*position] = true;
         }
-        readen_rows += 1;
-    }
-    
-    let mut query = Query::new(args.container_values.iter().map(|f|f.1.clone()).collect());
-    let mut page_bucket : Vec<u64> = Vec::with_capacity(100);
-    let mut page_bucket_len = 0;
-
-    for i in rows{
-        if args.conditions.row_match(&i.0)?{
-            page_bucket.push(i.1 as u64); page_bucket_len += 1;
-            if page_bucket_len >= 100{
-                query.push((page_bucket.clone(),args.container_name.clone()));
-                page_bucket.clear(); page_bucket_len = 0;
-            }
-        }
+        self.after = Some(After { keyset: keyset.clone(), order: order_by.to_vec(), positions });
+        Ok(())
     }
-    if page_bucket_len > 0 {
-        query.push((page_bucket.clone(),args.container_name.clone()));
+    fn follows(&self, row: &[AlbaTypes], address: u64) -> bool {
+        self.after.as_ref().is_none_or(|after| after.follows(row, address))
+    }
+    /// Whether the row at `address` is known to come before the keyset without reading it.
+    fn skips(&self, address: u64) -> bool {
+        self.after.as_ref().and_then(After::address).is_some_and(|after| address <= after)
+    }
+    /// Deserializes only the columns read by the conditions and by `order_by` while matching.
+    pub fn load_only_needed(&mut self, order_by: &[SortKey]) {
+        let conditions = self.conditions.columns();
+        self.columns = self.container_values.iter().map(|(name, _)| {
+            conditions.contains(&name.as_str()) || order_by.iter().any(|key| key.column == *name)
+        }).collect();
     }
-    drop(page_bucket);
-    let _ = page_bucket_len;
-
-    Ok(query)
 }
 
+/// Deleted rows are zeroed on commit, the same rule `load_containers` uses to fill the graveyard.
+pub fn is_tombstone(buffer : &[u8]) -> bool{
+    buffer.iter().all(|b| *b == 0)
+}
 
-pub async fn indexed_search(container : Arc<RwLock<Container>>,args : SearchArguments,address : &BTreeSet<u64>) -> Result<Query,Error>{
-    let element_size = args.element_size;
-    let header_offset = args.header_offset;
-
-    let file = args.file.read().await;
+pub async fn search_direct(container: Arc<RwLock<Container>>, args: SearchArguments) -> Result<Vec<(Vec<AlbaTypes>, u64)>, Error> {
+    let mut result: Vec<(Vec<AlbaTypes>, u64)> = Vec::new();
+    scan_matches(&container, &args, |row, address| {
+        result.push((row, address));
+        Ok(true)
+    }).await?;
+    Ok(result)
+}
 
-    
-    let container = container.read().await;
-    let mut rows : Vec<(Row,u64)> = Vec::new();
-    for i in address{
-        let mut buffer = vec![0u8;element_size];
-        file.read_exact_at(&mut buffer,((*i*element_size as u64)+header_offset as u64) as u64)?;
-        let row = match container.deserialize_row(&buffer).await{
-            Ok(row_content) => {
-                let mut data : HashMap<String,AlbaTypes> = HashMap::new();
-                for (index,value) in container.headers.iter().enumerate(){
-                    let column_value = match row_content.get(index){
-                        Some(a) => {
-                            let cv = a.to_owned();
-                            if std::mem::discriminant(&cv) != std::mem::discriminant(&value.1){
-                                return Err(gerr("Invalid alba type row order, unmatching stuff"))
-                            }
-                            cv
-                        },
-                        None => {
-                            return Err(gerr("Invalid alba type row order, missing stuff"));
-                        }
-                    };
-                    data.insert(value.0.clone(),column_value);
-                }
-                Row{
-                    data
-                }
-            },
-            Err(e) => {
-                return Err(e)
+pub async fn indexed_search_direct(container: Arc<RwLock<Container>>, args: SearchArguments, address: &BTreeSet<u64>) -> Result<Vec<(Vec<AlbaTypes>, u64)>, Error> {
+    let mut result: Vec<(Vec<AlbaTypes>, u64)> = Vec::new();
+    fetch_matches(&container, &args, address.iter().copied(), |row, address| {
+        result.push((row, address));
+        Ok(true)
+    }).await?;
+    Ok(result)
+}
+/// Deserializes a stored row and returns its values when it satisfies the conditions.
+async fn match_row(container : &Container, args : &SearchArguments, buffer : &[u8]) -> Result<Option<Vec<AlbaTypes>>, Error> {
+    if is_tombstone(buffer) {
+        return Ok(None);
+    }
+    args.counters.examined(1);
+    let row_content = container.deserialize_columns(buffer, &args.columns).await?;
+    for (index, value) in container.headers.iter().enumerate() {
+        if !args.columns[index] {
+            continue;
+        }
+        match row_content.get(index) {
+            Some(a) if std::mem::discriminant(a) != std::mem::discriminant(&value.1) => {
+                return Err(gerr("Invalid alba type row order, unmatching stuff"));
             }
-        };
-        rows.push((row,*i));
-    }
-    
-    let mut query = Query::new(args.container_values.iter().map(|f|f.1.clone()).collect());
-    let mut page_bucket : Vec<u64> = Vec::with_capacity(100);
-    let mut page_bucket_len = 0;
-
-    for i in rows{
-        if args.conditions.row_match(&i.0)?{
-            page_bucket.push(i.1); page_bucket_len += 1;
-            if page_bucket_len >= 100{
-                query.push((page_bucket.clone(),args.container_name.clone()));
-                page_bucket.clear(); page_bucket_len = 0;
+            Some(_) => {}
+            None => {
+                return Err(gerr("Invalid alba type row order, missing stuff"));
             }
         }
     }
-    if page_bucket_len > 0 {
-        query.push((page_bucket.clone(),args.container_name.clone()));
+    match_values(container, args, row_content)
+}
+
+/// Returns the values of a pending row when it is live and satisfies the conditions.
+fn match_pending(container : &Container, args : &SearchArguments, texts : &HashMap<String, (bool, String)>, pending : &PendingRow) -> Result<Option<Vec<AlbaTypes>>, Error> {
+    let (deleted, row) = pending;
+    if *deleted {
+        return Ok(None);
     }
-    drop(page_bucket);
-    let _ = page_bucket_len;
+    args.counters.examined(1);
+    match_values(container, args, pending_values(texts, row))
+}
 
-    Ok(query)
+fn match_values(container : &Container, args : &SearchArguments, values : Vec<AlbaTypes>) -> Result<Option<Vec<AlbaTypes>>, Error> {
+    let data: HashMap<String, AlbaTypes> = container.headers.iter().zip(values.iter()).enumerate()
+        .filter(|(index, _)| args.columns[*index])
+        .map(|(_, (header, value))| (header.0.clone(), value.clone()))
+        .collect();
+    if !args.conditions.row_match(&Row { data })? {
+        return Ok(None);
+    }
+    args.counters.matched(1);
+    Ok(Some(values))
 }
 
-pub async fn search_direct(container: Arc<RwLock<Container>>, args: SearchArguments) -> Result<Vec<(Vec<AlbaTypes>, u64)>, Error> {
+/// Reads the whole container in physical order and hands every matching row to `visit`,
+/// stopping as soon as `visit` returns false.
+pub async fn scan_matches<F: FnMut(Vec<AlbaTypes>, u64) -> Result<bool, Error>>(container: &Arc<RwLock<Container>>, args: &SearchArguments, mut visit: F) -> Result<(), Error> {
     let element_size = args.element_size;
     let header_offset = args.header_offset;
 
     let file = args.file.read().await;
     let file_size = file.metadata()?.size() as usize;
     let total_rows = (file_size - header_offset) / element_size;
-    let mut readen_rows = 0;
+    // rows in address order start right after the keyset
+    let mut readen_rows = match args.after.as_ref().and_then(After::address) {
+        Some(address) => usize::try_from(address.saturating_add(1)).unwrap_or(usize::MAX).min(total_rows),
+        None => 0
+    };
     let rows_per_iteration = std::cmp::max(1, CHUNK_MATRIX / element_size).min(total_rows);
 
     let container = container.read().await;
-    let mut result: Vec<(Vec<AlbaTypes>, u64)> = Vec::new();
-    
+    let mvcc = container.mvcc.read().await;
+    let changes = args.pending.map(|session| changes_of(&mvcc, &session));
     while readen_rows < total_rows {
         let to_read = rows_per_iteration.min(total_rows - readen_rows);
         let read_size = to_read * element_size;
         let mut buffer = vec![0u8; read_size];
         file.read_exact_at(&mut buffer, (header_offset + (readen_rows * element_size)) as u64)?;
-        
+        args.counters.read(read_size as u64);
         for i in 0..to_read {
-            let buff = &buffer[(i * element_size)..((i + 1) * element_size)];
-            let row_address = (readen_rows + i) as u64;
-            
-            let row = match container.deserialize_row(buff).await {
-                Ok(row_content) => {
-                    let mut data: HashMap<String, AlbaTypes> = HashMap::new();
-                    for (index, value) in container.headers.iter().enumerate() {
-                        let column_value = match row_content.get(index) {
-                            Some(a) => {
-                                let cv = a.to_owned();
-                                if std::mem::discriminant(&cv) != std::mem::discriminant(&value.1) {
-                                    return Err(gerr("Invalid alba type row order, unmatching stuff"));
-                                }
-                                cv
-                            }
-                            None => {
-                                return Err(gerr("Invalid alba type row order, missing stuff"));
-                            }
-                        };
-                        data.insert(value.0.clone(), column_value);
-                    }
-                    Row {
-                        data
-                    }
-                }
-                Err(e) => {
-                    return Err(e);
-                }
+            let address = (readen_rows + i) as u64;
+            let row = match changes.and_then(|changes| changes.0.get(&address).map(|row| (changes, row))) {
+                Some((changes, row)) => match_pending(&container, args, &changes.1, row)?,
+                _ => match_row(&container, args, &buffer[(i * element_size)..((i + 1) * element_size)]).await?
             };
-
-            if args.conditions.row_match(&row)? {
-                result.push((row.data.values().map(|f|f.to_owned()).collect(), row_address));
+            if let Some(row) = row
+                && args.follows(&row, address)
+                && !visit(row, address)? {
+                return Ok(());
             }
         }
         readen_rows += to_read;
     }
-
-    Ok(result)
+    // the rows created since the last commit lie past the end of the file
+    let Some(changes) = changes else { return Ok(()) };
+    let mut created: Vec<(&u64, &PendingRow)> = changes.0.iter().filter(|(address, _)| **address >= total_rows as u64).collect();
+    created.sort_unstable_by_key(|(address, _)| **address);
+    for (address, row) in created {
+        if let Some(row) = match_pending(&container, args, &changes.1, row)?
+            && args.follows(&row, *address)
+            && !visit(row, *address)? {
+            return Ok(());
+        }
+    }
+    Ok(())
 }
 
-pub async fn indexed_search_direct(container: Arc<RwLock<Container>>, args: SearchArguments, address: &BTreeSet<u64>) -> Result<Vec<(Vec<AlbaTypes>, u64)>, Error> {
+/// Reads the rows at `addresses`, in the given order, and hands every matching one to `visit`,
+/// stopping as soon as `visit` returns false.
+pub async fn fetch_matches<I: IntoIterator<Item = u64>, F: FnMut(Vec<AlbaTypes>, u64) -> Result<bool, Error>>(container: &Arc<RwLock<Container>>, args: &SearchArguments, addresses: I, mut visit: F) -> Result<(), Error> {
     let element_size = args.element_size;
     let header_offset = args.header_offset;
 
     let file = args.file.read().await;
     let container = container.read().await;
-    let mut result: Vec<(Vec<AlbaTypes>, u64)> = Vec::new();
-
-    for &row_address in address {
-        let mut buffer = vec![0u8; element_size];
-        file.read_exact_at(&mut buffer, ((row_address * element_size as u64) + header_offset as u64) as u64)?;
-        
-        let row_content = match container.deserialize_row(&buffer).await {
-            Ok(row_content) => {
-                let mut data: HashMap<String, AlbaTypes> = HashMap::new();
-                for (index, value) in container.headers.iter().enumerate() {
-                    let column_value = match row_content.get(index) {
-                        Some(a) => {
-                            let cv = a.to_owned();
-                            if std::mem::discriminant(&cv) != std::mem::discriminant(&value.1) {
-                                return Err(gerr("Invalid alba type row order, unmatching stuff"));
-                            }
-                            cv
-                        }
-                        None => {
-                            return Err(gerr("Invalid alba type row order, missing stuff"));
-                        }
-                    };
-                    data.insert(value.0.clone(), column_value);
-                }
-                let row = Row {
-                    data
-                };
-
-                if args.conditions.row_match(&row)? {
-                    Some(row_content)
-                } else {
-                    None
-                }
-            }
-            Err(e) => {
-                return Err(e);
+    let mvcc = container.mvcc.read().await;
+    let changes = args.pending.map(|session| changes_of(&mvcc, &session));
+    let mut buffer = vec![0u8; element_size];
+    // the addresses up to the keyset were handed out by an earlier page
+    for row_address in addresses.into_iter().filter(|address| !args.skips(*address)) {
+        let row = match changes.and_then(|changes| changes.0.get(&row_address).map(|row| (changes, row))) {
+            Some((changes, row)) => match_pending(&container, args, &changes.1, row)?,
+            _ => {
+                file.read_exact_at(&mut buffer, (row_address * element_size as u64) + header_offset as u64)?;
+                args.counters.read(element_size as u64);
+                match_row(&container, args, &buffer).await?
             }
         };
+        if let Some(row) = row
+            && args.follows(&row, row_address)
+            && !visit(row, row_address)? {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
 
-        if let Some(content) = row_content {
-            result.push((content, row_address));
+/// Builds the pages of an `ORDER BY` from index `entries` already in key order (reversed when the
+/// first sort key is descending). Rows sharing an index key are the only ones sorted in memory,
+/// and no key group is read once `pages` holds every row the window asks for.
+pub async fn index_ordered_search(container: Arc<RwLock<Container>>, args: SearchArguments, entries: Vec<(u64, u64)>, order: &[SortKey], pages: &mut PageBuilder) -> Result<(), Error> {
+    let positions = key_positions(&args.container_values, order)?;
+    let mut groups: Vec<&[(u64, u64)]> = entries.chunk_by(|a, b| a.0 == b.0).collect();
+    let descending = order.first().is_some_and(|k| k.descending);
+    if descending {
+        groups.reverse();
+    }
+    // a resumed search starts at the key group of its keyset, the ones before it are not read
+    if let Some(after) = &args.after
+        && let Some(key) = after.keyset.keys.first().map(|key| key.get_index()) {
+        let start = groups.partition_point(|group| if descending { group[0].0 > key } else { group[0].0 < key });
+        groups.drain(..start);
+    }
+    for group in groups {
+        if pages.is_complete() {
+            break;
+        }
+        let mut rows: Vec<(Vec<AlbaTypes>, u64)> = Vec::with_capacity(group.len());
+        let addresses: Vec<u64> = group.iter().map(|e| e.1).collect();
+        fetch_matches(&container, &args, addresses, |row, address| {
+            let keys = extract_keys(&row, &positions);
+            // an entry left behind by an edit points to a row that no longer holds its key
+            if keys.first().is_none_or(|key| key.get_index() == group[0].0) {
+                rows.push((keys, address));
+            }
+            Ok(true)
+        }).await?;
+        rows.sort_by(|a, b| compare_keys(&a.0, &b.0, order).then(a.1.cmp(&b.1)));
+        for (_, address) in rows {
+            if !pages.push(address, &args.container_name) {
+                break;
+            }
         }
     }
+    Ok(())
+}
 
-    Ok(result)
-}
\ No newline at end of file
+/// `LIMIT` and `OFFSET` of a search.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct QueryWindow {
+    pub limit: Option<u64>,
+    pub offset: u64,
+}
+
+impl QueryWindow {
+    /// Rows that have to be produced before the window is complete, `None` without a limit.
+    pub fn wanted_rows(&self) -> Option<u64> {
+        self.limit.map(|limit| limit.saturating_add(self.offset))
+    }
+}
+
+/// Fills the pages of a query one row address at a time, skipping the `OFFSET` rows and
+/// telling the producer to stop once the `LIMIT` is reached.
+pub struct PageBuilder {
+    query: Query,
+    window: QueryWindow,
+    seen: u64,
+    described: bool,
+}
+
+impl PageBuilder {
+    pub fn new(window: QueryWindow, page_size: usize, projection: Projection) -> Self {
+        let mut query = Query::new(Vec::new());
+        query.page_size = page_size;
+        query.projections = vec![projection];
+        PageBuilder { query, window, seen: 0, described: false }
+    }
+    /// Makes the pages hold the pairs of a JOIN with rows of `container`.
+    pub fn joined(mut self, container: &str) -> Self {
+        self.query.joined = Some(container.to_string());
+        self
+    }
+    /// Checks the projection against the headers of a source. The first source decides the
+    /// column names and types of the result.
+    pub fn columns(&mut self, headers: &[(String, AlbaTypes)], container_name: &str) -> Result<(), Error> {
+        let resolved = self.query.projections[0].resolve(headers, container_name)?;
+        if !self.described {
+            self.query.column_names = resolved.names;
+            self.query.column_types = resolved.types;
+            self.query.trim();
+            self.described = true;
+        }
+        Ok(())
+    }
+    pub fn is_complete(&self) -> bool {
+        self.window.wanted_rows().is_some_and(|wanted| self.seen >= wanted)
+    }
+    /// Adds the next row in result order, returns false once no more rows are wanted.
+    pub fn push(&mut self, address: u64, container_name: &str) -> bool {
+        self.push_row(|query| query.push_address(address, container_name, 0))
+    }
+    /// Adds the next pair of a JOIN in result order, returns false once no more rows are wanted.
+    pub fn push_pair(&mut self, left: u64, right: u64, container_name: &str) -> bool {
+        self.push_row(|query| query.push_pair(left, right, container_name))
+    }
+    fn push_row(&mut self, add: impl FnOnce(&mut Query)) -> bool {
+        if self.is_complete() {
+            return false;
+        }
+        self.seen += 1;
+        if self.seen > self.window.offset {
+            add(&mut self.query);
+        }
+        !self.is_complete()
+    }
+    pub fn finish(self) -> Query {
+        self.query
+    }
+}
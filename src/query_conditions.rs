@@ -1,9 +1,11 @@
-use std::{collections::HashMap, io::{self, Error, ErrorKind},mem::discriminant, ops::{Range, RangeInclusive}};
+use std::{cmp::Ordering, collections::HashMap, io::{self, Error, ErrorKind}, ops::RangeInclusive};
 
-use ahash::AHashMap;
 use regex::Regex;
 
-use crate::{alba_types::AlbaTypes, gerr, indexing::GetIndex, lexer_functions::Token, query::PrimitiveQueryConditions, row::Row};
+use crate::{alba_types::{get_string_from_alba_type, AlbaTypes}, expression::BoundExpression, gerr, indexing::GetIndex, lexer_functions::Token, query::{PrimitivePredicate, PrimitiveQueryConditions}, row::Row};
+
+/// Values of an IN list shown by `describe` before the rest are counted instead.
+const DESCRIBED_IN_VALUES : usize = 10;
 
 
 fn string_to_char(s: String) -> Result<char, io::Error> {
@@ -15,22 +17,33 @@ fn string_to_char(s: String) -> Result<char, io::Error> {
     }
 }
 
-#[derive(Clone, Copy)]
-enum LogicalGate{
-    And,
-    Or,
+#[derive(Clone)]
+pub struct QueryConditionAtom{
+    pub column : String,
+    pub operator : Operator,
+    pub value : AlbaTypes,
+    /// Operands of IN (the list) and BETWEEN (low and high).
+    pub values : Vec<AlbaTypes>,
 }
 
+/// Typed boolean expression over the columns of a container. AND binds tighter than OR,
+/// children are evaluated left to right and stop as soon as the result is known.
 #[derive(Clone)]
-pub struct QueryConditionAtom{
-    column : String,
-    operator : Operator,
-    value : AlbaTypes,
+pub enum Predicate{
+    Atom(QueryConditionAtom),
+    /// Two computed values, or two columns, compared row by row. It can not drive an index.
+    Compare(BoundExpression, Operator, BoundExpression),
+    /// The value of a subquery that does not depend on the row, like an uncorrelated `EXISTS`.
+    Constant(bool),
+    Not(Box<Predicate>),
+    And(Vec<Predicate>),
+    Or(Vec<Predicate>),
 }
+
 #[derive(Clone,Default)]
 pub struct QueryConditions{
     primary_key : Option<String>,
-    chain : Vec<(QueryConditionAtom,Option<LogicalGate>)>
+    predicate : Option<Predicate>
 }
 
 fn gather_regex<'a>(regex_map: &'a mut HashMap<String, Regex>, key: String) -> Result<&'a Regex, Error> {
@@ -45,10 +58,12 @@ fn gather_regex<'a>(regex_map: &'a mut HashMap<String, Regex>, key: String) -> R
     Ok(regex_map.get(&key).unwrap())
 }
 
+#[derive(Clone, Debug, PartialEq)]
 pub enum QueryIndexType {
     Strict(u64),
-    Range(Range<u64>),
     InclusiveRange(RangeInclusive<u64>), 
+    /// Sorted, deduplicated keys, one lookup each.
+    Set(Vec<u64>),
 }
 
 pub enum QueryType{
@@ -56,8 +71,15 @@ pub enum QueryType{
     Indexed(QueryIndexType),
 }
 
-#[derive(Clone)]
-enum Operator{
+/// An index lookup that returns a superset of the rows matching the conditions on `column`.
+#[derive(Clone, Debug)]
+pub struct IndexCandidate{
+    pub column : String,
+    pub lookup : QueryIndexType,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operator{
     Equal,
     StrictEqual,
     Greater,
@@ -67,528 +89,557 @@ enum Operator{
     Different,
     StringContains,
     StringCaseInsensitiveContains,
-    StringRegularExpression
+    StringRegularExpression,
+    In,
+    Between,
+    IsNull,
+    Like,
+    StartsWith,
+    EndsWith
 }
 
-impl QueryConditions{
-    pub fn from_primitive_conditions(primitive_conditions : PrimitiveQueryConditions, column_properties : &HashMap<String,AlbaTypes>,primary_key : String) -> Result<Self,Error>{
-        let mut chain : Vec<(QueryConditionAtom,Option<LogicalGate>)> = Vec::new();
-        let condition_chunk = primitive_conditions.0;
-        let condition_logical_gates_vec = primitive_conditions.1;
-        let mut condition_logical_gates = AHashMap::new();
-        for i in condition_logical_gates_vec{
-            condition_logical_gates.insert(i.0, match i.1{
-                'a'|'A' => LogicalGate::And,
-                'o'|'O' => LogicalGate::Or,
-                _ => return  Err(gerr("Failed to load LogicalGate, invalid token."))
-            });
+impl Operator{
+    pub fn symbol(&self) -> &'static str{
+        match self{
+            Operator::Equal => "=",
+            Operator::StrictEqual => "==",
+            Operator::Greater => ">",
+            Operator::Lower => "<",
+            Operator::GreaterEquality => ">=",
+            Operator::LowerEquality => "<=",
+            Operator::Different => "!=",
+            Operator::StringContains => "&>",
+            Operator::StringCaseInsensitiveContains => "&&>",
+            Operator::StringRegularExpression => "&&&>",
+            Operator::In => "IN",
+            Operator::Between => "BETWEEN",
+            Operator::IsNull => "IS NULL",
+            Operator::Like => "LIKE",
+            Operator::StartsWith => "STARTS WITH",
+            Operator::EndsWith => "ENDS WITH",
         }
-        for (index,value) in condition_chunk.iter().enumerate(){
-            let value = value.to_owned();
-            
-            let column = if let Token::String(name) = value.0{
-                name
-            }else{
-                return Err(gerr("Failed to get QueryConditions, but failed to gather the column_name."))
-            };
-            
-            let operator = if let Token::Operator(operator_name) = value.1{
-                match operator_name.as_str(){
-                    "=" => Operator::Equal,
-                    "==" => Operator::StrictEqual,
-                    ">=" => Operator::GreaterEquality,
-                    "<=" => Operator::LowerEquality,
-                    ">" => Operator::Greater,
-                    "<" => Operator::Lower,
-                    "!=" => Operator::Different,
-                    "&>" => Operator::StringContains,
-                    "&&>" => Operator::StringCaseInsensitiveContains,
-                    "&&&>" => Operator::StringRegularExpression,
-                    _ => {
-                        return Err(gerr("Failed to get operator, invalid token contant."))
+    }
+}
+
+fn column_name(column : Token) -> Result<String,Error>{
+    if let Token::String(name) = column{
+        Ok(name)
+    }else{
+        Err(gerr("Failed to get QueryConditions, but failed to gather the column_name."))
+    }
+}
+
+fn comparison_operator(operator : Token) -> Result<Operator,Error>{
+    if let Token::Operator(operator_name) = operator{
+        Ok(match operator_name.as_str(){
+            "=" => Operator::Equal,
+            "==" => Operator::StrictEqual,
+            ">=" => Operator::GreaterEquality,
+            "<=" => Operator::LowerEquality,
+            ">" => Operator::Greater,
+            "<" => Operator::Lower,
+            "!=" => Operator::Different,
+            "&>" => Operator::StringContains,
+            "&&>" => Operator::StringCaseInsensitiveContains,
+            "&&&>" => Operator::StringRegularExpression,
+            "LIKE" => Operator::Like,
+            "STARTS WITH" => Operator::StartsWith,
+            "ENDS WITH" => Operator::EndsWith,
+            "IS NULL" => Operator::IsNull,
+            _ => {
+                return Err(gerr("Failed to get operator, invalid token contant."))
+            }
+        })
+    }else{
+        Err(gerr("Failed to get operator, invalid token,"))
+    }
+}
+
+impl QueryConditionAtom{
+    fn from_tokens(column : Token,operator : Token,value : Token,column_properties : &HashMap<String,AlbaTypes>) -> Result<Self,Error>{
+        let column = column_name(column)?;
+        let operator = comparison_operator(operator)?;
+        let value = typed_value(&column, value, column_properties)?;
+        Ok(QueryConditionAtom{column,operator,value,values:Vec::new()})
+    }
+    fn in_list(column : Token,list : Vec<Token>,column_properties : &HashMap<String,AlbaTypes>) -> Result<Self,Error>{
+        let column = column_name(column)?;
+        let values = list.into_iter().map(|v| typed_value(&column, v, column_properties)).collect::<Result<Vec<AlbaTypes>,Error>>()?;
+        Ok(QueryConditionAtom{column,operator:Operator::In,value:AlbaTypes::NONE,values:sorted_set(values)})
+    }
+    /// IN over the values a subquery returned. They are converted to the type of the column, and
+    /// the ones that change on the way, like 1.5 for an INT column, can not be equal to any of its values.
+    fn in_values(column : Token,list : Vec<AlbaTypes>,column_properties : &HashMap<String,AlbaTypes>) -> Result<Self,Error>{
+        let column = column_name(column)?;
+        let column_type = match column_properties.get(&column){
+            Some(column_type) => column_type,
+            None => return Err(gerr(&format!("There is no column {}",column)))
+        };
+        let mut values = Vec::with_capacity(list.len());
+        for value in list{
+            if !column_type.is_comparable_with(&value){
+                return Err(gerr(&format!("The subquery of {} IN returns {:?} values, which can not be compared with the column",column,value)))
+            }
+            if let Ok(typed) = column_type.try_from_existing(value.clone()) && typed.total_cmp(&value) == Ordering::Equal{
+                values.push(typed);
+            }
+        }
+        Ok(QueryConditionAtom{column,operator:Operator::In,value:AlbaTypes::NONE,values:sorted_set(values)})
+    }
+    fn between(column : Token,low : Token,high : Token,column_properties : &HashMap<String,AlbaTypes>) -> Result<Self,Error>{
+        let column = column_name(column)?;
+        let values = vec![typed_value(&column, low, column_properties)?, typed_value(&column, high, column_properties)?];
+        Ok(QueryConditionAtom{column,operator:Operator::Between,value:AlbaTypes::NONE,values})
+    }
+    fn is_null(column : Token) -> Result<Self,Error>{
+        Ok(QueryConditionAtom{column:column_name(column)?,operator:Operator::IsNull,value:AlbaTypes::NONE,values:Vec::new()})
+    }
+}
+
+/// The values sorted and without repeats, so IN can binary search them.
+fn sorted_set(mut values : Vec<AlbaTypes>) -> Vec<AlbaTypes>{
+    values.sort_by(|a, b| a.total_cmp(b));
+    values.dedup_by(|a, b| a.total_cmp(b) == Ordering::Equal);
+    values
+}
+
+/// Converts a condition value to the type of its column.
+fn typed_value(column : &str,value : Token,column_properties : &HashMap<String,AlbaTypes>) -> Result<AlbaTypes,Error>{
+    let column_value = if let Some(column_type) = column_properties.get(column){
+        match column_type{
+            AlbaTypes::Text(_) => {
+                if let Token::String(string) = value{
+                    AlbaTypes::Text(string)
+                }else {
+                    return Err(gerr("No string found in the ComparisionToken"))
+                }
+            },
+            AlbaTypes::Int(_) => {
+                if let Token::Int(number) = value{
+                    // a literal out of the range of the column is still compared by its value
+                    match i32::try_from(number){
+                        Ok(number) => AlbaTypes::Int(number),
+                        Err(_) => AlbaTypes::Bigint(number)
                     }
+                }else {
+                    return Err(gerr("No integer found in the ComparisionToken"))
                 }
-            }else{
-                return Err(gerr("Failed to get operator, invalid token,"))
-            };
+            },
+            AlbaTypes::Bigint(_) => {
+                if let Token::Int(number) = value{
+                    AlbaTypes::Bigint(number)
+                }else {
+                    return Err(gerr("No integer found in the ComparisionToken"))
+                }
+            },
+            AlbaTypes::Float(_) => {
+                if let Token::Float(number) = value{
+                    AlbaTypes::Float(number)
+                }else {
+                    return Err(gerr("No float found in the ComparisionToken"))
+                }
+            },
+            AlbaTypes::Bool(_) => {
+                if let Token::Bool(bool) = value{
+                    AlbaTypes::Bool(bool)
+                }else {
+                    return Err(gerr("No bool found in the ComparisionToken"))
+                }
+            },
+            AlbaTypes::Char(_) => {
+                if let Token::String(char) = value{
+                    AlbaTypes::Char(string_to_char(char)?)
+                }else {
+                    return Err(gerr("No char found in the ComparisionToken"))
+                }
+            },
+            AlbaTypes::NanoString(_) => {
+                if let Token::String(mut nano_string) = value{
+                    nano_string.truncate(10);
+                    AlbaTypes::NanoString(nano_string)
+                }else {
+                    return Err(gerr("No nano_string found in the ComparisionToken"))
+                }
+            },
+            AlbaTypes::SmallString(_) => {
+                if let Token::String(mut small_string) = value{
+                    small_string.truncate(100);
+                    AlbaTypes::SmallString(small_string)
+                }else {
+                    return Err(gerr("No small_string found in the ComparisionToken"))
+                }
+            },
+            AlbaTypes::MediumString(_) => {
+                if let Token::String(mut medium_string) = value{
+                    medium_string.truncate(500);
+                    AlbaTypes::MediumString(medium_string)
+                }else {
+                    return Err(gerr("No medium_string found in the ComparisionToken"))
+                }
+            },
+            AlbaTypes::BigString(_) => {
+                if let Token::String(mut big_string) = value{
+                    big_string.truncate(2000);
+                    AlbaTypes::BigString(big_string)
+                }else {
+                    return Err(gerr("No big_string found in the ComparisionToken"))
+                }
+            },
+            AlbaTypes::LargeString(_) => {
+                if let Token::String(mut large_string) = value{
+                    large_string.truncate(3000);
+                    AlbaTypes::LargeString(large_string)
+                }else {
+                    return Err(gerr("No large_string found in the ComparisionToken"))
+                }
+            },
+            AlbaTypes::NanoBytes(_) => {
+                if let Token::Bytes(mut nano_bytes) = value{
+                    nano_bytes.truncate(10);
+                    AlbaTypes::NanoBytes(nano_bytes)
+                }else {
+                    return Err(gerr("No nano_bytes found in the ComparisionToken"))
+                }
+            },
+            AlbaTypes::SmallBytes(_) => {
+                if let Token::Bytes(mut small_bytes) = value{
+                    small_bytes.truncate(1000);
+                    AlbaTypes::SmallBytes(small_bytes)
+                }else {
+                    return Err(gerr("No small_bytes found in the ComparisionToken"))
+                }
+            },
+            AlbaTypes::MediumBytes(_) => {
+                if let Token::Bytes(mut medium_bytes) = value{
+                    medium_bytes.truncate(10000);
+                    AlbaTypes::MediumBytes(medium_bytes)
+                }else {
+                    return Err(gerr("No medium_bytes found in the ComparisionToken"))
+                }
+            },
+            AlbaTypes::BigSBytes(_) => {
+                if let Token::Bytes(mut big_bytes) = value{
+                    big_bytes.truncate(100000);
+                    AlbaTypes::BigSBytes(big_bytes)
+                }else {
+                    return Err(gerr("No big_bytes found in the ComparisionToken"))
+                }
+            },
+            AlbaTypes::LargeBytes(_) => {
+                if let Token::Bytes(mut large_bytes) = value{
+                    large_bytes.truncate(1000000);
+                    AlbaTypes::LargeBytes(large_bytes)
+                }else {
+                    return Err(gerr("No large_bytes found in the ComparisionToken"))
+                }
+            },
+            AlbaTypes::NONE => {
+                return Err(gerr("Failed to extract the value from the column_properties"))
+            },
+        } 
+    }else{
+        return Err(gerr("Failed to generate QueryConditions, that happened because no column_property has been found with the given column-names"))
+    };
+    Ok(column_value)
+}
 
-            let column_value = if let Some(column_type) = column_properties.get(&column){
-                match column_type{
-                    AlbaTypes::Text(_) => {
-                        if let Token::String(string) = value.2{
-                            AlbaTypes::Text(string)
-                        }else {
-                            return Err(gerr("No string found in the ComparisionToken"))
-                        }
-                    },
-                    AlbaTypes::Int(_) => {
-                        if let Token::Int(number) = value.2{
-                            AlbaTypes::Int(number as i32)
-                        }else {
-                            return Err(gerr("No integer found in the ComparisionToken"))
-                        }
-                    },
-                    AlbaTypes::Bigint(_) => {
-                        if let Token::Int(number) = value.2{
-                            AlbaTypes::Bigint(number)
-                        }else {
-                            return Err(gerr("No integer found in the ComparisionToken"))
-                        }
-                    },
-                    AlbaTypes::Float(_) => {
-                        if let Token::Float(number) = value.2{
-                            AlbaTypes::Float(number)
-                        }else {
-                            return Err(gerr("No float found in the ComparisionToken"))
-                        }
-                    },
-                    AlbaTypes::Bool(_) => {
-                        if let Token::Bool(bool) = value.2{
-                            AlbaTypes::Bool(bool)
-                        }else {
-                            return Err(gerr("No bool found in the ComparisionToken"))
-                        }
-                    },
-                    AlbaTypes::Char(_) => {
-                        if let Token::String(char) = value.2{
-                            AlbaTypes::Char(string_to_char(char)?)
-                        }else {
-                            return Err(gerr("No char found in the ComparisionToken"))
-                        }
-                    },
-                    AlbaTypes::NanoString(_) => {
-                        if let Token::String(mut nano_string) = value.2{
-                            nano_string.truncate(10);
-                            AlbaTypes::NanoString(nano_string)
-                        }else {
-                            return Err(gerr("No nano_string found in the ComparisionToken"))
-                        }
-                    },
-                    AlbaTypes::SmallString(_) => {
-                        if let Token::String(mut small_string) = value.2{
-                            small_string.truncate(100);
-                            AlbaTypes::SmallString(small_string)
-                        }else {
-                            return Err(gerr("No small_string found in the ComparisionToken"))
-                        }
-                    },
-                    AlbaTypes::MediumString(_) => {
-                        if let Token::String(mut medium_string) = value.2{
-                            medium_string.truncate(500);
-                            AlbaTypes::SmallString(medium_string)
-                        }else {
-                            return Err(gerr("No medium_string found in the ComparisionToken"))
-                        }
-                    },
-                    AlbaTypes::BigString(_) => {
-                        if let Token::String(mut big_string) = value.2{
-                            big_string.truncate(2000);
-                            AlbaTypes::SmallString(big_string)
-                        }else {
-                            return Err(gerr("No big_string found in the ComparisionToken"))
-                        }
-                    },
-                    AlbaTypes::LargeString(_) => {
-                        if let Token::String(mut large_string) = value.2{
-                            large_string.truncate(3000);
-                            AlbaTypes::SmallString(large_string)
-                        }else {
-                            return Err(gerr("No large_string found in the ComparisionToken"))
-                        }
-                    },
-                    AlbaTypes::NanoBytes(_) => {
-                        if let Token::Bytes(mut nano_bytes) = value.2{
-                            nano_bytes.truncate(10);
-                            AlbaTypes::NanoBytes(nano_bytes)
-                        }else {
-                            return Err(gerr("No nano_bytes found in the ComparisionToken"))
-                        }
-                    },
-                    AlbaTypes::SmallBytes(_) => {
-                        if let Token::Bytes(mut small_bytes) = value.2{
-                            small_bytes.truncate(1000);
-                            AlbaTypes::SmallBytes(small_bytes)
-                        }else {
-                            return Err(gerr("No small_bytes found in the ComparisionToken"))
-                        }
-                    },
-                    AlbaTypes::MediumBytes(_) => {
-                        if let Token::Bytes(mut medium_bytes) = value.2{
-                            medium_bytes.truncate(10000);
-                            AlbaTypes::MediumBytes(medium_bytes)
-                        }else {
-                            return Err(gerr("No medium_bytes found in the ComparisionToken"))
-                        }
-                    },
-                    AlbaTypes::BigSBytes(_) => {
-                        if let Token::Bytes(mut big_bytes) = value.2{
-                            big_bytes.truncate(100000);
-                            AlbaTypes::BigSBytes(big_bytes)
-                        }else {
-                            return Err(gerr("No big_bytes found in the ComparisionToken"))
-                        }
-                    },
-                    AlbaTypes::LargeBytes(_) => {
-                        if let Token::Bytes(mut large_bytes) = value.2{
-                            large_bytes.truncate(1000000);
-                            AlbaTypes::BigSBytes(large_bytes)
-                        }else {
-                            return Err(gerr("No large_bytes found in the ComparisionToken"))
-                        }
-                    },
-                    AlbaTypes::NONE => {
-                        return Err(gerr("Failed to extract the value from the column_properties"))
-                    },
-                } 
-            }else{
-                return Err(gerr("Failed to generate QueryConditions, that happened because no column_property has been found with the given column-names"))
-            };
+impl QueryConditionAtom{
+    /// Evaluates `row[column] OPERATOR value`.
+    fn matches(&self,row : &Row,regex_cache : &mut HashMap<String,Regex>) -> Result<bool,Error>{
+        let value = if let Some(val) = row.data.get(&self.column){
+            val
+        }else{
+            return Err(gerr("No value found to that column name"))
+        };
+        match self.operator{
+            Operator::In => Ok(self.values.binary_search_by(|v| v.total_cmp(value)).is_ok()),
+            Operator::Between => Ok(value.total_cmp(&self.values[0]) != Ordering::Less && value.total_cmp(&self.values[1]) != Ordering::Greater),
+            _ => compare(&self.operator, value, &self.value, regex_cache)
+        }
+    }
+
+    fn describe(&self) -> String{
+        let text = |value : &AlbaTypes| match value{
+            AlbaTypes::NONE => "NONE".to_string(),
+            v => get_string_from_alba_type(v.clone()).unwrap_or_default()
+        };
+        match self.operator{
+            // the list of a subquery can be long, only its start is shown
+            Operator::In if self.values.len() > DESCRIBED_IN_VALUES => format!("{} IN ({}, ... {} values)",self.column,self.values[..DESCRIBED_IN_VALUES].iter().map(text).collect::<Vec<String>>().join(", "),self.values.len()),
+            Operator::In => format!("{} IN ({})",self.column,self.values.iter().map(text).collect::<Vec<String>>().join(", ")),
+            Operator::Between => format!("{} BETWEEN {} AND {}",self.column,text(&self.values[0]),text(&self.values[1])),
+            Operator::IsNull => format!("{} IS NULL",self.column),
+            _ => format!("{} {} {}",self.column,self.operator.symbol(),text(&self.value))
+        }
+    }
+}
 
-            let gate = condition_logical_gates
-                .get(&index)
-                .map(|a| a.clone());
+/// `value OPERATOR target` for the operators comparing two single values.
+fn compare(operator : &Operator,value : &AlbaTypes,target : &AlbaTypes,regex_cache : &mut HashMap<String,Regex>) -> Result<bool,Error>{
+    Ok(match operator{
+        Operator::Equal => value.total_cmp(target) == Ordering::Equal,
+        Operator::StrictEqual => *target == *value,
+        Operator::Different => value.total_cmp(target) != Ordering::Equal,
+        Operator::Greater => value.total_cmp(target) == Ordering::Greater,
+        Operator::Lower => value.total_cmp(target) == Ordering::Less,
+        Operator::GreaterEquality => value.total_cmp(target) != Ordering::Less,
+        Operator::LowerEquality => value.total_cmp(target) != Ordering::Greater,
+        Operator::StringContains => {
+            let (val,s) = (string_of(value)?,string_of(target)?);
+            val.contains(s.as_str())
+        },
+        Operator::StringCaseInsensitiveContains => {
+            let (val,s) = (string_of(value)?,string_of(target)?);
+            val.to_lowercase().contains(&s.to_lowercase())
+        },
+        Operator::StringRegularExpression => {
+            let (val,s) = (string_of(value)?,string_of(target)?);
+            let reg = gather_regex(regex_cache, s.clone())?;
+            reg.is_match(val)
+        },
+        Operator::IsNull => matches!(value, AlbaTypes::NONE),
+        Operator::Like => {
+            let (val,pattern) = (string_of(value)?,string_of(target)?);
+            like_match(&val.chars().collect::<Vec<char>>(), &pattern.chars().collect::<Vec<char>>())
+        },
+        Operator::StartsWith => string_of(value)?.starts_with(string_of(target)?.as_str()),
+        Operator::EndsWith => string_of(value)?.ends_with(string_of(target)?.as_str()),
+        Operator::In | Operator::Between => return Err(gerr(&format!("{} compares a value with a list",operator.symbol()))),
+    })
+}
+
+/// SQL LIKE: `%` matches any run of characters, `_` exactly one.
+fn like_match(text : &[char],pattern : &[char]) -> bool{
+    let (mut t, mut p) = (0, 0);
+    let mut backtrack : Option<(usize,usize)> = None;
+    while t < text.len(){
+        if p < pattern.len() && (pattern[p] == '_' || pattern[p] == text[t]){
+            t += 1;
+            p += 1;
+        }else if p < pattern.len() && pattern[p] == '%'{
+            backtrack = Some((p, t));
+            p += 1;
+        }else if let Some((star, matched)) = backtrack{
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        }else{
+            return false
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '%')
+}
+
+fn string_of(value : &AlbaTypes) -> Result<&String,Error>{
+    match value{
+        AlbaTypes::NanoString(s)|AlbaTypes::SmallString(s)|AlbaTypes::MediumString(s)|AlbaTypes::BigString(s)|AlbaTypes::LargeString(s)|AlbaTypes::Text(s) => Ok(s),
+        _ => Err(gerr("Invalid row type"))
+    }
+}
+
+/// Types whose index keys keep the order of the values, so a range of keys covers a range of values.
+pub fn is_range_indexable(value : &AlbaTypes) -> bool{
+    matches!(value, AlbaTypes::Int(_) | AlbaTypes::Bigint(_) | AlbaTypes::Float(_) | AlbaTypes::Char(_) | AlbaTypes::Bool(_))
+}
 
-            chain.push((QueryConditionAtom{column,operator,value:column_value},gate));
+impl Predicate{
+    pub fn from_primitive(primitive : PrimitivePredicate,column_properties : &HashMap<String,AlbaTypes>) -> Result<Self,Error>{
+        let children = |list : Vec<PrimitivePredicate>| list.into_iter().map(|p| Predicate::from_primitive(p, column_properties)).collect::<Result<Vec<Predicate>,Error>>();
+        Ok(match primitive{
+            PrimitivePredicate::Comparison(column, operator, value) => Predicate::Atom(QueryConditionAtom::from_tokens(column, operator, value, column_properties)?),
+            PrimitivePredicate::Compare(left, operator, right) => {
+                let (left, operator, right) = (left.bind(column_properties)?, comparison_operator(operator)?, right.bind(column_properties)?);
+                let (a, b) = (&left.result_type, &right.result_type);
+                let known = !matches!(a, AlbaTypes::NONE) && !matches!(b, AlbaTypes::NONE);
+                if known && !a.is_comparable_with(b){
+                    return Err(gerr(&format!("Can not compare {} with {}, their types differ",left.describe(),right.describe())))
+                }
+                Predicate::Compare(left, operator, right)
+            },
+            PrimitivePredicate::In(column, list) => Predicate::Atom(QueryConditionAtom::in_list(column, list, column_properties)?),
+            PrimitivePredicate::Between(column, low, high) => Predicate::Atom(QueryConditionAtom::between(column, low, high, column_properties)?),
+            PrimitivePredicate::IsNull(column) => Predicate::Atom(QueryConditionAtom::is_null(column)?),
+            PrimitivePredicate::InValues(column, list) => Predicate::Atom(QueryConditionAtom::in_values(column, list, column_properties)?),
+            PrimitivePredicate::Constant(value) => Predicate::Constant(value),
+            PrimitivePredicate::InSearch(..) | PrimitivePredicate::Exists(_) => return Err(gerr("Subqueries are not supported in these conditions")),
+            PrimitivePredicate::Not(inner) => Predicate::Not(Box::new(Predicate::from_primitive(*inner, column_properties)?)),
+            PrimitivePredicate::And(list) => Predicate::And(children(list)?),
+            PrimitivePredicate::Or(list) => Predicate::Or(children(list)?),
+        })
+    }
+    pub fn matches(&self,row : &Row,regex_cache : &mut HashMap<String,Regex>) -> Result<bool,Error>{
+        match self{
+            Predicate::Atom(atom) => atom.matches(row, regex_cache),
+            Predicate::Compare(left, operator, right) => {
+                let (value, target) = (left.evaluate(row, regex_cache)?, right.evaluate(row, regex_cache)?);
+                compare(operator, &value, &target, regex_cache)
+            },
+            Predicate::Constant(value) => Ok(*value),
+            Predicate::Not(inner) => Ok(!inner.matches(row, regex_cache)?),
+            Predicate::And(list) => {
+                for predicate in list{
+                    if !predicate.matches(row, regex_cache)?{
+                        return Ok(false)
+                    }
+                }
+                Ok(true)
+            },
+            Predicate::Or(list) => {
+                for predicate in list{
+                    if predicate.matches(row, regex_cache)?{
+                        return Ok(true)
+                    }
+                }
+                Ok(false)
+            }
+        }
+    }
+    /// The predicate as it would be written, with parentheses only where precedence needs them.
+    pub fn describe(&self) -> String{
+        match self{
+            Predicate::Atom(atom) => atom.describe(),
+            Predicate::Compare(left, Operator::IsNull, _) => format!("{} IS NULL",left.describe()),
+            Predicate::Compare(left, operator, right) => format!("{} {} {}",left.describe(),operator.symbol(),right.describe()),
+            Predicate::Constant(value) => if *value { "TRUE".to_string() } else { "FALSE".to_string() },
+            Predicate::Not(inner) => match **inner{
+                Predicate::Atom(_) | Predicate::Compare(..) | Predicate::Constant(_) | Predicate::Not(_) => format!("NOT {}",inner.describe()),
+                _ => format!("NOT ({})",inner.describe())
+            },
+            Predicate::And(list) => list.iter().map(|p| match p{
+                Predicate::Or(_) => format!("({})",p.describe()),
+                _ => p.describe()
+            }).collect::<Vec<String>>().join(" AND "),
+            Predicate::Or(list) => list.iter().map(|p| p.describe()).collect::<Vec<String>>().join(" OR "),
         }
-        return Ok(QueryConditions { chain, primary_key : Some(primary_key)})
+    }
+    pub fn columns<'a>(&'a self,output : &mut Vec<&'a str>){
+        match self{
+            Predicate::Atom(atom) => output.push(&atom.column),
+            Predicate::Compare(left, _, right) => { left.columns(output); right.columns(output); },
+            Predicate::Constant(_) => {},
+            Predicate::Not(inner) => inner.columns(output),
+            Predicate::And(list) | Predicate::Or(list) => list.iter().for_each(|p| p.columns(output)),
+        }
+    }
+    fn conjuncts<'a>(&'a self,output : &mut Vec<&'a Predicate>){
+        match self{
+            Predicate::And(list) => list.iter().for_each(|p| p.conjuncts(output)),
+            _ => output.push(self)
+        }
+    }
+}
+
+impl QueryConditions{
+    pub fn from_primitive_conditions(primitive_conditions : PrimitiveQueryConditions, column_properties : &HashMap<String,AlbaTypes>,primary_key : String) -> Result<Self,Error>{
+        let predicate = match primitive_conditions{
+            Some(primitive) => Some(Predicate::from_primitive(primitive, column_properties)?),
+            None => None
+        };
+        return Ok(QueryConditions { predicate, primary_key : Some(primary_key)})
     }
     pub fn row_match(&self,row : &Row) -> Result<bool,Error>{
-        if self.chain.is_empty(){
-            return Ok(true)
+        match &self.predicate{
+            None => Ok(true),
+            Some(predicate) => {
+                let mut regex_cache : HashMap<String,Regex> = HashMap::new();
+                predicate.matches(row, &mut regex_cache)
+            }
+        }
+    }
+    pub fn predicate(&self) -> Option<&Predicate>{
+        self.predicate.as_ref()
+    }
+    /// Every column the conditions read.
+    pub fn columns(&self) -> Vec<&str>{
+        let mut columns = Vec::new();
+        if let Some(predicate) = &self.predicate{
+            predicate.columns(&mut columns);
+        }
+        columns
+    }
+    /// The top level terms joined by AND. Every row returned must satisfy each one of them,
+    /// which makes them the only terms able to drive an index.
+    pub fn conjuncts(&self) -> Vec<&Predicate>{
+        let mut conjuncts = Vec::new();
+        if let Some(predicate) = &self.predicate{
+            predicate.conjuncts(&mut conjuncts);
         }
-        let mut regex_cache : HashMap<String,Regex> = HashMap::new();
-        let mut result = false;
-        for (query_atom,logicgate) in self.chain.iter(){
-            let value = if let Some(val) = row.data.get(&query_atom.column){
-                val
-            }else{
-                return Err(gerr("No value found to that column name"))
+        conjuncts
+    }
+    /// The conjuncts in evaluation order, the ones after the first prefixed with AND.
+    pub fn describe(&self) -> Vec<String>{
+        self.conjuncts().iter().enumerate().map(|(i,p)| {
+            let text = match p{
+                Predicate::Or(_) if i > 0 || self.conjuncts().len() > 1 => format!("({})",p.describe()),
+                _ => p.describe()
             };
-            let check = match query_atom.operator{
-                Operator::Equal|Operator::StrictEqual => {
-                    query_atom.value == *value
-                },
-                Operator::Greater => {
-                    match query_atom.value {
-                        AlbaTypes::Int(n) => {
-                            n > match *value {
-                                AlbaTypes::Int(n) => n as i32,
-                                AlbaTypes::Float(n) => n as i32,
-                                AlbaTypes::Bigint(n) => n as i32,
-                                _ => {
-                                    return Err(gerr("Invalid row type"))
-                                }
-                            }
-                        },
-                        AlbaTypes::Bigint(n) => {
-                            n > match *value {
-                                AlbaTypes::Int(n) => n as i64,
-                                AlbaTypes::Float(n) => n as i64,
-                                AlbaTypes::Bigint(n) => n as i64,
-                                _ => {
-                                    return Err(gerr("Invalid row type"))
-                                }
-                            }
-                        },
-                        AlbaTypes::Float(n) => {
-                            n > match *value {
-                                AlbaTypes::Int(n) => n as f64,
-                                AlbaTypes::Float(n) => n as f64,
-                                AlbaTypes::Bigint(n) => n as f64,
-                                _ => {
-                                    return Err(gerr("Invalid row type"))
-                                }
-                            }
-                        },
-                        _ => {
-                            return Err(gerr("Invalid query atom type"))
-                        }
-                    }
-                },
-                Operator::Lower => {
-                    match query_atom.value {
-                        AlbaTypes::Int(n) => {
-                            n < match *value {
-                                AlbaTypes::Int(n) => n as i32,
-                                AlbaTypes::Float(n) => n as i32,
-                                AlbaTypes::Bigint(n) => n as i32,
-                                _ => {
-                                    return Err(gerr("Invalid row type"))
-                                }
-                            }
-                        },
-                        AlbaTypes::Bigint(n) => {
-                            n < match *value {
-                                AlbaTypes::Int(n) => n as i64,
-                                AlbaTypes::Float(n) => n as i64,
-                                AlbaTypes::Bigint(n) => n as i64,
-                                _ => {
-                                    return Err(gerr("Invalid row type"))
-                                }
-                            }
-                        },
-                        AlbaTypes::Float(n) => {
-                            n < match *value {
-                                AlbaTypes::Int(n) => n as f64,
-                                AlbaTypes::Float(n) => n as f64,
-                                AlbaTypes::Bigint(n) => n as f64,
-                                _ => {
-                                    return Err(gerr("Invalid row type"))
-                                }
-                            }
-                        },
-                        _ => {
-                            return Err(gerr("Invalid query atom type"))
-                        }
-                    }
-                },
-                Operator::GreaterEquality => {
-                    match query_atom.value {
-                        AlbaTypes::Int(n) => {
-                            n >= match *value {
-                                AlbaTypes::Int(n) => n as i32,
-                                AlbaTypes::Float(n) => n as i32,
-                                AlbaTypes::Bigint(n) => n as i32,
-                                _ => {
-                                    return Err(gerr("Invalid row type"))
-                                }
-                            }
-                        },
-                        AlbaTypes::Bigint(n) => {
-                            n >= match *value {
-                                AlbaTypes::Int(n) => n as i64,
-                                AlbaTypes::Float(n) => n as i64,
-                                AlbaTypes::Bigint(n) => n as i64,
-                                _ => {
-                                    return Err(gerr("Invalid row type"))
-                                }
-                            }
-                        },
-                        AlbaTypes::Float(n) => {
-                            n >= match *value {
-                                AlbaTypes::Int(n) => n as f64,
-                                AlbaTypes::Float(n) => n as f64,
-                                AlbaTypes::Bigint(n) => n as f64,
-                                _ => {
-                                    return Err(gerr("Invalid row type"))
-                                }
-                            }
-                        },
-                        _ => {
-                            return Err(gerr("Invalid query atom type"))
-                        }
-                    }
-                },
-                Operator::LowerEquality => {
-                    match query_atom.value {
-                        AlbaTypes::Int(n) => {
-                            n <= match *value {
-                                AlbaTypes::Int(n) => n as i32,
-                                AlbaTypes::Float(n) => n as i32,
-                                AlbaTypes::Bigint(n) => n as i32,
-                                _ => {
-                                    return Err(gerr("Invalid row type"))
-                                }
-                            }
-                        },
-                        AlbaTypes::Bigint(n) => {
-                            n <= match *value {
-                                AlbaTypes::Int(n) => n as i64,
-                                AlbaTypes::Float(n) => n as i64,
-                                AlbaTypes::Bigint(n) => n as i64,
-                                _ => {
-                                    return Err(gerr("Invalid row type"))
-                                }
-                            }
-                        },
-                        AlbaTypes::Float(n) => {
-                            n <= match *value {
-                                AlbaTypes::Int(n) => n as f64,
-                                AlbaTypes::Float(n) => n as f64,
-                                AlbaTypes::Bigint(n) => n as f64,
-                                _ => {
-                                    return Err(gerr("Invalid row type"))
-                                }
-                            }
-                        },
-                        _ => {
-                            return Err(gerr("Invalid query atom type"))
-                        }
-                    }
-                },
-                Operator::Different => {
-                    query_atom.value != *value
-                },
-                Operator::StringContains => {
-                    match &query_atom.value{
-                        AlbaTypes::NanoString(s)|AlbaTypes::SmallString(s)|AlbaTypes::MediumString(s)|AlbaTypes::BigString(s)|AlbaTypes::LargeString(s)|AlbaTypes::Text(s) => {
-                            let val = match value{
-                                AlbaTypes::NanoString(s) => s,
-                                AlbaTypes::SmallString(s) => s,
-                                AlbaTypes::MediumString(s) => s,
-                                AlbaTypes::BigString(s) => s,
-                                AlbaTypes::LargeString(s) => s,
-                                AlbaTypes::Text(s) => s,
-                                _ => return Err(gerr("Invalid row type"))
-                            };
-                            s.contains(val)
-                        },
-                        _ => {
-                            return Err(gerr("Invalid row type"))
-                        }
-                    }
+            if i == 0 { text } else { format!("AND {}",text) }
+        }).collect()
+    }
+    /// Index lookups, one per column, able to drive the query. Equalities win over ranges on the same column.
+    pub fn index_candidates(&self) -> Vec<IndexCandidate>{
+        let mut candidates : Vec<IndexCandidate> = Vec::new();
+        let mut bounds : Vec<(String,u64,u64)> = Vec::new();
+        for conjunct in self.conjuncts(){
+            let atom = match conjunct{
+                Predicate::Atom(atom) => atom,
+                // no row can match, an empty lookup on the primary index reads none of them
+                Predicate::Constant(false) => match &self.primary_key{
+                    Some(column) => return vec![IndexCandidate { column: column.clone(), lookup: QueryIndexType::Set(Vec::new()) }],
+                    None => continue
                 },
-                Operator::StringCaseInsensitiveContains => {
-                    match &query_atom.value{
-                        AlbaTypes::NanoString(s)|AlbaTypes::SmallString(s)|AlbaTypes::MediumString(s)|AlbaTypes::BigString(s)|AlbaTypes::LargeString(s)|AlbaTypes::Text(s) => {
-                            let val = match value{
-                                AlbaTypes::NanoString(s) => s,
-                                AlbaTypes::SmallString(s) => s,
-                                AlbaTypes::MediumString(s) => s,
-                                AlbaTypes::BigString(s) => s,
-                                AlbaTypes::LargeString(s) => s,
-                                AlbaTypes::Text(s) => s,
-                                _ => return Err(gerr("Invalid row type"))
-                            };
-                            s.to_lowercase().contains(&val.to_lowercase())
-                        },
-                        _ => {
-                            return Err(gerr("Invalid row type"))
-                        }
-                    }
+                _ => continue
+            };
+            let key = atom.value.get_index();
+            let (low, high) = match atom.operator{
+                Operator::Equal | Operator::StrictEqual => {
+                    candidates.retain(|c| c.column != atom.column);
+                    bounds.retain(|b| b.0 != atom.column);
+                    candidates.push(IndexCandidate { column: atom.column.clone(), lookup: QueryIndexType::Strict(key) });
+                    continue;
                 },
-                Operator::StringRegularExpression => {
-                    match &query_atom.value{
-                        AlbaTypes::NanoString(s)|AlbaTypes::SmallString(s)|AlbaTypes::MediumString(s)|AlbaTypes::BigString(s)|AlbaTypes::LargeString(s)|AlbaTypes::Text(s) => {
-                            let val = match value{
-                                AlbaTypes::NanoString(s) => s,
-                                AlbaTypes::SmallString(s) => s,
-                                AlbaTypes::MediumString(s) => s,
-                                AlbaTypes::BigString(s) => s,
-                                AlbaTypes::LargeString(s) => s,
-                                AlbaTypes::Text(s) => s,
-                                _ => return Err(gerr("Invalid row type"))
-                            };
-                            let reg = gather_regex(&mut regex_cache, val.clone())?;
-                            reg.is_match(&s)
-                        },
-                        _ => {
-                            return Err(gerr("Invalid row type"))
-                        }
+                Operator::In => {
+                    if candidates.iter().any(|c| c.column == atom.column){
+                        continue;
                     }
+                    let mut keys : Vec<u64> = atom.values.iter().map(|v| v.get_index()).collect();
+                    keys.sort_unstable();
+                    keys.dedup();
+                    candidates.push(IndexCandidate { column: atom.column.clone(), lookup: QueryIndexType::Set(keys) });
+                    continue;
                 },
+                Operator::Between if atom.values.iter().all(is_range_indexable) => (atom.values[0].get_index(), atom.values[1].get_index()),
+                _ if !is_range_indexable(&atom.value) => continue,
+                Operator::Greater | Operator::GreaterEquality => (key, u64::MAX),
+                Operator::Lower | Operator::LowerEquality => (0, key),
+                _ => continue
             };
-            result = check;
-            if let Some(logical_gate) = logicgate{
-                match logical_gate {
-                    LogicalGate::And => if !check{return Ok(false)},
-                    LogicalGate::Or => if check{return Ok(true)} 
-                }
+            if candidates.iter().any(|c| c.column == atom.column){
+                continue;
+            }
+            match bounds.iter_mut().find(|b| b.0 == atom.column){
+                Some(b) => { b.1 = b.1.max(low); b.2 = b.2.min(high); },
+                None => bounds.push((atom.column.clone(), low, high))
             }
         }
-
-        Ok(result)
-    }
-    pub fn raw_chain(&self) -> Vec<QueryConditionAtom>{
-        return self.chain.iter().cloned().map(|group|group.0).collect()
+        for (column, low, high) in bounds{
+            if candidates.iter().any(|c| c.column == column){
+                continue;
+            }
+            candidates.push(IndexCandidate { column, lookup: QueryIndexType::InclusiveRange(low..=high) });
+        }
+        candidates
     }
+    /// Rule based access choice on the primary key, used when the container has no statistics.
     pub fn query_type(&self) -> Result<QueryType,Error>{
-        let mut raw_chain = self.raw_chain();
-        if raw_chain.is_empty(){
-            return Ok(QueryType::Scan) 
-        }
         let primary_key = if let Some(pk) = &self.primary_key{
             pk
         }else{
             return Ok(QueryType::Scan)
         };
-        let mut sorting = (
-            Vec::with_capacity(raw_chain.len()/10),
-            Vec::with_capacity(raw_chain.len()/10),
-            Vec::with_capacity(raw_chain.len()/10),
-            Vec::with_capacity(raw_chain.len()/10),
-            Vec::with_capacity(raw_chain.len()/10),
-            Vec::with_capacity(raw_chain.len()/10),
-            Vec::with_capacity(raw_chain.len()/10),
-            Vec::with_capacity(raw_chain.len()/10),
-            Vec::with_capacity(raw_chain.len()/10),
-            Vec::with_capacity(raw_chain.len()/10),
-        );
-    
-        for query_atom in raw_chain {
-            match query_atom.operator {
-                Operator::Equal => sorting.0.push(query_atom),
-                Operator::StrictEqual => sorting.1.push(query_atom),
-                Operator::Greater => sorting.4.push(query_atom),
-                Operator::Lower => sorting.5.push(query_atom),
-                Operator::GreaterEquality => sorting.2.push(query_atom),
-                Operator::LowerEquality => sorting.3.push(query_atom),
-                Operator::Different => sorting.6.push(query_atom),
-                Operator::StringContains => sorting.7.push(query_atom),
-                Operator::StringCaseInsensitiveContains => sorting.8.push(query_atom),
-                Operator::StringRegularExpression => sorting.9.push(query_atom),
-            }
-        }
-        raw_chain = Vec::new();
-
-        for vec in [&sorting.0, &sorting.1, &sorting.2, &sorting.3, &sorting.4, &sorting.5, &sorting.6, &sorting.7, &sorting.8, &sorting.9] {
-            let secvec = vec;
-            for i in secvec{
-                raw_chain.push((*i).clone());
-            }
-        }
-        drop(sorting);
-        if raw_chain.len() == 0{
-            return Ok(QueryType::Scan);
-        }       
-        let first : &QueryConditionAtom = &raw_chain[0];
-        let is_equality : bool = match first.operator{
-            Operator::Equal | Operator::StrictEqual => true,
-            _ => false
-        };
-        if is_equality && first.column == *primary_key{
-            return Ok(QueryType::Indexed(QueryIndexType::Strict(first.value.get_index())))
-        }
-        // 0 = Scan
-        // 1 = RangeEquality
-        // 2 = Range
-        let mut typo_range : (u64,u64)= (0,0);
-        let typo : u8 = match first.operator{
-            Operator::GreaterEquality => {
-                let mut a = 0;
-                for i in raw_chain.iter(){
-                    if discriminant(&i.operator) == discriminant(&Operator::LowerEquality){
-                        typo_range = (first.value.get_index(),i.value.get_index());
-                        a=1;
-                    }
-                    if discriminant(&i.operator) == discriminant(&Operator::Lower){
-                        typo_range = (first.value.get_index(),i.value.get_index());
-                        a=2;
-                    }
-                }
-                a
-            },
-            Operator::Greater => {
-                let mut a = 0;
-                for i in raw_chain.iter(){
-                    if discriminant(&i.operator) == discriminant(&Operator::LowerEquality){
-                        typo_range = (first.value.get_index(),i.value.get_index());
-                        a=1
-                    }
-                    if discriminant(&i.operator) == discriminant(&Operator::Lower){
-                        typo_range = (first.value.get_index(),i.value.get_index());
-                        a=2
-                    }
-                }
-                a
-            },
-            _ => {0}
-        };
-        return Ok(match typo{
-            0 => QueryType::Scan,
-            1 => QueryType::Indexed(QueryIndexType::Range(typo_range.0..typo_range.1)),
-            2 => QueryType::Indexed(QueryIndexType::InclusiveRange(typo_range.0..=typo_range.1)),
-            _ => QueryType::Scan,
+        Ok(match self.index_candidates().into_iter().find(|c| c.column == *primary_key){
+            Some(candidate) => QueryType::Indexed(candidate.lookup),
+            None => QueryType::Scan
         })
     }
-}
\ No newline at end of file
+}
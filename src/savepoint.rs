@@ -0,0 +1,53 @@
+use std::collections::HashMap;
+
+use ahash::AHashMap;
+
+use crate::alba_types::AlbaTypes;
+
+/// A pending row: deleted or not, and its values.
+pub type PendingRow = (bool, Vec<AlbaTypes>);
+
+/// A savepoint of the pending changes of a container. It keeps what the changes made after it
+/// replaced, until the next savepoint is set, so they can be undone without copying the rest.
+#[derive(Debug)]
+pub struct Savepoint{
+    pub name : String,
+    /// The pending entry of every address changed since, `None` when it had none.
+    rows : AHashMap<u64, Option<PendingRow>>,
+    /// Codes of the Text contents added since.
+    texts : Vec<String>,
+}
+
+impl Savepoint{
+    pub fn new(name : &str) -> Self{
+        Savepoint { name: name.to_string(), rows: AHashMap::new(), texts: Vec::new() }
+    }
+    /// Remembers the entry `address` had, unless it already changed after the savepoint.
+    pub fn record_row(&mut self, address : u64, previous : Option<&PendingRow>){
+        self.rows.entry(address).or_insert_with(|| previous.cloned());
+    }
+    pub fn record_text(&mut self, code : &str){
+        self.texts.push(code.to_string());
+    }
+    /// Puts the pending rows and texts back as they were when the savepoint was set. The Text
+    /// contents added since are dropped, so their codes are never written.
+    pub fn undo(&mut self, rows : &mut AHashMap<u64, PendingRow>, texts : &mut HashMap<String, (bool, String)>){
+        for (address, previous) in self.rows.drain(){
+            match previous{
+                Some(entry) => { rows.insert(address, entry); },
+                None => { rows.remove(&address); },
+            }
+        }
+        for code in self.texts.drain(..){
+            texts.remove(&code);
+        }
+    }
+    /// Hands what this savepoint remembers to the one set before it, when it is released. The
+    /// older savepoint keeps its own entry for an address both remember, it is the older one.
+    pub fn merge_into(self, older : &mut Savepoint){
+        for (address, previous) in self.rows{
+            older.rows.entry(address).or_insert(previous);
+        }
+        older.texts.extend(self.texts);
+    }
+}
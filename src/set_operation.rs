@@ -0,0 +1,158 @@
+use std::{cmp::Ordering, collections::BTreeSet, fmt, io::Error};
+
+use crate::{aggregate::OrderedValue, alba_types::AlbaTypes, database::Database, explain::ExecutionCounters, gerr, query::{Query, QueryPage, QueryWindow}, sorting::{compare_keys, SortEntry, SortKey}, SetOperator};
+
+impl fmt::Display for SetOperator{
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result{
+        f.write_str(match self{
+            SetOperator::Union => "UNION",
+            SetOperator::UnionAll => "UNION ALL",
+            SetOperator::Intersect => "INTERSECT",
+            SetOperator::Except => "EXCEPT",
+        })
+    }
+}
+
+/// A row of an operand: where it is stored, the column list it is shown with and the values of
+/// those columns.
+struct SetRow{
+    container : String,
+    address : u64,
+    projection : usize,
+    values : Vec<AlbaTypes>,
+}
+
+impl SetRow{
+    fn key(&self) -> Vec<OrderedValue>{
+        self.values.iter().cloned().map(OrderedValue).collect()
+    }
+}
+
+/// The result of one side of a set operation, with the values of its rows read.
+pub struct SetOperand{
+    query : Query,
+    rows : Vec<SetRow>,
+}
+
+impl SetOperand{
+    /// Reads every page of `query` with its own column list. Rows deleted since the search are left out.
+    pub async fn read(query : Query, database : &Database) -> Result<Self, Error>{
+        let mut rows = Vec::new();
+        for page in query.pages.iter(){
+            for (address, values) in page.addresses.iter().zip(page_values(&query, page, database).await?){
+                if let Some(values) = values{
+                    rows.push(SetRow { container: page.container.clone(), address: *address, projection: page.projection, values });
+                }
+            }
+        }
+        Ok(SetOperand { query, rows })
+    }
+    /// The values of the rows, in the order they were read.
+    pub fn into_values(self) -> Vec<Vec<AlbaTypes>>{
+        self.rows.into_iter().map(|row| row.values).collect()
+    }
+}
+
+/// Combines the rows of two operands. Rows are equal when the values of their columns are, so
+/// UNION, INTERSECT and EXCEPT keep the first of equal rows only, while UNION ALL keeps them all.
+/// The rows come out in the order of the left operand, followed by the right one for a union,
+/// and are paged by address like the rows of any search, with the page size of the left operand.
+pub fn combine(operator : SetOperator, left : SetOperand, right : SetOperand) -> Result<Query, Error>{
+    let (left_types, right_types) = (&left.query.column_types, &right.query.column_types);
+    // an operand whose sources were all empty subqueries never learned its columns
+    if !left_types.is_empty() && !right_types.is_empty(){
+        if left_types.len() != right_types.len(){
+            return Err(gerr(&format!("The operands of {} need the same number of columns, but found {} and {}", operator, left_types.len(), right_types.len())))
+        }
+        for (position, (a, b)) in left_types.iter().zip(right_types.iter()).enumerate(){
+            if !a.is_comparable_with(b){
+                return Err(gerr(&format!("The column {} of the operands of {} can not be compared, found {:?} and {:?}", position + 1, operator, a, b)))
+            }
+        }
+    }
+    let described = if left_types.is_empty() { &right.query } else { &left.query };
+    let mut query = Query::new(described.column_types.clone());
+    query.column_names = described.column_names.clone();
+    query.page_size = left.query.page_size;
+    query.projections = left.query.projections.clone();
+
+    let rows : Vec<SetRow> = match operator{
+        SetOperator::Union | SetOperator::UnionAll => {
+            // the pages of the right rows point past the column lists of the left operand
+            let offset = query.projections.len();
+            query.projections.extend(right.query.projections);
+            let right_rows = right.rows.into_iter().map(|row| SetRow { projection: row.projection + offset, ..row });
+            let mut seen = BTreeSet::new();
+            let all = operator == SetOperator::UnionAll;
+            left.rows.into_iter().chain(right_rows).filter(|row| all || seen.insert(row.key())).collect()
+        },
+        SetOperator::Intersect | SetOperator::Except => {
+            let right_keys : BTreeSet<Vec<OrderedValue>> = right.rows.iter().map(|row| row.key()).collect();
+            let mut seen = BTreeSet::new();
+            left.rows.into_iter().filter(|row| {
+                let key = row.key();
+                right_keys.contains(&key) == (operator == SetOperator::Intersect) && seen.insert(key)
+            }).collect()
+        }
+    };
+    for row in rows{
+        query.push_address(row.address, &row.container, row.projection);
+    }
+    Ok(query)
+}
+
+/// The values of the rows of a page with the column list of the page, None for the rows
+/// deleted since the search.
+async fn page_values(query : &Query, page : &QueryPage, database : &Database) -> Result<Vec<Option<Vec<AlbaTypes>>>, Error>{
+    let container = match database.container.get(&page.container){
+        Some(a) => a.read().await,
+        None => return Err(gerr(&format!("There is no container in the given database named {}", page.container)))
+    };
+    let projection = match query.projections.get(page.projection){
+        Some(a) => a.resolve(&container.headers, &container.name)?,
+        None => return Err(gerr("There is no column list for the page"))
+    };
+    container.get_rows(&page.addresses, &projection.positions, database.session()).await?.into_iter()
+        .map(|values| values.map(|values| projection.project(values)).transpose())
+        .collect()
+}
+
+/// `SEARCH DISTINCT`: keeps the first of the rows with equal values, in the order the search
+/// returned them, then applies the window of the search to what is left. The rows are read a
+/// page at a time into the external sort, keyed by their values and then by their position in
+/// the result, so the first of every run of equal rows is the one kept.
+pub async fn distinct(query : Query, window : QueryWindow, database : &Database, counters : &ExecutionCounters) -> Result<Query, Error>{
+    let order = vec![SortKey { column: String::new(), descending: false }; query.column_names.len()];
+    let mut sorter = database.sorter(order.clone());
+    let mut position = 0u64;
+    for page in query.pages.iter(){
+        for values in page_values(&query, page, database).await?{
+            if let Some(keys) = values{
+                sorter.push(SortEntry { keys, source: 0, address: position })?;
+            }
+            position += 1;
+        }
+    }
+    counters.spilled(sorter.spilled_runs());
+    counters.spilled_bytes(sorter.spilled_bytes());
+    let mut kept = vec![false; usize::try_from(position).unwrap_or(usize::MAX)];
+    let mut previous : Option<Vec<AlbaTypes>> = None;
+    sorter.finish(|entry| {
+        if previous.as_ref().is_none_or(|keys| compare_keys(keys, &entry.keys, &order) != Ordering::Equal){
+            kept[entry.address as usize] = true;
+            previous = Some(entry.keys);
+        }
+        Ok(true)
+    })?;
+
+    let mut result = Query::new(query.column_types.clone());
+    result.column_names = query.column_names.clone();
+    result.page_size = query.page_size;
+    result.projections = query.projections.clone();
+    let limit = window.limit.map_or(usize::MAX, |l| usize::try_from(l).unwrap_or(usize::MAX));
+    let rows = query.pages.iter().flat_map(|page| page.addresses.iter().map(move |address| (page, *address)));
+    for (page, address) in rows.zip(kept).filter(|(_, kept)| *kept).map(|(row, _)| row).skip(usize::try_from(window.offset).unwrap_or(usize::MAX)).take(limit){
+        result.push_address(address, &page.container, page.projection);
+    }
+    Ok(result)
+}
@@ -0,0 +1,247 @@
+use std::{cmp::Ordering, io::Error};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{alba_types::AlbaTypes, gerr, memory::MemoryGrant, spill::{SpillReader, SpillWriter}};
+
+/// One column of an `ORDER BY` clause.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SortKey{
+    pub column : String,
+    pub descending : bool,
+}
+
+/// Positions of the sort columns in the rows of a container.
+pub fn key_positions(headers : &[(String,AlbaTypes)], order : &[SortKey]) -> Result<Vec<usize>, Error>{
+    order.iter().map(|key| match headers.iter().position(|h| h.0 == key.column){
+        Some(position) => Ok(position),
+        None => Err(gerr(&format!("Failed to sort, there is no column named {}", key.column)))
+    }).collect()
+}
+
+pub fn extract_keys(row : &[AlbaTypes], positions : &[usize]) -> Vec<AlbaTypes>{
+    positions.iter().map(|p| row.get(*p).cloned().unwrap_or(AlbaTypes::NONE)).collect()
+}
+
+/// Compares two key lists column by column, honouring the direction of every sort key.
+pub fn compare_keys(a : &[AlbaTypes], b : &[AlbaTypes], order : &[SortKey]) -> Ordering{
+    for ((x, y), key) in a.iter().zip(b.iter()).zip(order.iter()){
+        let ordering = x.total_cmp(y);
+        if ordering != Ordering::Equal{
+            return if key.descending { ordering.reverse() } else { ordering }
+        }
+    }
+    Ordering::Equal
+}
+
+/// A row waiting to be sorted: its sort keys and where it lives.
+/// `source` tells apart the containers of a search over several of them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SortEntry{
+    pub keys : Vec<AlbaTypes>,
+    pub source : usize,
+    pub address : u64,
+}
+
+impl SortEntry{
+    fn memory_size(&self) -> u64{
+        (size_of::<SortEntry>() + self.keys.iter().map(|k| k.memory_size()).sum::<usize>()) as u64
+    }
+}
+
+// Equal keys keep the physical order, so the result never depends on how the runs were cut.
+fn compare_entries(a : &SortEntry, b : &SortEntry, order : &[SortKey]) -> Ordering{
+    compare_keys(&a.keys, &b.keys, order).then(a.source.cmp(&b.source)).then(a.address.cmp(&b.address))
+}
+
+enum Run{
+    Disk(SpillReader<SortEntry>),
+    Memory(std::vec::IntoIter<SortEntry>),
+}
+
+impl Run{
+    fn next(&mut self) -> Result<Option<SortEntry>, Error>{
+        match self{
+            Run::Memory(entries) => Ok(entries.next()),
+            Run::Disk(reader) => reader.next()
+        }
+    }
+}
+
+/// Sorts entries within a memory grant. Entries are kept in memory while the grant can grow,
+/// then the buffer is sorted and written to `spill_directory` as a run;
+/// `finish` merges every run back into a single ordered stream.
+/// With a `limit` only the first `limit` entries are ever wanted, so the buffer is cut down to
+/// them whenever it grows past twice that.
+pub struct ExternalSorter{
+    order : Vec<SortKey>,
+    grant : MemoryGrant,
+    limit : Option<usize>,
+    spill_directory : String,
+    buffer : Vec<SortEntry>,
+    runs : Vec<SpillReader<SortEntry>>,
+    spilled_bytes : u64,
+}
+
+impl ExternalSorter{
+    pub fn new(order : Vec<SortKey>, grant : MemoryGrant, spill_directory : String) -> Self{
+        ExternalSorter { order, grant, limit: None, spill_directory, buffer: Vec::new(), runs: Vec::new(), spilled_bytes: 0 }
+    }
+    pub fn with_limit(mut self, limit : Option<u64>) -> Self{
+        self.limit = limit.map(|l| usize::try_from(l).unwrap_or(usize::MAX));
+        self
+    }
+    pub fn push(&mut self, entry : SortEntry) -> Result<(), Error>{
+        let size = entry.memory_size();
+        if !self.grant.grow(size){
+            self.spill()?;
+            self.grant.force(size);
+        }
+        self.buffer.push(entry);
+        if let Some(limit) = self.limit && self.buffer.len() >= limit.saturating_mul(2).max(1024){
+            let mut entries = self.sort_buffer();
+            entries.truncate(limit);
+            let kept = entries.iter().map(|e| e.memory_size()).sum::<u64>();
+            self.grant.shrink(self.grant.held().saturating_sub(kept));
+            self.buffer = entries;
+        }
+        Ok(())
+    }
+    /// Runs written to disk so far.
+    pub fn spilled_runs(&self) -> u64{
+        self.runs.len() as u64
+    }
+    /// Bytes of the runs written to disk so far.
+    pub fn spilled_bytes(&self) -> u64{
+        self.spilled_bytes
+    }
+    fn sort_buffer(&mut self) -> Vec<SortEntry>{
+        let mut entries = std::mem::take(&mut self.buffer);
+        entries.sort_by(|a, b| compare_entries(a, b, &self.order));
+        entries
+    }
+    fn spill(&mut self) -> Result<(), Error>{
+        if self.buffer.is_empty(){
+            return Ok(())
+        }
+        let mut run = SpillWriter::create(&self.spill_directory, "sort")?;
+        for entry in self.sort_buffer(){
+            run.write(&entry)?;
+        }
+        self.spilled_bytes += run.bytes();
+        self.runs.push(run.finish()?);
+        self.grant.release();
+        Ok(())
+    }
+    /// Hands every entry to `visit` in sorted order, until `visit` returns false or `limit` entries went out.
+    pub fn finish<F : FnMut(SortEntry) -> Result<bool, Error>>(mut self, mut visit : F) -> Result<(), Error>{
+        let mut runs : Vec<Run> = std::mem::take(&mut self.runs).into_iter().map(Run::Disk).collect();
+        runs.push(Run::Memory(self.sort_buffer().into_iter()));
+
+        let mut heads : Vec<Option<SortEntry>> = Vec::with_capacity(runs.len());
+        for run in runs.iter_mut(){
+            heads.push(run.next()?);
+        }
+        let mut remaining = self.limit.unwrap_or(usize::MAX);
+        while remaining > 0{
+            let mut smallest : Option<usize> = None;
+            for (index, head) in heads.iter().enumerate(){
+                let entry = match head{
+                    Some(entry) => entry,
+                    None => continue
+                };
+                smallest = match smallest{
+                    Some(current) if compare_entries(heads[current].as_ref().unwrap(), entry, &self.order) != Ordering::Greater => Some(current),
+                    _ => Some(index)
+                };
+            }
+            let index = match smallest{
+                Some(index) => index,
+                None => break
+            };
+            let entry = std::mem::replace(&mut heads[index], runs[index].next()?);
+            if let Some(entry) = entry{
+                if !visit(entry)?{
+                    break;
+                }
+                remaining -= 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::sync::Arc;
+
+    use crate::{database::generate_secure_code, memory::MemoryAccountant};
+
+    use super::*;
+
+    // room for a handful of entries, so a few dozen pushes write several runs
+    fn sorter(order : Vec<SortKey>) -> (ExternalSorter, String){
+        let directory = std::env::temp_dir().join(format!("tyto-sort-{}", generate_secure_code(16))).to_string_lossy().to_string();
+        let accountant = Arc::new(MemoryAccountant::new(4 * size_of::<SortEntry>() as u64 + 256));
+        (ExternalSorter::new(order, accountant.grant(), directory.clone()), directory)
+    }
+
+    fn key(column : &str, descending : bool) -> SortKey{
+        SortKey { column: column.to_string(), descending }
+    }
+
+    fn sorted(sorter : ExternalSorter) -> Vec<SortEntry>{
+        let mut out = Vec::new();
+        sorter.finish(|entry| { out.push(entry); Ok(true) }).unwrap();
+        out
+    }
+
+    #[test]
+    fn spilled_runs_merge_in_order(){
+        let (mut sorter, directory) = sorter(vec![key("a", false), key("b", true)]);
+        for address in 0..60u64{
+            let b = if address % 5 == 0 { AlbaTypes::Float(f64::NAN) } else { AlbaTypes::Int((address % 4) as i32) };
+            sorter.push(SortEntry { keys: vec![AlbaTypes::Bigint((address * 7 % 11) as i64), b], source: 0, address }).unwrap();
+        }
+        assert!(sorter.spilled_runs() > 1);
+        let out = sorted(sorter);
+        assert_eq!(out.len(), 60);
+        for pair in out.windows(2){
+            assert_ne!(compare_entries(&pair[0], &pair[1], &[key("a", false), key("b", true)]), Ordering::Greater, "{:?}", pair);
+        }
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn limit_keeps_the_first_entries(){
+        let (sorter, directory) = sorter(vec![key("a", true)]);
+        let mut sorter = sorter.with_limit(Some(3));
+        for address in 0..40u64{
+            sorter.push(SortEntry { keys: vec![AlbaTypes::Int(address as i32)], source: 0, address }).unwrap();
+        }
+        assert!(sorter.spilled_runs() > 1);
+        let out : Vec<u64> = sorted(sorter).iter().map(|e| e.address).collect();
+        assert_eq!(out, vec![39, 38, 37]);
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn ties_follow_source_then_address(){
+        let (mut sorter, directory) = sorter(vec![key("a", false)]);
+        // pushed backwards, so every run holds the ties in the opposite order
+        for address in (0..30u64).rev(){
+            for source in (0..2).rev(){
+                sorter.push(SortEntry { keys: vec![AlbaTypes::Int((address % 2) as i32)], source, address }).unwrap();
+            }
+        }
+        assert!(sorter.spilled_runs() > 1);
+        let out : Vec<(i32, usize, u64)> = sorted(sorter).into_iter().map(|e| match e.keys[0]{
+            AlbaTypes::Int(k) => (k, e.source, e.address),
+            _ => unreachable!()
+        }).collect();
+        let mut expected = out.clone();
+        expected.sort();
+        assert_eq!(out, expected);
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+}
@@ -0,0 +1,184 @@
+use std::{fs::{self, File}, io::{BufReader, BufWriter, Error, ErrorKind, Read, Write}, marker::PhantomData, path::PathBuf};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{database::generate_secure_code, gerr, memory::MemoryGrant};
+
+/// A temporary file of an operator, removed once the operator is done with it.
+struct SpillFile{
+    path : PathBuf,
+}
+
+impl Drop for SpillFile{
+    fn drop(&mut self){
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Values written one after the other to a temporary file under `directory`, each as its
+/// length followed by its bincode, which keeps every float bit for bit, NaN and infinities too.
+pub struct SpillWriter<T>{
+    file : SpillFile,
+    writer : BufWriter<File>,
+    bytes : u64,
+    values : PhantomData<T>,
+}
+
+impl<T : Serialize> SpillWriter<T>{
+    pub fn create(directory : &str, kind : &str) -> Result<Self, Error>{
+        fs::create_dir_all(directory)?;
+        let file = SpillFile { path: PathBuf::from(format!("{}/{}-{}", directory, kind, generate_secure_code(32))) };
+        let writer = BufWriter::new(File::create_new(&file.path)?);
+        Ok(SpillWriter { file, writer, bytes: 0, values: PhantomData })
+    }
+    pub fn write(&mut self, value : &T) -> Result<(), Error>{
+        let bytes = bincode::serialize(value).map_err(|e| gerr(&format!("Failed to write a temporary file: {}", e)))?;
+        self.writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&bytes)?;
+        self.bytes += bytes.len() as u64 + 4;
+        Ok(())
+    }
+    /// Bytes written so far.
+    pub fn bytes(&self) -> u64{
+        self.bytes
+    }
+    /// Reads the values back from the first one.
+    pub fn finish(mut self) -> Result<SpillReader<T>, Error>{
+        self.writer.flush()?;
+        let reader = BufReader::new(File::open(&self.file.path)?);
+        Ok(SpillReader { _file: self.file, reader, values: PhantomData })
+    }
+}
+
+pub struct SpillReader<T>{
+    // removed with the reader
+    _file : SpillFile,
+    reader : BufReader<File>,
+    values : PhantomData<T>,
+}
+
+impl<T : DeserializeOwned> SpillReader<T>{
+    pub fn next(&mut self) -> Result<Option<T>, Error>{
+        let mut length = [0u8; 4];
+        match self.reader.read_exact(&mut length){
+            Ok(()) => {},
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e)
+        }
+        let mut buffer = vec![0u8; u32::from_le_bytes(length) as usize];
+        self.reader.read_exact(&mut buffer)?;
+        bincode::deserialize(&buffer).map(Some).map_err(|e| gerr(&format!("Failed to read a temporary file: {}", e)))
+    }
+}
+
+/// Values kept in the order they were pushed, in memory while the grant allows and in a
+/// temporary file after that.
+pub struct SpillQueue<T>{
+    memory : Vec<T>,
+    disk : Option<SpillWriter<T>>,
+    grant : MemoryGrant,
+    directory : String,
+}
+
+impl<T : Serialize + DeserializeOwned> SpillQueue<T>{
+    pub fn new(grant : MemoryGrant, directory : String) -> Self{
+        SpillQueue { memory: Vec::new(), disk: None, grant, directory }
+    }
+    /// Adds a value taking `size` bytes in memory.
+    pub fn push(&mut self, value : T, size : u64) -> Result<(), Error>{
+        if self.disk.is_none() && self.grant.grow(size){
+            self.memory.push(value);
+            return Ok(())
+        }
+        let disk = match &mut self.disk{
+            Some(disk) => disk,
+            None => self.disk.insert(SpillWriter::create(&self.directory, "queue")?)
+        };
+        disk.write(&value)
+    }
+    /// Bytes written to disk.
+    pub fn spilled_bytes(&self) -> u64{
+        self.disk.as_ref().map_or(0, |disk| disk.bytes())
+    }
+    /// Hands the values out again, in the order they were pushed.
+    pub fn drain(self) -> Result<SpillDrain<T>, Error>{
+        let disk = match self.disk{
+            Some(disk) => Some(disk.finish()?),
+            None => None
+        };
+        Ok(SpillDrain { memory: self.memory.into_iter(), disk, _grant: self.grant })
+    }
+}
+
+pub struct SpillDrain<T>{
+    memory : std::vec::IntoIter<T>,
+    disk : Option<SpillReader<T>>,
+    _grant : MemoryGrant,
+}
+
+impl<T : DeserializeOwned> SpillDrain<T>{
+    pub fn next(&mut self) -> Result<Option<T>, Error>{
+        if let Some(value) = self.memory.next(){
+            return Ok(Some(value))
+        }
+        match &mut self.disk{
+            Some(disk) => disk.next(),
+            None => Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::sync::Arc;
+
+    use crate::{alba_types::AlbaTypes, memory::MemoryAccountant};
+
+    use super::*;
+
+    fn directory() -> String{
+        std::env::temp_dir().join(format!("tyto-spill-{}", generate_secure_code(16))).to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn floats_come_back_bit_for_bit(){
+        let directory = directory();
+        let floats = [f64::NAN, -f64::NAN, f64::INFINITY, f64::NEG_INFINITY, -0.0, f64::MIN_POSITIVE, 0.1];
+        let mut writer = SpillWriter::create(&directory, "test").unwrap();
+        for float in floats{
+            writer.write(&(vec![AlbaTypes::Float(float), AlbaTypes::NONE], 7u64)).unwrap();
+        }
+        let mut reader = writer.finish().unwrap();
+        for float in floats{
+            let (row, address) : (Vec<AlbaTypes>, u64) = reader.next().unwrap().unwrap();
+            match row.as_slice(){
+                [AlbaTypes::Float(read), AlbaTypes::NONE] => assert_eq!(read.to_bits(), float.to_bits()),
+                other => panic!("unexpected row {:?}", other)
+            }
+            assert_eq!(address, 7);
+        }
+        assert!(reader.next().unwrap().is_none());
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn queue_keeps_the_push_order_across_the_spill(){
+        let directory = directory();
+        let accountant = Arc::new(MemoryAccountant::new(16));
+        let mut queue = SpillQueue::new(accountant.grant(), directory.clone());
+        for i in 0..10u64{
+            queue.push(AlbaTypes::Float(if i % 3 == 0 { f64::NAN } else { i as f64 }), 8).unwrap();
+        }
+        assert!(queue.spilled_bytes() > 0);
+        let mut drain = queue.drain().unwrap();
+        for i in 0..10u64{
+            match drain.next().unwrap(){
+                Some(AlbaTypes::Float(read)) if i % 3 == 0 => assert!(read.is_nan()),
+                Some(AlbaTypes::Float(read)) => assert_eq!(read, i as f64),
+                other => panic!("unexpected value {:?}", other)
+            }
+        }
+        assert!(drain.next().unwrap().is_none());
+        let _ = fs::remove_dir_all(&directory);
+    }
+}
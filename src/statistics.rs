@@ -0,0 +1,150 @@
+use std::{fs, io::Error, sync::Arc};
+
+use ahash::AHashSet;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use crate::{alba_types::AlbaTypes, container::Container, gerr, indexing::GetIndex, query::{search_direct, Query}, query_conditions::QueryConditions};
+
+const HISTOGRAM_BUCKETS : usize = 32;
+
+/// Statistics of a single column, gathered by `ANALYZE`.
+/// The histogram is equi-depth over the index keys (`GetIndex`) of the column, so every
+/// bucket holds the same share of rows and can be compared directly with index lookups.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ColumnStatistics{
+    pub name : String,
+    pub distinct_values : u64,
+    pub distinct_keys : u64,
+    pub histogram : Vec<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ContainerStatistics{
+    pub row_count : u64,
+    pub analyzed_at : String,
+    pub columns : Vec<ColumnStatistics>,
+}
+
+impl ColumnStatistics{
+    fn new(name : String, mut keys : Vec<u64>, fingerprints : &AHashSet<u64>) -> Self{
+        keys.sort_unstable();
+        let mut histogram = Vec::with_capacity(HISTOGRAM_BUCKETS + 1);
+        if !keys.is_empty(){
+            let buckets = HISTOGRAM_BUCKETS.min(keys.len());
+            for i in 0..buckets{
+                histogram.push(keys[(i * keys.len()) / buckets]);
+            }
+            histogram.push(keys[keys.len() - 1]);
+        }
+        let mut distinct_keys = 0;
+        let mut last : Option<u64> = None;
+        for key in keys.iter(){
+            if last != Some(*key){
+                distinct_keys += 1;
+                last = Some(*key);
+            }
+        }
+        ColumnStatistics { name, distinct_values: fingerprints.len() as u64, distinct_keys, histogram }
+    }
+
+    /// Fraction of the rows holding one particular value of the column.
+    pub fn value_equal_fraction(&self) -> f64{
+        if self.distinct_values == 0{
+            return 0.0
+        }
+        1.0 / self.distinct_values as f64
+    }
+
+    /// Fraction of the rows stored under the index key `key`.
+    pub fn key_equal_fraction(&self, key : u64) -> f64{
+        match (self.histogram.first(), self.histogram.last()){
+            (Some(min), Some(max)) if key >= *min && key <= *max && self.distinct_keys > 0 => 1.0 / self.distinct_keys as f64,
+            _ => 0.0
+        }
+    }
+
+    /// Fraction of the rows stored under an index key inside `start..=end`.
+    pub fn key_range_fraction(&self, start : u64, end : u64) -> f64{
+        if self.histogram.len() < 2 || start > end{
+            return 0.0
+        }
+        let buckets = (self.histogram.len() - 1) as f64;
+        let mut fraction = 0.0;
+        for bounds in self.histogram.windows(2){
+            let (low, high) = (bounds[0], bounds[1]);
+            if end < low || start > high{
+                continue;
+            }
+            if start <= low && end >= high || low == high{
+                fraction += 1.0;
+                continue;
+            }
+            let covered = (end.min(high) - start.max(low)) as f64 + 1.0;
+            fraction += (covered / ((high - low) as f64 + 1.0)).min(1.0);
+        }
+        (fraction / buckets).min(1.0)
+    }
+}
+
+impl ContainerStatistics{
+    pub fn load(path : &str) -> Result<Option<Self>, Error>{
+        if !fs::exists(path)?{
+            return Ok(None)
+        }
+        let raw = fs::read_to_string(path)?;
+        match serde_yaml::from_str(&raw){
+            Ok(statistics) => Ok(Some(statistics)),
+            Err(e) => Err(gerr(&format!("Failed to load the statistics at {}: {}", path, e)))
+        }
+    }
+    pub fn save(&self, path : &str) -> Result<(), Error>{
+        let yaml = serde_yaml::to_string(self).map_err(|e| gerr(&e.to_string()))?;
+        fs::write(path, yaml)
+    }
+    pub fn column(&self, name : &str) -> Option<&ColumnStatistics>{
+        self.columns.iter().find(|c| c.name == name)
+    }
+    /// One row per column, so the result of `ANALYZE` can be read like any other query.
+    pub fn to_query(&self) -> Query{
+        let mut query = Query::new_none(vec![AlbaTypes::Text(String::new()), AlbaTypes::Bigint(0), AlbaTypes::Bigint(0), AlbaTypes::Bigint(0), AlbaTypes::Bigint(0)]);
+        query.column_names = vec!["column".to_string(), "rows".to_string(), "distinct_values".to_string(), "distinct_keys".to_string(), "histogram_buckets".to_string()];
+        let rows = self.columns.iter().map(|c| vec![
+            AlbaTypes::Text(c.name.clone()),
+            AlbaTypes::Bigint(self.row_count as i64),
+            AlbaTypes::Bigint(c.distinct_values as i64),
+            AlbaTypes::Bigint(c.distinct_keys as i64),
+            AlbaTypes::Bigint(c.histogram.len().saturating_sub(1) as i64),
+        ]).collect();
+        query.rows = (query.column_names.clone(), rows);
+        query
+    }
+}
+
+/// Reads every committed row of the container once and builds its statistics.
+pub async fn analyze(container : Arc<RwLock<Container>>) -> Result<ContainerStatistics, Error>{
+    let (arguments, headers) = {
+        let container = container.read().await;
+        let arguments = container.search_arguments(QueryConditions::default(), None);
+        (arguments, container.headers.clone())
+    };
+    let rows = search_direct(container, arguments).await?;
+
+    let mut keys : Vec<Vec<u64>> = vec![Vec::with_capacity(rows.len()); headers.len()];
+    let mut fingerprints : Vec<AHashSet<u64>> = vec![AHashSet::new(); headers.len()];
+    for (row, _) in rows.iter(){
+        for (index, value) in row.iter().enumerate().take(headers.len()){
+            keys[index].push(value.get_index());
+            fingerprints[index].insert(value.fingerprint());
+        }
+    }
+    let columns = headers.iter().zip(keys).zip(fingerprints.iter())
+        .map(|((header, keys), fingerprints)| ColumnStatistics::new(header.0.clone(), keys, fingerprints))
+        .collect();
+
+    Ok(ContainerStatistics{
+        row_count: rows.len() as u64,
+        analyzed_at: chrono::Local::now().to_rfc3339(),
+        columns,
+    })
+}
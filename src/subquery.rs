@@ -0,0 +1,125 @@
+use std::io::Error;
+
+use crate::{database::is_aggregate, expression::Expression, gerr, join::conjunction, lexer_functions::Token, parser::debug_tokens, projection::{Projection, ProjectionItem}, query::PrimitivePredicate, AST};
+
+/// The containers of the outer command, with their column names, that a subquery can refer to.
+pub type OuterSources = [(String, Vec<String>)];
+
+/// A comparison of a subquery column with a column of the outer row, `(container, column)`.
+struct Correlation{
+    inner : String,
+    outer : (String, String),
+}
+
+/// The SEARCH of an EXISTS, parsed and ready to run once.
+pub enum ExistsSubquery{
+    /// Does not depend on the outer row, the EXISTS is the same for all of them.
+    Uncorrelated(AST),
+    /// Compares a column of its container with the column `outer` of the outer row. The comparison
+    /// is taken out of its WHERE and the search returns that column instead, so the EXISTS becomes
+    /// `outer IN (values)`.
+    Correlated{ search : AST, outer : (String, String) },
+}
+
+/// The IN and EXISTS subqueries of a WHERE, which have to run before it can be planned.
+pub fn subquery_predicates<'a>(predicate : &'a mut PrimitivePredicate, output : &mut Vec<&'a mut PrimitivePredicate>){
+    if matches!(predicate, PrimitivePredicate::InSearch(..) | PrimitivePredicate::Exists(_)){
+        output.push(predicate);
+        return
+    }
+    match predicate{
+        PrimitivePredicate::Not(inner) => subquery_predicates(inner, output),
+        PrimitivePredicate::And(terms) | PrimitivePredicate::Or(terms) => terms.iter_mut().for_each(|t| subquery_predicates(t, output)),
+        _ => {}
+    }
+}
+
+/// Parses the SEARCH of `column IN (SEARCH ...)`, which must not refer to the outer row.
+pub fn parse_in(command : &Vec<Token>, outer : &OuterSources) -> Result<AST, Error>{
+    let (search, correlations) = parse_subquery(command, outer)?;
+    if !correlations.is_empty(){
+        return Err(gerr("The SEARCH of IN can not refer to the outer row, write it as a correlated EXISTS"))
+    }
+    Ok(search)
+}
+
+/// Parses the SEARCH of `EXISTS (SEARCH ...)`. It refers to the outer row with one term
+/// `column = container.column` among the ones joined by AND at the top of its WHERE.
+pub fn parse_exists(command : &Vec<Token>, outer : &OuterSources) -> Result<ExistsSubquery, Error>{
+    let (mut search, mut correlations) = parse_subquery(command, outer)?;
+    let Correlation { inner, outer } = match correlations.len(){
+        0 => return Ok(ExistsSubquery::Uncorrelated(search)),
+        1 => correlations.remove(0),
+        _ => return Err(gerr("A correlated EXISTS can compare only one of its columns with the outer row")),
+    };
+    let AST::Search(structure) = &mut search else {
+        return Err(gerr("The subquery of EXISTS must be a SEARCH"))
+    };
+    if is_aggregate(structure) || structure.join.is_some(){
+        return Err(gerr("A correlated EXISTS can not use aggregates, GROUP BY, HAVING or JOIN"))
+    }
+    if structure.window.limit.is_some() || structure.window.offset > 0{
+        return Err(gerr("A correlated EXISTS can not use LIMIT or OFFSET"))
+    }
+    structure.projection = Projection { items: vec![ProjectionItem::Column { name: inner, alias: None }] };
+    structure.order_by.clear();
+    Ok(ExistsSubquery::Correlated { search, outer })
+}
+
+/// The parsed subquery and its comparisons with the outer row, taken out of its WHERE.
+fn parse_subquery(command : &Vec<Token>, outer : &OuterSources) -> Result<(AST, Vec<Correlation>), Error>{
+    let mut search = debug_tokens(command)?;
+    let AST::Search(structure) = &mut search else {
+        return Ok((search, Vec::new()))
+    };
+    let terms = match structure.conditions.take(){
+        None => Vec::new(),
+        Some(PrimitivePredicate::And(terms)) => terms,
+        Some(term) => vec![term],
+    };
+    let (mut correlations, mut kept) = (Vec::new(), Vec::new());
+    for term in terms{
+        match correlation(&term, outer){
+            Some(pair) => correlations.push(pair),
+            None => kept.push(term),
+        }
+    }
+    structure.conditions = conjunction(kept);
+    if let Some(conditions) = &structure.conditions && refers_to_outer(conditions, outer){
+        return Err(gerr("A subquery can only refer to the outer row with 'column' = 'container.column' joined by AND to the rest of its WHERE"))
+    }
+    Ok((search, correlations))
+}
+
+/// The outer column a value written `container.column` names.
+fn outer_column(value : &str, outer : &OuterSources) -> Option<(String, String)>{
+    outer.iter().find_map(|(container, columns)| {
+        let column = value.strip_prefix(container.as_str())?.strip_prefix('.')?;
+        columns.iter().any(|c| c == column).then(|| (container.clone(), column.to_string()))
+    })
+}
+
+fn correlation(term : &PrimitivePredicate, outer : &OuterSources) -> Option<Correlation>{
+    match term{
+        PrimitivePredicate::Comparison(Token::String(inner), Token::Operator(operator), Token::String(value)) if operator == "=" || operator == "==" => {
+            outer_column(value, outer).map(|column| Correlation { inner: inner.clone(), outer: column })
+        },
+        PrimitivePredicate::Compare(Expression::Column(inner), Token::Operator(operator), Expression::Column(value)) if operator == "=" || operator == "==" => {
+            outer_column(value, outer).map(|column| Correlation { inner: inner.clone(), outer: column })
+        },
+        _ => None
+    }
+}
+
+fn refers_to_outer(predicate : &PrimitivePredicate, outer : &OuterSources) -> bool{
+    let named = |value : &Token| matches!(value, Token::String(v) if outer_column(v, outer).is_some());
+    match predicate{
+        PrimitivePredicate::Comparison(_, _, value) => named(value),
+        PrimitivePredicate::In(_, values) => values.iter().any(named),
+        PrimitivePredicate::Between(_, low, high) => named(low) || named(high),
+        PrimitivePredicate::Compare(..) => crate::join::columns(predicate).iter().any(|c| outer_column(c, outer).is_some()),
+        PrimitivePredicate::Not(inner) => refers_to_outer(inner, outer),
+        PrimitivePredicate::And(terms) | PrimitivePredicate::Or(terms) => terms.iter().any(|t| refers_to_outer(t, outer)),
+        _ => false
+    }
+}